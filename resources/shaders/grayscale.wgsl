@@ -0,0 +1,10 @@
+// Example for --shader grayscale: the frame in shades of gray. Custom shaders
+// go after src/screen.wgsl, so they only need an fs_main and can use what's
+// declared there: screen, screen_sampler, the uniforms in u, SCREEN and PI
+
+@fragment
+fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(screen, screen_sampler, vertex.uv, 0.0).rgb;
+    let gray = dot(color, vec3<f32>(0.299, 0.587, 0.114));
+    return vec4<f32>(vec3<f32>(gray), 1.0);
+}
@@ -0,0 +1,90 @@
+//
+//      Headless benchmark
+//
+
+// `rustboy-emu bench <rom> --frames N` runs the rom without a window as fast
+// as possible and prints emulation speed. A second, shorter run times every
+// subsystem separately; timing each instruction slows things down, so its
+// numbers are only good as proportions.
+
+use std::time::Instant;
+
+use config::Config;
+use emulator::{Emulator, SCREEN_REFRESH_INTERVAL};
+use input::Joypad;
+use perf::{self, SubsystemTimes};
+
+pub const USAGE: &'static str =
+"USAGE: rustboy-emu bench <path/to/rom> [--frames N] [OPTIONS]";
+
+const DEFAULT_FRAMES: u32 = 3600;
+// Frame rate of the real hardware
+const GB_FPS: f64 = 4194304. / SCREEN_REFRESH_INTERVAL as f64;
+
+// Arguments after "bench"
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut frames = DEFAULT_FRAMES;
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--frames" {
+            let val = try!(args.next().ok_or("--frames needs a value"));
+            frames = try!(val.parse().map_err(|_| format!("Invalid frame count \"{}\"", val)));
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    let mut config = try!(Config::from_args(&rest));
+    config.uncapped = true;
+
+    let mut emu = Emulator::new(&config);
+    println!("Benchmarking {} for {} frames", emu.game_title(), frames);
+
+    // Speed
+    let start = Instant::now();
+    let requested = frames;
+    let frames = run_frames(&mut emu, frames);
+    let secs = perf::to_ms(start.elapsed()) / 1000.;
+    if frames == 0 {
+        return Err(String::from("No frames were run"));
+    }
+
+    let fps = frames as f64 / secs;
+    println!("{:.2}s, {:.1} frames/s ({:.1}x real time), {:.0} cycles/s",
+        secs, fps, fps / GB_FPS, fps * SCREEN_REFRESH_INTERVAL as f64);
+
+    // Breakdown, the CPU is stuck in STOP if it ended early
+    if frames < requested {
+        return Ok(());
+    }
+    let profile_frames = (frames / 10).max(60);
+    emu.profile = Some(SubsystemTimes::default());
+    let profile_frames = run_frames(&mut emu, profile_frames);
+    let times = emu.profile.take().unwrap();
+
+    let total = perf::to_ms(times.total());
+    println!("Breakdown over {} frames:", profile_frames);
+    for &(name, time) in [("CPU", times.cpu), ("Timer", times.timer),
+                          ("GPU", times.gpu), ("Frame end", times.frame_end)].iter() {
+        let ms = perf::to_ms(time);
+        println!("  {:<10} {:>9.1}ms {:>5.1}%", name, ms, ms / total * 100.);
+    }
+    Ok(())
+}
+
+// Returns the frames actually run, STOP ends the benchmark early
+fn run_frames(emu: &mut Emulator, frames: u32) -> u32 {
+    let mut done = 0;
+    while done < frames {
+        let events = emu.step_frame(Joypad::default());
+        if events.stopped {
+            println!("STOP executed after {} frames", done);
+            break;
+        }
+        if events.frame_ready {
+            done += 1;
+        }
+    }
+    done
+}
@@ -0,0 +1,302 @@
+//
+//      Breakpoints
+//
+
+// Addresses execution pauses at, before the instruction there runs. The
+// emulator checks them between instructions (see Emulator::run_frame) and
+// the console lists, adds and deletes them. One in 0x4000-0x7FFF can be
+// limited to a ROM bank, so it only stops the code it was meant for and not
+// whatever else gets mapped there.
+//
+// Watchpoints stop execution after an instruction reads or writes an address
+// in their range instead. Memory notes the first access of the instruction
+// and the emulator stops once it's done, with the instruction's address.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    // Only stops with this ROM bank mapped, any bank when None
+    pub bank: Option<u8>,
+    // Times execution stopped here
+    pub hits: u32,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(bank) = self.bank {
+            try!(write!(f, "{:02X}:", bank));
+        }
+        write!(f, "{:04X} (hit {} times)", self.addr, self.hits)
+    }
+}
+
+pub struct Breakpoints {
+    pub list: Vec<Breakpoint>,
+    // Index of the one execution last stopped at, until taken
+    hit: Option<usize>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { list: Vec::new(), hit: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // Returns its index
+    pub fn add(&mut self, bank: Option<u8>, addr: u16) -> Result<usize, String> {
+        if bank.is_some() && (addr < 0x4000 || addr > 0x7FFF) {
+            return Err(format!("{:04X} isn't in a switchable ROM bank", addr));
+        }
+        if self.list.iter().any(|b| b.addr == addr && b.bank == bank) {
+            return Err(format!("There's already a breakpoint at {:04X}", addr));
+        }
+        self.list.push(Breakpoint { addr: addr, bank: bank, hits: 0 });
+        Ok(self.list.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Breakpoint> {
+        if index >= self.list.len() {
+            return None;
+        }
+        self.hit = None;
+        Some(self.list.remove(index))
+    }
+
+    // Whether execution should stop before running the instruction at pc,
+    // with rom_bank mapped at 0x4000-0x7FFF
+    pub fn check(&mut self, pc: u16, rom_bank: u8) -> bool {
+        let found = self.list.iter()
+            .position(|b| b.addr == pc && b.bank.map_or(true, |bank| bank == rom_bank));
+        match found {
+            Some(i) => {
+                self.list[i].hits += 1;
+                self.hit = Some(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The breakpoint execution stopped at since the last call
+    pub fn take_hit(&mut self) -> Option<(usize, &Breakpoint)> {
+        match self.hit.take() {
+            Some(i) => Some((i, &self.list[i])),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    pub fn parse(s: &str) -> Result<WatchKind, String> {
+        match s {
+            "r" => Ok(WatchKind::Read),
+            "w" => Ok(WatchKind::Write),
+            "rw" => Ok(WatchKind::Access),
+            _ => Err(format!("Unknown watchpoint kind \"{}\", expected r, w or rw", s)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            WatchKind::Read => "r",
+            WatchKind::Write => "w",
+            WatchKind::Access => "rw",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    // Both ends included
+    pub start: u16,
+    pub end: u16,
+    pub hits: u32,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:<2} {:04X}", self.kind.name(), self.start));
+        if self.end != self.start {
+            try!(write!(f, "-{:04X}", self.end));
+        }
+        write!(f, " (hit {} times)", self.hits)
+    }
+}
+
+// An access that set a watchpoint off
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub addr: u16,
+    pub write: bool,
+    // The value before and after, the same for reads
+    pub old: u8,
+    pub new: u8,
+    // Instruction that did it
+    pub pc: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.write {
+            write!(f, "watchpoint {}: {:04X} written by the instruction at {:04X}, {:02X} -> {:02X}",
+                self.index, self.addr, self.pc, self.old, self.new)
+        } else {
+            write!(f, "watchpoint {}: {:04X} read by the instruction at {:04X}, {:02X}",
+                self.index, self.addr, self.pc, self.old)
+        }
+    }
+}
+
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+    // Noted by Memory during the instruction, pc isn't known yet
+    pending: Option<WatchHit>,
+    // The one execution last stopped at, until taken
+    hit: Option<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints { list: Vec::new(), pending: None, hit: None }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // Returns its index
+    pub fn add(&mut self, kind: WatchKind, start: u16, end: u16) -> Result<usize, String> {
+        if end < start {
+            return Err(format!("Range {:04X}-{:04X} ends before it starts", start, end));
+        }
+        self.list.push(Watchpoint { kind: kind, start: start, end: end, hits: 0 });
+        Ok(self.list.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index >= self.list.len() {
+            return None;
+        }
+        self.pending = None;
+        self.hit = None;
+        Some(self.list.remove(index))
+    }
+
+    // Called by Memory for every access the CPU makes. Only the first one
+    // in an instruction counts
+    pub fn access(&mut self, addr: u16, write: bool, old: u8, new: u8) {
+        if self.pending.is_some() {
+            return;
+        }
+        let found = self.list.iter().position(|w| addr >= w.start && addr <= w.end && match w.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        });
+        if let Some(i) = found {
+            self.list[i].hits += 1;
+            self.pending = Some(WatchHit { index: i, addr: addr, write: write, old: old, new: new, pc: 0 });
+        }
+    }
+
+    // The instruction at pc is done, returns whether it set one off
+    pub fn retire(&mut self, pc: u16) -> bool {
+        match self.pending.take() {
+            Some(mut hit) => {
+                hit.pc = pc;
+                self.hit = Some(hit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn peek_hit(&self) -> Option<&WatchHit> {
+        self.hit.as_ref()
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod breakpoints_tests {
+    use super::*;
+
+    #[test]
+    fn add_check_remove() {
+        let mut bps = Breakpoints::new();
+        assert_eq!(bps.add(None, 0x0150), Ok(0));
+        assert_eq!(bps.add(None, 0xC000), Ok(1));
+        assert!(bps.add(None, 0x0150).is_err());
+
+        assert!(!bps.check(0x0151, 1));
+        assert!(bps.take_hit().is_none());
+        assert!(bps.check(0xC000, 1));
+        assert_eq!(bps.take_hit().map(|(i, b)| (i, b.hits)), Some((1, 1)));
+        assert!(bps.take_hit().is_none());
+
+        assert_eq!(bps.remove(0).map(|b| b.addr), Some(0x0150));
+        assert!(bps.remove(1).is_none());
+        assert!(!bps.check(0x0150, 1));
+    }
+
+    #[test]
+    fn banks() {
+        let mut bps = Breakpoints::new();
+        assert_eq!(bps.add(Some(2), 0x4000), Ok(0));
+        assert_eq!(bps.add(Some(3), 0x4000), Ok(1));
+        assert!(bps.add(Some(2), 0x0150).is_err());
+
+        assert!(!bps.check(0x4000, 1));
+        assert!(bps.check(0x4000, 3));
+        assert_eq!(bps.take_hit().map(|(i, _)| i), Some(1));
+        assert_eq!(bps.list[1].to_string(), "03:4000 (hit 1 times)");
+
+        // Unqualified ones stop in any bank
+        bps.add(None, 0x5000).unwrap();
+        assert!(bps.check(0x5000, 7));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut wps = Watchpoints::new();
+        assert_eq!(wps.add(WatchKind::Write, 0xC000, 0xC0FF), Ok(0));
+        assert_eq!(wps.add(WatchKind::Read, 0xFF44, 0xFF44), Ok(1));
+        assert!(wps.add(WatchKind::Access, 0xD000, 0xCFFF).is_err());
+        assert_eq!(wps.list[0].to_string(), "w  C000-C0FF (hit 0 times)");
+
+        wps.access(0xC010, false, 0x12, 0x12);
+        wps.access(0xC100, true, 0x12, 0x34);
+        assert!(!wps.retire(0x0150));
+
+        // The first access of the instruction is the one reported
+        wps.access(0xC010, true, 0x12, 0x34);
+        wps.access(0xFF44, false, 0x90, 0x90);
+        assert!(wps.retire(0x0150));
+        assert_eq!(wps.take_hit(), Some(WatchHit {
+            index: 0, addr: 0xC010, write: true, old: 0x12, new: 0x34, pc: 0x0150,
+        }));
+        assert!(wps.take_hit().is_none());
+    }
+}
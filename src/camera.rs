@@ -0,0 +1,186 @@
+//
+//      Game Boy Camera photos
+//
+
+// `rustboy-emu camera export <save> [dir]` decodes the photos in a Game Boy
+// Camera save into PNGs, `camera import <save> <slot> <png>` replaces one with
+// an edited image. This reads the .sav directly, no emulation (or printer)
+// involved, so saves dumped from a real cartridge work too.
+//
+// The 128KB of cartridge RAM hold 30 photo slots of 128x112 pixels, stored as
+// 16x14 tiles in the usual 2bpp format starting at 0x2000, a slot every
+// 0x1000 bytes. The byte per slot at 0x11B2 is the photo's number in the
+// album, or 0xFF if the slot is free.
+
+use std::path::{Path, PathBuf};
+
+use image::{self, ColorType};
+
+use saves;
+
+pub const USAGE: &'static str =
+"USAGE: rustboy-emu camera export <path/to/save> [output dir]
+       rustboy-emu camera import <path/to/save> <slot> <photo.png>";
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 112;
+const NUM_SLOTS: usize = 30;
+const PHOTOS_START: usize = 0x2000;
+const SLOT_SIZE: usize = 0x1000;
+const ALBUM_INDEX: usize = 0x11B2;
+const FREE_SLOT: u8 = 0xFF;
+const SAVE_SIZE: usize = 0x20000;
+
+// Shade 0 is white
+const GRAYS: [u8; 4] = [255, 170, 85, 0];
+
+// Arguments after "camera"
+pub fn run(args: &[String]) -> Result<(), String> {
+    match (args.get(0).map(|s| s.as_str()), args.len()) {
+        (Some("export"), 2) | (Some("export"), 3) => {
+            let dir = args.get(2).map(PathBuf::from).unwrap_or(PathBuf::from("."));
+            export(Path::new(&args[1]), &dir)
+        }
+        (Some("import"), 4) => {
+            let slot = try!(args[2].parse().map_err(|_| format!("Invalid slot \"{}\"", args[2])));
+            import(Path::new(&args[1]), slot, Path::new(&args[3]))
+        }
+        _ => Err(String::from("Expected export or import")),
+    }
+}
+
+fn read_save(path: &Path) -> Result<Vec<u8>, String> {
+    let sram = try!(saves::read(path).map_err(|e| format!("{}: {}", path.display(), e)));
+    if sram.len() != SAVE_SIZE {
+        return Err(format!("{} is {} bytes, Game Boy Camera saves are {}", path.display(), sram.len(), SAVE_SIZE));
+    }
+    Ok(sram)
+}
+
+fn export(save: &Path, dir: &Path) -> Result<(), String> {
+    let sram = try!(read_save(save));
+    let slots = used_slots(&sram);
+    if slots.is_empty() {
+        println!("No photos in {}", save.display());
+        return Ok(());
+    }
+
+    for &(slot, number) in slots.iter() {
+        let pixels: Vec<u8> = decode_photo(&sram, slot).iter().map(|&s| GRAYS[s as usize]).collect();
+        let path = dir.join(format!("slot_{:02}.png", slot));
+        try!(image::save_buffer(&path, &pixels, WIDTH as u32, HEIGHT as u32, ColorType::Gray(8))
+            .map_err(|e| format!("{}: {}", path.display(), e)));
+        println!("Photo {} (slot {}) -> {}", number + 1, slot, path.display());
+    }
+    Ok(())
+}
+
+fn import(save: &Path, slot: usize, png: &Path) -> Result<(), String> {
+    let mut sram = try!(read_save(save));
+    if !used_slots(&sram).iter().any(|&(s, _)| s == slot) {
+        return Err(format!("Slot {} has no photo, only existing photos can be replaced", slot));
+    }
+
+    let img = try!(image::open(png).map_err(|e| format!("{}: {}", png.display(), e))).to_luma();
+    if img.width() as usize != WIDTH || img.height() as usize != HEIGHT {
+        return Err(format!("{} is {}x{}, photos are {}x{}", png.display(),
+            img.width(), img.height(), WIDTH, HEIGHT));
+    }
+    let mut shades = Vec::with_capacity(WIDTH * HEIGHT);
+    for y in 0..HEIGHT as u32 {
+        for x in 0..WIDTH as u32 {
+            shades.push(shade(img.get_pixel(x, y).data[0]));
+        }
+    }
+
+    encode_photo(&mut sram, slot, &shades);
+    try!(saves::write_atomic(save, &sram).map_err(|e| format!("{}: {}", save.display(), e)));
+    println!("Replaced slot {} with {}", slot, png.display());
+    Ok(())
+}
+
+// (slot, number in the album) of every photo taken
+pub fn used_slots(sram: &[u8]) -> Vec<(usize, u8)> {
+    (0..NUM_SLOTS)
+        .map(|slot| (slot, sram[ALBUM_INDEX + slot]))
+        .filter(|&(_, number)| number != FREE_SLOT)
+        .collect()
+}
+
+// Byte offset of the pixel's tile row, and the pixel's bit in it
+fn pixel_location(slot: usize, x: usize, y: usize) -> (usize, u8) {
+    let tile = (y / 8) * (WIDTH / 8) + x / 8;
+    let offset = PHOTOS_START + slot * SLOT_SIZE + tile * 16 + (y % 8) * 2;
+    (offset, 7 - (x % 8) as u8)
+}
+
+// A shade (0-3) per pixel, row by row
+pub fn decode_photo(sram: &[u8], slot: usize) -> Vec<u8> {
+    let mut shades = Vec::with_capacity(WIDTH * HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let (offset, bit) = pixel_location(slot, x, y);
+            let lsb = (sram[offset] >> bit) & 1;
+            let msb = (sram[offset + 1] >> bit) & 1;
+            shades.push(msb << 1 | lsb);
+        }
+    }
+    shades
+}
+
+pub fn encode_photo(sram: &mut [u8], slot: usize, shades: &[u8]) {
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let (offset, bit) = pixel_location(slot, x, y);
+            let shade = shades[y * WIDTH + x];
+            sram[offset] = sram[offset] & !(1 << bit) | (shade & 1) << bit;
+            sram[offset + 1] = sram[offset + 1] & !(1 << bit) | (shade >> 1 & 1) << bit;
+        }
+    }
+}
+
+// Nearest of the 4 shades to a gray level
+fn shade(gray: u8) -> u8 {
+    ((255 - gray as u32 + 42) / 85) as u8
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod camera_tests {
+    use super::*;
+
+    #[test]
+    fn photo_round_trip() {
+        let mut sram = vec![0u8; SAVE_SIZE];
+        for b in sram[ALBUM_INDEX..ALBUM_INDEX + NUM_SLOTS].iter_mut() {
+            *b = FREE_SLOT;
+        }
+        sram[ALBUM_INDEX + 3] = 0;
+
+        let shades: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| (i % 7 % 4) as u8).collect();
+        encode_photo(&mut sram, 3, &shades);
+        assert_eq!(decode_photo(&sram, 3), shades);
+        assert_eq!(used_slots(&sram), vec![(3, 0)]);
+
+        // Top left pixel is the MSB of the first tile's first two bytes
+        let start = PHOTOS_START + 3 * SLOT_SIZE;
+        assert_eq!(sram[start] >> 7, shades[0] & 1);
+        assert_eq!(sram[start + 1] >> 7, shades[0] >> 1);
+    }
+
+    #[test]
+    fn gray_levels() {
+        for (i, &gray) in GRAYS.iter().enumerate() {
+            assert_eq!(shade(gray) as usize, i);
+        }
+        // In between they go to the closest
+        assert_eq!(shade(200), 1);
+        assert_eq!(shade(213), 0);
+        assert_eq!(shade(212), 1);
+        assert_eq!(shade(40), 3);
+        assert_eq!(shade(45), 2);
+    }
+}
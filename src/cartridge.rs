@@ -62,9 +62,11 @@ impl CartridgeHeader {
         use std::str;
         use std::env;
         
+        // The last byte is the CGB flag on color games
+        let len = if self.cgb_flag() & 0x80 != 0 { 15 } else { 16 };
         let args: Vec<_> = env::args().collect();
         let mut title = String::from(
-            match str::from_utf8(&self.game_title) {
+            match str::from_utf8(&self.game_title[..len]) {
                 Ok(val) => val,
                 Err(err) => {
                     warn!("Couldn't read rom name from header, using file name instead");
@@ -77,6 +79,44 @@ impl CartridgeHeader {
         title.drain(end_offset..).collect::<String>();
         title
     }
+
+    // SGB functions need both the SGB flag and the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licence_code == 0x33
+    }
+
+    // Raw title bytes, the last one doubles as the CGB flag in newer games
+    pub fn title_bytes(&self) -> [u8; 16] {
+        self.game_title
+    }
+
+    // 80h - Game supports CGB functions, but works on old gameboys also.
+    // C0h - Game works on CGB only (physically the same as 80h).
+    pub fn cgb_flag(&self) -> u8 {
+        self.game_title[15]
+    }
+
+    pub fn ram_size(&self) -> u8 {
+        self.ram_size
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    // Stored big endian, unlike everything else
+    pub fn global_checksum(&self) -> u16 {
+        u16::from_be(self.global_checksum)
+    }
+
+    // Games published by Nintendo, used by the CGB to pick DMG palettes
+    pub fn is_nintendo(&self) -> bool {
+        match self.old_licence_code {
+            0x01 => true,
+            0x33 => self.new_licence_code == [b'0', b'1'],
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Debug for CartridgeHeader {
@@ -104,6 +144,18 @@ impl fmt::Debug for CartridgeHeader {
 }
 
 
+// Bytes of external RAM for a header RAM size code
+pub fn ram_size_bytes(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,      // 2KB
+        0x02 => 0x2000,     // 8KB
+        0x03 => 0x8000,     // 32KB, 4 banks
+        0x04 => 0x20000,    // 128KB, 16 banks
+        0x05 => 0x10000,    // 64KB, 8 banks
+        _ => 0,
+    }
+}
+
 pub fn read_header_impl(emu: &Emulator) -> CartridgeHeader {
     use std::slice;
     use std::io::Read;
@@ -144,4 +196,21 @@ mod cart_tests {
 
         assert_eq!(0x50, mem::size_of::<CartridgeHeader>());
     }
+
+    #[test]
+    fn titles() {
+        let mut header: CartridgeHeader = Default::default();
+        header.game_title = *b"TETRIS\0\0\0\0\0\0\0\0\0\0";
+        assert_eq!(header.get_game_title(), "TETRIS");
+        header.game_title = *b"CPU_INSTRS\0\0\0\0\0\x80";
+        assert_eq!(header.get_game_title(), "CPU_INSTRS");
+    }
+
+    #[test]
+    fn ram_sizes() {
+        assert_eq!(ram_size_bytes(0x00), 0);
+        assert_eq!(ram_size_bytes(0x01), 2 * 1024);
+        assert_eq!(ram_size_bytes(0x03), 32 * 1024);
+        assert_eq!(ram_size_bytes(0x05), 64 * 1024);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,302 @@
+//
+//      DMG colorization on the CGB
+//
+
+// When a DMG-only game boots on a CGB, the boot ROM picks a set of BG/OBJ0/OBJ1
+// palettes for it. Games published by Nintendo are looked up by the sum of the
+// title bytes (plus the 4th title letter for colliding sums), everything else
+// gets the default palette. The player can also pick one of 12 palettes by
+// holding a button combination while the logo is shown.
+//
+// The tables below are the ones from the CGB boot ROM, as laid out in
+// SameBoy's open source boot ROM.
+
+use cartridge::CartridgeHeader;
+use gpu::{self, Palette};
+
+pub struct CompatPalettes {
+    pub bg: Palette,
+    pub obp0: Palette,
+    pub obp1: Palette,
+}
+
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, // Default
+    0x88, // ALLEY WAY
+    0x16, // YAKUMAN
+    0x36, // BASEBALL, (Game and Watch 2)
+    0xD1, // TENNIS
+    0xDB, // TETRIS
+    0xF2, // QIX
+    0x3C, // DR.MARIO
+    0x8C, // RADARMISSION
+    0x92, // F1RACE
+    0x3D, // YOSSY NO TAMAGO
+    0x5C,
+    0x58, // X
+    0xC9, // MARIOLAND2
+    0x3E, // YOSSY NO COOKIE
+    0x70, // ZELDA
+    0x1D,
+    0x59,
+    0x69, // TETRIS FLASH
+    0x19, // DONKEY KONG
+    0x35, // MARIO'S PICROSS
+    0xA8,
+    0x14, // POKEMON RED, (GAMEBOYCAMERA G)
+    0xAA, // POKEMON GREEN
+    0x75, // PICROSS 2
+    0x95, // YOSSY NO PANEPON
+    0x99, // KIRAKIRA KIDS
+    0x34, // GAMEBOY GALLERY
+    0x6F, // POCKETCAMERA
+    0x15,
+    0xFF, // BALLOON KID
+    0x97, // KINGOFTHEZOO
+    0x4B, // DMG FOOTBALL
+    0x90, // WORLD CUP
+    0x17, // OTHELLO
+    0x10, // SUPER RC PRO-AM
+    0x39, // DYNABLASTER
+    0xF7, // BOY AND BLOB GB2
+    0xF6, // MEGAMAN
+    0xA2, // STAR WARS-NOA
+    0x49,
+    0x4E, // WAVERACE
+    0x43,
+    0x68, // LOLO2
+    0xE0, // YOSHI'S COOKIE
+    0x8B, // MYSTIC QUEST
+    0xF0,
+    0xCE, // TOPRANKINGTENNIS
+    0x0C, // MANSELL
+    0x29, // MEGAMAN3
+    0xE8, // SPACE INVADERS
+    0xB7, // GAME&WATCH
+    0x86, // DONKEYKONGLAND95
+    0x9A, // ASTEROIDS/MISCMD
+    0x52, // STREET FIGHTER 2
+    0x01, // DEFENDER/JOUST
+    0x9D, // KILLERINSTINCT95
+    0x71, // TETRIS BLAST
+    0x9C, // PINOCCHIO
+    0xBD,
+    0x5D, // BA.TOSHINDEN
+    0x6D, // NETTOU KOF 95
+    0x67,
+    0x3F, // TETRIS PLUS
+    0x6B, // DONKEYKONGLAND 3
+    // From here on the 4th letter of the title is checked too
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27,
+    0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+const FIRST_DUPLICATE: usize = 65;
+const DUPLICATES: usize = 14;
+
+// 4th title letter for each of the checksums above, in rows of 14
+//   ???, SUPER MARIOLAND, GOLF, SOLARSTRIKER, GBWARS, KAERUNOTAMENI, ???,
+//   POKEMON BLUE, DONKEYKONGLAND, GAMEBOY GALLERY2, DONKEYKONGLAND 2,
+//   KID ICARUS, TETRIS2, ???,
+//   MOGURANYA, ???, GALAGA&GALAXIAN, BT2RAGNAROKWORLD, KEN GRIFFEY JR, ???,
+//   MAGNETIC SOCCER, VEGAS STAKES, ???, MILLI/CENTI/PEDE, MARIO & YOSHI,
+//   SOCCER, POKEBOM, G&W GALLERY,
+//   TETRIS ATTACK
+const DUPLICATE_LETTERS: &'static [u8] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination for every entry in TITLE_CHECKSUMS, followed by the
+// entries for the duplicated checksums (one per letter in DUPLICATE_LETTERS)
+const COMBINATION_PER_CHECKSUM: [u8; 94] = [
+     0,  4,  5, 35, 34,  3, 31, 15, 10,  5, 19, 36,  7, 37, 30, 44,
+    21, 32, 31, 20,  5, 33, 13, 14,  5, 29,  5, 18,  9,  3,  2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+     5, 42,  6,  5, 33, 25, 42, 42, 40,  2, 16, 25, 42, 42,  5,  0,
+    39,
+    36, 22, 25,  6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46,  6, 27,  0, 47, 41, 41,  0,  0, 34, 23, 18, 29,
+    28,
+];
+
+// OBJ0, OBJ1 and BG palettes of each combination, as offsets into COLORS.
+// Most start at a palette boundary but a few of them straddle two palettes.
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [ 4*4,  4*4, 29*4], // 0, Right + A (default)
+    [18*4, 18*4, 18*4], // 1, Right
+    [20*4, 20*4, 20*4],
+    [24*4, 24*4, 24*4], // 3, Down + A
+    [ 9*4,  9*4,  9*4],
+    [ 0*4,  0*4,  0*4], // 5, Up
+    [27*4, 27*4, 27*4], // 6, Right + B
+    [ 5*4,  5*4,  5*4], // 7, Left + B
+    [12*4, 12*4, 12*4], // 8, Down
+    [26*4, 26*4, 26*4],
+    [16*4,  8*4,  8*4],
+    [ 4*4, 28*4, 28*4],
+    [ 4*4,  2*4,  2*4],
+    [ 3*4,  4*4,  4*4],
+    [ 4*4, 29*4, 29*4],
+    [28*4,  4*4, 28*4],
+    [ 2*4, 17*4,  2*4],
+    [16*4, 16*4,  8*4],
+    [ 4*4,  4*4,  7*4],
+    [ 4*4,  4*4, 18*4],
+    [ 4*4,  4*4, 20*4],
+    [19*4, 19*4,  9*4],
+    [4*4-1, 4*4-1, 11*4],
+    [17*4, 17*4,  2*4],
+    [ 4*4,  4*4,  2*4],
+    [ 4*4,  4*4,  3*4],
+    [28*4, 28*4,  0*4],
+    [ 3*4,  3*4,  0*4],
+    [ 0*4,  0*4,  1*4], // 28, Up + B
+    [18*4, 22*4, 18*4],
+    [20*4, 22*4, 20*4],
+    [24*4, 22*4, 24*4],
+    [16*4, 22*4,  8*4],
+    [17*4,  4*4, 13*4],
+    [28*4-1, 0*4, 14*4],
+    [28*4-1, 4*4, 15*4],
+    [19*4, 22*4,  9*4],
+    [16*4, 28*4, 10*4],
+    [ 4*4, 23*4, 28*4],
+    [17*4, 22*4,  2*4],
+    [ 4*4,  0*4,  2*4], // 40, Left + A
+    [ 4*4, 28*4,  3*4],
+    [28*4,  3*4,  0*4],
+    [ 3*4, 28*4,  4*4], // 43, Up + A
+    [21*4, 28*4,  4*4],
+    [ 3*4, 28*4,  0*4],
+    [25*4,  3*4, 28*4],
+    [ 0*4, 28*4,  8*4],
+    [ 4*4,  3*4, 28*4], // 48, Left
+    [28*4,  3*4,  6*4], // 49, Down + B
+    [ 4*4, 28*4, 29*4],
+];
+
+// RGB555 colors, 4 per palette
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Button combinations that can be held during the boot logo
+const MANUAL_COMBINATIONS: [(&'static str, usize); 12] = [
+    ("up", 5),    ("up+a", 43),    ("up+b", 28),
+    ("left", 48), ("left+a", 40),  ("left+b", 7),
+    ("down", 8),  ("down+a", 3),   ("down+b", 49),
+    ("right", 1), ("right+a", 0),  ("right+b", 6),
+];
+
+// Pick the palettes the CGB boot ROM would use for this game. `buttons` is a
+// manual selection like "up+a", which overrides the automatic one.
+pub fn palettes(header: &CartridgeHeader, buttons: Option<&str>) -> CompatPalettes {
+    let combination = match buttons {
+        Some(keys) => match MANUAL_COMBINATIONS.iter().find(|c| c.0 == keys) {
+            Some(&(_, combination)) => combination,
+            None => {
+                warn!("Unknown CGB palette selection \"{}\", using the default", keys);
+                0
+            }
+        },
+        None => combination_for(header),
+    };
+    info!("CGB compatibility palette combination: {}", combination);
+
+    let offsets = COMBINATIONS[combination];
+    CompatPalettes {
+        obp0: palette_at(offsets[0]),
+        obp1: palette_at(offsets[1]),
+        bg: palette_at(offsets[2]),
+    }
+}
+
+fn combination_for(header: &CartridgeHeader) -> usize {
+    // Only Nintendo's own games get special treatment
+    if !header.is_nintendo() {
+        return 0;
+    }
+    let title = header.title_bytes();
+    let checksum = title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+    let index = match TITLE_CHECKSUMS.iter().position(|&c| c == checksum) {
+        Some(index) => index,
+        None => return 0,
+    };
+
+    if index < FIRST_DUPLICATE {
+        return COMBINATION_PER_CHECKSUM[index] as usize;
+    }
+
+    // Several games share this checksum, tell them apart by their 4th letter
+    let mut i = index - FIRST_DUPLICATE;
+    while i < DUPLICATE_LETTERS.len() {
+        if DUPLICATE_LETTERS[i] == title[3] {
+            return COMBINATION_PER_CHECKSUM[FIRST_DUPLICATE + i] as usize;
+        }
+        i += DUPLICATES;
+    }
+    0
+}
+
+fn palette_at(offset: usize) -> Palette {
+    let mut pal = [[0; 4]; 4];
+    for i in 0..4 {
+        pal[i] = gpu::rgb555(COLORS[offset + i]);
+    }
+    pal
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod colorize_tests {
+    use super::*;
+
+    #[test]
+    fn tables_line_up() {
+        assert_eq!(TITLE_CHECKSUMS.len(), FIRST_DUPLICATE + DUPLICATES);
+        assert_eq!(COMBINATION_PER_CHECKSUM.len(),
+                   FIRST_DUPLICATE + DUPLICATE_LETTERS.len());
+        for offsets in COMBINATIONS.iter() {
+            for &offset in offsets.iter() {
+                assert!(offset + 4 <= COLORS.len());
+            }
+        }
+    }
+
+    #[test]
+    fn default_palette() {
+        let pal = palette_at(COMBINATIONS[0][2]);
+        assert_eq!(pal[0], [255, 255, 255, 255]);
+        assert_eq!(pal[3], [0, 0, 0, 255]);
+    }
+}
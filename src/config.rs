@@ -0,0 +1,566 @@
+//
+//      Command line options
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use emulator::Model;
+use saves::PostSaveHook;
+use splash::SplashKind;
+use gpu::BlendMode;
+use recording::RecordFormat;
+use filter::Filter;
+use display::{DisplayMode, Orientation};
+use terminal::TerminalMode;
+use watch;
+use debugger;
+use cpu::trace::TraceFormat;
+
+// Window scales --scale accepts
+pub const MIN_SCALE: u32 = 1;
+pub const MAX_SCALE: u32 = 8;
+
+// Highest percentage --overclock accepts
+pub const MAX_OVERCLOCK: u32 = 400;
+
+pub const USAGE: &'static str =
+"USAGE: rustboy-emu [OPTIONS] <path/to/rom or dir>...
+       rustboy-emu bench <path/to/rom> [--frames N] [OPTIONS]
+       rustboy-emu camera <export|import> ...
+
+Several roms (or directories of them) can be given, Tab switches between them.
+
+OPTIONS:
+    --model <dmg|sgb|cgb>     Hardware to emulate (picked from the header by default)
+    --cgb-palette <keys>      Palette the CGB colorizes DMG games with, as the
+                              buttons held at boot: up, left+b, right+a, ...
+    --boot-splash <splash>    Show something before the game starts: logo (the
+                              scrolling logo), an image, or skip (default)
+    --palette <name>          Colors for DMG games: green, bw, puke-green,
+                              pocket, light or one from --palettes (C cycles
+                              through them, K picks one for the game)
+    --palettes <file>         Load more palettes, a line per palette:
+                              name | #E0F8D0 | #88C070 | #346856 | #081820
+    --pixel-fifo              Draw pixel by pixel like the hardware does, slower
+                              but effects changing registers mid-line show up
+    --scale <1-8>             How many times bigger than 160x144 the window is,
+                              4 by default (- and = change it)
+    --display <mode>          How the screen fills the window: integer (whole
+                              multiples, default), fit (keep the shape) or
+                              stretch
+    --rotate <degrees>        Turn the picture clockwise: 0, 90, 180 or 270
+    --mirror                  Flip the picture left to right
+    --filter <filter>         Smooth the screen: scale2x, xbr or none (default)
+    --frame-blend             Average every frame with the one before, for games
+                              that flicker sprites to make them see-through
+    --lcd-ghosting            Fade pixels between shades over a few frames like
+                              the DMG's slow LCD
+    --lcd-grid                Draw the gaps between the LCD's pixels over the
+                              screen (toggle with G)
+    --cpu-overlay             Show the CPU registers, PPU mode, LY and frame
+                              count over the screen (toggle with I)
+    --crt                     Curve the screen and add scanlines like an old TV,
+                              winit frontend only
+    --terminal <mode>         Play in the terminal instead of a window: blocks
+                              (color), braille or sixel
+    --shader <name|file>      WGSL fragment shader to draw the screen with, a file
+                              or one in resources/shaders, reloaded when it
+                              changes. winit frontend only
+    --unlocked-vram           Let the CPU use VRAM and OAM while the screen is
+                              being drawn, for debugging
+    --oam-bug                 Emulate the DMG's OAM corruption bug
+    --uncapped                Run as fast as possible instead of at 60 FPS
+                              (toggle with U)
+    --frame-skip <n/m>        While uncapped, don't draw n of every m frames
+                              (e.g. 3/4), the game runs the same
+    --scaled-screenshots      Save F12 screenshots at the window's size instead
+                              of the Game Boy's 160x144
+    --record-format <format>  What F9 records to: y4m (default, one file ffmpeg
+                              can read) or png (a directory of frames)
+    --run-frames <n>          Run n frames without a window and print a hash of
+                              the last one
+    --expect-hash <hex>       With --run-frames, exit with status 1 unless the
+                              hash is this
+    --frame-stats             Log frames slower than the 99th percentile, H dumps
+                              the recent frame times to frame_times.csv
+    --measure-latency         Log the time from key presses to the game reading
+                              them and to the frame showing up
+    --overclock <percent>     Run the CPU faster than the rest of the hardware,
+                              less slowdown in games that lag but inaccurate,
+                              up to 400%
+    --hardcore                Disable cheats, slowdown and overclocking (memory
+                              writes and stepping in the console, --overclock)
+                              for the session
+    --touch-controls          Draw a d-pad and buttons for touchscreens
+    --touch-layout <file>     Where the touch controls go (implies
+                              --touch-controls)
+    --touch-opacity <0-1>     How see-through the touch controls are
+    --console                 Read debugger commands (step, regs, read, ...)
+                              from the terminal
+    --trace <file>            Log every instruction to a file (- for stdout) to
+                              diff against other emulators' logs
+    --trace-format <format>   Lines of the --trace log: doctor (default, what
+                              Gameboy Doctor reads), cycles (doctor plus the
+                              cycle count), labels (doctor with PC's label in
+                              front) or wadatsumi
+    --break <addr>            Pause before running the instruction at addr (hex),
+                              bank:addr for one in a ROM bank. Can be repeated
+    --gdb <port>              Let gdb attach over TCP on this port
+                              (target remote localhost:<port>)
+    --sym <file>              Labels for the debugger, disassembly, profile and
+                              trace (the rom's .sym file by default)
+    --profile-game <file>     Count the cycles of every instruction the game
+                              runs, with its call stack, and write them as
+                              folded stacks for flamegraphs on exit
+    --heatmap                 Count memory accesses, M saves them to
+                              heatmap.png
+    --watch <expr>            Show an expression's value in the debug panel,
+                              e.g. \"[WRAM+0x123]*256+[WRAM+0x124]\"
+    --watch-csv <file>        Log the watched values every frame
+    --hd-pack <dir>           Replace tiles with high resolution versions, P
+                              dumps the tiles on screen to <dir>/dump
+    --mobile-adapter          Plug a Mobile Adapter GB into the serial port, its
+                              connections go out over TCP
+    --mobile-server <host>    Send the adapter's DNS queries to this community
+                              server (implies --mobile-adapter)
+    --discord <app id>        Show the game being played on Discord (needs the
+                              `discord` feature)
+    --post-save-cmd <cmd>     Shell command to run after the save file is
+                              written, its path is in $RUSTBOY_SAVE
+    --save-sync-dir <dir>     Copy the save file to this directory after writing it";
+
+#[derive(Default, Clone)]
+pub struct Config {
+    // The rom to start with, the first of `roms`
+    pub rom_path: String,
+    // Every rom given, directories are replaced by the roms in them. Tab
+    // switches between them
+    pub roms: Vec<String>,
+    pub model: Option<Model>,
+    pub cgb_palette: Option<String>,
+    // DMG palette to start with, and a file of more of them
+    pub palette: Option<String>,
+    pub palettes_file: Option<String>,
+
+    // Discord application to show Rich Presence through
+    pub discord_app_id: Option<u64>,
+
+    pub post_save_hook: Option<PostSaveHook>,
+
+    // Shown before the game starts, the boot ROM isn't run
+    pub boot_splash: SplashKind,
+
+    // Start without frame pacing
+    pub uncapped: bool,
+
+    // Frames not drawn while uncapped, n of every m
+    pub frame_skip: Option<(u32, u32)>,
+
+    // Use the pixel FIFO renderer
+    pub pixel_fifo: bool,
+
+    // Window size in multiples of the screen
+    pub scale: Option<u32>,
+
+    // How the screen is sized in the window
+    pub display: DisplayMode,
+    // Turned and/or mirrored
+    pub orientation: Orientation,
+
+    // Upscaling filter for the screen
+    pub filter: Filter,
+
+    // Show frames mixed with the previous ones
+    pub frame_blend: Option<BlendMode>,
+
+    // Pixel grid over the screen
+    pub lcd_grid: bool,
+
+    // Register overlay over the screen
+    pub cpu_overlay: bool,
+
+    // CRT shader in the winit frontend
+    pub crt: bool,
+    // Custom fragment shader for the winit frontend, see screen_shader.rs
+    pub shader: Option<String>,
+    // Draw in the terminal, see terminal.rs
+    pub terminal: Option<TerminalMode>,
+
+    // No VRAM/OAM access restrictions while the PPU draws
+    pub unlocked_vram: bool,
+
+    // Emulate the OAM bug, see Gpu::corrupt_oam
+    pub oam_bug: bool,
+
+    // Screenshots at the window scale rather than 1x
+    pub scaled_screenshots: bool,
+
+    // What recordings are saved as
+    pub record_format: RecordFormat,
+
+    // Keep frame time statistics and report stutters
+    pub frame_stats: bool,
+
+    // Headless run to check a frame hash, see framehash.rs
+    pub run_frames: Option<u32>,
+    pub expect_hash: Option<u32>,
+
+    // Report how long key presses take to reach the game and the screen
+    pub measure_latency: bool,
+
+    // Extra CPU speed in percent
+    pub overclock: u32,
+
+    // No cheats, slowdown or overclocking for the whole session
+    pub hardcore: bool,
+
+    // On-screen controls, their layout file and opacity
+    pub touch_controls: bool,
+    pub touch_layout: Option<String>,
+    pub touch_opacity: Option<f32>,
+
+    // Read debugger commands from the terminal
+    pub console: bool,
+
+    // Log every instruction to this file, or stdout for "-"
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+
+    // Addresses to pause at and the ROM banks they need, see breakpoints.rs
+    pub breakpoints: Vec<(Option<u8>, u16)>,
+
+    // Port the GDB stub listens on, see gdb.rs
+    pub gdb_port: Option<u16>,
+
+    // Symbol file to use instead of the one next to the rom
+    pub sym_file: Option<String>,
+
+    // Where to write the game code profile, see profiler.rs
+    pub profile_game: Option<String>,
+
+    // Count memory accesses for the heatmap
+    pub heatmap: bool,
+
+    // Watch expressions and the CSV file to log them to
+    pub watches: Vec<String>,
+    pub watch_csv: Option<String>,
+
+    // Directory of HD tile replacements
+    pub hd_pack: Option<String>,
+
+    // Mobile Adapter GB on the serial port, and the server standing in for
+    // every host the games look up
+    pub mobile_adapter: bool,
+    pub mobile_server: Option<String>,
+}
+
+impl Config {
+    // Parse the arguments, not including the program name
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--model" => {
+                    let val = try!(args.next().ok_or("--model needs a value"));
+                    config.model = Some(match val.to_lowercase().as_str() {
+                        "dmg" => Model::Dmg,
+                        "sgb" => Model::Sgb,
+                        "cgb" => Model::Cgb,
+                        _ => return Err(format!("Unknown model \"{}\"", val)),
+                    });
+                }
+                "--cgb-palette" => {
+                    let val = try!(args.next().ok_or("--cgb-palette needs a value"));
+                    config.cgb_palette = Some(val.to_lowercase());
+                }
+                "--palette" => {
+                    let val = try!(args.next().ok_or("--palette needs a name"));
+                    config.palette = Some(val.clone());
+                }
+                "--palettes" => {
+                    let val = try!(args.next().ok_or("--palettes needs a file"));
+                    config.palettes_file = Some(val.clone());
+                }
+                "--boot-splash" => {
+                    let val = try!(args.next().ok_or("--boot-splash needs logo, skip or an image"));
+                    config.boot_splash = try!(val.parse());
+                }
+                "--uncapped" => config.uncapped = true,
+                "--frame-skip" => {
+                    let val = try!(args.next().ok_or("--frame-skip needs n/m, like 3/4"));
+                    let bad = || format!("Invalid frame skip \"{}\", expected n/m with n below m, like 3/4", val);
+                    let mut parts = val.splitn(2, '/').map(|p| p.parse::<u32>());
+                    let skip = match (parts.next(), parts.next()) {
+                        (Some(Ok(n)), Some(Ok(m))) if n < m => (n, m),
+                        _ => return Err(bad()),
+                    };
+                    config.frame_skip = Some(skip);
+                }
+                "--pixel-fifo" => config.pixel_fifo = true,
+                "--scale" => {
+                    let val = try!(args.next().ok_or("--scale needs a number"));
+                    let scale = try!(val.trim_right_matches('x').parse()
+                        .map_err(|_| format!("Invalid scale \"{}\"", val)));
+                    if scale < MIN_SCALE || scale > MAX_SCALE {
+                        return Err(format!("Scale {} isn't between {} and {}", scale, MIN_SCALE, MAX_SCALE));
+                    }
+                    config.scale = Some(scale);
+                }
+                "--rotate" => {
+                    let val = try!(args.next().ok_or("--rotate needs 0, 90, 180 or 270"));
+                    config.orientation.rotation = try!(val.parse());
+                }
+                "--mirror" => config.orientation.mirror = true,
+                "--display" => {
+                    let val = try!(args.next().ok_or("--display needs integer, fit or stretch"));
+                    config.display = try!(val.parse());
+                }
+                "--filter" => {
+                    let val = try!(args.next().ok_or("--filter needs scale2x, xbr or none"));
+                    config.filter = try!(val.parse());
+                }
+                "--frame-blend" => config.frame_blend = Some(BlendMode::Average),
+                "--lcd-ghosting" => config.frame_blend = Some(BlendMode::Ghosting),
+                "--lcd-grid" => config.lcd_grid = true,
+                "--cpu-overlay" => config.cpu_overlay = true,
+                "--crt" => config.crt = true,
+                "--terminal" => {
+                    let val = try!(args.next().ok_or("--terminal needs blocks, braille or sixel"));
+                    config.terminal = Some(try!(val.parse()));
+                }
+                "--shader" => {
+                    let val = try!(args.next().ok_or("--shader needs a name or file"));
+                    config.shader = Some(val.clone());
+                }
+                "--unlocked-vram" => config.unlocked_vram = true,
+                "--oam-bug" => config.oam_bug = true,
+                "--scaled-screenshots" => config.scaled_screenshots = true,
+                "--record-format" => {
+                    let val = try!(args.next().ok_or("--record-format needs y4m or png"));
+                    config.record_format = try!(val.parse());
+                }
+                "--frame-stats" => config.frame_stats = true,
+                "--run-frames" => {
+                    let val = try!(args.next().ok_or("--run-frames needs a number"));
+                    config.run_frames = Some(try!(val.parse()
+                        .map_err(|_| format!("Invalid frame count \"{}\"", val))));
+                }
+                "--expect-hash" => {
+                    let val = try!(args.next().ok_or("--expect-hash needs a hash"));
+                    config.expect_hash = Some(try!(u32::from_str_radix(val.trim_left_matches("0x"), 16)
+                        .map_err(|_| format!("Invalid hash \"{}\"", val))));
+                }
+                "--measure-latency" => config.measure_latency = true,
+                "--overclock" => {
+                    let val = try!(args.next().ok_or("--overclock needs a percentage"));
+                    let percent = try!(val.trim_right_matches('%').parse()
+                        .map_err(|_| format!("Invalid overclock \"{}\"", val)));
+                    if percent > MAX_OVERCLOCK {
+                        return Err(format!("Overclock {}% is over {}%", percent, MAX_OVERCLOCK));
+                    }
+                    config.overclock = percent;
+                }
+                "--hardcore" => config.hardcore = true,
+                "--touch-controls" => config.touch_controls = true,
+                "--touch-layout" => {
+                    let val = try!(args.next().ok_or("--touch-layout needs a file"));
+                    config.touch_controls = true;
+                    config.touch_layout = Some(val.clone());
+                }
+                "--touch-opacity" => {
+                    let val = try!(args.next().ok_or("--touch-opacity needs a value"));
+                    let opacity: f32 = try!(val.parse().map_err(|_| format!("Invalid opacity \"{}\"", val)));
+                    if opacity < 0. || opacity > 1. {
+                        return Err(format!("Opacity {} isn't between 0 and 1", opacity));
+                    }
+                    config.touch_opacity = Some(opacity);
+                }
+                "--console" => config.console = true,
+                "--trace" => {
+                    let val = try!(args.next().ok_or("--trace needs a file, or - for stdout"));
+                    config.trace = Some(val.clone());
+                }
+                "--trace-format" => {
+                    let val = try!(args.next().ok_or("--trace-format needs doctor, cycles, labels or wadatsumi"));
+                    config.trace_format = try!(val.parse());
+                }
+                "--break" => {
+                    let val = try!(args.next().ok_or("--break needs an address"));
+                    config.breakpoints.push(try!(debugger::parse_bank_addr(val)));
+                }
+                "--sym" => {
+                    let val = try!(args.next().ok_or("--sym needs a file"));
+                    config.sym_file = Some(val.clone());
+                }
+                "--profile-game" => {
+                    let val = try!(args.next().ok_or("--profile-game needs a file"));
+                    config.profile_game = Some(val.clone());
+                }
+                "--gdb" => {
+                    let val = try!(args.next().ok_or("--gdb needs a port"));
+                    config.gdb_port = Some(try!(val.parse()
+                        .map_err(|_| format!("Invalid port \"{}\"", val))));
+                }
+                "--heatmap" => config.heatmap = true,
+                "--watch" => {
+                    let val = try!(args.next().ok_or("--watch needs an expression"));
+                    try!(watch::validate(val).map_err(|e| format!("Invalid watch \"{}\": {}", val, e)));
+                    config.watches.push(val.clone());
+                }
+                "--hd-pack" => {
+                    let val = try!(args.next().ok_or("--hd-pack needs a directory"));
+                    config.hd_pack = Some(val.clone());
+                }
+                "--mobile-adapter" => config.mobile_adapter = true,
+                "--mobile-server" => {
+                    let val = try!(args.next().ok_or("--mobile-server needs a host"));
+                    config.mobile_adapter = true;
+                    config.mobile_server = Some(val.clone());
+                }
+                "--watch-csv" => {
+                    let val = try!(args.next().ok_or("--watch-csv needs a file"));
+                    config.watch_csv = Some(val.clone());
+                }
+                "--discord" => {
+                    let val = try!(args.next().ok_or("--discord needs an application id"));
+                    config.discord_app_id = Some(try!(val.parse()
+                        .map_err(|_| format!("Invalid Discord application id \"{}\"", val))));
+                }
+                "--post-save-cmd" => {
+                    let val = try!(args.next().ok_or("--post-save-cmd needs a command"));
+                    config.post_save_hook = Some(PostSaveHook::Command(val.clone()));
+                }
+                "--save-sync-dir" => {
+                    let val = try!(args.next().ok_or("--save-sync-dir needs a directory"));
+                    config.post_save_hook = Some(PostSaveHook::CopyTo(PathBuf::from(val)));
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option \"{}\"", arg));
+                }
+                _ => {
+                    if Path::new(arg).is_dir() {
+                        config.roms.extend(try!(roms_in_dir(arg)));
+                    } else {
+                        config.roms.push(arg.clone());
+                    }
+                }
+            }
+        }
+
+        match config.roms.first() {
+            Some(rom) => config.rom_path = rom.clone(),
+            None => return Err(String::from("No rom given")),
+        }
+        if config.expect_hash.is_some() && config.run_frames.is_none() {
+            return Err(String::from("--expect-hash needs --run-frames"));
+        }
+        Ok(config)
+    }
+}
+
+// .gb and .gbc files in a directory, sorted by name
+fn roms_in_dir(dir: &str) -> Result<Vec<String>, String> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e)));
+    let mut roms: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc"),
+            None => false,
+        })
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    roms.sort();
+    if roms.is_empty() {
+        return Err(format!("No roms in {}", dir));
+    }
+    Ok(roms)
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use display::Rotation;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Config::from_args(&args)
+    }
+
+    #[test]
+    fn rom_only() {
+        let config = parse(&["tetris.gb"]).unwrap();
+        assert_eq!(config.rom_path, "tetris.gb");
+        assert_eq!(config.model, None);
+    }
+
+    #[test]
+    fn options() {
+        let config = parse(&["--model", "CGB", "tetris.gb", "--cgb-palette", "up+a"]).unwrap();
+        assert_eq!(config.model, Some(Model::Cgb));
+        assert_eq!(config.cgb_palette, Some(String::from("up+a")));
+        assert!(parse(&["--model", "gba", "tetris.gb"]).is_err());
+        assert!(parse(&["--model"]).is_err());
+        assert!(parse(&[]).is_err());
+        assert_eq!(parse(&["--discord", "1234", "tetris.gb"]).unwrap().discord_app_id, Some(1234));
+        assert!(parse(&["--discord", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--save-sync-dir", "sync", "tetris.gb"]).unwrap().post_save_hook,
+                   Some(PostSaveHook::CopyTo(PathBuf::from("sync"))));
+        assert!(parse(&["--hardcore", "tetris.gb"]).unwrap().hardcore);
+        assert_eq!(parse(&["--overclock", "50%", "tetris.gb"]).unwrap().overclock, 50);
+        assert!(parse(&["--overclock", "401", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--scale", "3x", "tetris.gb"]).unwrap().scale, Some(3));
+        assert!(parse(&["--scale", "9", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--frame-skip", "3/4", "tetris.gb"]).unwrap().frame_skip, Some((3, 4)));
+        assert!(parse(&["--frame-skip", "4/4", "tetris.gb"]).is_err());
+        assert!(parse(&["--frame-skip", "3", "tetris.gb"]).is_err());
+        let config = parse(&["--run-frames", "60", "--expect-hash", "0x1234ABCD", "tetris.gb"]).unwrap();
+        assert_eq!((config.run_frames, config.expect_hash), (Some(60), Some(0x1234ABCD)));
+        assert!(parse(&["--expect-hash", "1234ABCD", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--shader", "grayscale", "tetris.gb"]).unwrap().shader, Some(String::from("grayscale")));
+        assert_eq!(parse(&["--terminal", "sixel", "tetris.gb"]).unwrap().terminal, Some(TerminalMode::Sixel));
+        assert!(parse(&["--terminal", "tetris.gb"]).is_err());
+        let config = parse(&["--rotate", "90", "--mirror", "tetris.gb"]).unwrap();
+        assert_eq!(config.orientation, Orientation { rotation: Rotation::Deg90, mirror: true });
+        assert!(parse(&["--rotate", "45", "tetris.gb"]).is_err());
+        let config = parse(&["--trace", "-", "--trace-format", "cycles", "tetris.gb"]).unwrap();
+        assert_eq!((config.trace, config.trace_format), (Some(String::from("-")), TraceFormat::Cycles));
+        assert!(parse(&["--trace-format", "bgb", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--break", "$150", "--break", "2:4000", "tetris.gb"]).unwrap().breakpoints,
+                   vec![(None, 0x150), (Some(2), 0x4000)]);
+        assert!(parse(&["--break", "10000", "tetris.gb"]).is_err());
+        assert_eq!(parse(&["--gdb", "2345", "tetris.gb"]).unwrap().gdb_port, Some(2345));
+        assert!(parse(&["--gdb", "70000", "tetris.gb"]).is_err());
+        assert!(parse(&["--cpu-overlay", "tetris.gb"]).unwrap().cpu_overlay);
+        assert_eq!(parse(&["--profile-game", "game.folded", "tetris.gb"]).unwrap().profile_game,
+                   Some(String::from("game.folded")));
+        assert_eq!(parse(&["--sym", "game.sym", "tetris.gb"]).unwrap().sym_file, Some(String::from("game.sym")));
+    }
+
+    #[test]
+    fn playlist() {
+        use std::env;
+        use std::fs::File;
+
+        let dir = env::temp_dir().join("rustboy_config_tests");
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["b.gbc", "a.gb", "notes.txt"].iter() {
+            File::create(dir.join(name)).unwrap();
+        }
+        let dir_str = dir.to_string_lossy().into_owned();
+
+        let config = parse(&["tetris.gb", &dir_str]).unwrap();
+        assert_eq!(config.rom_path, "tetris.gb");
+        assert_eq!(config.roms, vec![String::from("tetris.gb"),
+            dir.join("a.gb").to_string_lossy().into_owned(),
+            dir.join("b.gbc").to_string_lossy().into_owned()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
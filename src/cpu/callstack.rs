@@ -0,0 +1,138 @@
+//
+//      Call stack
+//
+
+// The calls the game is currently inside of, worked out from the CALLs,
+// RSTs and interrupt dispatches the CPU makes, for the debugger's backtrace
+// and crash reports. The real stack isn't read back: a frame is gone once SP
+// moves above where its return address was pushed, so RET and RETI unwind
+// it just like games that pop the return address or reset SP do.
+
+use super::Interrupt;
+use symbols::{self, Symbols};
+
+// Recursion that never returns shouldn't grow it forever
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt(Interrupt),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // Where it went and where it returns to
+    pub addr: u16,
+    pub ret: u16,
+    // ROM bank mapped at 0x4000-0x7FFF when it happened
+    pub rom_bank: u8,
+    // SP right after the return address was pushed
+    pub sp: u16,
+}
+
+impl Frame {
+    // "02:4567" for ROM, with the bank it was in, and the label if there's
+    // one
+    fn location(&self, addr: u16, symbols: &Symbols) -> String {
+        let bank = symbols::bank_of(addr, self.rom_bank);
+        let loc = match bank {
+            Some(bank) => format!("{:02X}:{:04X}", bank, addr),
+            None => format!("{:04X}", addr),
+        };
+        match symbols.describe(bank, addr) {
+            Some(label) => format!("{} {}", loc, label),
+            None => loc,
+        }
+    }
+
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        let how = match self.kind {
+            FrameKind::Call => String::from("called from"),
+            FrameKind::Rst => String::from("rst from"),
+            FrameKind::Interrupt(kind) => format!("{} interrupt at", kind.name()),
+        };
+        format!("{:>7}  {} {}  SP: {:04X}",
+            self.location(self.addr, symbols), how, self.location(self.ret, symbols), self.sp)
+    }
+}
+
+pub struct CallStack {
+    // Outermost first
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack { frames: Vec::new() }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn entered(&mut self, kind: FrameKind, addr: u16, ret: u16, rom_bank: u8, sp: u16) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame { kind: kind, addr: addr, ret: ret, rom_bank: rom_bank, sp: sp });
+    }
+
+    // SP after an instruction, drops the frames whose return address it's
+    // above now
+    #[inline]
+    pub fn unwind(&mut self, sp: u16) {
+        while self.frames.last().map_or(false, |frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    // A line per frame, innermost first
+    pub fn backtrace(&self, symbols: &Symbols) -> Vec<String> {
+        self.frames.iter().rev().enumerate()
+            .map(|(i, frame)| format!("#{:<2} {}", i, frame.describe(symbols)))
+            .collect()
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod callstack_tests {
+    use super::*;
+
+    #[test]
+    fn unwinding() {
+        let mut calls = CallStack::new();
+        calls.entered(FrameKind::Call, 0x4000, 0x0153, 2, 0xFFFC);
+        calls.entered(FrameKind::Interrupt(Interrupt::Vblank), 0x0040, 0x4010, 2, 0xFFF8);
+        calls.entered(FrameKind::Rst, 0x0038, 0x0042, 2, 0xFFF6);
+        assert_eq!(calls.backtrace(&Symbols::new()), vec![
+            String::from("#0  00:0038  rst from 00:0042  SP: FFF6"),
+            String::from("#1  00:0040  VBlank interrupt at 02:4010  SP: FFF8"),
+            String::from("#2  02:4000  called from 00:0153  SP: FFFC"),
+        ]);
+        let symbols = Symbols::parse("00:0150 Main\n02:4000 Draw\n");
+        assert_eq!(calls.backtrace(&symbols)[2], "#2  02:4000 Draw  called from 00:0153 Main+$3  SP: FFFC");
+
+        // Pushes inside the innermost one leave it alone
+        calls.unwind(0xFFF4);
+        assert_eq!(calls.frames().len(), 3);
+        // RET, then RETI
+        calls.unwind(0xFFF8);
+        assert_eq!(calls.frames().len(), 2);
+        calls.unwind(0xFFFA);
+        assert_eq!(calls.frames().len(), 1);
+        // SP reset to the top
+        calls.unwind(0xFFFE);
+        assert!(calls.frames().is_empty());
+    }
+}
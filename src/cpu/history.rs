@@ -0,0 +1,113 @@
+//
+//      Interrupt history
+//
+
+// Ring buffer of the most recent interrupt dispatches, for tracking down
+// missed VBlanks and STAT storms from the debug console.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::Interrupt;
+
+const MAX_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptEvent {
+    pub kind: Interrupt,
+    pub pc: u16,        // where the CPU was interrupted
+    pub cycle: u32,     // Cpu::total_cycles at dispatch
+    pub handler: u16,
+    // Cycles until the handler's RETI, None while it's still running
+    pub duration: Option<u32>,
+}
+
+pub struct InterruptHistory {
+    events: VecDeque<InterruptEvent>,
+    // Cycles of the handlers waiting for their RETI, innermost last
+    open: Vec<u32>,
+}
+
+impl InterruptHistory {
+    pub fn new() -> InterruptHistory {
+        InterruptHistory { events: VecDeque::new(), open: Vec::new() }
+    }
+
+    pub fn dispatched(&mut self, kind: Interrupt, pc: u16, cycle: u32, handler: u16) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(InterruptEvent {
+            kind: kind,
+            pc: pc,
+            cycle: cycle,
+            handler: handler,
+            duration: None,
+        });
+        self.open.push(cycle);
+    }
+
+    pub fn reti(&mut self, cycle: u32) {
+        let start = match self.open.pop() {
+            Some(start) => start,
+            None => return,
+        };
+        // The event may have been pushed out of the buffer already
+        if let Some(event) = self.events.iter_mut().rev().find(|e| e.cycle == start) {
+            event.duration = Some(cycle.wrapping_sub(start));
+        }
+    }
+
+    // The last `count` events, oldest first
+    pub fn last(&self, count: usize) -> Vec<InterruptEvent> {
+        let skip = self.events.len().saturating_sub(count);
+        self.events.iter().skip(skip).cloned().collect()
+    }
+}
+
+impl fmt::Display for InterruptEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:>10}  {:<7} PC: {:04X} -> {:04X}",
+            self.cycle, self.kind.name(), self.pc, self.handler));
+        match self.duration {
+            Some(cycles) => write!(f, "  RETI after {} cycles", cycles),
+            None => write!(f, "  no RETI yet"),
+        }
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    #[test]
+    fn nested_handlers() {
+        let mut history = InterruptHistory::new();
+        history.dispatched(Interrupt::LCDStat, 0x0150, 100, 0x48);
+        history.dispatched(Interrupt::Vblank, 0x0049, 120, 0x40);
+        history.reti(200);
+        history.reti(300);
+        history.reti(400); // no handler running
+
+        let events = history.last(10);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].duration, Some(200));
+        assert_eq!(events[1].duration, Some(80));
+    }
+
+    #[test]
+    fn keeps_the_newest() {
+        let mut history = InterruptHistory::new();
+        for i in 0..MAX_EVENTS as u32 + 10 {
+            history.dispatched(Interrupt::Timer, 0, i, 0x50);
+        }
+        let events = history.last(MAX_EVENTS * 2);
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].cycle, 10);
+        assert_eq!(history.last(1)[0].duration, None);
+    }
+}
@@ -2,10 +2,10 @@ use super::*;
 use super::super::mmu;
 
 impl Cpu {
-    // TODO: implement
-    pub fn stop(&mut self) {
-        warn!("STOP instruction issued at {:04X}", self.regs.pc);
-        self.is_running = false;
+    // A button was pressed in STOP mode
+    pub fn wake(&mut self) {
+        info!("Leaving STOP mode at {:04X}", self.regs.pc);
+        self.regs.stop = false;
     }
 }
 
@@ -30,19 +30,19 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! call (
         () => ({
-            r.sp = r.sp.wrapping_sub(2);
-            m.ww(r.sp, r.pc + 2);
             let target = m.rw(r.pc);
+            let ret = r.pc.wrapping_add(2);
+            r.push(m, ret);
             debug!("CALL to {:04X}", target);
             r.pc = target;
         6 }) );
 
     macro_rules! call_if (
-        ($should_call:expr) => (if $should_call {call!()} else {r.pc += 2;
+        ($should_call:expr) => (if $should_call {call!()} else {r.pc = r.pc.wrapping_add(2);
         3 }) );
 
     macro_rules! ret_if (
-        ($should_ret:expr) => (if $should_ret {r.ret(m); 5} else {
+        ($should_ret:expr) => (if $should_ret {m.idle(); r.ret(m); 5} else {
         2 }) );
 
     macro_rules! jp (
@@ -52,12 +52,12 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         4 }) );
 
     macro_rules! jp_n (
-        ($should_jp:expr) => (if $should_jp {jp!()} else {r.pc += 2;
+        ($should_jp:expr) => (if $should_jp {jp!()} else {r.pc = r.pc.wrapping_add(2);
         3 }) );
 
     macro_rules! jr (
         () => ({
-            let target = add_signed(r.pc, m.rb(r.bump())) + 1;
+            let target = add_signed(r.pc, m.rb(r.bump())).wrapping_add(1);
             //debug!("JUMP(REL) to {:04X}", target);
             r.pc = target;
         3 }) );
@@ -76,6 +76,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! inc_16(
         ($reg1:ident, $reg2: ident) => ({
+            m.inc_dec_16((r.$reg1 as u16) << 8 | r.$reg2 as u16);
             r.$reg2 = r.$reg2.wrapping_add(1);
             if r.$reg2 == 0 { r.$reg1 = r.$reg1.wrapping_add(1); }
         2 }) );
@@ -90,14 +91,15 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! dec_16(
         ($reg1:ident, $reg2: ident) => ({
+            m.inc_dec_16((r.$reg1 as u16) << 8 | r.$reg2 as u16);
             r.$reg2 = r.$reg2.wrapping_sub(1);
             if r.$reg2 == 0xFF { r.$reg1 = r.$reg1.wrapping_sub(1); }
         2 }) );
 
     macro_rules! rst (
     ($e:expr) => ({
-        r.sp = r.sp.wrapping_sub(2);
-        m.ww(r.sp, r.pc);
+        let ret = r.pc;
+        r.push(m, ret);
         r.pc = $e;
     4 }) );
 
@@ -106,14 +108,14 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.a ^= $val;
         r.f.reset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! or_a (
     ($val:expr) => ({
         r.a |= $val;
         r.f.reset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! and_a (
     ($val:expr) => ({
@@ -122,7 +124,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.f.h.set();
         r.f.c.unset();
         r.f.z.set_if(r.a == 0);
-    4 }) );
+    1 }) );
 
     macro_rules! cp_a (
     ($val:expr) => ({
@@ -132,7 +134,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         if r.a < v {r.f.c.set()} else {r.f.c.unset()};
         r.f.h.set_if((r.a & 0xF) < (v & 0xF));
         //debug!("{:02X} & 0xF < ({:2X} & 0xF)    c:{:?} h:{:?} ", r.a, v, r.f.c.get(),r.f.h.get());
-    4 }) );
+    1 }) );
 
     macro_rules! rl( ($reg:expr, $cy:expr) => ({
         let ci = if r.f.c.get() {1} else {0};
@@ -157,14 +159,14 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         r.f.reset();
         r.$reg = r.$reg.rotate_left($n);
         r.f.c.set_if(r.$reg & 0x1 == 1);
-    4 }) );
+    1 }) );
 
     macro_rules! rrc (
     ($reg:ident, $n:expr) => ({
         r.f.reset();
         r.$reg = r.$reg.rotate_right($n);
         r.f.c.set_if(r.$reg & 0x80 != 0);
-    4 }) );
+    1 }) );
 
     macro_rules! add_hl(
     ($reg:expr) => ({
@@ -180,8 +182,8 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     macro_rules! push (
     ($reg:ident) => ({
-        r.sp = r.sp.wrapping_sub(2);
-        m.ww(r.sp, r.$reg());
+        let val = r.$reg();
+        r.push(m, val);
     4 }) );
 
     macro_rules! add_a (
@@ -254,45 +256,6 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
 
     // TODO: use set_or_else for everything
 
-    macro_rules! daa (
-    ($r:ident) => ({
-        let mut a = r.a as u16;
-
-        let c = r.f.c.get();
-        let h = r.f.h.get();
-        let n = r.f.n.get();
-
-        let mut correction = if c {
-            0x60u16
-        } else {
-            0x00u16
-        };
-
-        if h || (!n && a & 0x0F > 9) {
-            correction |= 0x06;
-        }
-
-        if c || (!n && a > 0x99) {
-            correction |= 0x60;
-        }
-
-        if n {
-            a = a.wrapping_sub(correction);
-        } else {
-            a = a.wrapping_add(correction);
-        }
-
-        if (correction << 2 & 0x100) != 0 {
-            r.f.c.set();
-        }
-
-        // Half-carry is always unset (unlike a Z-80)
-        r.f.h.unset();
-        r.f.z.set_if(a & 0xFF == 0);
-
-        r.a = (a & 0xFF) as u8;
-    }) );
-
     // if inst != 0 {
     //  info!("Decoding {:02X}", inst);
     // }
@@ -310,7 +273,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x05 => dec!(b),                                            // dec_b
         0x06 => ld_n!(b),                                           // ld_bn
         0x07 => rlc!(a, 1),                                         // rlca
-        0x08 => { let a = m.rw(r.pc); m.ww(a, r.sp); r.pc += 2; 5 } // ld_nnsp
+        0x08 => { let a = m.rw(r.pc); m.ww(a, r.sp); r.pc = r.pc.wrapping_add(2); 5 } // ld_nnsp
         0x09 => add_hl!(r.bc()),                                    // add_hlbc
         0x0a => { r.a = m.rb(r.bc()); 2 }                           // ld_abc
         0x0b => dec_16!(b, c),                                      // dec_bc
@@ -319,7 +282,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x0e => ld_n!(c),                                           // ld_cn
         0x0f => rrc!(a, 1),                                         // rrca
 
-        0x10 => { r.stop = true; 1}                                 // stop
+        0x10 => { r.bump(); r.stop = true; 1 }                      // stop
         0x11 => ld_nn!(d, e),                                       // ld_denn
         0x12 => { m.wb(r.de(), r.a); 2 }                            // ld_dea
         0x13 => inc_16!(d, e),                                      // inc_de
@@ -343,10 +306,10 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x24 => inc!(h),                                            // inc_h
         0x25 => dec!(h),                                            // dec_h
         0x26 => ld_n!(h),                                           // ld_hn
-        0x27 => { daa!(r); 1 },                                     // daa
+        0x27 => { daa(r); 1 },                                      // daa
         0x28 => jr_n!(r.f.z.get()),                                 // jr_z_n
         0x29 => add_hl!(r.hl()),                                    // add_hlhl
-        0x2a => { r.a = m.rb(r.hl()); r.inc_hl(); 2 },              // ldi_ahlm
+        0x2a => { r.a = m.rb_inc_dec(r.hl()); r.inc_hl(); 2 },      // ldi_ahlm
         0x2b => dec_16!(h, l),                                      // dec_hl
         0x2c => inc!(l),                                            // inc_l
         0x2d => dec!(l),                                            // dec_l
@@ -354,17 +317,17 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0x2f => { r.a ^= 0xff; r.f.n.set(); r.f.h.set(); 1 }        // cpl
 
         0x30 => jr_n!(!r.f.c.get()),                                // jr_nc_n
-        0x31 => { r.sp = m.rw(r.pc); r.pc += 2; 3 }                 // ld_spnn
+        0x31 => { r.sp = m.rw(r.pc); r.pc = r.pc.wrapping_add(2); 3 } // ld_spnn
         0x32 => { m.wb(r.hl(), r.a); r.dec_hl(); 2 }                // ldd_hlma
-        0x33 => { r.sp = r.sp.wrapping_add(1); 2 }                  // inc_sp
+        0x33 => { m.inc_dec_16(r.sp); r.sp = r.sp.wrapping_add(1); 2 } // inc_sp
         0x34 => { r.inc_hlm(m); 3 }                                 // inc_hlm
         0x35 => { r.dec_hlm(m); 3 }                                 // dec_hlm
         0x36 => { let v = m.rb(r.bump()); m.wb(r.hl(), v); 3 }      // ld_hlmn
         0x37 => { r.f.n.unset(); r.f.h.unset(); r.f.c.set(); 1 }    // scf
         0x38 => jr_n!(r.f.c.get()),                                 // jr_c_n
         0x39 => { r.add_hlsp(); 2 }                                 // add_hlsp
-        0x3a => { r.a = m.rb(r.hl()); r.dec_hl(); 2 }               // ldd_ahlm
-        0x3b => { r.sp = r.sp.wrapping_sub(1); 2 }                  // dec_sp
+        0x3a => { r.a = m.rb_inc_dec(r.hl()); r.dec_hl(); 2 }       // ldd_ahlm
+        0x3b => { m.inc_dec_16(r.sp); r.sp = r.sp.wrapping_sub(1); 2 } // dec_sp
         //0x3c => {inc!(a); info!("inc a: {}",r.a); 1 },                                            // inc_a
         0x3c => inc!(a),                                            // inc_a
         0x3d => dec!(a),                                            // dec_a
@@ -508,7 +471,7 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xbf => cp_a!(r.a),                                         // cp_aa
 
         0xc0 => ret_if!(!r.f.z.get()),                              // ret_nz
-        0xc1 => {let sp=r.sp; r.bc_set(m.rw(sp)); r.sp += 2; 3},    // pop_bc
+        0xc1 => {let sp=r.sp; r.bc_set(m.rw(sp)); r.pop(); 3},      // pop_bc
         //0xc2 => { warn!("jp at {:04X}",r.pc);jp_n!(!r.f.z.get())},                                // jp_nz_nn
         0xc2 => jp_n!(!r.f.z.get()),                                // jp_nz_nn
         0xc3 => jp!(),                                              // jp_nn
@@ -526,9 +489,9 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xcf => rst!(0x08),                                         // rst_08
 
         0xd0 => ret_if!(!r.f.c.get()),                              // ret_nc
-        0xd1 => {let sp=r.sp; r.de_set(m.rw(sp)); r.sp += 2; 3},    // pop_de
+        0xd1 => {let sp=r.sp; r.de_set(m.rw(sp)); r.pop(); 3},      // pop_de
         0xd2 => jp_n!(!r.f.c.get()),                                // jp_nc_nn
-        0xd3 => xx(),                                               // xx
+        0xd3 => xx(r, inst),                                        // xx
         0xd4 => call_if!(!r.f.c.get()),                             // call_nc_n
         0xd5 => push!(de),                                          // push_de
         0xd6 => { sub_a!(m.rb(r.bump())); 2 }                       // sub_an
@@ -536,45 +499,45 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         0xd8 => ret_if!(r.f.c.get()),                               // ret_c
         0xd9 => { r.ei(m); r.ret(m); 4 }                            // reti
         0xda => jp_n!(r.f.c.get()),                                 // jp_c_nn
-        0xdb => xx(),                                               // xx
+        0xdb => xx(r, inst),                                        // xx
         0xdc => call_if!(r.f.c.get()),                              // call_c_n
-        0xdd => xx(),                                               // xx
+        0xdd => xx(r, inst),                                        // xx
         0xde => { sbc_a!(m.rb(r.bump())); 2 }                       // sbc_an
         0xdf => rst!(0x18),                                         // rst_18
 
         0xe0 => {let n=m.rb(r.bump());
             m.wb(0xFF00 | n as u16, r.a); 3 }                       // ld_IOan
-        0xe1 => {let sp=r.sp; r.hl_set(m.rw(sp)); r.sp += 2; 3},    // pop_hl
+        0xe1 => {let sp=r.sp; r.hl_set(m.rw(sp)); r.pop(); 3},      // pop_hl
         0xe2 => { m.wb(0xFF00 | (r.c as u16), r.a); 2 }             // ld_IOca
-        0xe3 => xx(),                                               // xx
-        0xe4 => xx(),                                               // xx
+        0xe3 => xx(r, inst),                                        // xx
+        0xe4 => xx(r, inst),                                        // xx
         0xe5 => push!(hl),                                          // push_hl
-        0xe6 => and_a!(m.rb(r.bump())),                             // and_an
+        0xe6 => { and_a!(m.rb(r.bump())); 2 }                       // and_an
         //0xe6 => {and_a!(m.rb(r.bump())); warn!("and a:{:02X}",r.a); 2 }                       // and_an
         0xe7 => rst!(0x20),                                         // rst_20
         0xe8 => { add_spn(r, m); 4 }                                // add_spn
         0xe9 => { r.pc = r.hl(); 1 }                                // jp_hl
-        0xea => { let n = m.rw(r.pc); m.wb(n, r.a); r.pc += 2; 4 }  // ld_nna
-        0xeb => xx(),                                               // xx
-        0xec => xx(),                                               // xx
-        0xed => xx(),                                               // xx
+        0xea => { let n = m.rw(r.pc); m.wb(n, r.a); r.pc = r.pc.wrapping_add(2); 4 } // ld_nna
+        0xeb => xx(r, inst),                                        // xx
+        0xec => xx(r, inst),                                        // xx
+        0xed => xx(r, inst),                                        // xx
         0xee => { xor_a!(m.rb(r.bump())); 2 }                       // xor_an
         0xef => rst!(0x28),                                         // rst_28
 
         0xf0 => ld_aIOn!(),                                         // ld_aIOn
-        0xf1 => { let sp=r.sp; r.af_set(m.rw(sp)); r.sp += 2; 3 },  // pop_af
+        0xf1 => { let sp=r.sp; r.af_set(m.rw(sp)); r.pop(); 3 },    // pop_af
         0xf2 => { r.a = m.rb(0xff00 | (r.c as u16)); 2 }            // ld_aIOc
         0xf3 => { r.di(); 1 }                                       // di
-        0xf4 => xx(),                                               // xx
+        0xf4 => xx(r, inst),                                        // xx
         0xf5 => push!(af),                                          // push_af
         0xf6 => { or_a!(m.rb(r.bump())); 2 }                        // or_an
         0xf7 => rst!(0x30),                                         // rst_30
         0xf8 => { ld_hlspn!() }                                     // ld_hlspn
         0xf9 => { r.sp = r.hl(); 2 }                                // ld_sphl
-        0xfa => { let b = m.rw(r.pc); r.a = m.rb(b); r.pc += 2; 4 } // ld_ann
+        0xfa => { let b = m.rw(r.pc); r.a = m.rb(b); r.pc = r.pc.wrapping_add(2); 4 } // ld_ann
         0xfb => { r.ei(m); 1 }                                      // ei
-        0xfc => xx(),                                               // xx
-        0xfd => xx(),                                               // xx
+        0xfc => xx(r, inst),                                        // xx
+        0xfd => xx(r, inst),                                        // xx
         0xfe => { cp_a!(m.rb(r.bump())); 2 }                        // cp_an
         0xff => rst!(0x38),                                         // rst_38
 
@@ -584,10 +547,39 @@ pub fn exec(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
     }
 }
 
-fn xx() -> u32 { panic!("Invalid instruction opcode"); }
+// The 11 unused opcodes hang the CPU on real hardware
+fn xx(r: &mut Registers, inst: u8) -> u32 {
+    error!("Invalid opcode {:02X} at {:04X}, the CPU is locked up", inst, r.pc.wrapping_sub(1));
+    r.locked = true;
+    1
+}
+
+// Turns A back into BCD after adding or subtracting (N) two BCD numbers,
+// using the carries out of each digit (H and C) to tell a 0x10 from a 10.
+// Works for every A and flags, not just the results of a BCD add/sub
+fn daa(r: &mut Registers) {
+    let (a, carry) = daa_value(r.a, r.f.n.get(), r.f.h.get(), r.f.c.get());
+    r.a = a;
+    r.f.z.set_if(a == 0);
+    // Half-carry is always unset (unlike a Z-80)
+    r.f.h.unset();
+    r.f.c.set_if(carry);
+}
+
+fn daa_value(a: u8, n: bool, h: bool, c: bool) -> (u8, bool) {
+    let mut correction = 0;
+    if h || (!n && a & 0x0F > 0x09) {
+        correction |= 0x06;
+    }
+    if c || (!n && a > 0x99) {
+        correction |= 0x60;
+    }
+    let a = if n {a.wrapping_sub(correction)} else {a.wrapping_add(correction)};
+    (a, correction & 0x60 != 0)
+}
 
 fn add_signed(a: u16, b: u8) -> u16 {
-    (a as i16 + (b as i8 as i16)) as u16
+    a.wrapping_add(b as i8 as i16 as u16)
 }
 
 fn add_spn(r: &mut Registers, m: &mut mmu::Memory) {
@@ -977,3 +969,380 @@ pub fn exec_cb(inst: u8, r: &mut Registers, m: &mut mmu::Memory) -> u32 {
         _ => 0
     }
 }
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod instructions_tests {
+    use super::*;
+    use mmu::Memory;
+
+    // Flags as they sit in F
+    const Z: u8 = 0x80;
+    const N: u8 = 0x40;
+    const H: u8 = 0x20;
+    const C: u8 = 0x10;
+
+    // Runs from WRAM with BC = 0x0F01, DE = 0xF0FF and HL = 0xC100, which
+    // holds 0x80
+    fn setup(code: &[u8]) -> (Registers, Memory) {
+        let mut r = Registers::default();
+        let mut m = Memory::new();
+        r.pc = 0xC000;
+        r.sp = 0xD000;
+        r.bc_set(0x0F01);
+        r.de_set(0xF0FF);
+        r.hl_set(0xC100);
+        m.wb(0xC100, 0x80);
+        for (i, &b) in code.iter().enumerate() {
+            m.wb(0xC000 + i as u16, b);
+        }
+        (r, m)
+    }
+
+    // One instruction, in M-cycles
+    fn step(r: &mut Registers, m: &mut Memory) -> u32 {
+        let op = m.rb(r.bump());
+        exec(op, r, m)
+    }
+
+    #[test]
+    fn alu() {
+        // Code, A and F before, A and F after, M-cycles
+        let cases: &[(&[u8], u8, u8, u8, u8, u32)] = &[
+            (&[0x80],       0x01, 0,     0x10, H,         1),   // add a,b
+            (&[0x82],       0x10, 0,     0x00, Z | C,     1),   // add a,d
+            (&[0x86],       0x80, 0,     0x00, Z | C,     2),   // add a,(hl)
+            (&[0xC6, 0xFF], 0x01, 0,     0x00, Z | H | C, 2),   // add a,n
+            (&[0x89],       0x0E, C,     0x10, H,         1),   // adc a,c
+            (&[0x8B],       0x00, C,     0x00, Z | H | C, 1),   // adc a,e
+            (&[0x90],       0x10, 0,     0x01, N | H,     1),   // sub b
+            (&[0x97],       0x42, 0,     0x00, Z | N,     1),   // sub a
+            (&[0x99],       0x01, C,     0xFF, N | H | C, 1),   // sbc a,c
+            (&[0xDE, 0x01], 0x10, 0,     0x0F, N | H,     2),   // sbc a,n
+            (&[0xA0],       0xF0, N | C, 0x00, Z | H,     1),   // and b
+            (&[0xE6, 0x3C], 0xFF, 0,     0x3C, H,         2),   // and n
+            (&[0xAB],       0xFF, N | H | C, 0x00, Z,     1),   // xor e
+            (&[0xAE],       0x01, Z,     0x81, 0,         2),   // xor (hl)
+            (&[0xB1],       0x00, Z | N | H | C, 0x01, 0, 1),   // or c
+            (&[0xF6, 0x00], 0x00, 0,     0x00, Z,         2),   // or n
+            (&[0xB8],       0x0F, 0,     0x0F, Z | N,     1),   // cp b
+            (&[0xBA],       0x10, 0,     0x10, N | C,     1),   // cp d
+            (&[0xFE, 0x01], 0x10, 0,     0x10, N | H,     2),   // cp n
+            (&[0x3C],       0xFF, C,     0x00, Z | H | C, 1),   // inc a
+            (&[0x3D],       0x01, 0,     0x00, Z | N,     1),   // dec a
+            (&[0x3D],       0x10, 0,     0x0F, N | H,     1),   // dec a
+            (&[0x27],       0x3C, 0,     0x42, 0,         1),   // daa
+            (&[0x27],       0x0F, N | H, 0x09, N,         1),   // daa
+            (&[0x27],       0x9A, 0,     0x00, Z | C,     1),   // daa
+            (&[0x2F],       0x35, Z | C, 0xCA, Z | N | H | C, 1), // cpl
+            (&[0x37],       0x00, Z | N | H, 0x00, Z | C, 1),   // scf
+            (&[0x3F],       0x00, N | H | C, 0x00, 0,     1),   // ccf
+            (&[0x07],       0x85, Z,     0x0B, C,         1),   // rlca
+            (&[0x0F],       0x01, 0,     0x80, C,         1),   // rrca
+            (&[0x17],       0x80, 0,     0x00, C,         1),   // rla
+            (&[0x1F],       0x01, C,     0x80, C,         1),   // rra
+            (&[0x7E],       0x00, 0,     0x80, 0,         2),   // ld a,(hl)
+        ];
+        for &(code, a, f, a_out, f_out, cycles) in cases.iter() {
+            let (mut r, mut m) = setup(code);
+            r.af_set((a as u16) << 8 | f as u16);
+            assert_eq!(step(&mut r, &mut m), cycles, "{:02X?}", code);
+            assert_eq!((r.a, r.f()), (a_out, f_out), "{:02X?} with A {:02X}", code, a);
+            assert_eq!(r.pc, 0xC000 + code.len() as u16);
+        }
+    }
+
+    #[test]
+    fn cb() {
+        // Opcode, B or (HL) before, F before, after, M-cycles
+        let cases: &[(u8, u8, u8, u8, u8, u32)] = &[
+            (0x00, 0x85, 0,     0x0B, C,         2),    // rlc b
+            (0x00, 0x00, C,     0x00, Z,         2),    // rlc b
+            (0x08, 0x01, 0,     0x80, C,         2),    // rrc b
+            (0x10, 0x80, 0,     0x00, Z | C,     2),    // rl b
+            (0x16, 0x01, C,     0x03, 0,         4),    // rl (hl)
+            (0x18, 0x01, C,     0x80, C,         2),    // rr b
+            (0x20, 0xC0, 0,     0x80, C,         2),    // sla b
+            (0x28, 0x81, 0,     0xC0, C,         2),    // sra b
+            (0x30, 0xF1, N | H | C, 0x1F, 0,     2),    // swap b
+            (0x36, 0x00, 0,     0x00, Z,         4),    // swap (hl)
+            (0x38, 0x01, 0,     0x00, Z | C,     2),    // srl b
+            (0x78, 0x80, C,     0x80, H | C,     2),    // bit 7,b
+            (0x40, 0xFE, N,     0xFE, Z | H,     2),    // bit 0,b
+            (0x7E, 0x00, 0,     0x00, Z | H,     3),    // bit 7,(hl)
+            (0xB8, 0xFF, Z | N | H | C, 0x7F, Z | N | H | C, 2), // res 7,b
+            (0x86, 0x01, 0,     0x00, 0,         4),    // res 0,(hl)
+            (0xD8, 0x00, 0,     0x08, 0,         2),    // set 3,b
+            (0xFE, 0x00, 0,     0x80, 0,         4),    // set 7,(hl)
+        ];
+        for &(op, val, f, val_out, f_out, cycles) in cases.iter() {
+            let (mut r, mut m) = setup(&[0xCB, op]);
+            let on_hl = op & 7 == 6;
+            if on_hl {m.wb(0xC100, val)} else {r.b = val};
+            r.af_set(f as u16);
+            assert_eq!(step(&mut r, &mut m), cycles, "CB {:02X}", op);
+            let result = if on_hl {m.rb(0xC100)} else {r.b};
+            assert_eq!((result, r.f()), (val_out, f_out), "CB {:02X} on {:02X}", op, val);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit() {
+        // add hl,bc: half carry out of bit 11, Z is left alone
+        let (mut r, mut m) = setup(&[0x09]);
+        r.hl_set(0x0FFF);
+        r.bc_set(0x0001);
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!((r.hl(), r.f()), (0x1000, Z | H));
+
+        // add hl,sp
+        let (mut r, mut m) = setup(&[0x39]);
+        r.hl_set(0x8000);
+        r.sp = 0x8000;
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!((r.hl(), r.f()), (0x0000, C));
+
+        // add sp,e and ld hl,sp+e take their flags from the low byte
+        let (mut r, mut m) = setup(&[0xE8, 0xFF]);
+        r.sp = 0x0001;
+        assert_eq!(step(&mut r, &mut m), 4);
+        assert_eq!((r.sp, r.f()), (0x0000, H | C));
+        let (mut r, mut m) = setup(&[0xF8, 0x01]);
+        r.sp = 0x00FF;
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!((r.hl(), r.f()), (0x0100, H | C));
+
+        // inc/dec don't touch the flags
+        let (mut r, mut m) = setup(&[0x03, 0x1B, 0x33, 0x3B]);
+        r.bc_set(0x00FF);
+        r.de_set(0x0000);
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!((r.bc(), r.de(), r.f()), (0x0100, 0xFFFF, 0));
+        step(&mut r, &mut m);
+        step(&mut r, &mut m);
+        assert_eq!(r.sp, 0xD000);
+
+        // ldi/ldd
+        let (mut r, mut m) = setup(&[0x2A, 0x32]);
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!((r.a, r.hl()), (0x80, 0xC101));
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!((m.rb(0xC101), r.hl()), (0x80, 0xC100));
+
+        // pop af drops the low nibble of F
+        let (mut r, mut m) = setup(&[0xC5, 0xF1]);
+        r.bc_set(0x12FF);
+        assert_eq!(step(&mut r, &mut m), 4);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!((r.af(), r.sp), (0x12F0, 0xD000));
+    }
+
+    #[test]
+    fn wrapping() {
+        // jp nz not taken at the end of memory, its operand wraps to 0000
+        let (mut r, mut m) = setup(&[]);
+        m.wb(0xFFFE, 0xC2);
+        r.pc = 0xFFFE;
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!(r.pc, 0x0001);
+
+        // ld sp,nn with the operand in FFFE-FFFF
+        let (mut r, mut m) = setup(&[]);
+        m.wb(0xFFFD, 0x31);
+        m.wb(0xFFFE, 0x34);
+        m.wb(0xFFFF, 0x12);
+        r.pc = 0xFFFD;
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!((r.sp, r.pc), (0x1234, 0x0000));
+
+        // pop bc and ld (nn),sp across FFFF
+        let (mut r, mut m) = setup(&[0xC1, 0x08, 0xFF, 0xFF]);
+        m.wb(0xFFFF, 0x34);
+        r.sp = 0xFFFF;
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!((r.bc(), r.sp), ((m.rb(0x0000) as u16) << 8 | 0x34, 0x0001));
+        r.sp = 0xD0AB;
+        assert_eq!(step(&mut r, &mut m), 5);
+        assert_eq!((m.rb(0xFFFF), r.pc), (0xAB, 0xC004));
+    }
+
+    #[test]
+    fn oam_bug() {
+        use gpu::{Gpu, OamAccess};
+
+        // The PPU is on row 5 of OAM, mode 2 right after the LCD is turned
+        // on
+        let oam_bug_at = |code: &[u8], hl: u16| {
+            let (mut r, mut m) = setup(code);
+            m.wb(0xFF40, 0x80);
+            m.gpu.oam_bug = true;
+            m.gpu.clock = 20;
+            for (i, b) in m.gpu.oam.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            r.hl_set(hl);
+            step(&mut r, &mut m);
+            m.gpu.oam
+        };
+        let expected = |access: OamAccess| {
+            let mut gpu = Gpu::new();
+            gpu.wb(0xFF40, 0x80);
+            gpu.oam_bug = true;
+            gpu.clock = 20;
+            for (i, b) in gpu.oam.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            gpu.corrupt_oam(0xFE00, access);
+            gpu.oam
+        };
+
+        // Writing and changing HL in the same cycle is a single write
+        assert_eq!(&oam_bug_at(&[0x22], 0xFE00)[..], &expected(OamAccess::Write)[..]);
+        assert_eq!(&oam_bug_at(&[0x32], 0xFE00)[..], &expected(OamAccess::Write)[..]);
+        // Reading has its own pattern
+        assert_eq!(&oam_bug_at(&[0x2A], 0xFE00)[..], &expected(OamAccess::ReadIncDec)[..]);
+        assert_eq!(&oam_bug_at(&[0x3A], 0xFE00)[..], &expected(OamAccess::ReadIncDec)[..]);
+        assert!(&expected(OamAccess::ReadIncDec)[..] != &expected(OamAccess::Read)[..]);
+    }
+
+    #[test]
+    fn control_flow() {
+        // jr back onto itself, then not taken
+        let (mut r, mut m) = setup(&[0x18, 0xFE]);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!(r.pc, 0xC000);
+        let (mut r, mut m) = setup(&[0x20, 0x10]);
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 2);
+        assert_eq!(r.pc, 0xC002);
+        assert_eq!(add_signed(0x7FFF, 0x01), 0x8000);
+        assert_eq!(add_signed(0x0000, 0xFF), 0xFFFF);
+
+        // jp nz taken and not
+        let (mut r, mut m) = setup(&[0xC2, 0x34, 0x12]);
+        assert_eq!(step(&mut r, &mut m), 4);
+        assert_eq!(r.pc, 0x1234);
+        let (mut r, mut m) = setup(&[0xC2, 0x34, 0x12]);
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!(r.pc, 0xC003);
+
+        // call, then ret z not taken and taken
+        let (mut r, mut m) = setup(&[0xCD, 0x00, 0xC2]);
+        m.wb(0xC200, 0xC8);
+        m.wb(0xC201, 0xC8);
+        assert_eq!(step(&mut r, &mut m), 6);
+        assert_eq!((r.pc, r.sp, m.rw(0xCFFE)), (0xC200, 0xCFFE, 0xC003));
+        assert_eq!(step(&mut r, &mut m), 2);
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 5);
+        assert_eq!((r.pc, r.sp), (0xC003, 0xD000));
+
+        // call nz not taken, rst, jp hl
+        let (mut r, mut m) = setup(&[0xC4, 0x00, 0xC2, 0xFF]);
+        r.af_set(Z as u16);
+        assert_eq!(step(&mut r, &mut m), 3);
+        assert_eq!(step(&mut r, &mut m), 4);
+        assert_eq!((r.pc, m.rw(0xCFFE)), (0x0038, 0xC004));
+        let (mut r, mut m) = setup(&[0xE9]);
+        assert_eq!(step(&mut r, &mut m), 1);
+        assert_eq!(r.pc, 0xC100);
+    }
+
+    fn bcd(n: u32) -> u8 {
+        ((n / 10) << 4 | n % 10) as u8
+    }
+
+    #[test]
+    fn daa_bcd() {
+        // Every sum and difference of two BCD bytes comes out right, with
+        // the flags ADD/SUB leave
+        for x in 0..100 {
+            for y in 0..100 {
+                let (a, b) = (bcd(x), bcd(y));
+                let sum = a as u16 + b as u16;
+                let h = (a & 0x0F) + (b & 0x0F) > 0x0F;
+                let (out, carry) = daa_value(sum as u8, false, h, sum > 0xFF);
+                assert_eq!((out, carry), (bcd((x + y) % 100), x + y >= 100), "{} + {}", x, y);
+
+                let h = a & 0x0F < b & 0x0F;
+                let (out, carry) = daa_value(a.wrapping_sub(b), true, h, a < b);
+                assert_eq!((out, carry), (bcd((x + 100 - y) % 100), x < y), "{} - {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn daa_all() {
+        // Every A with every flag combination, against adjusting one digit
+        // at a time the way it's usually described
+        let (mut r, mut m) = setup(&[0x27]);
+        for a in 0..256u32 {
+            for flags in 0..16u8 {
+                let (z, n, h, c) = (flags & 8 != 0, flags & 4 != 0, flags & 2 != 0, flags & 1 != 0);
+                let (mut expect, mut carry) = (a as u8, c);
+                if !n {
+                    if c || expect > 0x99 { expect = expect.wrapping_add(0x60); carry = true; }
+                    if h || expect & 0x0F > 0x09 { expect = expect.wrapping_add(0x06); }
+                } else {
+                    if c { expect = expect.wrapping_sub(0x60); }
+                    if h { expect = expect.wrapping_sub(0x06); }
+                }
+
+                let f = (z as u8) << 7 | (n as u8) << 6 | (h as u8) << 5 | (c as u8) << 4;
+                r.af_set((a as u16) << 8 | f as u16);
+                r.pc = 0xC000;
+                step(&mut r, &mut m);
+                let f_out = if expect == 0 {Z} else {0} | if n {N} else {0} | if carry {C} else {0};
+                assert_eq!((r.a, r.f()), (expect, f_out), "A {:02X} F {:02X}", a, f);
+            }
+        }
+    }
+
+    // Runs blargg's test ROMs headless until each prints its verdict over
+    // serial
+    fn blargg(roms: &[&str]) {
+        use std::path::Path;
+        use config::Config;
+        use emulator::{try_open_rom, Emulator};
+        use input::Joypad;
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testroms");
+        for name in roms.iter() {
+            let rom = try_open_rom(dir.join(format!("{}.gb", name)));
+            let mut emu = Emulator::with_rom(rom, &Config::default());
+            let mut out = Vec::new();
+            for _ in 0..60 * 60 {
+                out.extend(emu.step_frame(Joypad::default()).serial);
+                let text = String::from_utf8_lossy(&out);
+                if text.contains("Passed") || text.contains("Failed") {
+                    break;
+                }
+            }
+            let text = String::from_utf8_lossy(&out);
+            assert!(text.contains("Passed"), "{}: {}", name, text);
+        }
+    }
+
+    #[test]
+    fn cpu_instrs() {
+        blargg(&["01-special", "02-interrupts", "03-op sp,hl", "04-op r,imm", "05-op rp",
+            "06-ld r,r", "07-jr,jp,call,ret,rst", "08-misc instrs", "09-op r,r", "10-bit ops",
+            "11-op a,(hl)"]);
+    }
+
+    // These time instructions and where in them the accesses land with DIV
+    // and the timer
+    #[test]
+    fn timing() {
+        blargg(&["instr_timing", "mem_timing", "01-read_timing", "02-write_timing",
+            "03-modify_timing"]);
+    }
+}
@@ -5,22 +5,28 @@
 #![allow(dead_code)]
 
 pub mod instructions;
+pub mod history;
+pub mod trace;
+pub mod callstack;
 
 use std::str;
 use std::fmt;
-use std::io::prelude::*;
-use std::fs::{OpenOptions, File};
 
 use colored::*;
 use mmu::Memory;
+use heatmap::Access;
+use self::history::InterruptHistory;
+use self::trace::Tracer;
+use self::callstack::{CallStack, FrameKind};
+use symbols::Symbols;
 
 
 // CPU Clock speed
 // TODO: Disable if log level > TRACE
 pub const INSTR_DEBUG: bool = false;    // very laggy, needs 'trace' log level
-pub const WADATSUMI_DEBUG: bool = true;    // Format debug text the same way Wadatsume does (for easy comparison)
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Interrupt {
     Vblank  = 0x01,
     LCDStat = 0x02,
@@ -29,6 +35,28 @@ pub enum Interrupt {
     Joypad  = 0x10,
 }
 
+// Highest priority first
+const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::Vblank, Interrupt::LCDStat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad,
+];
+
+impl Interrupt {
+    // Where its handler is, 0x40 to 0x60
+    pub fn vector(&self) -> u16 {
+        0x40 + (*self as u8).trailing_zeros() as u16 * 8
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Interrupt::Vblank  => "VBlank",
+            Interrupt::LCDStat => "STAT",
+            Interrupt::Timer   => "Timer",
+            Interrupt::Serial  => "Serial",
+            Interrupt::Joypad  => "Joypad",
+        }
+    }
+}
+
 // Tell the compiler to generate a default() function
 // Which zero initializes everything
 
@@ -38,6 +66,8 @@ pub struct Registers  {
     pub ime: bool,
     halt: bool,
     pub stop: bool,
+    // An invalid opcode hung the CPU, only a reset gets it going again
+    locked: bool,
 
     a: u8,      // A: Accumulator
     b: u8,
@@ -72,14 +102,18 @@ impl Registers {
     pub fn bc_set(&mut self, new: u16){ self.b = (new >> 8) as u8; self.c = new as u8; }
     pub fn de_set(&mut self, new: u16){ self.d = (new >> 8) as u8; self.e = new as u8; }
     pub fn hl_set(&mut self, new: u16){ self.h = (new >> 8) as u8; self.l = new as u8; }
+    pub fn sp_set(&mut self, new: u16){ self.sp = new; }
+    pub fn pc_set(&mut self, new: u16){ self.pc = new; }
 
     pub fn pc(&self) -> u16 { self.pc }
+    pub fn sp(&self) -> u16 { self.sp }
+    pub fn halted(&self) -> bool { self.halt }
+    pub fn locked(&self) -> bool { self.locked }
 
     #[inline]
     pub fn bump(&mut self) -> u16 {
         let ret = self.pc;
-        self.pc += 1;
-        // Could just a return ret++ work here?
+        self.pc = self.pc.wrapping_add(1);
         return ret;
     }
 
@@ -95,7 +129,7 @@ impl Registers {
 
     // Schedule enabling of interrupts
     pub fn ei(&mut self, m: &mut Memory) {
-        if self.delay == 2 || m.rb(self.pc) == 0x76 {   // 0x76 == HALT
+        if self.delay == 2 || m.peek(self.pc) == 0x76 {   // 0x76 == HALT
             self.delay = 1;
         } else {
             self.delay = 2;
@@ -128,7 +162,20 @@ impl Registers {
     fn ret(&mut self, m: &mut Memory) {
         self.pc = m.rw(self.sp);
         debug!("RET to {:04X}", self.pc);
-        self.sp += 2;
+        self.pop();
+    }
+
+    fn pop(&mut self) {
+        self.sp = self.sp.wrapping_add(2);
+    }
+
+    // An internal cycle first, then the high byte goes down before the low
+    fn push(&mut self, m: &mut Memory, val: u16) {
+        m.idle();
+        self.sp = self.sp.wrapping_sub(1);
+        m.wb(self.sp, (val >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        m.wb(self.sp, val as u8);
     }
 
     fn inc_hlm(&mut self, m: &mut Memory) {
@@ -214,22 +261,23 @@ macro_rules! flag_to_ch (
     })
 );
 
-// Interrupt handlers
-macro_rules! rst (
-($sel:ident, $mem:ident, $isr:expr) => ({
-    $sel.regs.ime = false;
-    $sel.regs.sp -= 2;
-    $mem.ww($sel.regs.sp, $sel.regs.pc);
-    $sel.regs.pc = $isr;
-    $sel.total_cycles += 12;
-}) );
+// Two wait states, pushing PC and jumping. One more coming out of HALT
+const INTERRUPT_CYCLES: u32 = 20;
+
+// How long the CPU stalls switching between normal and double speed, 2050
+// M-cycles
+const SPEED_SWITCH_CYCLES: u32 = 8200;
 
 pub struct Cpu {
     regs: Registers,
 
     pub total_cycles: u32,
     pub is_running: bool,
-    trace_file: Option<File>,
+    // Logs every instruction when set, see trace.rs
+    pub tracer: Option<Tracer>,
+
+    pub int_history: InterruptHistory,
+    pub calls: CallStack,
 }
 
 impl Cpu {
@@ -238,15 +286,10 @@ impl Cpu {
             regs: Default::default(),
             total_cycles: 0,
             is_running: true,
-            trace_file: None,
+            tracer: None,
+            int_history: InterruptHistory::new(),
+            calls: CallStack::new(),
         };
-        cpu.trace_file = Some(OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .truncate(true)
-                            .open("trace_log.txt")
-                            .unwrap());
-
         cpu.reset_state();
         cpu
     }
@@ -262,6 +305,8 @@ impl Cpu {
         self.regs.hl_set(0x014D);
         self.regs.sp = 0xFFFE;
         self.regs.pc = 0x0100;
+        self.regs.locked = false;
+        self.calls.clear();
     }
 
     pub fn get_regs(&self) -> &Registers {
@@ -277,12 +322,32 @@ impl Cpu {
         &mut self.regs.f
     }
 
+    // What's logged when the emulator stops on an error: the registers and
+    // the calls that led there
+    pub fn crash_report(&self, symbols: &Symbols) -> String {
+        let mut report = format!("Registers\n{:?}\nCall stack", self.regs);
+        if self.calls.frames().is_empty() {
+            report.push_str(" is empty");
+        }
+        for line in self.calls.backtrace(symbols) {
+            report.push_str("\n ");
+            report.push_str(&line);
+        }
+        report
+    }
+
     // Dispatcher
     // Executes 1 instruction
     pub fn exec(&mut self, mem: &mut Memory) -> u32 {
+        // Locked up for good, not even interrupts get through. Everything
+        // else keeps going with the screen frozen on whatever it shows
+        if self.regs.locked {
+            return 4;
+        }
 
         // Interrupts
-        if self.handle_interrupts(mem) {return 0};
+        let cycles = self.handle_interrupts(mem);
+        if cycles != 0 {return cycles};
 
         // Fetch opcode
         let op: u8 = mem.rb(self.regs.pc);
@@ -290,34 +355,6 @@ impl Cpu {
         // Save previous pc
         let pc_before = self.regs.pc;
 
-        if WADATSUMI_DEBUG {
-            let ff44 = mem.rb(0xff44);
-            // let line = format!("PC[0x{:02X}]: 0x{:04X} AF: 0x{:04X} BC: 0x{:04X} DE: 0x{:04X} HL: 0x{:04X} SP: 0x{:04X} IE: {:08b} IF: {:08b} DIV: {} LY: {:02X}\n",
-            let line = format!("PC[0x{:02X}]: 0x{:04X} AF: 0x{:04X} BC: 0x{:04X} DE: 0x{:04X} HL: 0x{:04X} SP: 0x{:04X} IE: {:08b} IF: {:08b}\n",
-                     //self.total_cycles,
-                     //op.format(&self.ctx, bus).unwrap(),
-                     op,
-                     self.regs.pc,
-                     self.regs.af(),
-                     self.regs.bc(),
-                     self.regs.de(),
-                     self.regs.hl(),
-                     self.regs.sp,
-                      mem.ie_,
-                      mem.if_,
-                    //  mem.timer.div,
-                    //  ff44
-            );
-
-            let trace_file = self.trace_file.as_mut().unwrap();
-            trace_file.write_all(line.as_bytes()).unwrap();
-        }
-
-        // OAM DMA
-        if mem.is_dma {
-            mem.handle_dma_transfer();
-        }
-
         // HALT
         if self.regs.halt {
             if mem.ie_ & mem.if_ != 0 {
@@ -328,11 +365,48 @@ impl Cpu {
             return 4;
         }
 
+        let failed = match self.tracer {
+            Some(ref mut tracer) => tracer.log(&self.regs, mem, self.total_cycles).err(),
+            None => None,
+        };
+        if let Some(err) = failed {
+            error!("Trace stopped: {}", err);
+            self.tracer = None;
+        }
+
+        if let Some(ref mut heat) = mem.heatmap {
+            heat.count(Access::Exec, pc_before);
+        }
+
         // Increment PC
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         // Execute instruction
-        let cycles = instructions::exec(op, &mut self.regs, mem) * 4;
+        let sp_before = self.regs.sp;
+        let mut cycles = instructions::exec(op, &mut self.regs, mem) * 4;
+
+        // Conditional calls only push when they're taken
+        let entered = match op {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Some((FrameKind::Call, 3)),
+            _ if op & 0xC7 == 0xC7 => Some((FrameKind::Rst, 1)),
+            _ => None,
+        };
+        match entered {
+            Some((kind, len)) if self.regs.sp == sp_before.wrapping_sub(2) => self.calls.entered(
+                kind, self.regs.pc, pc_before.wrapping_add(len), mem.rom_bank(), self.regs.sp),
+            _ => self.calls.unwind(self.regs.sp),
+        }
+
+        // STOP resets DIV. When KEY1 asked for it the CGB switches speed and
+        // carries on after a stall, otherwise everything stops until a
+        // button is pressed, see Emulator::run_frame
+        if op == 0x10 {
+            mem.timer.div = 0;
+            if mem.switch_speed() {
+                self.regs.stop = false;
+                cycles += SPEED_SWITCH_CYCLES;
+            }
+        }
 
         if INSTR_DEBUG {
             let pc_diff = self.regs.pc as i32 - pc_before as i32;
@@ -342,9 +416,9 @@ impl Cpu {
                     1 => format!("[0x{:04X}] 0x{:02X}          ",
                         pc_before, op),
                     2 => format!("[0x{:04X}] 0x{:02X} 0x{:02X}     ",
-                        pc_before, op, mem.rb(pc_before + 1)),
+                        pc_before, op, mem.peek(pc_before + 1)),
                     3 => format!("[0x{:04X}] 0x{:02X} 0x{:02X} 0x{:02X}",
-                        pc_before, op, mem.rb(pc_before + 1), mem.rb(pc_before + 2)),
+                        pc_before, op, mem.peek(pc_before + 1), mem.peek(pc_before + 2)),
                     _ => format!("[0x{:04X}] 0x{:02X} (JUMP)   ",
                         pc_before, op),
                            //print!("Jump offset: {}", self.regs.pc as i32
@@ -372,53 +446,52 @@ impl Cpu {
 
         self.total_cycles += cycles;
 
+        // RETI
+        if op == 0xD9 {
+            self.int_history.reti(self.total_cycles);
+        }
+
         //debug!("Cycles: {}", self.total_cycles);
 
         return cycles;
     }
 
-    fn handle_interrupts(&mut self, mem: &mut Memory) -> bool {
+    // Returns the cycles dispatching took, 0 if nothing was
+    fn handle_interrupts(&mut self, mem: &mut Memory) -> u32 {
         self.regs.int_step();
-        let interrupts = mem.ie_ & mem.if_;
-            macro_rules! print_interrupt (
-            ($s:expr) => {
-                warn!("{} IF: {:#08b}", $s.magenta(), mem.if_);
-            } );
-
-        if self.regs.ime && (interrupts != 0) {
-            // Vertical blank (ISR: 40 )
-            if interrupts & Interrupt::Vblank as u8 != 0 {
-                mem.if_ &= !(Interrupt::Vblank as u8);
-                rst!(self, mem, 0x40);
-                print_interrupt!("VBLANK");
-            }
-            // LCD status triggers (ISR: 48 )
-            if interrupts & Interrupt::LCDStat as u8 != 0 {
-                mem.if_ &= !(Interrupt::LCDStat as u8);
-                rst!(self, mem, 0x48);
-                print_interrupt!("LCD status triggers");
-            }
-            // Timer overflow (ISR: 50 )
-            if interrupts & Interrupt::Timer as u8 != 0 {
-                mem.if_ &= !(Interrupt::Timer as u8);
-                rst!(self, mem, 0x50);
-                print_interrupt!("Timer overflow");
-            }
-            // Serial link (ISR: 58 )
-            if interrupts & Interrupt::Serial as u8 != 0 {
-                mem.if_ &= !(Interrupt::Serial as u8);
-                rst!(self, mem, 0x58);
-                print_interrupt!("Serial link");
+        if !self.regs.ime || mem.ie_ & mem.if_ & 0x1F == 0 {
+            return 0;
+        }
+        self.regs.ime = false;
+        let cycles = INTERRUPT_CYCLES + if self.regs.halt {4} else {0};
+        self.regs.halt = false;
+
+        // PC is pushed high byte first and which interrupt it's for is only
+        // decided after that. With SP at 0x0000 the write lands on IE, and
+        // if that leaves nothing to service the dispatch is cancelled and
+        // execution goes on at 0x0000
+        let pc = self.regs.pc;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        mem.wb(self.regs.sp, (pc >> 8) as u8);
+        let pending = mem.ie_ & mem.if_;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        mem.wb(self.regs.sp, pc as u8);
+
+        match INTERRUPTS.iter().find(|&&kind| pending & kind as u8 != 0) {
+            Some(&kind) => {
+                mem.if_ &= !(kind as u8);
+                self.int_history.dispatched(kind, pc, self.total_cycles, kind.vector());
+                self.calls.entered(FrameKind::Interrupt(kind), kind.vector(), pc, mem.rom_bank(), self.regs.sp);
+                warn!("{} IF: {:#08b}", kind.name().magenta(), mem.if_);
+                self.regs.pc = kind.vector();
             }
-            // LCD status triggers (ISR: 60 )
-            if interrupts & Interrupt::Joypad as u8 != 0 {
-                mem.if_ &= !(Interrupt::Joypad as u8);
-                rst!(self, mem, 0x60);
-                print_interrupt!("Joypad press");
+            None => {
+                warn!("Interrupt dispatch cancelled, IE was overwritten");
+                self.regs.pc = 0x0000;
             }
-            return true;
         }
-        return false;
+        self.total_cycles += cycles;
+        cycles
     }
 
 }
@@ -470,6 +543,163 @@ impl fmt::Debug for Flags {
 mod cpu_tests {
     use super::*;
 
+    #[test]
+    fn interrupt_dispatch() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        cpu.regs.pc = 0xC000;
+        cpu.regs.sp = 0xD000;
+        cpu.regs.ime = true;
+        cpu.regs.halt = true;
+
+        // VBlank and Timer pending, only VBlank is serviced and HALT ends,
+        // a cycle later
+        mem.ie_ = 0x05;
+        mem.if_ = 0x05;
+        assert_eq!(cpu.exec(&mut mem), 24);
+        assert_eq!((cpu.regs.pc, mem.if_, cpu.regs.halt), (0x40, 0x04, false));
+        assert_eq!(mem.rw(0xCFFE), 0xC000);
+        assert_eq!(cpu.int_history.last(1)[0].handler, 0x40);
+
+        // Not before IME is set
+        assert_eq!(cpu.exec(&mut mem), 4);
+
+        // Pushing PC's high byte (0x04) over IE leaves only the Timer
+        cpu.regs.ime = true;
+        cpu.regs.sp = 0x0000;
+        cpu.regs.pc = 0x04C0;
+        mem.ie_ = 0x05;
+        mem.if_ = 0x05;
+        assert_eq!(cpu.exec(&mut mem), 20);
+        assert_eq!((cpu.regs.pc, mem.ie_, mem.if_), (0x50, 0x04, 0x01));
+
+        // And 0x00 cancels it altogether, IF stays as it was
+        cpu.regs.ime = true;
+        cpu.regs.sp = 0x0000;
+        cpu.regs.pc = 0x00C0;
+        mem.ie_ = 0x01;
+        mem.if_ = 0x01;
+        assert_eq!(cpu.exec(&mut mem), 20);
+        assert_eq!((cpu.regs.pc, mem.ie_, mem.if_), (0x0000, 0x00, 0x01));
+        assert_eq!(Interrupt::Joypad.vector(), 0x60);
+    }
+
+    #[test]
+    fn stop() {
+        use emulator::Model;
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        cpu.regs.pc = 0xC000;
+        mem.wb(0xC000, 0x10);
+        mem.wb(0xC002, 0x10);
+        mem.timer.div = 0x1234;
+
+        // STOP mode, skipping the byte after it
+        assert_eq!(cpu.exec(&mut mem), 4);
+        assert_eq!((cpu.regs.pc, cpu.regs.stop, mem.timer.div), (0xC002, true, 0));
+        cpu.wake();
+
+        // The CGB switches speed instead when KEY1 is armed
+        mem.model = Model::Cgb;
+        mem.wb(0xFF4D, 0x01);
+        assert_eq!(cpu.exec(&mut mem), 4 + SPEED_SWITCH_CYCLES);
+        assert!(!cpu.regs.stop);
+        assert!(mem.double_speed);
+    }
+
+    #[test]
+    fn speed_switch_stall() {
+        use emulator::Model;
+        use gpu::WIDTH;
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        mem.model = Model::Cgb;
+        // The background is all tile 1, which is black
+        for addr in 0x8010..0x8020 {
+            mem.wb(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            mem.wb(addr, 1);
+        }
+        mem.wb(0xFF47, 0xE4);
+        // LCD, tiles at 0x8000 and BG on. The first frame isn't shown, start
+        // on line 1 of the next one
+        mem.wb(0xFF40, 0x91);
+        while mem.peek(0xFF44) != 144 {
+            mem.tick(4);
+        }
+        while mem.peek(0xFF44) != 1 {
+            mem.tick(4);
+        }
+
+        cpu.regs.pc = 0xC000;
+        mem.wb(0xC000, 0x10);
+        mem.wb(0xFF4D, 0x01);
+        let clock = mem.gpu.clock;
+        let cycles = cpu.exec(&mut mem);
+        mem.tick(cycles);
+
+        // The PPU sees half the stall in double speed, and draws every line
+        // it goes through
+        let ly = 1 + (clock + cycles / 2) / 456;
+        assert_eq!(mem.peek(0xFF44) as u32, ly);
+        let black = mem.gpu.base_bg_palette()[3];
+        for y in 1..ly as usize {
+            let i = y * WIDTH * 4;
+            assert_eq!(&mem.gpu.image_data[i..i + 4], &black[..], "line {}", y);
+        }
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        cpu.regs.pc = 0xC000;
+        mem.wb(0xC000, 0xD3);
+        assert_eq!(cpu.exec(&mut mem), 4);
+        assert!(cpu.regs.locked());
+
+        // Stuck, even with an interrupt to take
+        cpu.regs.ime = true;
+        mem.ie_ = 0x01;
+        mem.if_ = 0x01;
+        for _ in 0..3 {
+            assert_eq!(cpu.exec(&mut mem), 4);
+        }
+        assert_eq!((cpu.regs.pc, mem.if_), (0xC001, 0x01));
+
+        cpu.reset_state();
+        assert!(!cpu.regs.locked());
+    }
+
+    #[test]
+    fn call_stack() {
+        let mut cpu = Cpu::new();
+        let mut mem = Memory::new();
+        // call $C010; call nz, $C010 / $C010: ret
+        for (i, &b) in [0xCD, 0x10, 0xC0, 0xC4, 0x10, 0xC0].iter().enumerate() {
+            mem.wb(0xC000 + i as u16, b);
+        }
+        mem.wb(0xC010, 0xC9);
+        cpu.regs.pc = 0xC000;
+
+        cpu.exec(&mut mem);
+        assert_eq!(cpu.calls.frames().len(), 1);
+        assert_eq!((cpu.calls.frames()[0].addr, cpu.calls.frames()[0].ret), (0xC010, 0xC003));
+        cpu.exec(&mut mem);
+        assert!(cpu.calls.frames().is_empty());
+        // Z is set, so it isn't taken
+        cpu.exec(&mut mem);
+        assert!(cpu.calls.frames().is_empty());
+
+        cpu.regs.ime = true;
+        mem.ie_ = 0x04;
+        mem.if_ = 0x04;
+        cpu.exec(&mut mem);
+        assert_eq!(cpu.calls.backtrace(&Symbols::new()), vec![String::from("#0  00:0050  Timer interrupt at C006  SP: FFFC")]);
+        assert!(cpu.crash_report(&Symbols::new()).ends_with("Call stack\n #0  00:0050  Timer interrupt at C006  SP: FFFC"));
+    }
+
     #[test]
     fn reg_get_and_set() {
         let mut cpu = Cpu::new();
@@ -0,0 +1,136 @@
+//
+//      Execution trace
+//
+
+// A line per instruction, written before it runs, so a run can be diffed
+// against another emulator's log. The doctor format is the one Gameboy
+// Doctor checks and most emulators can write:
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+// cycles is the same with the CPU's cycle count on the end, labels puts
+// where PC is in the game's symbols in front, and wadatsumi matches the
+// Wadatsumi emulator's logs.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+use mmu::Memory;
+use symbols::{self, Symbols};
+use super::Registers;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Doctor,
+    Cycles,
+    Labels,
+    Wadatsumi,
+}
+
+impl Default for TraceFormat {
+    fn default() -> TraceFormat {
+        TraceFormat::Doctor
+    }
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TraceFormat, String> {
+        match s {
+            "doctor" => Ok(TraceFormat::Doctor),
+            "cycles" => Ok(TraceFormat::Cycles),
+            "labels" => Ok(TraceFormat::Labels),
+            "wadatsumi" => Ok(TraceFormat::Wadatsumi),
+            _ => Err(format!("Unknown trace format \"{}\", expected doctor, cycles, labels or wadatsumi", s)),
+        }
+    }
+}
+
+pub struct Tracer {
+    out: Box<Write>,
+    format: TraceFormat,
+    // For the labels format
+    pub symbols: Symbols,
+}
+
+impl Tracer {
+    // A file, or stdout for "-"
+    pub fn open(path: &str, format: TraceFormat) -> Result<Tracer, String> {
+        if path == "-" {
+            return Ok(Tracer::new(io::stdout(), format));
+        }
+        let file = try!(File::create(path).map_err(|e| format!("{}: {}", path, e)));
+        Ok(Tracer::new(file, format))
+    }
+
+    pub fn new<W: Write + 'static>(out: W, format: TraceFormat) -> Tracer {
+        Tracer { out: Box::new(BufWriter::new(out)), format: format, symbols: Symbols::new() }
+    }
+
+    pub fn log(&mut self, r: &Registers, mem: &Memory, cycles: u32) -> io::Result<()> {
+        let line = format_line(self.format, r, mem, cycles, &self.symbols);
+        writeln!(self.out, "{}", line)
+    }
+}
+
+fn format_line(format: TraceFormat, r: &Registers, mem: &Memory, cycles: u32, symbols: &Symbols) -> String {
+    match format {
+        TraceFormat::Doctor => doctor_line(r, mem),
+        TraceFormat::Cycles => format!("{} CY:{}", doctor_line(r, mem), cycles),
+        TraceFormat::Labels => {
+            let bank = symbols::bank_of(r.pc, mem.rom_bank());
+            let label = symbols.describe(bank, r.pc).unwrap_or_else(|| match bank {
+                Some(bank) => format!("{:02X}:{:04X}", bank, r.pc),
+                None => format!("{:04X}", r.pc),
+            });
+            format!("{:<24} {}", label, doctor_line(r, mem))
+        }
+        TraceFormat::Wadatsumi => format!(
+            "PC[0x{:02X}]: 0x{:04X} AF: 0x{:04X} BC: 0x{:04X} DE: 0x{:04X} HL: 0x{:04X} SP: 0x{:04X} IE: {:08b} IF: {:08b}",
+            mem.peek(r.pc), r.pc, r.af(), r.bc(), r.de(), r.hl(), r.sp, mem.ie_, mem.if_),
+    }
+}
+
+fn doctor_line(r: &Registers, mem: &Memory) -> String {
+    let pcmem: Vec<String> = (0..4)
+        .map(|i| format!("{:02X}", mem.peek(r.pc.wrapping_add(i))))
+        .collect();
+    format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        r.a, r.f(), r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc, pcmem.join(","))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let mut mem: Memory = Memory::new();
+        for (i, &b) in [0x00, 0xC3, 0x13, 0x02].iter().enumerate() {
+            mem.wb(0xC000 + i as u16, b);
+        }
+        let mut regs = Registers::default();
+        regs.af_set(0x01B0);
+        regs.bc_set(0x0013);
+        regs.de_set(0x00D8);
+        regs.hl_set(0x014D);
+        regs.sp = 0xFFFE;
+        regs.pc = 0xC000;
+
+        let none = Symbols::new();
+        assert_eq!(format_line(TraceFormat::Doctor, &regs, &mem, 0, &none),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,C3,13,02");
+        assert_eq!(format_line(TraceFormat::Cycles, &regs, &mem, 1234, &none),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,C3,13,02 CY:1234");
+        assert!(format_line(TraceFormat::Wadatsumi, &regs, &mem, 0, &none).starts_with("PC[0x00]: 0xC000 AF: 0x01B0"));
+        let symbols = Symbols::parse("00:C000 wCode\n");
+        assert_eq!(format_line(TraceFormat::Labels, &regs, &mem, 0, &symbols),
+            format!("{:<24} {}", "wCode", format_line(TraceFormat::Doctor, &regs, &mem, 0, &none)));
+        assert_eq!("cycles".parse(), Ok(TraceFormat::Cycles));
+        assert!("bgb".parse::<TraceFormat>().is_err());
+    }
+}
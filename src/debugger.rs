@@ -0,0 +1,480 @@
+//
+//      Debug console
+//
+
+// Commands are read from the terminal on a separate thread and run between
+// events of the main loop, so the game window stays live while debugging.
+
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use disasm;
+use emulator::Emulator;
+use heatmap::Access;
+use breakpoints::WatchKind;
+use oamview;
+use palview;
+
+const HELP: &'static str =
+"Commands (numbers are hex, $ or 0x prefixes are optional, addresses can be
+labels from the game's .sym file):
+    s, step [count]          Run instructions, 1 by default
+    n, next                  Step over a CALL or RST, running all of it
+    o, out                   Run until the current routine returns
+    u, until [addr]          Run until addr is reached once, or the last one
+                             again (bank:addr for one in a ROM bank)
+    c, continue              Resume emulation
+    p, pause                 Pause emulation
+    r, regs                  Show the CPU registers and flags
+    m, read <addr> [len]     Dump memory, 16 bytes by default
+    w, write <addr> <byte>   Write a byte to memory
+    l, list [addr] [count]   Disassemble count instructions, 16 by default,
+                             from addr (bank:addr for another ROM bank) or
+                             around PC
+    b, break [addr]          Pause before the instruction at addr runs (only
+                             with that bank mapped for bank:addr), or list
+                             the breakpoints
+    d, delete <n>            Remove breakpoint n
+    watch [r|w|rw <range>]   Stop after an instruction reads (r), writes (w)
+                             or does either (rw) to an address or a range
+                             like C000-C0FF, or list the watchpoints
+    unwatch <n>              Remove watchpoint n
+    i, ints [count]          Show the latest interrupts, 16 by default
+    bt, backtrace            Show the calls and interrupts the CPU is in
+    prof [count]             Functions and addresses that took the most cycles
+                             (--profile-game), 16 by default
+    disp [expr]              Watch an expression, or show the watched ones
+    undisp <n>               Stop watching expression n
+    hot <r|w|x> [count]      Most read/written/executed addresses (--heatmap)
+    oam                      List the sprites in OAM
+    pal                      List the palettes and their colors
+    dump [name]              Write VRAM and OAM to <name>_vram0.bin (and
+                             _vram1.bin on the CGB) and <name>_oam.bin
+    h, help                  Show this";
+
+pub struct Debugger {
+    lines: Receiver<String>,
+    // A next or out is running, where it stops gets shown
+    stepping: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => if tx.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        println!("Debug console ready, type \"help\" for commands");
+        prompt();
+        Debugger { lines: rx, stepping: false }
+    }
+
+    // Run the commands typed since the last call
+    pub fn poll(&mut self, emu: &mut Emulator) {
+        if self.stepping && !emu.is_running() {
+            self.stepping = false;
+            println!("");
+            show_position(emu);
+            prompt();
+        }
+        let hit = emu.take_breakpoint_hit().map(|(i, bp)| (i, bp.addr));
+        if let Some((i, addr)) = hit {
+            println!("\nStopped at breakpoint {}", i);
+            print_line("", &line_at(emu, None, addr));
+            prompt();
+        }
+        if let Some(hit) = emu.take_watchpoint_hit() {
+            println!("\nStopped at {}", hit);
+            print_line("", &line_at(emu, None, hit.pc));
+            prompt();
+        }
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => {
+                    match run_command(emu, &line) {
+                        Ok(running) => self.stepping = running,
+                        Err(err) => println!("{}", err),
+                    }
+                    prompt();
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+fn prompt() {
+    print!("> ");
+    io::stdout().flush().unwrap();
+}
+
+// Registers and the next instruction
+fn show_position(emu: &Emulator) {
+    println!("{:?}", emu.cpu.get_regs());
+    print_line("", &line_at(emu, None, emu.cpu.get_regs().pc()));
+}
+
+// The instruction at addr, with the game's labels
+fn line_at(emu: &Emulator, bank: Option<u8>, addr: u16) -> disasm::Line {
+    let mut line = disasm::line_at(&emu.mem, bank, addr);
+    line.symbolize(&emu.symbols, emu.mem.rom_bank());
+    line
+}
+
+// With its label on a line of its own
+fn print_line(prefix: &str, line: &disasm::Line) {
+    if let Some(ref label) = line.label {
+        println!("{}:", label);
+    }
+    println!("{}{}", prefix, line);
+}
+
+// Returns whether a next or out was started that hasn't finished yet
+fn run_command(emu: &mut Emulator, line: &str) -> Result<bool, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(false);
+    }
+    let args = &words[1..];
+
+    match words[0] {
+        "s" | "step" => {
+            let count = match args.get(0) {
+                Some(n) => try!(parse_number(n)),
+                None => 1,
+            };
+            emu.set_running(false);
+            for _ in 0..count {
+                try!(emu.step_instruction());
+            }
+            show_position(emu);
+        }
+        "n" | "next" => {
+            emu.set_running(false);
+            if !try!(emu.step_over()) {
+                return Ok(true);
+            }
+            show_position(emu);
+        }
+        "o" | "out" => {
+            try!(emu.step_out());
+            return Ok(true);
+        }
+        "u" | "until" => {
+            match args.get(0) {
+                Some(arg) => {
+                    let (bank, addr) = try!(parse_location(emu, arg));
+                    try!(emu.run_to(bank, addr));
+                }
+                None => try!(emu.run_to_cursor()),
+            }
+            return Ok(true);
+        }
+        "c" | "continue" => emu.set_running(true),
+        "p" | "pause" => {
+            emu.set_running(false);
+            println!("{:?}", emu.cpu.get_regs());
+        }
+        "r" | "regs" => {
+            println!("{:?}", emu.cpu.get_regs());
+            println!("{:?}", emu.cpu.get_flags());
+            if emu.cpu.get_regs().locked() {
+                println!(" The CPU is locked up by an invalid opcode");
+            }
+        }
+        "m" | "read" => {
+            let (_, addr) = try!(parse_location(emu, try!(args.get(0).ok_or("Missing address"))));
+            let len = match args.get(1) {
+                Some(n) => try!(parse_number(n)),
+                None => 16,
+            };
+            dump_memory(emu, addr, len);
+        }
+        "w" | "write" => {
+            let (_, addr) = try!(parse_location(emu, try!(args.get(0).ok_or("Missing address"))));
+            let val = try!(args.get(1).ok_or("Missing value").and_then(|v|
+                parse_number(v).map_err(|_| "Invalid value")));
+            if val > 0xFF {
+                return Err(String::from("Value doesn't fit in a byte"));
+            }
+            try!(emu.poke(addr, val as u8));
+        }
+        "l" | "list" => {
+            let count = match args.get(1) {
+                Some(n) => try!(parse_number(n)) as usize,
+                None => 0x10,
+            };
+            let lines = match args.get(0) {
+                Some(arg) => {
+                    let (bank, addr) = try!(parse_location(emu, arg));
+                    let mut lines = Vec::new();
+                    let mut addr = addr;
+                    for _ in 0..count {
+                        let line = line_at(emu, bank, addr);
+                        addr = addr.wrapping_add(line.bytes.len() as u16);
+                        lines.push(line);
+                    }
+                    lines
+                }
+                None => {
+                    let mut lines = disasm::around(&emu.mem, emu.cpu.get_regs().pc(), count / 2, count - count / 2 - 1);
+                    for line in lines.iter_mut() {
+                        line.symbolize(&emu.symbols, emu.mem.rom_bank());
+                    }
+                    lines
+                }
+            };
+            let pc = emu.cpu.get_regs().pc();
+            for line in lines {
+                print_line(if line.addr == pc {"> "} else {"  "}, &line);
+            }
+        }
+        "b" | "break" => {
+            if !args.is_empty() {
+                let (bank, addr) = try!(parse_location(emu, args[0]));
+                let n = try!(emu.add_breakpoint(bank, addr));
+                println!("Breakpoint {}: {}", n, emu.breakpoints()[n]);
+                return Ok(false);
+            }
+            for (i, bp) in emu.breakpoints().iter().enumerate() {
+                println!("{}: {}", i, bp);
+            }
+        }
+        "d" | "delete" => {
+            let n = try!(args.get(0).ok_or("Which one?").and_then(|n|
+                n.parse::<usize>().map_err(|_| "Invalid index")));
+            try!(emu.remove_breakpoint(n).ok_or("No such breakpoint"));
+        }
+        "watch" => {
+            if !args.is_empty() {
+                let kind = try!(WatchKind::parse(args[0]));
+                let (start, end) = try!(parse_range(emu, args.get(1)));
+                let n = try!(emu.add_watchpoint(kind, start, end));
+                println!("Watchpoint {}: {}", n, emu.watchpoints()[n]);
+                return Ok(false);
+            }
+            for (i, wp) in emu.watchpoints().iter().enumerate() {
+                println!("{}: {}", i, wp);
+            }
+        }
+        "unwatch" => {
+            let n = try!(args.get(0).ok_or("Which one?").and_then(|n|
+                n.parse::<usize>().map_err(|_| "Invalid index")));
+            try!(emu.remove_watchpoint(n).ok_or("No such watchpoint"));
+        }
+        "i" | "ints" => {
+            let count = match args.get(0) {
+                Some(n) => try!(parse_number(n)),
+                None => 0x10,
+            };
+            for event in emu.cpu.int_history.last(count as usize) {
+                println!("{}", event);
+            }
+        }
+        "bt" | "backtrace" => {
+            let lines = emu.cpu.calls.backtrace(&emu.symbols);
+            if lines.is_empty() {
+                println!("Not inside any call");
+            }
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        "prof" => {
+            let count = match args.get(0) {
+                Some(n) => try!(parse_number(n)) as usize,
+                None => 0x10,
+            };
+            let prof = try!(emu.profiler.as_ref().ok_or("Start with --profile-game to count cycles"));
+            let percent = |cycles: u64| cycles as f64 * 100. / prof.total.max(1) as f64;
+            println!("Functions:");
+            for (name, cycles) in prof.functions(&emu.symbols, count) {
+                println!("{:>6.2}%  {:>12}  {}", percent(cycles), cycles, name);
+            }
+            println!("Addresses:");
+            for (loc, cycles) in prof.hottest(count) {
+                println!("{:>6.2}%  {:>12}  {}", percent(cycles), cycles, loc);
+            }
+        }
+        "disp" => {
+            let src = line.trim()[words[0].len()..].trim();
+            if !src.is_empty() {
+                try!(emu.watches.add(src));
+                emu.watches.refresh(emu.cpu.get_regs(), &emu.mem);
+            }
+            for (i, watch) in emu.watches.watches.iter().enumerate() {
+                println!("{}: {}", i, watch.display());
+            }
+        }
+        "undisp" => {
+            let n = try!(args.get(0).ok_or("Which one?").and_then(|n|
+                n.parse::<usize>().map_err(|_| "Invalid index")));
+            try!(emu.watches.remove(n).ok_or("No such watch"));
+        }
+        "hot" => {
+            let access = match args.get(0) {
+                Some(&"r") => Access::Read,
+                Some(&"w") => Access::Write,
+                Some(&"x") => Access::Exec,
+                _ => return Err(String::from("Which accesses? r, w or x")),
+            };
+            let count = match args.get(1) {
+                Some(n) => try!(parse_number(n)),
+                None => 0x10,
+            };
+            let heat = try!(emu.mem.heatmap.as_ref().ok_or("Start with --heatmap to count accesses"));
+            for (addr, n) in heat.hottest(access, count as usize) {
+                println!("{:04X}: {}", addr, n);
+            }
+        }
+        "oam" => {
+            for i in 0..40 {
+                println!("{}", oamview::describe(&emu.mem.gpu, i));
+            }
+        }
+        "pal" => {
+            for row in 0..palview::PaletteViewer::rows(&emu.mem.gpu) {
+                println!("{}", palview::describe(&emu.mem.gpu, row));
+            }
+        }
+        "dump" => {
+            let name = args.get(0).map_or(format!("dump_{}", emu.frame_count), |s| s.to_string());
+            let files = try!(emu.mem.gpu.dump_memory(&name).map_err(|e| format!("Couldn't dump: {}", e)));
+            println!("Wrote {}", files.join(", "));
+        }
+        "h" | "help" => println!("{}", HELP),
+        cmd => return Err(format!("Unknown command \"{}\", try \"help\"", cmd)),
+    }
+    Ok(false)
+}
+
+fn dump_memory(emu: &mut Emulator, addr: u16, len: u32) {
+    let end = (addr as u32 + len).min(0x10000);
+    let mut line_start = addr as u32;
+    while line_start < end {
+        let line_end = (line_start + 16).min(end);
+        let bytes: Vec<String> = (line_start..line_end)
+            .map(|a| format!("{:02X}", emu.mem.peek(a as u16)))
+            .collect();
+        println!("{:04X}: {}", line_start, bytes.join(" "));
+        line_start = line_end;
+    }
+}
+
+fn parse_addr(arg: Option<&&str>) -> Result<u16, String> {
+    let arg = try!(arg.ok_or("Missing address"));
+    let n = try!(parse_number(arg));
+    if n > 0xFFFF {
+        return Err(format!("Address {} out of range", arg));
+    }
+    Ok(n as u16)
+}
+
+// An address or a range of them, like C000-C0FF or wStart-wEnd
+fn parse_range(emu: &Emulator, arg: Option<&&str>) -> Result<(u16, u16), String> {
+    let arg = try!(arg.ok_or("Missing address"));
+    let mut parts = arg.splitn(2, '-');
+    let (_, start) = try!(parse_location(emu, parts.next().unwrap_or("")));
+    let end = match parts.next() {
+        Some(end) => try!(parse_location(emu, end)).1,
+        None => start,
+    };
+    Ok((start, end))
+}
+
+// A label, or what parse_bank_addr() takes
+fn parse_location(emu: &Emulator, arg: &str) -> Result<(Option<u8>, u16), String> {
+    match emu.symbols.find(arg) {
+        Some((bank, addr)) if addr >= 0x4000 && addr < 0x8000 => Ok((Some(bank), addr)),
+        Some((_, addr)) => Ok((None, addr)),
+        None => parse_bank_addr(arg),
+    }
+}
+
+// An address, or bank:addr for a ROM address in a bank that might not be
+// mapped
+pub fn parse_bank_addr(arg: &str) -> Result<(Option<u8>, u16), String> {
+    let mut parts = arg.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(bank), Some(addr)) => {
+            let bank = try!(parse_number(bank));
+            let addr = try!(parse_addr(Some(&addr)));
+            if bank > 0xFF {
+                return Err(format!("Bank {} out of range", bank));
+            }
+            if addr < 0x4000 || addr > 0x7FFF {
+                return Err(format!("{:04X} isn't in a switchable ROM bank", addr));
+            }
+            Ok((Some(bank as u8), addr))
+        }
+        _ => Ok((None, try!(parse_addr(Some(&arg))))),
+    }
+}
+
+// Hex, with an optional $ or 0x
+pub fn parse_number(arg: &str) -> Result<u32, String> {
+    let digits = if arg.starts_with("0x") || arg.starts_with("0X") {
+        &arg[2..]
+    } else if arg.starts_with('$') {
+        &arg[1..]
+    } else {
+        arg
+    };
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid number \"{}\"", arg))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("C000"), Ok(0xC000));
+        assert_eq!(parse_number("$ff"), Ok(0xFF));
+        assert_eq!(parse_number("0x10"), Ok(0x10));
+        assert!(parse_number("xyz").is_err());
+        assert!(parse_addr(Some(&"10000")).is_err());
+        assert!(parse_addr(None).is_err());
+        assert_eq!(parse_bank_addr("02:4000"), Ok((Some(2), 0x4000)));
+        assert_eq!(parse_bank_addr("$150"), Ok((None, 0x150)));
+        assert!(parse_bank_addr("02:C000").is_err());
+    }
+
+    #[test]
+    fn labels() {
+        use config::Config;
+        use symbols::Symbols;
+
+        let mut emu = Emulator::with_rom(vec![0; 0x8000], &Config::default());
+        emu.symbols = Symbols::parse("00:0150 Main\n02:4000 Draw\n00:C100 wHP\n");
+        assert_eq!(parse_location(&emu, "Main"), Ok((None, 0x0150)));
+        assert_eq!(parse_location(&emu, "Draw"), Ok((Some(2), 0x4000)));
+        assert_eq!(parse_location(&emu, "C000"), Ok((None, 0xC000)));
+        assert_eq!(line_at(&emu, None, 0x0150).label, Some(String::from("Main")));
+
+        assert_eq!(parse_range(&emu, Some(&"C000-C0FF")), Ok((0xC000, 0xC0FF)));
+        assert_eq!(parse_range(&emu, Some(&"$FF44")), Ok((0xFF44, 0xFF44)));
+        assert_eq!(parse_range(&emu, Some(&"wHP")), Ok((0xC100, 0xC100)));
+        assert_eq!(parse_range(&emu, Some(&"C0F0-wHP")), Ok((0xC0F0, 0xC100)));
+        assert!(parse_range(&emu, Some(&"C000-")).is_err());
+
+        // Watching and writing take them too
+        assert!(run_command(&mut emu, "watch w wHP").is_ok());
+        assert_eq!((emu.watchpoints()[0].start, emu.watchpoints()[0].end), (0xC100, 0xC100));
+        assert!(run_command(&mut emu, "w wHP 5").is_ok());
+        assert_eq!(emu.mem.peek(0xC100), 5);
+    }
+}
@@ -0,0 +1,333 @@
+//
+//      Disassembler
+//
+
+// Turns SM83 machine code back into RGBDS style assembly, e.g.
+// `01:4000  C3 50 01  jp $0150`. Relative jumps show where they land and
+// unused opcodes come out as `db`. Addresses in 0x4000-0x7FFF can be read
+// from any ROM bank, not just the one mapped, with the bank in front. With
+// the game's symbols, addresses that have a label show it instead.
+
+use std::fmt;
+
+use mmu::Memory;
+use symbols::{self, Symbols};
+
+const R: [&'static str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const RP: [&'static str; 4] = ["bc", "de", "hl", "sp"];
+const RP2: [&'static str; 4] = ["bc", "de", "hl", "af"];
+const CC: [&'static str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&'static str; 8] = ["add a, ", "adc a, ", "sub ", "sbc a, ", "and ", "xor ", "or ", "cp "];
+const ROT: [&'static str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const MISC: [&'static str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub addr: u16,
+    // ROM bank the bytes came from, None outside of ROM
+    pub bank: Option<u8>,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // The label at addr, see symbolize()
+    pub label: Option<String>,
+}
+
+impl Line {
+    // Puts in the labels for the line and the addresses it uses. Ones in
+    // 0x4000-0x7FFF are in the line's own bank if it's in one, otherwise in
+    // rom_bank, the one mapped
+    pub fn symbolize(&mut self, symbols: &Symbols, rom_bank: u8) {
+        if symbols.is_empty() {
+            return;
+        }
+        self.label = symbols.label_at(self.bank, self.addr).map(String::from);
+        let bank = match self.bank {
+            Some(bank) if self.addr >= 0x4000 => bank,
+            _ => rom_bank,
+        };
+
+        let mut text = String::new();
+        let mut rest = &self.text[..];
+        while let Some(i) = rest.find('$') {
+            text.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let digits = after.chars().take_while(|c| c.is_digit(16)).count();
+            // Not the $FF00 of $FF00+c
+            let label = if digits == 4 && !after[4..].starts_with('+') {
+                let addr = u16::from_str_radix(&after[..4], 16).unwrap();
+                symbols.label_at(symbols::bank_of(addr, bank), addr)
+            } else {
+                None
+            };
+            match label {
+                Some(label) => {
+                    text.push_str(label);
+                    rest = &after[4..];
+                }
+                None => {
+                    text.push('$');
+                    rest = after;
+                }
+            }
+        }
+        text.push_str(rest);
+        self.text = text;
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        match self.bank {
+            Some(bank) => try!(write!(f, "{:02X}:{:04X}", bank, self.addr)),
+            None => try!(write!(f, "   {:04X}", self.addr)),
+        }
+        write!(f, "  {:<8}  {}", bytes.join(" "), self.text)
+    }
+}
+
+// The instruction starting with `bytes`, at `addr`, and how long it is.
+// Bytes it doesn't use can be anything
+pub fn decode(bytes: [u8; 3], addr: u16) -> (String, u16) {
+    let op = bytes[0];
+    let n = bytes[1];
+    let nn = (bytes[2] as u16) << 8 | bytes[1] as u16;
+    // jr lands relative to the next instruction
+    let target = addr.wrapping_add(2).wrapping_add(n as i8 as u16);
+    let (x, y, z) = ((op >> 6) as usize, (op >> 3 & 7) as usize, (op & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+
+    let (text, len) = match (x, z) {
+        (0, 0) => match y {
+            0 => (String::from("nop"), 1),
+            1 => (format!("ld [${:04X}], sp", nn), 3),
+            2 => (String::from("stop"), 2),
+            3 => (format!("jr ${:04X}", target), 2),
+            _ => (format!("jr {}, ${:04X}", CC[y - 4], target), 2),
+        },
+        (0, 1) if q == 0 => (format!("ld {}, ${:04X}", RP[p], nn), 3),
+        (0, 1) => (format!("add hl, {}", RP[p]), 1),
+        (0, 2) => {
+            let m = ["[bc]", "[de]", "[hl+]", "[hl-]"][p];
+            (if q == 0 { format!("ld {}, a", m) } else { format!("ld a, {}", m) }, 1)
+        }
+        (0, 3) => (format!("{} {}", if q == 0 {"inc"} else {"dec"}, RP[p]), 1),
+        (0, 4) => (format!("inc {}", R[y]), 1),
+        (0, 5) => (format!("dec {}", R[y]), 1),
+        (0, 6) => (format!("ld {}, ${:02X}", R[y], n), 2),
+        (0, _) => (String::from(MISC[y]), 1),
+        (1, 6) if y == 6 => (String::from("halt"), 1),
+        (1, _) => (format!("ld {}, {}", R[y], R[z]), 1),
+        (2, _) => (format!("{}{}", ALU[y], R[z]), 1),
+        (_, 0) => match y {
+            0 ... 3 => (format!("ret {}", CC[y]), 1),
+            4 => (format!("ldh [$FF{:02X}], a", n), 2),
+            5 => (format!("add sp, {}", n as i8), 2),
+            6 => (format!("ldh a, [$FF{:02X}]", n), 2),
+            _ => (format!("ld hl, sp{:+}", n as i8), 2),
+        },
+        (_, 1) if q == 0 => (format!("pop {}", RP2[p]), 1),
+        (_, 1) => (String::from(["ret", "reti", "jp hl", "ld sp, hl"][p]), 1),
+        (_, 2) => match y {
+            0 ... 3 => (format!("jp {}, ${:04X}", CC[y], nn), 3),
+            4 => (String::from("ld [$FF00+c], a"), 1),
+            5 => (format!("ld [${:04X}], a", nn), 3),
+            6 => (String::from("ld a, [$FF00+c]"), 1),
+            _ => (format!("ld a, [${:04X}]", nn), 3),
+        },
+        (_, 3) if y == 0 => (format!("jp ${:04X}", nn), 3),
+        (_, 3) if y == 1 => (decode_cb(n), 2),
+        (_, 3) if y == 6 => (String::from("di"), 1),
+        (_, 3) if y == 7 => (String::from("ei"), 1),
+        (_, 4) if y < 4 => (format!("call {}, ${:04X}", CC[y], nn), 3),
+        (_, 5) if q == 0 => (format!("push {}", RP2[p]), 1),
+        (_, 5) if p == 0 => (format!("call ${:04X}", nn), 3),
+        (_, 6) => (format!("{}${:02X}", ALU[y], n), 2),
+        (_, 7) => (format!("rst ${:02X}", y * 8), 1),
+        // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+        _ => (format!("db ${:02X}", op), 1),
+    };
+    (text, len)
+}
+
+fn decode_cb(op: u8) -> String {
+    let (x, y, z) = (op >> 6, (op >> 3 & 7) as usize, (op & 7) as usize);
+    match x {
+        0 => format!("{} {}", ROT[y], R[z]),
+        1 => format!("bit {}, {}", y, R[z]),
+        2 => format!("res {}, {}", y, R[z]),
+        _ => format!("set {}, {}", y, R[z]),
+    }
+}
+
+// What the CPU would read, except that 0x4000-0x7FFF comes from `bank` when
+// there's one
+fn read(mem: &Memory, bank: Option<u8>, addr: u16) -> u8 {
+    match bank {
+        Some(bank) if addr >= 0x4000 && addr < 0x8000 => mem.rom_byte(bank, addr),
+        _ => mem.peek(addr),
+    }
+}
+
+// The instruction at addr, from `bank` or the mapped one
+pub fn line_at(mem: &Memory, bank: Option<u8>, addr: u16) -> Line {
+    let bytes = [
+        read(mem, bank, addr),
+        read(mem, bank, addr.wrapping_add(1)),
+        read(mem, bank, addr.wrapping_add(2)),
+    ];
+    let (text, len) = decode(bytes, addr);
+    Line {
+        addr: addr,
+        bank: match addr {
+            0x0000 ... 0x3FFF => Some(0),
+            0x4000 ... 0x7FFF => Some(bank.unwrap_or(mem.rom_bank())),
+            _ => None,
+        },
+        bytes: bytes[..len as usize].to_vec(),
+        text: text,
+        label: None,
+    }
+}
+
+// Every instruction starting in start..end
+pub fn disassemble(mem: &Memory, bank: Option<u8>, start: u16, end: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr < end as u32 {
+        let line = line_at(mem, bank, addr as u16);
+        addr += line.bytes.len() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+// Up to `before` instructions leading to pc, the one at pc and `after` more.
+// Code can't be decoded backwards, so this starts further and further back
+// until decoding from there lands right on pc
+pub fn around(mem: &Memory, pc: u16, before: usize, after: usize) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for back in (1..before as u16 * 3 + 1).rev() {
+        let start = pc.wrapping_sub(back);
+        let mut run = Vec::new();
+        let mut offset = 0;
+        while offset < back {
+            let line = line_at(mem, None, start.wrapping_add(offset));
+            offset += line.bytes.len() as u16;
+            run.push(line);
+        }
+        if offset == back {
+            let skip = run.len().saturating_sub(before);
+            lines = run.split_off(skip);
+            break;
+        }
+    }
+
+    let mut addr = pc;
+    for _ in 0..after + 1 {
+        let line = line_at(mem, None, addr);
+        addr = addr.wrapping_add(line.bytes.len() as u16);
+        lines.push(line);
+    }
+    lines
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod disasm_tests {
+    use super::*;
+
+    #[test]
+    fn opcodes() {
+        let cases: [([u8; 3], &str, u16); 22] = [
+            ([0x00, 0, 0], "nop", 1),
+            ([0x08, 0x34, 0x12], "ld [$1234], sp", 3),
+            ([0x10, 0x00, 0], "stop", 2),
+            ([0x18, 0xFE, 0], "jr $C000", 2),
+            ([0x20, 0x05, 0], "jr nz, $C007", 2),
+            ([0x21, 0x00, 0xD0], "ld hl, $D000", 3),
+            ([0x2A, 0, 0], "ld a, [hl+]", 1),
+            ([0x36, 0x42, 0], "ld [hl], $42", 2),
+            ([0x27, 0, 0], "daa", 1),
+            ([0x76, 0, 0], "halt", 1),
+            ([0x7E, 0, 0], "ld a, [hl]", 1),
+            ([0x89, 0, 0], "adc a, c", 1),
+            ([0xAF, 0, 0], "xor a", 1),
+            ([0xE0, 0x44, 0], "ldh [$FF44], a", 2),
+            ([0xE8, 0xFE, 0], "add sp, -2", 2),
+            ([0xF8, 0x05, 0], "ld hl, sp+5", 2),
+            ([0xE2, 0, 0], "ld [$FF00+c], a", 1),
+            ([0xCD, 0x50, 0x01], "call $0150", 3),
+            ([0xF5, 0, 0], "push af", 1),
+            ([0xFF, 0, 0], "rst $38", 1),
+            ([0xCB, 0x7C, 0], "bit 7, h", 2),
+            ([0xD3, 0, 0], "db $D3", 1),
+        ];
+        for &(bytes, text, len) in cases.iter() {
+            assert_eq!(decode(bytes, 0xC000), (String::from(text), len), "{:02X?}", bytes);
+        }
+        assert_eq!(decode([0xCB, 0x36, 0], 0).0, "swap [hl]");
+        assert_eq!(decode([0xFE, 0x90, 0], 0).0, "cp $90");
+    }
+
+    #[test]
+    fn banks() {
+        let mut mem: Memory = Memory::new();
+        let mut rom = vec![0; 0x10000];
+        rom[0x8000] = 0xC3;
+        rom[0x8001] = 0x50;
+        rom[0x8002] = 0x01;
+        mem.set_rom(rom);
+
+        let lines = disassemble(&mem, Some(2), 0x4000, 0x4004);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].text, "jp $0150");
+        assert_eq!(lines[0].to_string(), "02:4000  C3 50 01  jp $0150");
+        assert_eq!(lines[1].addr, 0x4003);
+        assert_eq!(line_at(&mem, Some(2), 0x0000).bank, Some(0));
+        assert_eq!(line_at(&mem, Some(2), 0xC000).bank, None);
+    }
+
+    #[test]
+    fn listing() {
+        let mut mem: Memory = Memory::new();
+        // ld a, $01; ld hl, $D000; inc a; ld [hl], a; jr -4
+        let code = [0x3E, 0x01, 0x21, 0x00, 0xD0, 0x3C, 0x77, 0x18, 0xFC];
+        for (i, &b) in code.iter().enumerate() {
+            mem.wb(0xC000 + i as u16, b);
+        }
+        let lines = around(&mem, 0xC005, 2, 2);
+        let addrs: Vec<u16> = lines.iter().map(|l| l.addr).collect();
+        assert_eq!(addrs, vec![0xC000, 0xC002, 0xC005, 0xC006, 0xC007]);
+        assert_eq!(lines[4].text, "jr $C005");
+    }
+
+    #[test]
+    fn labels() {
+        let mut mem: Memory = Memory::new();
+        let mut rom = vec![0; 0x10000];
+        // call $4010; jp $4000; ldh [$FF44], a; ld [$FF00+c], a
+        rom[0x8000..0x8006].copy_from_slice(&[0xCD, 0x10, 0x40, 0xC3, 0x00, 0x40]);
+        mem.set_rom(rom);
+        let symbols = Symbols::parse("02:4000 Draw\n02:4010 Draw.sprites\n03:4000 Other\n00:FF44 rLY\n00:FF00 rP1\n");
+
+        let mut lines = disassemble(&mem, Some(2), 0x4000, 0x4006);
+        for line in lines.iter_mut() {
+            line.symbolize(&symbols, 3);
+        }
+        assert_eq!(lines[0].label, Some(String::from("Draw")));
+        assert_eq!(lines[0].text, "call Draw.sprites");
+        assert_eq!(lines[1].label, None);
+        assert_eq!(lines[1].text, "jp Draw");
+
+        let mut line = Line { addr: 0x0150, bank: Some(0), bytes: vec![0xE0, 0x44], text: String::from("ldh [$FF44], a"), label: None };
+        line.symbolize(&symbols, 3);
+        assert_eq!(line.text, "ldh [rLY], a");
+        let mut line = Line { addr: 0x0150, bank: Some(0), bytes: vec![0xE2], text: String::from("ld [$FF00+c], a"), label: None };
+        line.symbolize(&symbols, 3);
+        assert_eq!(line.text, "ld [$FF00+c], a");
+    }
+}
@@ -0,0 +1,60 @@
+//
+//      Discord Rich Presence
+//
+
+// Only built with the `discord` feature. Shows the game title, how long it's
+// been played for and whether it's paused or being recorded.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use discord_rpc_client::Client;
+
+pub struct Presence {
+    client: Client,
+    title: String,
+    start: u64,     // UNIX timestamp the game was started at
+
+    // Last state sent, Discord rate limits updates so only send changes
+    // (running, recording)
+    state: Option<(bool, bool)>,
+}
+
+impl Presence {
+    pub fn new(app_id: u64, title: &str) -> Presence {
+        let mut client = Client::new(app_id);
+        client.start();
+
+        Presence {
+            client: client,
+            title: title.to_string(),
+            start: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs()).unwrap_or(0),
+            state: None,
+        }
+    }
+
+    // Another game was started
+    pub fn set_game(&mut self, title: &str) {
+        self.title = title.to_string();
+        self.start = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        self.state = None;
+    }
+
+    // Call once a frame
+    pub fn update(&mut self, running: bool, recording: bool) {
+        if self.state == Some((running, recording)) {
+            return;
+        }
+        self.state = Some((running, recording));
+
+        let title = &self.title;
+        let start = self.start;
+        let result = self.client.set_activity(|act| act
+            .details(title.as_str())
+            .state(if !running {"Paused"} else if recording {"Recording"} else {"Playing"})
+            .timestamps(|t| t.start(start)));
+        if let Err(err) = result {
+            warn!("Couldn't update Discord presence: {:?}", err);
+        }
+    }
+}
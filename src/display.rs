@@ -0,0 +1,180 @@
+//
+//      Display modes
+//
+
+// Where in the window the screen goes. The window can be resized freely, the
+// screen is then drawn:
+//
+// integer - as big as fits at a whole multiple of 160x144, centered, so every
+//           Game Boy pixel is the same size (the default)
+// fit     - as big as fits keeping the 10:9 shape, centered
+// stretch - over the whole window
+//
+// The picture can also be turned (--rotate) for a monitor on its side, and
+// mirrored (--mirror). Both only change how the frame is drawn into that
+// rect, the emulator, screenshots and recordings never see them.
+
+use std::str::FromStr;
+
+use gpu::{WIDTH, HEIGHT};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayMode {
+    Integer,
+    Fit,
+    Stretch,
+}
+
+impl Default for DisplayMode {
+    fn default() -> DisplayMode {
+        DisplayMode::Integer
+    }
+}
+
+impl FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DisplayMode, String> {
+        match s {
+            "integer" => Ok(DisplayMode::Integer),
+            "fit" => Ok(DisplayMode::Fit),
+            "stretch" => Ok(DisplayMode::Stretch),
+            _ => Err(format!("Unknown display mode \"{}\", expected integer, fit or stretch", s)),
+        }
+    }
+}
+
+// Clockwise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation::Deg0
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        match s {
+            "0" => Ok(Rotation::Deg0),
+            "90" => Ok(Rotation::Deg90),
+            "180" => Ok(Rotation::Deg180),
+            "270" => Ok(Rotation::Deg270),
+            _ => Err(format!("Unknown rotation \"{}\", expected 0, 90, 180 or 270", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    // Left and right swapped, before turning
+    pub mirror: bool,
+}
+
+impl Orientation {
+    // The screen's size once turned
+    pub fn size(&self) -> [f64; 2] {
+        match self.rotation {
+            Rotation::Deg90 | Rotation::Deg270 => [HEIGHT as f64, WIDTH as f64],
+            _ => [WIDTH as f64, HEIGHT as f64],
+        }
+    }
+
+    // Maps a point of something dims in size drawn over the screen (the
+    // frame, or the grid at window scale) to where it goes in `rect`, which
+    // has the turned shape
+    pub fn transform(&self, rect: [f64; 4], dims: [u32; 2]) -> [[f64; 3]; 2] {
+        // From 0-1 across the picture, mirrored or not, to 0-1 across rect:
+        // nx = p1 * s + q1 * t + r1, ny = p2 * s + q2 * t + r2
+        let ((p1, q1, r1), (p2, q2, r2)) = match self.rotation {
+            Rotation::Deg0 => ((1., 0., 0.), (0., 1., 0.)),
+            Rotation::Deg90 => ((0., -1., 1.), (1., 0., 0.)),
+            Rotation::Deg180 => ((-1., 0., 1.), (0., -1., 1.)),
+            Rotation::Deg270 => ((0., 1., 0.), (-1., 0., 1.)),
+        };
+        let (w, h) = (dims[0] as f64, dims[1] as f64);
+        // s = sa * x + sb, t = y / h
+        let (sa, sb) = if self.mirror {(-1. / w, 1.)} else {(1. / w, 0.)};
+        [[rect[2] * p1 * sa, rect[2] * q1 / h, rect[0] + rect[2] * (p1 * sb + r1)],
+         [rect[3] * p2 * sa, rect[3] * q2 / h, rect[1] + rect[3] * (p2 * sb + r2)]]
+    }
+}
+
+impl DisplayMode {
+    // [x, y, w, h] of the screen in a window of this size
+    pub fn screen_rect(&self, window: [f64; 2]) -> [f64; 4] {
+        self.rect_for(window, [WIDTH as f64, HEIGHT as f64])
+    }
+
+    // Same for a screen of another shape, a turned one
+    pub fn rect_for(&self, window: [f64; 2], size: [f64; 2]) -> [f64; 4] {
+        let (w, h) = (size[0], size[1]);
+        let fit = (window[0] / w).min(window[1] / h);
+        let scale = match *self {
+            DisplayMode::Stretch => return [0., 0., window[0], window[1]],
+            // Too small a window still shows something
+            DisplayMode::Integer => fit.floor().max(1.),
+            DisplayMode::Fit => fit,
+        };
+        let (sw, sh) = (w * scale, h * scale);
+        [((window[0] - sw) / 2.).floor(), ((window[1] - sh) / 2.).floor(), sw, sh]
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod display_tests {
+    use super::*;
+
+    #[test]
+    fn rects() {
+        let window = [1000., 600.];
+        assert_eq!(DisplayMode::Integer.screen_rect(window), [180., 12., 640., 576.]);
+        let fit = DisplayMode::Fit.screen_rect(window);
+        assert_eq!((fit[0], fit[1], fit[3]), (166., 0., 600.));
+        assert!((fit[2] / fit[3] - 160. / 144.).abs() < 1e-9);
+        assert_eq!(DisplayMode::Stretch.screen_rect(window), [0., 0., 1000., 600.]);
+        assert_eq!(DisplayMode::Integer.screen_rect([100., 100.]), [-30., -22., 160., 144.]);
+        assert_eq!("fit".parse(), Ok(DisplayMode::Fit));
+    }
+
+    #[test]
+    fn orientation() {
+        let apply = |m: [[f64; 3]; 2], x: f64, y: f64| {
+            (m[0][0] * x + m[0][1] * y + m[0][2], m[1][0] * x + m[1][1] * y + m[1][2])
+        };
+        let turned = Orientation { rotation: Rotation::Deg90, mirror: false };
+        assert_eq!(turned.size(), [144., 160.]);
+        let rect = DisplayMode::Integer.rect_for([1000., 600.], turned.size());
+        assert_eq!(rect, [284., 60., 432., 480.]);
+
+        // The top left corner of the frame ends up top right, the bottom
+        // left top left
+        let m = turned.transform(rect, [160, 144]);
+        assert_eq!(apply(m, 0., 0.), (716., 60.));
+        assert_eq!(apply(m, 0., 144.), (284., 60.));
+        assert_eq!(apply(m, 160., 144.), (284., 540.));
+
+        let mirrored = Orientation { rotation: Rotation::Deg0, mirror: true };
+        let m = mirrored.transform([10., 20., 320., 288.], [160, 144]);
+        assert_eq!(apply(m, 0., 0.), (330., 20.));
+        assert_eq!(apply(m, 160., 144.), (10., 308.));
+
+        let m = Orientation::default().transform([10., 20., 320., 288.], [160, 144]);
+        assert_eq!(apply(m, 80., 72.), (170., 164.));
+        assert_eq!("270".parse(), Ok(Rotation::Deg270));
+        assert!("45".parse::<Rotation>().is_err());
+    }
+}
@@ -1,47 +1,223 @@
-use piston::input::*;
-use piston_window::{PistonWindow, Texture};
-use gfx_device_gl::Resources as R;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
-use std::{io, fmt};
-use std::path::Path;
-use piston::window::Window;
+use std::{io, fmt, panic};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use cpu::Cpu;
+use cpu::trace::Tracer;
 use mmu::Memory;
+use input::Joypad;
+use perf::SubsystemTimes;
 use cartridge::*;
+use config::Config;
+use colorize;
+use palettes;
+use gamedb::{self, GameDb, GameInfo};
+use heatmap::Heatmap;
+use profiler::Profiler;
+use symbols::Symbols;
+use watch::WatchList;
+use breakpoints::{Breakpoint, Breakpoints, WatchHit, WatchKind, Watchpoint};
+use mobile::{self, MobileAdapter};
+use saves::{self, PostSaveHook};
+use recording::Recorder;
+use gpu::ScreenData;
 
 // Clock cycles between every screen refresh
 pub const SCREEN_REFRESH_INTERVAL: u32 = 70224; // clock cycles
 
+// What hardcore mode rules out
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Restricted {
+    // Changing memory from outside the game
+    Cheats,
+    // Stepping through instructions or frames
+    Slowdown,
+    // Running the CPU faster than the rest of the hardware
+    Overclock,
+}
+
+// What check_allowed() gives back, Memory only adds watchpoints with one
+pub struct Allowed(());
+
+impl Restricted {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Restricted::Cheats => "Cheats are",
+            Restricted::Slowdown => "Slowdown is",
+            Restricted::Overclock => "Overclocking is",
+        }
+    }
+}
+
+// Hardware being emulated
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Model {
+    Dmg,
+    Sgb,
+    Cgb,
+}
+
+impl Model {
+    // What the game would most likely be played on
+    pub fn from_header(header: &CartridgeHeader) -> Model {
+        if header.cgb_flag() & 0x80 != 0 {
+            Model::Cgb
+        } else if header.supports_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+}
+
+// Where a step over, step out or run to stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepGoal {
+    // Back at pc with the stack as it was, once a call returns
+    Return { pc: u16, sp: u16 },
+    // After a RET that takes the stack above sp
+    Out { sp: u16 },
+    // Before the instruction at addr, with the ROM bank mapped if there's one
+    At { bank: Option<u8>, addr: u16 },
+}
+
+// Length of a CALL or RST, None for other opcodes
+fn call_length(op: u8) -> Option<u16> {
+    match op {
+        0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => Some(3),
+        _ if op & 0xC7 == 0xC7 => Some(1),
+        _ => None,
+    }
+}
+
+fn is_return(op: u8) -> bool {
+    match op {
+        0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8 => true,
+        _ => false,
+    }
+}
+
+// What happened while running a frame
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FrameEvents {
+    // A complete frame is in mem.gpu.screen()
+    pub frame_ready: bool,
+    // The CPU is in STOP mode, the frame was cut short. Nothing runs until a
+    // button is pressed
+    pub stopped: bool,
+    // Bytes sent out of the serial port
+    pub serial: Vec<u8>,
+    // Always empty until there's an APU
+    pub audio_samples: Vec<i16>,
+    // Address of the breakpoint execution stopped at
+    pub breakpoint: Option<u16>,
+    // Or the watchpoint access that stopped it
+    pub watchpoint: Option<WatchHit>,
+    // A step over or out got where it was going
+    pub stepped: bool,
+    // The GPU had to recompile tiles
+    pub tileset_rebuilt: bool,
+}
+
 pub struct Emulator {
     pub cpu: Cpu,
     pub mem: Memory,
     pub rom_header: CartridgeHeader,
+    pub model: Model,
+    // Database entry for this game, if there is one
+    pub game_info: Option<GameInfo>,
+
+    // Battery save of the cartridge RAM
+    save_path: PathBuf,
+    // Where the palette picked for this game is kept, see palettes.rs
+    palette_path: PathBuf,
+    post_save_hook: Option<PostSaveHook>,
 
     is_frame_stepping: bool,
     is_instr_stepping: bool,
     is_debugging: bool,
+    is_uncapped: bool, // no frame pacing, run as fast as the host allows
+    // Frames the Gpu doesn't draw while uncapped, n of every m
+    frame_skip: Option<(u32, u32)>,
+    // Everything in Restricted is refused for the rest of the session
+    hardcore: bool,
+    // Extra CPU speed in percent, the PPU and timers still get the nominal
+    // cycles so games that lag get more done per frame. Not accurate at all
+    overclock: u32,
+    // Leftover of the cycle scaling, in hundredths of a cycle
+    overclock_rest: u32,
     frame_cycles: u32, // cycles left until the frame ends
     pub frame_count: u32,
+    // state_checksum() at the end of the last frame
+    pub frame_checksum: u32,
+    // Time spent per subsystem, only measured when set
+    pub profile: Option<SubsystemTimes>,
+    // Cycles per game function, written to profile_path when done
+    pub profiler: Option<Profiler>,
+    profile_path: Option<String>,
+    // Labels from the rom's .sym file, empty without one
+    pub symbols: Symbols,
+    // Evaluated at the end of every frame
+    pub watches: WatchList,
+    // Checked before every instruction, and the one execution stopped at so
+    // continuing runs it instead of stopping there again
+    breakpoints: Breakpoints,
+    break_skip: Option<u16>,
+    // Running until a call returns, see step_over() and step_out()
+    step_goal: Option<StepGoal>,
+    // Where the last run to went, see run_to_cursor()
+    cursor: Option<(Option<u8>, u16)>,
+    // Gets every frame while recording
+    pub recorder: Option<Recorder>,
 }
 
 impl Emulator {
-    pub fn new<W: Window>(window: &PistonWindow<W>, rom_path: &String) -> Emulator {
+    pub fn new(config: &Config) -> Emulator {
+        Emulator::with_rom(try_open_rom(&config.rom_path), config)
+    }
+
+    // Start a rom that's already in memory. Nothing here needs a window:
+    // step_frame() runs it and frame() has the picture after every VBlank,
+    // for tests and scripts. config.rom_path is still where saves go
+    pub fn with_rom(rom: Vec<u8>, config: &Config) -> Emulator {
         let mut emu = Emulator {
             cpu: Cpu::new(),
-            mem: Memory::new(window),
+            mem: Memory::new(),
             rom_header: Default::default(),
+            model: Model::Dmg,
+            game_info: None,
+            save_path: saves::save_path(&config.rom_path),
+            palette_path: palettes::preset_path(&config.rom_path),
+            post_save_hook: config.post_save_hook.clone(),
             is_frame_stepping: false,
             is_instr_stepping: false,
             is_debugging: true,
+            is_uncapped: config.uncapped,
+            frame_skip: config.frame_skip,
+            hardcore: config.hardcore,
+            overclock: 0,
+            overclock_rest: 0,
             frame_cycles: 0,
             frame_count: 0,
+            frame_checksum: 0,
+            profile: None,
+            profiler: None,
+            profile_path: config.profile_game.clone(),
+            symbols: load_symbols(config),
+            watches: WatchList::new(),
+            breakpoints: Breakpoints::new(),
+            break_skip: None,
+            step_goal: None,
+            cursor: None,
+            recorder: None,
         };
 
-        // Read rom and move ownership to memory component
-        emu.mem.set_rom(try_open_rom(&rom_path));
+        // Move ownership of the rom to memory component
+        emu.mem.set_rom(rom);
         emu.read_header();
 
         // If the rom is more than 32KB, it has VRAM so we need to copy it
@@ -50,7 +226,143 @@ impl Emulator {
         }
         emu.mem.copy_rom();
 
-        emu.mem.find_mbc(emu.rom_header.cartridge_type);
+        if config.heatmap {
+            emu.mem.heatmap = Some(Heatmap::new());
+        }
+        if config.profile_game.is_some() {
+            emu.profiler = Some(Profiler::new());
+        }
+        if config.overclock > 0 {
+            if let Err(err) = emu.set_overclock(config.overclock) {
+                warn!("{}, running at normal speed", err);
+            }
+        }
+        if config.mobile_adapter {
+            emu.mem.serial.device = Some(Box::new(MobileAdapter::new(config.mobile_server.clone(),
+                mobile::config_path(&config.rom_path))));
+        }
+
+        for src in config.watches.iter() {
+            if let Err(err) = emu.watches.add(src) {
+                warn!("Ignoring watch \"{}\": {}", src, err);
+            }
+        }
+        for &(bank, addr) in config.breakpoints.iter() {
+            if let Err(err) = emu.add_breakpoint(bank, addr) {
+                warn!("Ignoring breakpoint: {}", err);
+            }
+        }
+        if let Some(ref path) = config.trace {
+            match Tracer::open(path, config.trace_format) {
+                Ok(mut tracer) => {
+                    tracer.symbols = emu.symbols.clone();
+                    emu.cpu.tracer = Some(tracer);
+                }
+                Err(err) => error!("Couldn't start the trace: {}", err),
+            }
+        }
+        if let Some(ref path) = config.watch_csv {
+            if let Err(err) = emu.watches.log_to(path) {
+                error!("Couldn't create {}: {}", path, err);
+            }
+        }
+
+        let db = GameDb::load(gamedb::DEFAULT_PATH).unwrap_or_else(|err| {
+            warn!("Couldn't load game database {}: {}", gamedb::DEFAULT_PATH, err);
+            GameDb::new()
+        });
+        emu.game_info = db.lookup(&emu.rom_header).cloned();
+        info!("Game database entry: {:?}", emu.game_info);
+
+        // Trust the database over the header, some dumps have wrong ones
+        let mut cartridge_type = emu.rom_header.cartridge_type;
+        if let Some(db_type) = emu.game_info.as_ref().and_then(|info| info.cartridge_type) {
+            if db_type != cartridge_type {
+                warn!("Header says cartridge type {:02X}, database says {:02X}", cartridge_type, db_type);
+                cartridge_type = db_type;
+            }
+        }
+        emu.mem.find_mbc(cartridge_type);
+
+        let mut ram_size = emu.rom_header.ram_size();
+        if let Some(db_size) = emu.game_info.as_ref().and_then(|info| info.ram_size) {
+            ram_size = db_size;
+        }
+        if emu.mem.cart_has_ram() {
+            emu.mem.set_ext_ram_size(ram_size_bytes(ram_size));
+        }
+
+        if emu.mem.has_battery() && !emu.mem.ext_ram().is_empty() {
+            match saves::find_save(&config.rom_path) {
+                Some(path) => match saves::read(&path) {
+                    Ok(data) => {
+                        let (data, changes) = saves::normalize(&data, emu.mem.ext_ram().len());
+                        for change in changes.iter() {
+                            warn!("Save {}: {}", path.display(), change);
+                        }
+                        info!("Loaded save {}", path.display());
+                        emu.mem.load_ext_ram(&data);
+                    }
+                    Err(err) => error!("Couldn't read save {}: {}", path.display(), err),
+                },
+                None => info!("No save found for {}", config.rom_path),
+            }
+        }
+
+        emu.model = config.model.unwrap_or_else(|| {
+            let model = Model::from_header(&emu.rom_header);
+            let no_sgb = emu.game_info.as_ref().map_or(false, |info| info.has_quirk("no-sgb"));
+            if model == Model::Sgb && no_sgb {
+                info!("Not using the SGB, the game database says it's broken on it");
+                Model::Dmg
+            } else {
+                model
+            }
+        });
+        info!("Emulating {:?}", emu.model);
+        emu.mem.model = emu.model;
+
+        match emu.model {
+            Model::Sgb if emu.rom_header.supports_sgb() => {
+                info!("Game supports SGB functions");
+                emu.mem.sgb.enabled = true;
+                emu.mem.gpu.init_sgb_mode();
+            }
+            Model::Cgb => {
+                let dmg_compat = emu.rom_header.cgb_flag() & 0x80 == 0;
+                emu.mem.gpu.init_cgb_mode(dmg_compat);
+
+                // The CGB boot ROM colorizes games made for the DMG
+                if dmg_compat {
+                    let pals = colorize::palettes(&emu.rom_header,
+                        config.cgb_palette.as_ref().map(|s| s.as_str()));
+                    emu.mem.gpu.set_base_palettes(pals.bg, pals.obp0, pals.obp1);
+                }
+            }
+            _ => {}
+        }
+
+        emu.mem.gpu.blend_frames(config.frame_blend);
+        if config.pixel_fifo {
+            emu.mem.gpu.use_pixel_fifo();
+        }
+        emu.mem.gpu.restrict_access = !config.unlocked_vram;
+        emu.mem.gpu.oam_bug = config.oam_bug;
+
+        // Picking a palette overrides the CGB's
+        if let Some(ref path) = config.palettes_file {
+            match palettes::load(path) {
+                Ok(pals) => emu.mem.gpu.palettes.extend(pals),
+                Err(err) => error!("Couldn't load palettes {}: {}", path, err),
+            }
+        }
+        // --palette wins over the one picked for the game
+        let palette = config.palette.clone().or_else(|| palettes::load_preset(&emu.palette_path));
+        if let Some(ref name) = palette {
+            if let Err(err) = emu.mem.gpu.select_palette(name) {
+                warn!("{}, keeping the default", err);
+            }
+        }
 
         // Give immutable reference of rom header to memory component
         //emu.mem.borrow_rom_header(&emu.rom_header);
@@ -58,36 +370,414 @@ impl Emulator {
         emu
     }
 
-    // Render screen
-    pub fn render<W: Window>(&mut self, args: &RenderArgs, window: &mut PistonWindow<W>, framebuffer: &mut Texture<R>, evt: &Event) {
-        self.mem.gpu.display(window, evt);
-    }
-
     // Update state
     // Gets called once a frame
-    pub fn update(&mut self, args: &UpdateArgs) {
+    pub fn update(&mut self) -> FrameEvents {
+        self.run_frame_reporting()
+    }
+
+    // Run exactly one frame with the given buttons held on the first
+    // controller, for frontends other than the Piston one
+    pub fn step_frame(&mut self, input: Joypad) -> FrameEvents {
+        self.mem.input.set_joypad(0, &input);
+        self.run_frame_reporting()
+    }
+
+    // run_frame, logging where the game was if the emulator panics
+    fn run_frame_reporting(&mut self) -> FrameEvents {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.run_frame())) {
+            Ok(events) => events,
+            Err(err) => {
+                error!("The emulator crashed in frame {}\n{}", self.frame_count, self.cpu.crash_report(&self.symbols));
+                panic::resume_unwind(err)
+            }
+        }
+    }
+
+    // The last frame the Gpu finished, RGBA8
+    pub fn frame(&self) -> &ScreenData {
+        self.mem.gpu.frame()
+    }
+
+    fn run_frame(&mut self) -> FrameEvents {
+        let mut events = FrameEvents::default();
+        let rebuilds = self.mem.gpu.tileset_rebuilds;
+        // Recordings get every frame
+        self.mem.gpu.frame_skip = if self.is_uncapped && self.recorder.is_none() {self.frame_skip} else {None};
 
         // If is_stepping is false, runs for a frame (~70k clock cycles)
         // If it's true runs for just 1 instruction
 
-        while self.frame_cycles < SCREEN_REFRESH_INTERVAL {
-            let cycles = self.cpu.exec(&mut self.mem);
-            self.mem.timer.step(cycles, &mut self.mem.if_);
-            self.mem.gpu.step(cycles, &mut self.mem.if_);
-
-            self.frame_cycles += cycles;
+        while self.frame_cycles < self.frame_length() {
+            if self.cpu.get_regs().stop {
+                if !self.mem.input.any_pressed() {
+                    events.stopped = true;
+                    events.serial = self.mem.take_serial();
+                    return events;
+                }
+                self.cpu.wake();
+            }
+            if self.at_breakpoint() {
+                let pc = self.cpu.get_regs().pc();
+                info!("Breakpoint at {:04X}", pc);
+                self.set_running(false);
+                events.breakpoint = Some(pc);
+                events.serial = self.mem.take_serial();
+                return events;
+            }
+            let pc = self.cpu.get_regs().pc();
+            let op = if self.step_goal.is_some() {self.mem.peek(pc)} else {0};
+            self.exec_instruction();
+            if self.mem.retire_watchpoints(pc) {
+                info!("Watchpoint hit by the instruction at {:04X}", pc);
+                self.set_running(false);
+                events.watchpoint = self.mem.watch_hit().cloned();
+                events.serial = self.mem.take_serial();
+                return events;
+            }
+            if self.reached_goal(op) {
+                info!("Stopped at {:04X}", self.cpu.get_regs().pc());
+                self.set_running(false);
+                events.stepped = true;
+                events.serial = self.mem.take_serial();
+                return events;
+            }
 
-            if self.cpu.get_regs().stop {self.cpu.stop(); return; }
             if self.is_instr_stepping { self.set_running(false) }; // kinda broken
         }
-        if self.frame_cycles >= SCREEN_REFRESH_INTERVAL {
-            self.frame_cycles -= SCREEN_REFRESH_INTERVAL;
+        self.end_frame();
+
+        events.frame_ready = true;
+        events.serial = self.mem.take_serial();
+        events.tileset_rebuilt = self.mem.gpu.tileset_rebuilds != rebuilds;
+        events
+    }
+
+    // Whether to stop before the instruction at pc
+    fn at_breakpoint(&mut self) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+        let pc = self.cpu.get_regs().pc();
+        if self.break_skip == Some(pc) {
+            // Carrying on from it. It stays skipped while the CPU is halted
+            // (or locked up) there, the instruction only runs once it wakes up
+            if !self.cpu.get_regs().halted() && !self.cpu.get_regs().locked() {
+                self.break_skip = None;
+            }
+            return false;
+        }
+        self.break_skip = None;
+        if self.breakpoints.check(pc, self.mem.rom_bank()) {
+            self.break_skip = Some(pc);
+            return true;
+        }
+        false
+    }
+
+    // Run an instruction, or a whole CALL or RST and everything it calls.
+    // Returns whether it's done, otherwise the emulator runs until the call
+    // returns (or something else stops it)
+    pub fn step_over(&mut self) -> Result<bool, String> {
+        let pc = self.cpu.get_regs().pc();
+        let sp = self.cpu.get_regs().sp();
+        let op = self.mem.peek(pc);
+        try!(self.step_instruction());
+        if let Some(len) = call_length(op) {
+            self.step_goal = Some(StepGoal::Return { pc: pc.wrapping_add(len), sp: sp });
+            if !self.reached_goal(op) {
+                self.set_running(true);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Run until the routine returns to its caller, or the interrupt handler
+    // to the code it interrupted, going by the call stack. Calls it makes
+    // return to a lower stack, those don't count. Code the call stack
+    // doesn't know how it got into stops at the first RET that takes the
+    // stack above where it is now
+    pub fn step_out(&mut self) -> Result<(), String> {
+        try!(self.check_allowed(Restricted::Slowdown));
+        let sp = self.cpu.get_regs().sp();
+        self.step_goal = Some(match self.cpu.calls.frames().last() {
+            Some(frame) => StepGoal::Return { pc: frame.ret, sp: frame.sp.wrapping_add(2) },
+            None => StepGoal::Out { sp: sp },
+        });
+        self.set_running(true);
+        Ok(())
+    }
+
+    // Run until the instruction at addr is next, like a breakpoint that
+    // goes away once it's hit. With a bank it only stops while that bank
+    // is mapped. The address is kept as the cursor for run_to_cursor()
+    pub fn run_to(&mut self, bank: Option<u8>, addr: u16) -> Result<(), String> {
+        try!(self.check_allowed(Restricted::Slowdown));
+        if bank.is_some() && (addr < 0x4000 || addr > 0x7FFF) {
+            return Err(format!("{:04X} isn't in a switchable ROM bank", addr));
+        }
+        self.cursor = Some((bank, addr));
+        self.step_goal = Some(StepGoal::At { bank: bank, addr: addr });
+        self.set_running(true);
+        Ok(())
+    }
+
+    // Run to where the last run_to() went again
+    pub fn run_to_cursor(&mut self) -> Result<(), String> {
+        match self.cursor {
+            Some((bank, addr)) => self.run_to(bank, addr),
+            None => Err(String::from("No cursor yet, run to an address from the console first")),
+        }
+    }
+
+    // Whether the instruction that just ran, op, finished a step over or out
+    fn reached_goal(&mut self, op: u8) -> bool {
+        let regs = self.cpu.get_regs();
+        let reached = match self.step_goal {
+            Some(StepGoal::Return { pc, sp }) => regs.pc() == pc && regs.sp() == sp,
+            Some(StepGoal::Out { sp }) => is_return(op) && regs.sp() > sp,
+            Some(StepGoal::At { bank, addr }) =>
+                regs.pc() == addr && bank.map_or(true, |bank| bank == self.mem.rom_bank()),
+            None => false,
+        };
+        if reached {
+            self.step_goal = None;
+        }
+        reached
+    }
+
+    // Stopping the game counts as slowing it down
+    pub fn add_breakpoint(&mut self, bank: Option<u8>, addr: u16) -> Result<usize, String> {
+        try!(self.check_allowed(Restricted::Slowdown));
+        self.breakpoints.add(bank, addr)
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints.list
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(index)
+    }
+
+    // The breakpoint execution stopped at since the last call
+    pub fn take_breakpoint_hit(&mut self) -> Option<(usize, &Breakpoint)> {
+        self.breakpoints.take_hit()
+    }
+
+    // So does stopping when it touches memory
+    pub fn add_watchpoint(&mut self, kind: WatchKind, start: u16, end: u16) -> Result<usize, String> {
+        let allowed = try!(self.check_allowed(Restricted::Slowdown));
+        self.mem.add_watchpoint(allowed, kind, start, end)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.mem.watchpoints()
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        self.mem.remove_watchpoint(index)
+    }
+
+    // The watchpoint access execution stopped after since the last call
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchHit> {
+        self.mem.take_watch_hit()
+    }
+
+    // Run a single instruction, finishing the frame if it was the last one
+    // in it. Returns the cycles it took.
+    pub fn step_instruction(&mut self) -> Result<u32, String> {
+        try!(self.check_allowed(Restricted::Slowdown));
+        let pc = self.cpu.get_regs().pc();
+        let cycles = self.exec_instruction();
+        // Shown by the console, it doesn't stop anything more than stepping
+        self.mem.retire_watchpoints(pc);
+        if self.frame_cycles >= self.frame_length() {
+            self.end_frame();
+        }
+        Ok(cycles)
+    }
+
+    // Write to memory from outside the game, e.g. the debugger
+    pub fn poke(&mut self, addr: u16, val: u8) -> Result<(), String> {
+        try!(self.check_allowed(Restricted::Cheats));
+        self.mem.poke(addr, val);
+        // Nothing waits on a DMA started from here
+        self.mem.take_stalled();
+        Ok(())
+    }
+
+    fn exec_instruction(&mut self) -> u32 {
+        // Memory accesses step the hardware as they happen, what's left of
+        // the instruction's cycles after it retires are internal ones.
+        // Overclocking squeezes the cycles, so it all goes at the end
+        let cycles;
+        let hw_cycles;
+        let was_locked = self.cpu.get_regs().locked();
+        if let Some(ref mut prof) = self.profiler {
+            prof.start(self.cpu.calls.frames(), self.cpu.get_regs().pc(), self.mem.rom_bank());
+        }
+        self.mem.access_timing = self.overclock == 0;
+        match self.profile {
+            Some(ref mut times) => {
+                // The hardware stepped during accesses counts as CPU time
+                let start = Instant::now();
+                cycles = self.cpu.exec(&mut self.mem);
+                self.mem.access_timing = false;
+                hw_cycles = scale_cycles(cycles, self.overclock, &mut self.overclock_rest)
+                    + self.mem.take_stalled();
+                let rest = hw_cycles.saturating_sub(self.mem.take_ticked());
+                let cpu_done = Instant::now();
+                self.mem.tick_timers(rest);
+                let timer_done = Instant::now();
+                self.mem.tick_video(rest);
+
+                times.cpu += cpu_done - start;
+                times.timer += timer_done - cpu_done;
+                times.gpu += timer_done.elapsed();
+            }
+            None => {
+                cycles = self.cpu.exec(&mut self.mem);
+                self.mem.access_timing = false;
+                hw_cycles = scale_cycles(cycles, self.overclock, &mut self.overclock_rest)
+                    + self.mem.take_stalled();
+                let rest = hw_cycles.saturating_sub(self.mem.take_ticked());
+                self.mem.tick(rest);
+            }
+        }
+        if let Some(ref mut prof) = self.profiler {
+            prof.finish(cycles);
+        }
+        if self.cpu.get_regs().locked() && !was_locked {
+            error!("{}", self.cpu.crash_report(&self.symbols));
+        }
+
+        if self.mem.gpu.take_frame_done() {
+            self.record_frame();
+        }
+
+        self.frame_cycles += hw_cycles;
+        cycles
+    }
+
+    // Hand the finished frame to the recording, if there is one
+    fn record_frame(&mut self) {
+        let failed = match self.recorder {
+            Some(ref mut rec) => rec.push(self.mem.gpu.frame()).err(),
+            None => None,
+        };
+        if let Some(err) = failed {
+            error!("Recording stopped: {}", err);
+            self.recorder = None;
         }
+    }
 
+    // CPU cycles in a frame, twice as many in double speed as the PPU still
+    // takes the same time to draw it
+    fn frame_length(&self) -> u32 {
+        if self.mem.double_speed {
+            SCREEN_REFRESH_INTERVAL * 2
+        } else {
+            SCREEN_REFRESH_INTERVAL
+        }
+    }
+
+    fn end_frame(&mut self) {
+        let length = self.frame_length();
+        if self.frame_cycles >= length {
+            self.frame_cycles -= length;
+        }
+
+        let end_start = Instant::now();
         self.frame_count += 1;
+        self.frame_checksum = self.state_checksum();
         if self.is_frame_stepping { self.set_running(false) };
+        self.watches.update(self.frame_count, self.cpu.get_regs(), &self.mem);
         // Update gpu image data
         self.mem.gpu.update();
+        if let Some(ref mut times) = self.profile {
+            times.frame_end += end_start.elapsed();
+        }
+    }
+
+    // Write the game code profile out and log where the time went
+    pub fn finish_profile(&mut self) {
+        let (prof, path) = match (self.profiler.take(), self.profile_path.take()) {
+            (Some(prof), Some(path)) => (prof, path),
+            _ => return,
+        };
+        info!("Where the game spent its {} cycles:", prof.total);
+        for (name, cycles) in prof.functions(&self.symbols, 10) {
+            info!("{:>6.2}%  {}", cycles as f64 * 100. / prof.total.max(1) as f64, name);
+        }
+        match prof.save(&path, &self.symbols) {
+            Ok(_) => info!("Profile saved to {}", path),
+            Err(err) => error!("Couldn't save the profile: {}", err),
+        }
+    }
+
+    // Write the cartridge RAM to the save file, then run the post-save hook
+    pub fn flush_save(&self) {
+        if !self.mem.has_battery() || self.mem.ext_ram().is_empty() {
+            return;
+        }
+        if let Err(err) = saves::write_atomic(&self.save_path, self.mem.ext_ram()) {
+            error!("Couldn't write save {}: {}", self.save_path.display(), err);
+            return;
+        }
+        info!("Saved to {}", self.save_path.display());
+
+        if let Some(ref hook) = self.post_save_hook {
+            if let Err(err) = hook.run(&self.save_path) {
+                error!("Post-save hook {:?} failed: {}", hook, err);
+            }
+        }
+    }
+
+    // Switch to one of the Gpu's palettes and use it for this game from now
+    // on, it's loaded again next time
+    pub fn pick_palette(&mut self, name: &str) -> Result<(), String> {
+        try!(self.mem.gpu.select_palette(name));
+        try!(palettes::save_preset(&self.palette_path, name).map_err(|err|
+            format!("Couldn't write {}: {}", self.palette_path.display(), err)));
+        info!("Palette {} picked for this game", name);
+        Ok(())
+    }
+
+    // Canonical title and region if the game is known, otherwise the title
+    // in the header
+    pub fn game_title(&self) -> String {
+        match self.game_info {
+            Some(ref info) => info.full_title(),
+            None => self.rom_header.get_game_title(),
+        }
+    }
+
+    // Cheap hash of the registers, WRAM and VRAM. Two emulators that are in
+    // sync end every frame with the same checksum, so comparing these finds
+    // the exact frame a movie or netplay session desynced at.
+    pub fn state_checksum(&self) -> u32 {
+        let regs = self.cpu.get_regs();
+        let mut hash = FNV_OFFSET;
+        for &r in [regs.af(), regs.bc(), regs.de(), regs.hl(), regs.sp(), regs.pc()].iter() {
+            hash = fnv1a(hash, &[(r >> 8) as u8, r as u8]);
+        }
+        hash = fnv1a(hash, self.mem.wram());
+        hash = fnv1a(hash, &self.mem.gpu.vrambank[..]);
+        fnv1a(hash, &self.mem.gpu.vrambank1[..])
+    }
+
+    // Run frames back to back until `budget` is used up, so the emulator
+    // isn't held back by the 60 updates a second. Returns whether any of
+    // them rebuilt the tileset.
+    pub fn update_uncapped(&mut self, budget: Duration) -> bool {
+        let start = Instant::now();
+        let mut rebuilt = false;
+        while self.is_running() && start.elapsed() < budget {
+            rebuilt |= self.update().tileset_rebuilt;
+        }
+        rebuilt
     }
 
     fn read_header(&mut self) {
@@ -102,30 +792,117 @@ impl Emulator {
     pub fn is_running(&self) -> bool {
         self.cpu.is_running
     }
+    // Pausing gives up on a step over or out
     pub fn set_running(&mut self, state: bool) {
         self.cpu.is_running = state;
+        if !state {
+            self.step_goal = None;
+        }
     }
     pub fn toggle_running(&mut self) {
-        self.cpu.is_running = !self.cpu.is_running;
+        let running = self.cpu.is_running;
+        self.set_running(!running);
     }
     pub fn toggle_debugging(&mut self) {
         self.is_debugging = !self.is_debugging;
     }
+    pub fn is_hardcore(&self) -> bool {
+        self.hardcore
+    }
+    // There's no way back until the emulator is restarted
+    pub fn enable_hardcore(&mut self) {
+        self.hardcore = true;
+        self.overclock = 0;
+        info!("Hardcore mode on, cheats, slowdown and overclocking are disabled");
+    }
+    // Every feature that could give an unfair advantage asks here first
+    pub fn check_allowed(&self, what: Restricted) -> Result<Allowed, String> {
+        if self.hardcore {
+            return Err(format!("{} disabled in hardcore mode", what.name()));
+        }
+        Ok(Allowed(()))
+    }
+    pub fn overclock(&self) -> u32 {
+        self.overclock
+    }
+    // Extra CPU speed in percent, 0 for the real thing
+    pub fn set_overclock(&mut self, percent: u32) -> Result<(), String> {
+        if percent > 0 {
+            try!(self.check_allowed(Restricted::Overclock));
+            warn!("CPU overclocked by {}%, timing is no longer accurate", percent);
+        }
+        self.overclock = percent;
+        self.overclock_rest = 0;
+        Ok(())
+    }
+    pub fn is_uncapped(&self) -> bool {
+        self.is_uncapped
+    }
+    pub fn toggle_uncapped(&mut self) {
+        self.is_uncapped = !self.is_uncapped;
+        info!("Speed {}", if self.is_uncapped {"uncapped"} else {"capped"});
+    }
 
 }
 
 impl fmt::Debug for Emulator {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-" State: {}
- Frame: {}   Cycles: {}",
-            if self.cpu.is_running {"Running"} else {"Paused"},
+" State: {}{}{}
+ Frame: {}   Cycles: {}
+ Checksum: {:08X}",
+            match (self.cpu.is_running, self.is_uncapped) {
+                _ if self.cpu.get_regs().locked() => "Locked up (invalid opcode)",
+                (false, _) => "Paused",
+                (true, false) => "Running",
+                (true, true) => "Running (uncapped)",
+            },
+            if self.hardcore {String::from(" [hardcore]")} else {String::new()},
+            if self.overclock > 0 {format!(" [CPU +{}%]", self.overclock)} else {String::new()},
             self.frame_count,
             self.cpu.total_cycles,
+            self.frame_checksum,
         )
     }
 }
 
+// --sym, or the .sym file next to the rom
+fn load_symbols(config: &Config) -> Symbols {
+    if let Some(ref path) = config.sym_file {
+        match Symbols::load(path) {
+            Ok(symbols) => {
+                info!("Loaded {} symbols from {}", symbols.len(), path);
+                return symbols;
+            }
+            Err(err) => error!("Couldn't load symbols: {}", err),
+        }
+    }
+    Symbols::for_rom(&config.rom_path).unwrap_or_else(Symbols::new)
+}
+
+// The PPU, timer and serial cycles that pass while the CPU runs `cycles`
+// when it's overclocked by `percent`, carrying the fraction over in `rest`
+fn scale_cycles(cycles: u32, percent: u32, rest: &mut u32) -> u32 {
+    if percent == 0 {
+        return cycles;
+    }
+    let total = cycles * 100 + *rest;
+    *rest = total % (100 + percent);
+    total / (100 + percent)
+}
+
+pub const FNV_OFFSET: u32 = 0x811C9DC5;
+const FNV_PRIME: u32 = 0x01000193;
+
+// 32-bit FNV-1a, continuing from `hash`
+pub fn fnv1a(mut hash: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
 fn open_rom<P: AsRef<Path>>(rom_path: P) -> io::Result< Vec<u8> > {
 
     // try! to open the file
@@ -167,5 +944,226 @@ pub fn try_open_rom<P: AsRef<Path>>(rom_path: P) -> Vec<u8> {
 #[cfg(test)]
 mod emu_tests {
     use super::*;
+    use breakpoints::WatchKind;
+
+    #[test]
+    fn fnv1a_vectors() {
+        assert_eq!(fnv1a(FNV_OFFSET, b""), 0x811C9DC5);
+        assert_eq!(fnv1a(FNV_OFFSET, b"a"), 0xE40C292C);
+        assert_eq!(fnv1a(fnv1a(FNV_OFFSET, b"foo"), b"bar"), fnv1a(FNV_OFFSET, b"foobar"));
+    }
+
+    #[test]
+    fn model_from_header() {
+        // CGB flag, SGB flag, old licence code
+        let model = |cgb: u8, sgb: u8, licence: u8| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x143] = cgb;
+            rom[0x146] = sgb;
+            rom[0x14B] = licence;
+            Emulator::with_rom(rom, &Config::default()).model
+        };
+        assert_eq!(model(0x00, 0x00, 0x00), Model::Dmg);
+        assert_eq!(model(0x00, 0x03, 0x33), Model::Sgb);
+        // SGB functions only count with the new licence code
+        assert_eq!(model(0x00, 0x03, 0x01), Model::Dmg);
+        // Color games go to the CGB even when they support the SGB too
+        assert_eq!(model(0x80, 0x03, 0x33), Model::Cgb);
+        assert_eq!(model(0xC0, 0x00, 0x33), Model::Cgb);
+    }
+
+    #[test]
+    fn overclock_scaling() {
+        let mut rest = 0;
+        assert_eq!(scale_cycles(8, 0, &mut rest), 8);
+
+        // Twice as fast, the hardware sees half the cycles
+        let hw: u32 = (0..1000).map(|_| scale_cycles(4, 100, &mut rest)).sum();
+        assert_eq!(hw, 2000);
+        // Nothing gets lost to rounding
+        let hw: u32 = (0..300).map(|_| scale_cycles(4, 50, &mut rest)).sum();
+        assert_eq!(hw, 800);
+    }
+
+    #[test]
+    fn access_timing() {
+        // LDH A, (0x04) reads DIV in its third machine cycle, DIV ticks over
+        // right before that
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0xF0, 0x04]);
+        let mut emu = Emulator::with_rom(rom.clone(), &Config::default());
+        emu.mem.timer.div = 0x00F4;
+        assert_eq!(emu.exec_instruction(), 12);
+        assert_eq!((emu.cpu.get_regs().af() >> 8, emu.mem.timer.div), (0x01, 0x0100));
+
+        // Overclocked everything is stepped after the instruction
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        emu.set_overclock(100).unwrap();
+        emu.mem.timer.div = 0x00F4;
+        emu.exec_instruction();
+        assert_eq!((emu.cpu.get_regs().af() >> 8, emu.mem.timer.div), (0x00, 0x00FA));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[
+            0x3C,               // INC A
+            0x00,               // NOP
+            0x18, 0xFC,         // JR -4
+        ]);
+        let mut config = Config::default();
+        config.breakpoints.push((None, 0x0101));
+        let mut emu = Emulator::with_rom(rom, &config);
+
+        let events = emu.step_frame(Joypad::default());
+        assert_eq!((events.breakpoint, events.frame_ready), (Some(0x0101), false));
+        assert!(!emu.is_running());
+        assert_eq!(emu.cpu.get_regs().af() >> 8, 0x02);
+
+        // Continuing runs the instruction it stopped at, and stops there again
+        // the next time round
+        emu.set_running(true);
+        assert_eq!(emu.step_frame(Joypad::default()).breakpoint, Some(0x0101));
+        assert_eq!(emu.cpu.get_regs().af() >> 8, 0x03);
+        assert_eq!(emu.breakpoints()[0].hits, 2);
+
+        emu.remove_breakpoint(0);
+        assert!(emu.step_frame(Joypad::default()).frame_ready);
+
+        emu.enable_hardcore();
+        assert!(emu.add_breakpoint(None, 0x0100).is_err());
+        assert!(emu.add_watchpoint(WatchKind::Write, 0xC000, 0xC000).is_err());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x107].copy_from_slice(&[
+            0x21, 0x00, 0xC0,   // LD HL, 0xC000
+            0x34,               // INC (HL)
+            0x00,               // NOP
+            0x18, 0xFC,         // JR -4
+        ]);
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        emu.add_watchpoint(WatchKind::Write, 0xC000, 0xC000).unwrap();
+
+        let events = emu.step_frame(Joypad::default());
+        assert_eq!(events.watchpoint, Some(WatchHit {
+            index: 0, addr: 0xC000, write: true, old: 0x00, new: 0x01, pc: 0x0103,
+        }));
+        // Stopped after the instruction
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0104);
+        assert!(!emu.is_running());
+
+        emu.set_running(true);
+        assert_eq!(emu.step_frame(Joypad::default()).watchpoint.map(|hit| hit.new), Some(0x02));
+
+        // Writes from the console don't count
+        emu.poke(0xC000, 0x10).unwrap();
+        emu.remove_watchpoint(0);
+        emu.add_watchpoint(WatchKind::Read, 0xC000, 0xC0FF).unwrap();
+        assert_eq!(emu.step_frame(Joypad::default()).watchpoint.map(|hit| hit.old), Some(0x10));
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[
+            0xCD, 0x10, 0x01,   // CALL 0x0110
+            0x00,               // NOP
+            0x18, 0xFE,         // JR -2
+        ]);
+        rom[0x110..0x115].copy_from_slice(&[
+            0x3C,               // INC A
+            0xCD, 0x20, 0x01,   // CALL 0x0120
+            0xC9,               // RET
+        ]);
+        rom[0x120..0x124].copy_from_slice(&[
+            0xC5,               // PUSH BC
+            0x04,               // INC B
+            0xC1,               // POP BC
+            0xC9,               // RET
+        ]);
+
+        let mut emu = Emulator::with_rom(rom.clone(), &Config::default());
+        emu.set_running(false);
+        assert_eq!(emu.step_over(), Ok(false));
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!((emu.cpu.get_regs().pc(), emu.cpu.get_regs().sp()), (0x0103, 0xFFFE));
+        assert_eq!(emu.cpu.get_regs().af() >> 8, 0x02);
+        assert!(!emu.is_running());
+        // Anything else is a single step
+        assert_eq!(emu.step_over(), Ok(true));
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0104);
+
+        // Out of 0x0120 the POP doesn't count, the RET does
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        emu.set_running(false);
+        for _ in 0..4 {
+            emu.step_instruction().unwrap();
+        }
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0121);
+        emu.step_out().unwrap();
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0114);
+        emu.step_out().unwrap();
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0103);
+    }
+
+    #[test]
+    fn run_to() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[
+            0xCD, 0x10, 0x01,   // CALL 0x0110
+            0x00,               // NOP
+            0x18, 0xFA,         // JR -6
+        ]);
+        rom[0x110..0x114].copy_from_slice(&[
+            0x3C,               // INC A
+            0xC5,               // PUSH BC
+            0xC1,               // POP BC
+            0xC9,               // RET
+        ]);
+
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        emu.set_running(false);
+        assert!(emu.run_to_cursor().is_err());
+        assert!(emu.run_to(Some(1), 0x0112).is_err());
+        emu.run_to(None, 0x0112).unwrap();
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0112);
+        assert_eq!(emu.cpu.calls.frames().len(), 1);
+
+        // Run until return goes back to the caller, past the PUSH and POP
+        emu.step_out().unwrap();
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!((emu.cpu.get_regs().pc(), emu.cpu.get_regs().sp()), (0x0103, 0xFFFE));
+
+        // Round the loop to the same place again
+        emu.run_to_cursor().unwrap();
+        assert!(emu.step_frame(Joypad::default()).stepped);
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0112);
+        assert_eq!(emu.cpu.get_regs().af() >> 8, 0x03);
+    }
+
+    #[test]
+    fn headless() {
+        // A 32KB rom that turns the LCD on with the background off and loops
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[
+            0x3E, 0x80,         // LD A, 0x80
+            0xE0, 0x40,         // LDH (0x40), A
+            0x18, 0xFE,         // JR -2
+        ]);
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        for _ in 0..3 {
+            assert!(emu.step_frame(Joypad::default()).frame_ready);
+        }
+        assert!(emu.mem.gpu.frames() > 0);
+        // Nothing is drawn with the background off, every pixel is the same
+        assert!(emu.frame().chunks(4).all(|p| p == &emu.frame()[..4]));
+    }
 
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+//
+//      Game database
+//
+
+// Known games, keyed by the header and global checksums. Loaded from a text
+// file with one game per line:
+//
+//   global checksum | header checksum | title | region | cartridge type | RAM size | quirks
+//
+// Checksums and sizes are in hex, empty fields mean unknown and quirks are a
+// comma separated list of flags. Lines starting with '#' are comments.
+//
+// Quirks:
+//   no-sgb   boot on a DMG even though the header says it supports the SGB,
+//            for games whose SGB borders or palettes are broken
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use cartridge::CartridgeHeader;
+
+pub const DEFAULT_PATH: &'static str = "resources/gamedb.txt";
+
+const QUIRKS: [&'static str; 1] = ["no-sgb"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameInfo {
+    pub title: String,
+    pub region: String,
+    // Known-good values, for when the header is wrong
+    pub cartridge_type: Option<u8>,
+    pub ram_size: Option<u8>,
+    pub quirks: Vec<String>,
+}
+
+impl GameInfo {
+    // "Title (Region)"
+    pub fn full_title(&self) -> String {
+        if self.region.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} ({})", self.title, self.region)
+        }
+    }
+
+    pub fn has_quirk(&self, quirk: &str) -> bool {
+        self.quirks.iter().any(|q| q == quirk)
+    }
+}
+
+pub struct GameDb {
+    games: HashMap<(u16, u8), GameInfo>,
+}
+
+impl GameDb {
+    pub fn new() -> GameDb {
+        GameDb { games: HashMap::new() }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<GameDb> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        Ok(GameDb::parse(&text))
+    }
+
+    // Bad lines are skipped with a warning
+    pub fn parse(text: &str) -> GameDb {
+        let mut db = GameDb::new();
+        for (line_n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_line(line) {
+                Some((key, info)) => { db.games.insert(key, info); }
+                None => warn!("Game database: couldn't parse line {}: {}", line_n + 1, line),
+            }
+        }
+        db
+    }
+
+    pub fn lookup(&self, header: &CartridgeHeader) -> Option<&GameInfo> {
+        self.games.get(&(header.global_checksum(), header.header_checksum()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+}
+
+fn parse_line(line: &str) -> Option<((u16, u8), GameInfo)> {
+    let fields: Vec<&str> = line.split('|').map(|f| f.trim()).collect();
+    if fields.len() != 7 {
+        return None;
+    }
+
+    let global = match u16::from_str_radix(fields[0], 16) { Ok(v) => v, Err(_) => return None };
+    let header = match u8::from_str_radix(fields[1], 16) { Ok(v) => v, Err(_) => return None };
+    let optional_hex = |field: &str| -> Result<Option<u8>, ()> {
+        if field.is_empty() { return Ok(None) }
+        u8::from_str_radix(field, 16).map(Some).map_err(|_| ())
+    };
+
+    let info = GameInfo {
+        title: fields[2].to_string(),
+        region: fields[3].to_string(),
+        cartridge_type: match optional_hex(fields[4]) { Ok(v) => v, Err(_) => return None },
+        ram_size: match optional_hex(fields[5]) { Ok(v) => v, Err(_) => return None },
+        quirks: fields[6].split(',')
+            .map(|q| q.trim().to_string())
+            .filter(|q| !q.is_empty())
+            .collect(),
+    };
+    for quirk in info.quirks.iter().filter(|q| !QUIRKS.contains(&q.as_str())) {
+        warn!("Game database: unknown quirk {} for {}", quirk, info.title);
+    }
+    Some(((global, header), info))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod gamedb_tests {
+    use super::*;
+
+    #[test]
+    fn parse_entries() {
+        let db = GameDb::parse("
+            # comment
+            4DEB | 66 | cpu_instrs: 01-special | World | 01 | 00 |
+            38C2 | 47 | OAM Bug | World |  |  | no-sgb, slow
+            1234 | 56 | No region |  |  |  |
+            not a game
+        ");
+        assert_eq!(db.len(), 3);
+
+        let special = &db.games[&(0x4DEB, 0x66)];
+        assert_eq!(special.title, "cpu_instrs: 01-special");
+        assert_eq!(special.cartridge_type, Some(0x01));
+        assert!(special.quirks.is_empty());
+        assert_eq!(special.full_title(), "cpu_instrs: 01-special (World)");
+        assert_eq!(db.games[&(0x1234, 0x56)].full_title(), "No region");
+
+        let oam_bug = &db.games[&(0x38C2, 0x47)];
+        assert_eq!(oam_bug.ram_size, None);
+        assert!(oam_bug.has_quirk("slow"));
+        assert!(!oam_bug.has_quirk("no"));
+    }
+
+    #[test]
+    fn bundled_database() {
+        let db = GameDb::load(DEFAULT_PATH).unwrap();
+        assert!(db.len() > 0);
+    }
+}
@@ -0,0 +1,514 @@
+//
+//      GDB remote stub
+//
+
+// Lets gdb, lldb or an IDE debug the game over TCP with the GDB Remote Serial
+// Protocol: `target remote localhost:<port>`. Packets are `$data#checksum`,
+// each acked with `+`, and a lone 0x03 byte interrupts.
+//
+// There's no SM83 in gdb, target.xml tells it the registers: AF, BC, DE, HL,
+// SP and PC, 16 bits each, little endian like everything in the protocol.
+// Breakpoints (Z0/Z1) and watchpoints (Z2-Z4) go to the emulator's own, so
+// they show up in the console too.
+//
+// Like the console, it's polled from the main loop and the game keeps
+// running between packets while the client has let it continue.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+
+use breakpoints::WatchKind;
+use emulator::{Emulator, Restricted};
+
+const TARGET_XML: &'static str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustboy.sm83">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const NUM_REGS: usize = 6;
+
+// Stopped by a breakpoint or a step, and by an interrupt from the client
+const SIGTRAP: &'static str = "S05";
+const SIGINT: &'static str = "S02";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    // Reply once the emulator stops
+    Resume,
+    // The client is done, with a last reply or not
+    Close(Option<String>),
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    // Bytes received that don't make up a whole packet yet
+    buf: Vec<u8>,
+    // The client let the game run and waits for it to stop
+    resumed: bool,
+}
+
+impl GdbStub {
+    // Only takes connections from this machine
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+        try!(listener.set_nonblocking(true));
+        info!("Waiting for gdb on port {}", port);
+        Ok(GdbStub { listener: listener, client: None, buf: Vec::new(), resumed: false })
+    }
+
+    // Answer the packets that came in since the last call
+    pub fn poll(&mut self, emu: &mut Emulator) {
+        if self.client.is_none() {
+            self.accept(emu);
+        }
+        if let Err(err) = self.exchange(emu) {
+            warn!("gdb disconnected: {}", err);
+            self.client = None;
+            self.resumed = false;
+        }
+    }
+
+    fn accept(&mut self, emu: &mut Emulator) {
+        match self.listener.accept() {
+            Ok((stream, addr)) => {
+                if stream.set_nonblocking(true).is_err() {
+                    return;
+                }
+                info!("gdb connected from {}", addr);
+                // gdb expects the target stopped when it attaches
+                emu.set_running(false);
+                self.client = Some(stream);
+                self.buf.clear();
+                self.resumed = false;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => warn!("Couldn't accept a gdb connection: {}", err),
+        }
+    }
+
+    fn exchange(&mut self, emu: &mut Emulator) -> io::Result<()> {
+        let mut chunk = [0; 1024];
+        loop {
+            let read = match self.client {
+                Some(ref mut stream) => stream.read(&mut chunk),
+                None => return Ok(()),
+            };
+            match read {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        while let Some(packet) = next_packet(&mut self.buf) {
+            match packet {
+                Packet::Interrupt => {
+                    emu.set_running(false);
+                    if self.resumed {
+                        self.resumed = false;
+                        try!(self.send(SIGINT));
+                    }
+                }
+                Packet::Bad => try!(self.write(b"-")),
+                Packet::Data(data) => {
+                    try!(self.write(b"+"));
+                    match handle(emu, &data) {
+                        Action::Reply(reply) => try!(self.send(&reply)),
+                        Action::Resume => self.resumed = true,
+                        Action::Close(reply) => {
+                            if let Some(reply) = reply {
+                                try!(self.send(&reply));
+                            }
+                            info!("gdb detached");
+                            self.client = None;
+                            self.resumed = false;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.resumed && !emu.is_running() {
+            self.resumed = false;
+            let reply = stop_reply(emu);
+            try!(self.send(&reply));
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = frame(data);
+        self.write(packet.as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.client {
+            Some(ref mut stream) => {
+                // Replies are short, wait for them to go out whole
+                try!(stream.set_nonblocking(false));
+                let result = stream.write_all(bytes);
+                try!(stream.set_nonblocking(true));
+                result
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Data(Vec<u8>),
+    // Checksum didn't match
+    Bad,
+    // 0x03, like Ctrl-C
+    Interrupt,
+}
+
+// Takes the next packet off the front of buf, acks from the client are
+// skipped
+fn next_packet(buf: &mut Vec<u8>) -> Option<Packet> {
+    loop {
+        match buf.first().cloned() {
+            None => return None,
+            Some(0x03) => {
+                buf.remove(0);
+                return Some(Packet::Interrupt);
+            }
+            Some(b'$') => break,
+            Some(_) => { buf.remove(0); }
+        }
+    }
+    let end = match buf.iter().position(|&b| b == b'#') {
+        Some(end) if buf.len() >= end + 3 => end,
+        _ => return None,
+    };
+    let packet: Vec<u8> = buf.drain(..end + 3).collect();
+    let data = &packet[1..end];
+    match hex(&packet[end + 1..]) {
+        Ok(sum) if sum == checksum(data) as usize => Some(Packet::Data(data.to_vec())),
+        _ => Some(Packet::Bad),
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+fn stop_reply(emu: &mut Emulator) -> String {
+    match emu.take_watchpoint_hit() {
+        Some(hit) => {
+            let kind = match emu.watchpoints().get(hit.index).map(|w| w.kind) {
+                Some(WatchKind::Read) => "rwatch",
+                Some(WatchKind::Access) => "awatch",
+                _ => "watch",
+            };
+            format!("T05{}:{:04x};", kind, hit.addr)
+        }
+        None => String::from(SIGTRAP),
+    }
+}
+
+// Packets are taken as bytes, whatever the client sends. Anything malformed
+// gets E01
+fn handle(emu: &mut Emulator, data: &[u8]) -> Action {
+    let (cmd, args) = match data.split_first() {
+        Some((&cmd, args)) => (cmd, args),
+        None => return Action::Reply(String::new()),
+    };
+    let reply = match cmd {
+        b'?' => Ok(String::from(SIGTRAP)),
+        b'g' => Ok((0..NUM_REGS).map(|i| hex16(read_reg(emu, i))).collect()),
+        b'G' => write_regs(emu, args),
+        b'p' => hex(args)
+            .and_then(|i| if i < NUM_REGS { Ok(hex16(read_reg(emu, i))) } else { Err(()) }),
+        b'P' => write_reg(emu, args),
+        b'm' => read_mem(emu, args),
+        b'M' => write_mem(emu, args),
+        b'c' => {
+            // Continuing somewhere else writes PC, like P does
+            if let Ok(addr) = parse_addr(args) {
+                if emu.check_allowed(Restricted::Cheats).is_err() {
+                    return Action::Reply(String::from("E01"));
+                }
+                emu.cpu.get_regs_mut().pc_set(addr);
+            }
+            emu.set_running(true);
+            return Action::Resume;
+        }
+        b's' => {
+            emu.set_running(false);
+            emu.step_instruction().map(|_| String::from(SIGTRAP)).map_err(|_| ())
+        }
+        b'Z' | b'z' => set_point(emu, cmd == b'Z', args),
+        b'D' => return Action::Close(Some(String::from("OK"))),
+        b'k' => return Action::Close(None),
+        b'H' => Ok(String::from("OK")),
+        b'q' | b'Q' => Ok(query(data)),
+        // Anything else isn't supported, an empty reply says so
+        _ => Ok(String::new()),
+    };
+    Action::Reply(reply.unwrap_or(String::from("E01")))
+}
+
+fn query(data: &[u8]) -> String {
+    const FEATURES: &'static [u8] = b"qXfer:features:read:target.xml:";
+    if data.starts_with(b"qSupported") {
+        String::from("PacketSize=1000;qXfer:features:read+")
+    } else if data.starts_with(FEATURES) {
+        let mut parts = data[FEATURES.len()..].splitn(2, |&b| b == b',').map(hex);
+        match (parts.next(), parts.next()) {
+            (Some(Ok(offset)), Some(Ok(len))) => {
+                let xml = TARGET_XML.as_bytes();
+                let start = offset.min(xml.len());
+                let end = start.saturating_add(len).min(xml.len());
+                let more = if end < xml.len() {"m"} else {"l"};
+                format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+            }
+            _ => String::from("E01"),
+        }
+    } else if data == b"qAttached" {
+        String::from("1")
+    } else if data == b"qC" {
+        String::from("QC1")
+    } else if data == b"qfThreadInfo" {
+        String::from("m1")
+    } else if data == b"qsThreadInfo" {
+        String::from("l")
+    } else {
+        String::new()
+    }
+}
+
+fn hex16(val: u16) -> String {
+    format!("{:02x}{:02x}", val as u8, val >> 8)
+}
+
+// A hex number, Err unless it's all hex digits
+fn hex(s: &[u8]) -> Result<usize, ()> {
+    if s.is_empty() || !s.iter().all(|&b| (b as char).is_digit(16)) {
+        return Err(());
+    }
+    str::from_utf8(s).ok().and_then(|s| usize::from_str_radix(s, 16).ok()).ok_or(())
+}
+
+fn parse_addr(s: &[u8]) -> Result<u16, ()> {
+    hex(s).and_then(|n| if n <= 0xFFFF { Ok(n as u16) } else { Err(()) })
+}
+
+fn parse_byte(s: &[u8]) -> Result<u8, ()> {
+    hex(s).and_then(|n| if n <= 0xFF { Ok(n as u8) } else { Err(()) })
+}
+
+fn parse_hex16(s: &[u8]) -> Result<u16, ()> {
+    if s.len() != 4 {
+        return Err(());
+    }
+    let lo = try!(parse_byte(&s[..2]));
+    let hi = try!(parse_byte(&s[2..]));
+    Ok((hi as u16) << 8 | lo as u16)
+}
+
+fn read_reg(emu: &Emulator, i: usize) -> u16 {
+    let r = emu.cpu.get_regs();
+    match i {
+        0 => r.af(),
+        1 => r.bc(),
+        2 => r.de(),
+        3 => r.hl(),
+        4 => r.sp(),
+        _ => r.pc(),
+    }
+}
+
+fn set_reg(emu: &mut Emulator, i: usize, val: u16) {
+    let r = emu.cpu.get_regs_mut();
+    match i {
+        0 => r.af_set(val),
+        1 => r.bc_set(val),
+        2 => r.de_set(val),
+        3 => r.hl_set(val),
+        4 => r.sp_set(val),
+        _ => r.pc_set(val),
+    }
+}
+
+fn write_regs(emu: &mut Emulator, args: &[u8]) -> Result<String, ()> {
+    try!(emu.check_allowed(Restricted::Cheats).map_err(|_| ()));
+    if args.len() < NUM_REGS * 4 {
+        return Err(());
+    }
+    for i in 0..NUM_REGS {
+        let val = try!(parse_hex16(&args[i * 4..i * 4 + 4]));
+        set_reg(emu, i, val);
+    }
+    Ok(String::from("OK"))
+}
+
+fn write_reg(emu: &mut Emulator, args: &[u8]) -> Result<String, ()> {
+    try!(emu.check_allowed(Restricted::Cheats).map_err(|_| ()));
+    let mut parts = args.splitn(2, |&b| b == b'=');
+    let i = try!(parts.next().ok_or(()).and_then(hex));
+    let val = try!(parts.next().ok_or(()).and_then(parse_hex16));
+    if i >= NUM_REGS {
+        return Err(());
+    }
+    set_reg(emu, i, val);
+    Ok(String::from("OK"))
+}
+
+// addr,len
+fn parse_range(args: &[u8]) -> Result<(u16, usize), ()> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    let addr = try!(parts.next().ok_or(()).and_then(parse_addr));
+    let len = try!(parts.next().ok_or(()).and_then(hex));
+    Ok((addr, len))
+}
+
+fn read_mem(emu: &Emulator, args: &[u8]) -> Result<String, ()> {
+    let (addr, len) = try!(parse_range(args));
+    Ok((0..len.min(0x10000))
+        .map(|i| format!("{:02x}", emu.mem.peek(addr.wrapping_add(i as u16))))
+        .collect())
+}
+
+fn write_mem(emu: &mut Emulator, args: &[u8]) -> Result<String, ()> {
+    let mut parts = args.splitn(2, |&b| b == b':');
+    let (addr, len) = try!(parse_range(parts.next().unwrap_or(&[])));
+    let bytes = parts.next().unwrap_or(&[]);
+    if Some(bytes.len()) != len.checked_mul(2) {
+        return Err(());
+    }
+    for i in 0..len {
+        let val = try!(parse_byte(&bytes[i * 2..i * 2 + 2]));
+        try!(emu.poke(addr.wrapping_add(i as u16), val).map_err(|_| ()));
+    }
+    Ok(String::from("OK"))
+}
+
+// Z<type>,<addr>,<kind> adds one, z removes it
+fn set_point(emu: &mut Emulator, add: bool, args: &[u8]) -> Result<String, ()> {
+    let mut parts = args.split(|&b| b == b',');
+    let kind = parts.next().unwrap_or(&[]);
+    let addr = try!(parts.next().ok_or(()).and_then(parse_addr));
+    let len = parts.next().and_then(|n| parse_addr(n).ok()).unwrap_or(1).max(1);
+    let end = addr.saturating_add(len - 1);
+    let watch = match kind {
+        // Software and hardware breakpoints are the same thing here
+        b"0" | b"1" => {
+            if add {
+                try!(emu.add_breakpoint(None, addr).map_err(|_| ()));
+            } else {
+                let found = emu.breakpoints().iter().position(|b| b.addr == addr && b.bank.is_none());
+                try!(found.and_then(|i| emu.remove_breakpoint(i)).ok_or(()));
+            }
+            return Ok(String::from("OK"));
+        }
+        b"2" => WatchKind::Write,
+        b"3" => WatchKind::Read,
+        b"4" => WatchKind::Access,
+        _ => return Ok(String::new()),
+    };
+    if add {
+        try!(emu.add_watchpoint(watch, addr, end).map_err(|_| ()));
+    } else {
+        let found = emu.watchpoints().iter()
+            .position(|w| w.kind == watch && w.start == addr && w.end == end);
+        try!(found.and_then(|i| emu.remove_watchpoint(i)).ok_or(()));
+    }
+    Ok(String::from("OK"))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod gdb_tests {
+    use super::*;
+    use config::Config;
+
+    fn reply(emu: &mut Emulator, data: &str) -> String {
+        match handle(emu, data.as_bytes()) {
+            Action::Reply(reply) => reply,
+            action => panic!("{:?} for {}", action, data),
+        }
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        let mut buf = b"+$g#67$m0,2#00\x03$?".to_vec();
+        assert_eq!(next_packet(&mut buf), Some(Packet::Data(b"g".to_vec())));
+        assert_eq!(next_packet(&mut buf), Some(Packet::Bad));
+        assert_eq!(next_packet(&mut buf), Some(Packet::Interrupt));
+        // Not complete yet
+        assert_eq!(next_packet(&mut buf), None);
+        buf.extend_from_slice(b"#3f");
+        assert_eq!(next_packet(&mut buf), Some(Packet::Data(b"?".to_vec())));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn commands() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFD]);  // INC A; JR -3
+        let mut emu = Emulator::with_rom(rom, &Config::default());
+        emu.set_running(false);
+
+        assert_eq!(reply(&mut emu, "g"), "b0011300d8004d01feff0001");
+        assert_eq!(reply(&mut emu, "p5"), "0001");
+        assert_eq!(reply(&mut emu, "P1=3412"), "OK");
+        assert_eq!(emu.cpu.get_regs().bc(), 0x1234);
+        assert_eq!(reply(&mut emu, "m100,3"), "3c18fd");
+        assert_eq!(reply(&mut emu, "MC000,2:abcd"), "OK");
+        assert_eq!(reply(&mut emu, "mc000,2"), "abcd");
+        assert_eq!(reply(&mut emu, "s"), "S05");
+        assert_eq!(emu.cpu.get_regs().pc(), 0x0101);
+
+        assert_eq!(reply(&mut emu, "Z0,100,1"), "OK");
+        assert_eq!(emu.breakpoints()[0].addr, 0x0100);
+        assert_eq!(reply(&mut emu, "z0,100,1"), "OK");
+        assert!(emu.breakpoints().is_empty());
+        assert_eq!(reply(&mut emu, "Z2,c000,2"), "OK");
+        assert_eq!((emu.watchpoints()[0].start, emu.watchpoints()[0].end), (0xC000, 0xC001));
+        assert_eq!(reply(&mut emu, "z2,c000,2"), "OK");
+        assert_eq!(reply(&mut emu, "z2,c000,2"), "E01");
+
+        assert_eq!(handle(&mut emu, b"c"), Action::Resume);
+        assert!(emu.is_running());
+        assert_eq!(reply(&mut emu, "vMustReplyEmpty"), "");
+        assert!(reply(&mut emu, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+        assert!(reply(&mut emu, "qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+        // Malformed packets get an error, whatever is in them
+        assert_eq!(reply(&mut emu, "MC000,2:\u{e9}1"), "E01");
+        assert_eq!(reply(&mut emu, "Mc000,ffffffffffffffff:00"), "E01");
+        assert_eq!(handle(&mut emu, b"p\xff"), Action::Reply(String::from("E01")));
+        assert_eq!(handle(&mut emu, b"P1=\xc3\xa9\xc3\xa9"), Action::Reply(String::from("E01")));
+        assert_eq!(reply(&mut emu, "m+10,1"), "E01");
+
+        emu.enable_hardcore();
+        assert_eq!(reply(&mut emu, "MC000,1:00"), "E01");
+        let pc = emu.cpu.get_regs().pc();
+        assert_eq!(handle(&mut emu, b"c150"), Action::Reply(String::from("E01")));
+        assert_eq!(emu.cpu.get_regs().pc(), pc);
+    }
+}
@@ -0,0 +1,241 @@
+//
+//      GIF encoder
+//
+
+// Just enough of GIF89a to write looping animations of frames that already
+// use a palette, with a fixed delay between them.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// An indexed frame, with at most 256 colors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub palette: Vec<[u8; 3]>,
+    pub pixels: Vec<u8>,
+}
+
+pub struct Encoder<W: Write> {
+    out: W,
+    width: u16,
+    height: u16,
+    delay: u16,     // hundredths of a second
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(mut out: W, width: u16, height: u16, delay: u16) -> io::Result<Encoder<W>> {
+        try!(out.write_all(b"GIF89a"));
+        // Logical screen, no global color table
+        try!(out.write_all(&[width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8, 0, 0, 0]));
+        // Loop forever
+        try!(out.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00"));
+        Ok(Encoder { out: out, width: width, height: height, delay: delay })
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        assert!(!frame.palette.is_empty() && frame.palette.len() <= 256);
+        assert_eq!(frame.pixels.len(), self.width as usize * self.height as usize);
+
+        // Color tables hold 2^n colors, at least 4 since the LZW minimum code
+        // size can't go below 2
+        let mut bits = 2;
+        while 1 << bits < frame.palette.len() {
+            bits += 1;
+        }
+
+        // Graphic control: keep the frame in place, the delay, no transparency
+        try!(self.out.write_all(&[0x21, 0xF9, 0x04, 0x04, self.delay as u8, (self.delay >> 8) as u8, 0, 0]));
+        // Image descriptor with a local color table
+        let (w, h) = (self.width, self.height);
+        try!(self.out.write_all(&[0x2C, 0, 0, 0, 0, w as u8, (w >> 8) as u8, h as u8, (h >> 8) as u8,
+            0x80 | (bits - 1) as u8]));
+        for i in 0..1 << bits {
+            let color = frame.palette.get(i).cloned().unwrap_or([0, 0, 0]);
+            try!(self.out.write_all(&color));
+        }
+
+        try!(self.out.write_all(&[bits as u8]));
+        let data = lzw_encode(&frame.pixels, bits);
+        for block in data.chunks(255) {
+            try!(self.out.write_all(&[block.len() as u8]));
+            try!(self.out.write_all(block));
+        }
+        self.out.write_all(&[0])
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.out.write_all(&[0x3B]));
+        Ok(self.out)
+    }
+}
+
+const MAX_CODES: u16 = 4096;
+
+// Packs variable width codes, least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.acc |= (code as u32) << self.nbits;
+        self.nbits += size;
+        while self.nbits >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+fn lzw_encode(pixels: &[u8], min_size: u32) -> Vec<u8> {
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut out = BitWriter { bytes: Vec::new(), acc: 0, nbits: 0 };
+    let mut dict: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = min_size + 1;
+    let mut next = end + 1;
+
+    out.write(clear, size);
+    let mut prefix = match pixels.first() {
+        Some(&p) => p as u16,
+        None => {
+            out.write(end, size);
+            return out.finish();
+        }
+    };
+
+    for &p in pixels[1..].iter() {
+        if let Some(&code) = dict.get(&(prefix, p)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, size);
+        if next < MAX_CODES {
+            dict.insert((prefix, p), next);
+            next += 1;
+            // The decoder widens its codes one step behind us
+            if next > 1 << size && size < 12 {
+                size += 1;
+            }
+        } else {
+            out.write(clear, size);
+            dict.clear();
+            size = min_size + 1;
+            next = end + 1;
+        }
+        prefix = p as u16;
+    }
+
+    out.write(prefix, size);
+    if next >= 1 << size && size < 12 {
+        size += 1;
+    }
+    out.write(end, size);
+    out.finish()
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod gif_tests {
+    use super::*;
+
+    // Decodes the way giflib does
+    fn lzw_decode(data: &[u8], min_size: u32) -> Vec<u8> {
+        let clear = 1usize << min_size;
+        let end = clear + 1;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = min_size + 1;
+        let mut running = end + 1;
+        let mut last: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        let (mut acc, mut nbits, mut pos) = (0u32, 0u32, 0usize);
+
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            for i in 0..clear + 2 { table.push(vec![i as u8]); }
+        };
+        reset(&mut table);
+
+        loop {
+            while nbits < size {
+                acc |= (data[pos] as u32) << nbits;
+                pos += 1;
+                nbits += 8;
+            }
+            let code = (acc & ((1 << size) - 1)) as usize;
+            acc >>= size;
+            nbits -= size;
+
+            if code == clear {
+                reset(&mut table);
+                size = min_size + 1;
+                running = end + 1;
+                last = None;
+                continue;
+            }
+            if code == end {
+                return out;
+            }
+            let entry = if code < table.len() {
+                table[code].clone()
+            } else {
+                let mut e = last.clone().unwrap();
+                let first = e[0];
+                e.push(first);
+                e
+            };
+            if let Some(prev) = last {
+                let mut new = prev.clone();
+                new.push(entry[0]);
+                table.push(new);
+            }
+            running += 1;
+            if running > 1 << size && size < 12 {
+                size += 1;
+            }
+            out.extend_from_slice(&entry);
+            last = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        let mut pixels: Vec<u8> = Vec::new();
+        // Long enough to fill the dictionary and clear it a few times
+        let mut seed = 1u32;
+        for i in 0..40000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            pixels.push(if i % 3 == 0 { (seed >> 16) as u8 % 12 } else { (i / 50) as u8 % 4 });
+        }
+        assert_eq!(lzw_decode(&lzw_encode(&pixels, 4), 4), pixels);
+        assert_eq!(lzw_decode(&lzw_encode(&[1, 1, 1, 1, 1], 2), 2), vec![1, 1, 1, 1, 1]);
+        assert_eq!(lzw_decode(&lzw_encode(&[], 2), 2), vec![]);
+    }
+
+    #[test]
+    fn file_layout() {
+        let frame = Frame { palette: vec![[0, 0, 0], [255, 255, 255]], pixels: vec![0, 1, 1, 0] };
+        let mut enc = Encoder::new(Vec::new(), 2, 2, 5).unwrap();
+        enc.write_frame(&frame).unwrap();
+        let data = enc.finish().unwrap();
+
+        assert_eq!(&data[..6], b"GIF89a");
+        assert_eq!(&data[6..10], &[2, 0, 2, 0]);
+        assert_eq!(data[data.len() - 1], 0x3B);
+        // Delay of the first frame
+        assert_eq!(&data[36..38], &[5, 0]);
+    }
+}
@@ -3,22 +3,28 @@
 //
 #[allow(dead_code)]
 
-use cpu::Interrupt;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
 
-use piston::input;
-use piston_window::*;
-use graphics::types::SourceRectangle;
+use cpu::Interrupt;
+use emulator::{fnv1a, FNV_OFFSET};
+use sgb::SgbColors;
 
 const VRAM_SIZE: usize = 0x2000;
-pub const OAM_SIZE: usize = 0x9F;   // 0xfe00 - 0xfe9f is OAM
+pub const OAM_SIZE: usize = 0xA0;   // 0xfe00 - 0xfe9f is OAM
 const OAM_ENTRY_SIZE: usize = 4;
 const OBJ_COUNT: usize =  40;    // sprite count
-const NUM_TILES: usize = 192;       // number of in-memory tiles
+const MAX_LINE_SPRITES: usize = 10; // sprites drawn per line
+const NUM_TILES: usize = 384;       // number of in-memory tiles, 0x8000-0x97FF
 
 pub const HEIGHT: usize = 144;
 pub const WIDTH: usize = 160;
 
 pub type ScreenData = [u8; WIDTH * HEIGHT * 4];
+// A 2-bit color index per pixel: the DMG shade (0 lightest) after BGP/OBP0/
+// OBP1, or on the CGB the color in the pixel's palette
+pub type IndexData = [u8; WIDTH * HEIGHT];
 pub type Color = [u8; 4];
 pub type Palette = [Color; 4];
 
@@ -28,6 +34,40 @@ struct Palettes {
     obp1: Palette,
 }
 
+// CGB palette RAM, 8 palettes of 4 colors for each of BG and OBJ. Every color
+// is 2 bytes of 15-bit RGB, little endian
+struct CgbPalettes {
+    bg_ram: [u8; 64],
+    obj_ram: [u8; 64],
+    // 0xff68 - BCPS, 0xff6a - OCPS - bits 0-5: index into the RAM, bit 7:
+    // increment it after writing
+    bcps: u8,
+    ocps: u8,
+    // Compiled from the RAM as it's written
+    bg: [Palette; 8],
+    obj: [Palette; 8],
+}
+
+// How finished frames are mixed into what's shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    // Half this frame, half the one before
+    Average,
+    // Every pixel moves part of the way to its new shade each frame, like
+    // the slow DMG LCD
+    Ghosting,
+}
+
+// Of 256, how much of the old shade a ghosting pixel keeps every frame
+const GHOSTING_KEEP: i32 = 128;
+
+// Kept while frames are blended
+struct FrameBlend {
+    mode: BlendMode,
+    prev: ScreenData,
+    shown: ScreenData,
+}
+
 const PALETTE_BW: Palette = [
     [255, 255, 255, 255],
     [148, 148, 148, 255],
@@ -46,8 +86,29 @@ const PALETTE_PUKE_GREEN: Palette = [
     [ 53,  99, 56, 255],
     [ 13,  58, 8, 255],
 ];
-// TODO: Switch palettes at runtome
-const PALETTE: &'static Palette = &PALETTE_GREEN;
+// Game Boy Pocket, grays with a yellow tint
+const PALETTE_POCKET: Palette = [
+    [196, 207, 161, 255],
+    [139, 149, 109, 255],
+    [ 77,  83,  60, 255],
+    [ 31,  31,  31, 255],
+];
+// Game Boy Light with the backlight on
+const PALETTE_LIGHT: Palette = [
+    [  0, 181, 129, 255],
+    [  0, 154, 113, 255],
+    [  0, 105,  74, 255],
+    [  0,  79,  59, 255],
+];
+// The palettes C cycles through, the first one is the default
+const BUILTIN_PALETTES: [(&'static str, Palette); 5] = [
+    ("green", PALETTE_GREEN),
+    ("bw", PALETTE_BW),
+    ("puke-green", PALETTE_PUKE_GREEN),
+    ("pocket", PALETTE_POCKET),
+    ("light", PALETTE_LIGHT),
+];
+const PALETTE: &'static Palette = &BUILTIN_PALETTES[0].1;
 
 struct Tiles {
     data: [[[u8; 8]; 8]; NUM_TILES],
@@ -55,6 +116,112 @@ struct Tiles {
     to_update: [bool; NUM_TILES],
 }
 
+// Where a pixel of the frame came from, for HD tile replacements. x and y
+// are in the unflipped tile.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct TileSource {
+    pub hash: u32,
+    pub x: u8,
+    pub y: u8,
+}
+
+// The registers a line was drawn with, and `changes` for everything in
+// memory. A line with the same inputs as last frame comes out the same and
+// isn't drawn again, which saves most of the work on still screens
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+struct LineInputs {
+    lcdc: u8,
+    opri: u8,
+    scroll: (u8, u8),
+    // WX, WY and the window line
+    window: (u8, u8, u8),
+    // BGP, OBP0, OBP1
+    dmg_pal: (u8, u8, u8),
+    changes: u32,
+}
+
+// A background or window pixel waiting to be drawn. attrs are the CGB tile
+// attributes, 0 on the DMG
+#[derive(Debug, Copy, Clone)]
+struct BgPixel {
+    colori: u8,
+    attrs: u8,
+}
+
+// A sprite pixel waiting to be mixed in, color 0 is transparent
+#[derive(Debug, Copy, Clone)]
+struct ObjPixel {
+    colori: u8,
+    flags: u8,
+    oam: usize,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum FetchStep {
+    Tile,
+    Low,
+    High,
+    Push,
+}
+
+// The pixel FIFO renderer. During mode 3 a fetcher reads 8 pixels of
+// background or window at a time into a FIFO, a pixel comes out of it every
+// dot and gets mixed with the sprite FIFO. Registers are read as the line is
+// drawn, so changing them mid-line shows up.
+struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    step: FetchStep,
+    // Dots spent on the current step, each takes 2 except pushing
+    dots: u8,
+    tilei: u8,
+    attrs: u8,
+    tile_y: usize,
+    row: [u8; 8],
+    // Tile column the fetcher is at, from the left of the screen or window
+    fetch_x: usize,
+    // Next pixel to draw, WIDTH once the line is done
+    x: usize,
+    // Pixels to throw away for fine scrolling
+    discard: u8,
+    // Fetching the window
+    window: bool,
+    // Sprites on the line still to be fetched, left to right
+    sprites: Vec<usize>,
+    // Dots until pixels come out again: the first tile is fetched twice,
+    // and every sprite pauses the line while it's fetched
+    stall: u32,
+}
+
+impl PixelFifo {
+    fn new() -> PixelFifo {
+        PixelFifo {
+            bg: VecDeque::with_capacity(8),
+            obj: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            dots: 0,
+            tilei: 0,
+            attrs: 0,
+            tile_y: 0,
+            row: [0; 8],
+            fetch_x: 0,
+            x: WIDTH,
+            discard: 0,
+            window: false,
+            sprites: Vec::new(),
+            stall: 0,
+        }
+    }
+
+    // Start fetching from the left of the screen or the window
+    fn restart(&mut self) {
+        self.bg.clear();
+        self.step = FetchStep::Tile;
+        self.dots = 0;
+        self.fetch_x = 0;
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 enum Mode {
     HBlank = 0x00, // mode 0
@@ -63,24 +230,90 @@ enum Mode {
     RdVram = 0x03, // mode 3
 }
 
+// What the CPU did with an OAM address, for the OAM bug
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum OamAccess {
+    Read,
+    Write,
+    // ld a,(hl+) and ld a,(hl-) read and change HL in the same cycle
+    ReadIncDec,
+}
+
 pub struct Gpu {
     pub oam: [u8; OAM_SIZE],
 
+    // The frame being drawn, a line at a time. Lines that didn't change are
+    // left from the frame before
     pub image_data: Box<ScreenData>,
+    // The last complete frame, copied from image_data at VBlank. The
+    // frontend only ever sees this one, never a frame half drawn
+    finished: Box<ScreenData>,
+    // The same two frames as color indices
+    indices: Box<IndexData>,
+    finished_indices: Box<IndexData>,
+    // The previous frame and what's shown when frames are blended, see
+    // blend_frames
+    blend: Option<Box<FrameBlend>>,
 
     pub is_cgb: bool,
     pub is_sgb: bool,
+    // SGB palettes and attribute map, the finished frame is colored with them
+    pub sgb: Option<Box<SgbColors>>,
     c: u32,                                                      //remove
     d: u32,
     mode: Mode,
+    // The STAT interrupt sources OR'd together, see update_stat
+    stat_line: bool,
+    // STAT bit 2, LY = LYC as of the last compare. It keeps its value while
+    // the LCD is off
+    coincidence: bool,
+    // Set when the LCD is turned on, the frame drawn until VBlank isn't shown
+    skip_frame: bool,
+    // An HBlank started since take_hblank() was last called
+    hblank: bool,
+    // Same for VBlank, the frame in image_data is complete
+    frame_done: bool,
+    // Counts the times the screen changed, at VBlank or blanked, so the
+    // frontend only uploads a frame once
+    frames: u32,
+    // Don't draw n of every m frames, for fast-forwarding. Only the line
+    // renderer skips, the PPU still goes through every mode and line
+    pub frame_skip: Option<(u32, u32)>,
+    // Frames since the last drawn one, and whether this one is drawn
+    skipped: u32,
+    skip_render: bool,
+    // Dots mode 3 lasts on the current line
+    mode3_len: u32,
+    // The sprites on the current line from the OAM scan, in the order they
+    // win pixels in
+    line_objs: Vec<usize>,
+    // SCX, SCY and BGP as mode 3 started, and the writes to them during it
+    // with the pixel they land on. Raster effects change these mid line,
+    // render_line draws the background in pieces between the writes
+    line_start: (u8, u8, u8),
+    mid_line: Vec<(usize, u8, u8)>,
 
     pub clock: u32,
 
     pub vrambank: Box<[u8; VRAM_SIZE]>,
-
-    // Selects vrambank (only 0 supported since we don't do CGB)
+    // CGB only: more tile data, and the attributes of the tile maps in bank 0
+    //  bit 0-2  palette
+    //  bit 3    tile's VRAM bank
+    //  bit 5    horizontal flip
+    //  bit 6    vertical flip
+    //  bit 7    BG priority over sprites
+    pub vrambank1: Box<[u8; VRAM_SIZE]>,
+
+    // 0xff4f - VBK - Selects the VRAM bank the CPU sees, CGB only
     vrambank_sel: u8,
 
+    // The PPU keeps VRAM to itself during mode 3 and OAM during modes 2 and
+    // 3, the CPU reads 0xFF and its writes are lost (see Memory::rb and wb,
+    // the debugger still gets through). Can be turned off for debugging
+    pub restrict_access: bool,
+    // Emulate the DMG OAM bug, see corrupt_oam
+    pub oam_bug: bool,
+
     // 0xff40 - LCD control (LCDC) - in order from most to least significant bit
     pub lcdon: bool,    // LCD monitor turned on or off?
         winmap: bool,   // Window Tile Map Display (0=9800-9BFF, 1=9C00-9FFF)
@@ -133,35 +366,93 @@ pub struct Gpu {
     wy: u8,
     // 0xff4b - WX - Window X Position minus 7
     wx: u8,
+    // Line of the window to draw next. It only advances on lines the window
+    // was drawn on, so hiding it for some lines doesn't skip any of it
+    win_line: u8,
+
+    // Bumped by every write to VRAM, OAM or the palettes, see LineInputs
+    changes: u32,
+    // What every line in image_data was last drawn from, None if it has to
+    // be drawn again
+    drawn_lines: Box<[Option<LineInputs>; HEIGHT]>,
+
+    // 0xff4c - KEY0 - CGB mode, 0x04 = DMG compatibility. Only the CGB boot
+    // ROM can write it, afterwards it reads back as 0xff
+    key0: u8,
+    // 0xff6c - OPRI - Object priority mode, bit0: 0 = OAM order (CGB),
+    // 1 = X coordinate (DMG). Also set by the boot ROM
+    opri: u8,
+    is_cgb_hw: bool,
 
     // Compiled palettes. These are updated when writing to BGP/OBP0/OBP1. Meant
     // for non CGB use only. Each palette is an array of 4 color schemes. Each
-    // color scheme is one in PALETTE.
+    // color scheme is one in the matching base palette.
     pal: Box<Palettes>,
 
+    // Colors the shades in BGP/OBP0/OBP1 map to. PALETTE for all of them,
+    // unless a CGB colorizes a DMG game.
+    base_pal: Box<Palettes>,
+    // BCPD/OCPD, what CGB games color with instead of BGP/OBP0/OBP1
+    cgb_pal: Box<CgbPalettes>,
+    // Palettes to cycle through by name, and the one in use. None after the
+    // CGB picked colors for a DMG game, until the next switch
+    pub palettes: Vec<(String, Palette)>,
+    palette_sel: Option<usize>,
+
     // Compiled tiles
     tiles: Box<Tiles>,
+    // Times the tileset was rebuilt, for frame time statistics
+    pub tileset_rebuilds: u32,
+
+    // Draw pixel by pixel during mode 3 instead of a line at a time at
+    // HBlank. Slower, but mid-line effects work. Doesn't keep tile_sources
+    fifo: Option<Box<PixelFifo>>,
 
-    // Image for drawing
-    pub img: Image,
+    // The tile behind every pixel, only kept when an HD pack is loaded
+    pub tile_sources: Option<Vec<Option<TileSource>>>,
+    // The same for the last complete frame, copied along with it at VBlank
+    finished_sources: Option<Vec<Option<TileSource>>>,
 }
 
 impl Gpu {
-    pub fn new<W: Window>(window: &PistonWindow<W>) -> Gpu {
+    pub fn new() -> Gpu {
         let mut gpu: Gpu = Gpu {
             image_data: Box::new([255; HEIGHT * WIDTH * 4]),
+            finished: Box::new([0; HEIGHT * WIDTH * 4]),
+            indices: Box::new([0; HEIGHT * WIDTH]),
+            finished_indices: Box::new([0; HEIGHT * WIDTH]),
+            blend: None,
             oam: [0; OAM_SIZE],
             c:0,
             d:1,
             is_cgb: false,
             is_sgb: false,
+            sgb: None,
 
             clock: 0,
+            stat_line: false,
+            coincidence: false,
+            skip_frame: false,
+            hblank: false,
+            frame_done: false,
+            frames: 0,
+            frame_skip: None,
+            skipped: 0,
+            skip_render: false,
+            mode3_len: 172,
+            line_objs: Vec::new(),
+            line_start: (0, 0, 0xE4),
+            mid_line: Vec::new(),
             vrambank: Box::new([0; VRAM_SIZE]),
+            vrambank1: Box::new([0; VRAM_SIZE]),
             vrambank_sel: 0,
+            restrict_access: true,
+            oam_bug: false,
 
             mode: Mode::RdOam,
-            wx: 0, wy: 0, obp1: 0, obp0: 0, bgp: 0,
+            wx: 0, wy: 0, win_line: 0, obp1: 0, obp0: 0, bgp: 0,
+            changes: 0, drawn_lines: Box::new([None; HEIGHT]),
+            key0: 0, opri: 1, is_cgb_hw: false,
             lyc: 0, ly: 0, scx: 0, scy: 0,
             mode0int: false, mode1int: false, mode2int: false, lycly: false,
             bgon: false, objon: false, objsize: false, bgmap: false,
@@ -173,17 +464,32 @@ impl Gpu {
                 obp0: [[0; 4]; 4],
                 obp1: [[0; 4]; 4],
             }),
+            base_pal: Box::new(Palettes {
+                bg: *PALETTE,
+                obp0: *PALETTE,
+                obp1: *PALETTE,
+            }),
+            // The boot ROM leaves the BG palettes white
+            cgb_pal: Box::new(CgbPalettes {
+                bg_ram: [0xFF; 64],
+                obj_ram: [0; 64],
+                bcps: 0,
+                ocps: 0,
+                bg: [[[255; 4]; 4]; 8],
+                obj: [[[0, 0, 0, 255]; 4]; 8],
+            }),
+            palettes: BUILTIN_PALETTES.iter().map(|&(name, pal)| (String::from(name), pal)).collect(),
+            palette_sel: Some(0),
 
             tiles: Box::new(Tiles {
                 need_update: true,  // Does this need to be true?
                 to_update: [true;  NUM_TILES],
                 data: [[[0; 8]; 8]; NUM_TILES],
             }),
-
-            img: {
-                let r: SourceRectangle = [0.0, 0.0, ::SCREEN_DIMS[0] as f64, ::SCREEN_DIMS[1] as f64];
-                Image::new().src_rect(r)
-            }
+            tileset_rebuilds: 0,
+            fifo: None,
+            tile_sources: None,
+            finished_sources: None,
         };
 
         for i in 0..HEIGHT * WIDTH * 4 {
@@ -191,9 +497,9 @@ impl Gpu {
         }
 
         // Is this needed?
-        update_pal(&mut gpu.pal.bg, 0xE4);
-        update_pal(&mut gpu.pal.obp0, 0xE4);
-        update_pal(&mut gpu.pal.obp1, 0xE4);
+        update_pal(&mut gpu.pal.bg, &gpu.base_pal.bg, 0xE4);
+        update_pal(&mut gpu.pal.obp0, &gpu.base_pal.obp0, 0xE4);
+        update_pal(&mut gpu.pal.obp1, &gpu.base_pal.obp1, 0xE4);
 
         // BIOS SKIP
         gpu.clock = 0xABCC % 456;
@@ -206,23 +512,6 @@ impl Gpu {
         gpu
     }
 
-    pub fn display<W: Window>(&mut self, window: &mut PistonWindow<W>, evt: &input::Event) {
-        //self.update();
-
-        // window.draw_2d(&evt, |c, g| {
-        //     self.img.draw(&framebuffer, &c.draw_state, c.transform, g);
-        // })
-        //framebuffer = from_memory(&mut window.factory, &*emu.mem.gpu.image_data, 160, 144, &ts).unwrap();
-
-        // window.draw_2d(evt, |c, g| {
-        //     for y in 0..HEIGHT {
-        //         for x in 0..WIDTH {
-
-        //         }
-        //     }
-        // });
-    }
-
     pub fn update(&mut self) {
 
         // Debug code
@@ -240,40 +529,106 @@ impl Gpu {
         // self.clock += 1;
     }
 
+    pub fn vram_locked(&self) -> bool {
+        self.restrict_access && self.lcdon && self.mode == Mode::RdVram
+    }
+
+    pub fn oam_locked(&self) -> bool {
+        self.restrict_access && self.lcdon && (self.mode == Mode::RdOam || self.mode == Mode::RdVram)
+    }
+
+    pub fn rb_oam(&self, addr: u16) -> u8 {
+        self.oam[addr as usize - 0xFE00]
+    }
+
+    pub fn wb_oam(&mut self, addr: u16, data: u8) {
+        self.oam[addr as usize - 0xFE00] = data;
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    // The DMG OAM bug: while the PPU scans OAM (mode 2) the CPU putting an
+    // address in 0xFE00-0xFEFF on the bus garbles the row of 8 bytes the
+    // PPU is on. Reads, writes and 16 bit INC/DEC all do it, reads in their
+    // own way. The row is mixed with the one before, the first word going
+    // through a formula (from Pan Docs) and the other three copied. A read
+    // and an INC/DEC in the same cycle first mixes the two rows before into
+    // each other. The CGB doesn't have it
+    pub fn corrupt_oam(&mut self, addr: u16, access: OamAccess) {
+        if !self.oam_bug || self.is_cgb || !self.lcdon || self.mode != Mode::RdOam ||
+            addr < 0xFE00 || addr > 0xFEFF {
+            return;
+        }
+        // A row every 4 dots, the first can't be hit
+        let row = (self.clock as usize / 4).min(OAM_SIZE / 8 - 1);
+        if row == 0 {
+            return;
+        }
+        let (cur, prev) = (row * 8, row * 8 - 8);
+        let word = |oam: &[u8], i: usize| oam[i] as u16 | (oam[i + 1] as u16) << 8;
+
+        // Not for the first four rows or the last one
+        if access == OamAccess::ReadIncDec && row >= 4 && row < OAM_SIZE / 8 - 1 {
+            let two_before = prev - 8;
+            let a = word(&self.oam, two_before);
+            let b = word(&self.oam, prev);
+            let c = word(&self.oam, cur);
+            let d = word(&self.oam, two_before + 4);
+            let first = (b & (a | c | d)) | (a & c & d);
+            self.oam[prev] = first as u8;
+            self.oam[prev + 1] = (first >> 8) as u8;
+            for i in 0..8 {
+                self.oam[cur + i] = self.oam[prev + i];
+                self.oam[two_before + i] = self.oam[prev + i];
+            }
+        }
+
+        let a = word(&self.oam, cur);
+        let b = word(&self.oam, prev);
+        let c = word(&self.oam, prev + 4);
+        let first = match access {
+            OamAccess::Write => ((a ^ c) & (b ^ c)) ^ c,
+            OamAccess::Read | OamAccess::ReadIncDec => b | (a & c),
+        };
+        self.oam[cur] = first as u8;
+        self.oam[cur + 1] = (first >> 8) as u8;
+        for i in 2..8 {
+            self.oam[cur + i] = self.oam[prev + i];
+        }
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    // OAM DMA, which gets through whatever mode the PPU is in
+    pub fn dma_oam(&mut self, i: usize, data: u8) {
+        self.oam[i] = data;
+        self.changes = self.changes.wrapping_add(1);
+    }
+
     pub fn rb_vram(&self, addr: u16) -> u8 {
         match addr {
+            0x8000 ... 0x9FFF if self.vrambank_sel == 1 => self.vrambank1[addr as usize - 0x8000],
             0x8000 ... 0x9FFF => self.vrambank[addr as usize - 0x8000],
-            //0xA000 ... 0xBFFF => self.vrambanks[1][addr as usize - 0xA000],
             _ => unreachable!()
         }
     }
 
     pub fn wb_vram(&mut self, addr: u16, data: u8) {
+        self.changes = self.changes.wrapping_add(1);
         match addr {
+            // Bank 1 tiles aren't cached, CGB rendering reads them from VRAM
+            0x8000 ... 0x9FFF if self.vrambank_sel == 1 => {
+                self.vrambank1[addr as usize - 0x8000] = data;
+            }
             0x8000 ... 0x9FFF => {
                 //trace!("writing to VRAM1 {:04X}  data {:02X}", addr - 0x8000, data);
-                let mut tilei: u16;
-
-                tilei = (addr - 0x8000 as u16) / 16;
-
+                // The cache covers all of tile data, so a tile's index is
+                // its place in VRAM whichever way LCDC.4 addresses it
+                let tilei = (addr - 0x8000 as u16) / 16;
                 if tilei < NUM_TILES as u16 {
                     self.tiles.to_update[tilei as usize] = true;
                     self.tiles.need_update = true;
                 }
-
-                if !self.tiledata && addr >= 0x8800 {
-                    tilei = (addr - 0x8800 as u16) / 16;
-                    if tilei < NUM_TILES as u16 {
-                        self.tiles.to_update[tilei as usize] = true;
-                        self.tiles.need_update = true;
-                    }
-                }
                 self.vrambank[addr as usize - 0x8000] = data;
             },
-            // 0xA000 ... 0xBFFF => {
-            //    //trace!("writing to VRAM2 {:04X}  data {:02X}", addr - 0xA000 , data);
-            //    self.vrambanks[1][addr as usize - 0xA000] = data;
-            // }
             _ => unreachable!()
         }
     }
@@ -282,14 +637,7 @@ impl Gpu {
         match addr & 0xff {
             0x40 => {
                 warn!("BG read {}",self.bgon);
-                ((self.lcdon as u8)    << 7) |
-                ((self.winmap as u8)   << 6) |
-                ((self.winon as u8)    << 5) |
-                ((self.tiledata as u8) << 4) |
-                ((self.bgmap as u8)    << 3) |
-                ((self.objsize as u8)  << 2) |
-                ((self.objon as u8)    << 1) |
-                ((self.bgon as u8)     << 0)
+                self.lcdc()
             }
 
             0x41 => {
@@ -297,13 +645,13 @@ impl Gpu {
                 ((self.mode2int as u8)                                << 5) |
                 ((self.mode1int as u8)                                << 4) |
                 ((self.mode0int as u8)                                << 3) |
-                ((if self.lycly as u8 == self.ly {1} else {0} as u8) << 2) |
+                ((self.coincidence as u8)                             << 2) |
                 ((self.mode as u8)                                    << 0)
             }
 
             0x42 => self.scy,
             0x43 => self.scx,
-            0x44 => self.ly,
+            0x44 => self.ly_register(),
             0x45 => self.lyc,
             // 0x46 is DMA transfer, can't read
             0x47 => self.bgp,
@@ -311,7 +659,12 @@ impl Gpu {
             0x49 => self.obp1,
             0x4a => self.wy,
             0x4b => self.wx,
-            0x4f => self.vrambank_sel,
+            0x4f if self.is_cgb => 0xfe | self.vrambank_sel,
+            0x68 if self.is_cgb => self.cgb_pal.bcps | 0x40,
+            0x69 if self.is_cgb => self.cgb_pal.bg_ram[(self.cgb_pal.bcps & 0x3f) as usize],
+            0x6a if self.is_cgb => self.cgb_pal.ocps | 0x40,
+            0x6b if self.is_cgb => self.cgb_pal.obj_ram[(self.cgb_pal.ocps & 0x3f) as usize],
+            0x6c if self.is_cgb_hw && self.key0 & 0x04 == 0 => 0xfe | self.opri,
 
             _ => 0xff
         }
@@ -330,9 +683,21 @@ impl Gpu {
                 self.objsize  = (val >> 2) & 1 != 0;
                 self.objon    = (val >> 1) & 1 != 0;
                 self.bgon     = (val >> 0) & 1 != 0;
+                if before && !self.lcdon {
+                    // The PPU stops, LY stays at 0 in mode 0 and the screen
+                    // goes blank
+                    self.clock = 0;
+                    self.ly = 0;
+                    self.mode = Mode::HBlank;
+                    self.stat_line = false;
+                    self.win_line = 0;
+                    self.blank_screen();
+                }
                 if !before && self.lcdon {
                     self.clock = 4; // ??? why 4?!
                     self.ly = 0;
+                    // The first frame after turning the LCD on isn't shown
+                    self.skip_frame = true;
                 }
             }
 
@@ -345,16 +710,27 @@ impl Gpu {
                 // are read-only and won't be modified
             }
 
-            0x42 => { self.scy = val; }
-            0x43 => { self.scx = val; }
+            0x42 => { self.mid_line_write(addr, val); self.scy = val; }
+            0x43 => { self.mid_line_write(addr, val); self.scx = val; }
             // 0x44 self.ly is read-only
-            0x45 => { self.lyc = val; }
-            0x47 => { self.bgp = val; update_pal(&mut self.pal.bg, val); }
-            0x48 => { self.obp0 = val; update_pal(&mut self.pal.obp0, val); }
-            0x49 => { self.obp1 = val; update_pal(&mut self.pal.obp1, val); }
+            0x45 => {
+                self.lyc = val;
+                if self.lcdon {
+                    self.coincidence = self.ly_register() == val;
+                }
+            }
+            0x47 => { self.mid_line_write(addr, val); self.bgp = val; update_pal(&mut self.pal.bg, &self.base_pal.bg, val); }
+            0x48 => { self.obp0 = val; update_pal(&mut self.pal.obp0, &self.base_pal.obp0, val); }
+            0x49 => { self.obp1 = val; update_pal(&mut self.pal.obp1, &self.base_pal.obp1, val); }
             0x4a => { self.wy = val; }
             0x4b => { self.wx = val; }
             0x4f => { if self.is_cgb { self.vrambank_sel = val & 1; } }
+            0x68 => { if self.is_cgb { self.cgb_pal.bcps = val & 0xbf; } }
+            0x69 => { if self.is_cgb { self.cgb_pal.write_bg(val); self.changes = self.changes.wrapping_add(1); } }
+            0x6a => { if self.is_cgb { self.cgb_pal.ocps = val & 0xbf; } }
+            0x6b => { if self.is_cgb { self.cgb_pal.write_obj(val); self.changes = self.changes.wrapping_add(1); } }
+            // We skip the boot ROM, so KEY0 and OPRI are already locked
+            0x4c | 0x6c => debug!("Write to locked register FF{:02X}: {:02X}", addr & 0xff, val),
 
             _ => {}
         }
@@ -369,6 +745,13 @@ impl Gpu {
     // between a few different states. In one state, however, the rendering of a
     // screen occurs, but that doesn't always happen when calling this function.
     pub fn step(&mut self, clocks: u32, if_: &mut u8) {
+        if !self.lcdon {
+            return;
+        }
+        if self.fifo.is_some() {
+            return self.step_fifo(clocks, if_);
+        }
+
         // Timings located here:
         //      http://http://problemkaputt.de//pandocs.htm#lcdstatusregister
         self.clock += clocks;
@@ -377,29 +760,366 @@ impl Gpu {
         // have been part of a vblank or part of a scanline.
         if self.clock >= 456 {
             self.clock -= 456;
-            self.ly = (self.ly + 1) % 154; // 144 lines tall, 10 for a vblank
-
-            // debug!("Completed an entire line");
-
-            if self.ly >= 144 && self.mode != Mode::VBlank {
-                self.switch(Mode::VBlank, if_);
-            }
-
-            if self.ly == self.lyc && self.lycly {
-                *if_ |= Interrupt::LCDStat as u8;
-            }
+            self.next_line(if_);
         }
 
         // Hop between modes if we're not in vblank
         if self.ly < 144 {
             if self.clock <= 80 { // RDOAM takes 80 cycles
                 if self.mode != Mode::RdOam { self.switch(Mode::RdOam, if_); }
-            } else if self.clock <= 252 { // RDVRAM takes 172 cycles
-                if self.mode != Mode::RdVram { self.switch(Mode::RdVram, if_); }
+            } else if self.mode == Mode::RdOam || self.clock <= 80 + self.mode3_len {
+                // RDVRAM takes 172 cycles or more
+                if self.mode != Mode::RdVram {
+                    self.oam_scan();
+                    self.mode3_len = self.mode3_length();
+                    self.latch_line();
+                    self.switch(Mode::RdVram, if_);
+                }
+                if self.clock > 80 + self.mode3_len {
+                    self.switch(Mode::HBlank, if_);
+                }
             } else { // HBLANK takes rest of time before line rendered
                 if self.mode != Mode::HBlank { self.switch(Mode::HBlank, if_); }
             }
         }
+
+        // STAT or LYC may have been written since
+        self.update_stat(if_);
+    }
+
+    // How long mode 3 takes on this line. Fine scrolling throws pixels away,
+    // the window restarts the fetcher and every sprite pauses it for 6 to 11
+    // dots, depending on where it lands in a background tile
+    fn mode3_length(&self) -> u32 {
+        let mut len = 172 + (self.scx & 7) as u32;
+        if self.window_on_line() {
+            len += 6;
+        }
+        if self.objon {
+            for &i in self.line_objs.iter() {
+                let x = self.oam[i * OAM_ENTRY_SIZE + 1] as u32 + self.scx as u32;
+                len += 11 - (x % 8).min(5);
+            }
+        }
+        len
+    }
+
+    // Mode 3 is starting, the line is drawn from the registers as they are
+    // now until they're written to
+    fn latch_line(&mut self) {
+        self.line_start = (self.scx, self.scy, self.bgp);
+        self.mid_line.clear();
+    }
+
+    // The pixel FIFO reads the registers as it goes, the line renderer only
+    // draws at HBlank so it has to be told where writes during mode 3 land.
+    // Pixels come out over the last 160 dots of mode 3, near enough
+    fn mid_line_write(&mut self, addr: u16, val: u8) {
+        if self.mode == Mode::RdVram && self.fifo.is_none() && self.lcdon {
+            let x = (self.clock + WIDTH as u32).saturating_sub(80 + self.mode3_len);
+            self.mid_line.push(((x as usize).min(WIDTH), addr as u8, val));
+        }
+    }
+
+    fn next_line(&mut self, if_: &mut u8) {
+        self.ly = (self.ly + 1) % 154; // 144 lines tall, 10 for a vblank
+
+        // debug!("Completed an entire line");
+
+        if self.ly >= 144 && self.mode != Mode::VBlank {
+            self.switch(Mode::VBlank, if_);
+        }
+
+        self.update_stat(if_);
+    }
+
+    // Whether an HBlank started since the last call, for HBlank DMA
+    pub fn take_hblank(&mut self) -> bool {
+        let hblank = self.hblank;
+        self.hblank = false;
+        hblank
+    }
+
+    pub fn take_frame_done(&mut self) -> bool {
+        let done = self.frame_done;
+        self.frame_done = false;
+        done
+    }
+
+    // Changes whenever screen() has something new to show
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    // Show every frame mixed with the ones before it. Games that flicker
+    // sprites every other frame to make them see-through look like they
+    // meant to instead of flashing, and ghosting trails moving things the
+    // way the DMG screen does
+    pub fn blend_frames(&mut self, mode: Option<BlendMode>) {
+        self.blend = mode.map(|mode| {
+            Box::new(FrameBlend { mode: mode, prev: *self.finished, shown: *self.finished })
+        });
+        self.frames = self.frames.wrapping_add(1);
+    }
+
+    // image_data is complete, hand it over to screen()
+    fn finish_frame(&mut self) {
+        self.frames = self.frames.wrapping_add(1);
+        match self.sgb {
+            Some(ref sgb) => sgb.colorize(&self.indices, &mut self.finished),
+            None => self.finished.copy_from_slice(&self.image_data[..]),
+        }
+        self.finished_indices.copy_from_slice(&self.indices[..]);
+        if let (Some(sources), Some(finished)) = (self.tile_sources.as_ref(), self.finished_sources.as_mut()) {
+            finished.copy_from_slice(sources);
+        }
+        if let Some(ref mut blend) = self.blend {
+            blend.push(&self.finished);
+        }
+    }
+
+    // The last complete frame, not blended, as RGBA8
+    pub fn frame(&self) -> &ScreenData {
+        &self.finished
+    }
+
+    // FNV-1a of frame(), see framehash.rs
+    pub fn frame_hash(&self) -> u32 {
+        fnv1a(FNV_OFFSET, &self.finished[..])
+    }
+
+    // The tile behind every pixel of frame(), None unless they're tracked
+    pub fn frame_tile_sources(&self) -> Option<&[Option<TileSource>]> {
+        self.finished_sources.as_ref().map(|sources| &sources[..])
+    }
+
+    // The last complete frame as color indices, see IndexData
+    pub fn frame_indices(&self) -> &IndexData {
+        &self.finished_indices
+    }
+
+    // The last complete frame as RGB565, for displays and embedders that
+    // want 16 bits a pixel
+    pub fn frame_rgb565(&self) -> Vec<u16> {
+        self.finished.chunks(4).map(|p| {
+            (p[0] as u16 >> 3) << 11 | (p[1] as u16 >> 2) << 5 | p[2] as u16 >> 3
+        }).collect()
+    }
+
+    // The frame to put on screen, the last finished one unless frames are
+    // blended
+    pub fn screen(&self) -> &ScreenData {
+        match self.blend {
+            Some(ref blend) => &blend.shown,
+            None => &self.finished,
+        }
+    }
+
+    // Switch to the pixel FIFO renderer
+    pub fn use_pixel_fifo(&mut self) {
+        self.fifo = Some(Box::new(PixelFifo::new()));
+    }
+
+    // Same as step, a dot at a time. Mode 3 lasts until the FIFO has drawn
+    // the whole line
+    fn step_fifo(&mut self, clocks: u32, if_: &mut u8) {
+        for _ in 0..clocks {
+            self.clock += 1;
+            if self.clock >= 456 {
+                self.clock -= 456;
+                self.next_line(if_);
+            }
+            if self.ly >= 144 {
+                continue;
+            }
+
+            if self.clock <= 80 {
+                if self.mode != Mode::RdOam { self.switch(Mode::RdOam, if_); }
+            } else if self.mode == Mode::RdOam {
+                self.oam_scan();
+                self.switch(Mode::RdVram, if_);
+                self.start_fifo_line();
+            } else if self.mode == Mode::RdVram {
+                let mut fifo = self.fifo.take().unwrap();
+                self.fifo_dot(&mut fifo);
+                let done = fifo.x >= WIDTH;
+                self.fifo = Some(fifo);
+                if done { self.switch(Mode::HBlank, if_); }
+            }
+        }
+        self.update_stat(if_);
+    }
+
+    fn start_fifo_line(&mut self) {
+        if let Some(ref mut sources) = self.tile_sources {
+            let start = self.ly as usize * WIDTH;
+            for src in sources[start..start + WIDTH].iter_mut() {
+                *src = None;
+            }
+        }
+
+        // Fetched left to right whatever the priority
+        let mut sprites = if self.objon {self.line_objs.clone()} else {Vec::new()};
+        {
+            let oam = &self.oam;
+            sprites.sort_by_key(|&i| oam[i * OAM_ENTRY_SIZE + 1]);
+        }
+
+        let lcdon = self.lcdon;
+        let scx = self.scx;
+        let fifo = self.fifo.as_mut().unwrap();
+        fifo.restart();
+        fifo.obj.clear();
+        fifo.x = if lcdon {0} else {WIDTH};
+        fifo.discard = scx & 7;
+        fifo.window = false;
+        fifo.sprites = sprites;
+        fifo.stall = 6;
+    }
+
+    // One dot of mode 3: the fetcher moves on and a pixel comes out
+    fn fifo_dot(&mut self, fifo: &mut PixelFifo) {
+        // The window takes over from WX - 7 to the end of the line
+        if !fifo.window && (self.bgon || self.is_cgb) && self.winon && self.ly >= self.wy &&
+           self.wx <= 166 && fifo.x + 7 >= self.wx as usize {
+            fifo.window = true;
+            fifo.restart();
+            fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        if fifo.stall > 0 {
+            fifo.stall -= 1;
+            return;
+        }
+
+        // A sprite starting here pauses the line while it's fetched
+        if fifo.sprites.first().map_or(false, |&i| self.oam[i * OAM_ENTRY_SIZE + 1] as usize <= fifo.x + 8) {
+            let i = fifo.sprites.remove(0);
+            self.fetch_sprite(fifo, i);
+            fifo.stall = 6;
+            return;
+        }
+
+        self.fetch_bg(fifo);
+
+        let bg = match fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return,
+        };
+        if fifo.discard > 0 {
+            fifo.discard -= 1;
+            return;
+        }
+        let obj = fifo.obj.pop_front();
+
+        let colori = if self.bgon || self.is_cgb {bg.colori} else {0};
+        let bgp = if self.is_cgb {self.cgb_pal.bg[(bg.attrs & 7) as usize]} else {self.pal.bg};
+        let mut color = bgp[colori as usize];
+        let mut index = self.color_index(self.bgp, colori);
+        if let Some(obj) = obj {
+            // Same rules as render_sprites
+            let hidden = self.bgon && colori != 0 && (bg.attrs & 0x80 != 0 || obj.flags & 0x80 != 0);
+            if self.objon && obj.colori != 0 && !hidden {
+                let pal = if self.is_cgb {
+                    self.cgb_pal.obj[(obj.flags & 7) as usize]
+                } else if obj.flags & 0x10 != 0 {self.pal.obp1} else {self.pal.obp0};
+                color = pal[obj.colori as usize];
+                index = self.color_index(if obj.flags & 0x10 != 0 {self.obp1} else {self.obp0}, obj.colori);
+            }
+        }
+        if !self.skip_frame {
+            let i = self.ly as usize * WIDTH + fifo.x;
+            self.image_data[i * 4..i * 4 + 4].copy_from_slice(&color);
+            self.indices[i] = index;
+        }
+
+        fifo.x += 1;
+        if fifo.x == WIDTH && fifo.window {
+            self.win_line += 1;
+        }
+    }
+
+    fn fetch_bg(&mut self, fifo: &mut PixelFifo) {
+        fifo.dots += 1;
+        match fifo.step {
+            FetchStep::Tile if fifo.dots == 2 => {
+                let (addr, tile_y) = if fifo.window {
+                    let line = self.win_line as usize;
+                    (self.winbase() + (line >> 3 << 5) + (fifo.fetch_x & 31), line % 8)
+                } else {
+                    let line = (self.ly as usize + self.scy as usize) % 256;
+                    let col = (self.scx as usize >> 3) + fifo.fetch_x;
+                    (self.bgbase() + (line >> 3 << 5) + (col & 31), line % 8)
+                };
+                fifo.tilei = self.vrambank[addr];
+                fifo.attrs = if self.is_cgb {self.vrambank1[addr]} else {0};
+                fifo.tile_y = tile_y;
+                fifo.step = FetchStep::Low;
+                fifo.dots = 0;
+            }
+            FetchStep::Low if fifo.dots == 2 => {
+                fifo.step = FetchStep::High;
+                fifo.dots = 0;
+            }
+            FetchStep::High if fifo.dots == 2 => {
+                let ty = if fifo.attrs & 0x40 != 0 {7 - fifo.tile_y} else {fifo.tile_y};
+                fifo.row = self.tile_row(fifo.attrs, self.bg_tile_addr(fifo.tilei) + ty * 2);
+                if fifo.attrs & 0x20 != 0 {
+                    fifo.row.reverse();
+                }
+                fifo.step = FetchStep::Push;
+                fifo.dots = 0;
+            }
+            // Waits until the FIFO is empty
+            FetchStep::Push if fifo.bg.is_empty() => {
+                for &colori in fifo.row.iter() {
+                    fifo.bg.push_back(BgPixel { colori: colori, attrs: fifo.attrs });
+                }
+                fifo.fetch_x += 1;
+                fifo.step = FetchStep::Tile;
+                fifo.dots = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Mix a sprite's row into the sprite FIFO, lined up with the next pixel
+    fn fetch_sprite(&self, fifo: &mut PixelFifo, i: usize) {
+        let sprite = &self.oam[i * OAM_ENTRY_SIZE..(i + 1) * OAM_ENTRY_SIZE];
+        let line = self.ly as i32;
+        let mut yoff = sprite[0] as i32 - 16;
+        let xoff = sprite[1] as i32 - 8;
+        let mut tile = sprite[2] as usize;
+        let flags = sprite[3];
+        if self.objsize {
+            tile &= 0xfe;
+            if line - yoff >= 8 {
+                tile |= 1;
+                yoff += 8;
+            }
+        }
+        let ty = if flags & 0x40 != 0 {7 - (line - yoff)} else {line - yoff};
+        // Only the CGB has a second bank to take tiles from
+        let row = self.tile_row(if self.is_cgb {flags} else {0}, tile * 16 + ty as usize * 2);
+
+        for x in 0..8 {
+            let slot = xoff + x - fifo.x as i32;
+            if slot < 0 {
+                continue;
+            }
+            let tx = if flags & 0x20 != 0 {7 - x} else {x};
+            let pixel = ObjPixel { colori: row[tx as usize], flags: flags, oam: i };
+            match fifo.obj.get_mut(slot as usize) {
+                // Sprites fetched earlier have a smaller X, they keep their
+                // pixels on the DMG. The CGB goes by OAM order
+                Some(old) => {
+                    if old.colori == 0 || self.opri & 1 == 0 && pixel.colori != 0 && i < old.oam {
+                        *old = pixel;
+                    }
+                }
+                None => fifo.obj.push_back(pixel),
+            }
+        }
     }
 
     fn switch(&mut self, mode: Mode, if_: &mut u8) {
@@ -407,30 +1127,59 @@ impl Gpu {
         match mode {
             Mode::HBlank => {
                 trace!("HBlank! Rendering...");
-                self.render_line();
-                if self.mode0int {
-                    *if_ |= Interrupt::LCDStat as u8;
+                self.hblank = true;
+                if self.fifo.is_none() && !self.skip_frame {
+                    if !self.skip_render {
+                        self.render_line();
+                    } else if self.window_on_line() {
+                        // Same as if it was drawn
+                        self.win_line += 1;
+                    }
                 }
             }
             Mode::VBlank => {
-                // TODO: a frame is ready, it should be put on screen at this
-                // point
                 debug!("GPU: VBlank!");
-                *if_ |= Interrupt::Vblank as u8;
-                if self.mode1int {
-                    *if_ |= Interrupt::LCDStat as u8;
-                }
-            }
-            Mode::RdOam => {
-                if self.mode2int {
-                    *if_ |= Interrupt::LCDStat as u8;
+                self.frame_done = true;
+                // A skipped frame leaves the last drawn one on screen
+                if !self.skip_render {
+                    self.finish_frame();
                 }
+                self.skip_render = match self.frame_skip {
+                    Some((n, m)) => {
+                        self.skipped = (self.skipped + 1) % m;
+                        self.skipped < n
+                    }
+                    None => false,
+                };
+                self.win_line = 0;
+                self.skip_frame = false;
+                *if_ |= Interrupt::Vblank as u8;
             }
-            Mode::RdVram => {}
+            Mode::RdOam | Mode::RdVram => {}
+        }
+        self.update_stat(if_);
+    }
+
+    // All the STAT interrupt sources share a line, the interrupt is requested
+    // when it goes from low to high. A source becoming active while another
+    // one keeps the line high doesn't request another
+    fn update_stat(&mut self, if_: &mut u8) {
+        if self.lcdon {
+            self.coincidence = self.ly_register() == self.lyc;
+        }
+        let line = self.lcdon && (
+            self.lycly && self.coincidence ||
+            self.mode0int && self.mode == Mode::HBlank ||
+            self.mode1int && self.mode == Mode::VBlank ||
+            self.mode2int && self.mode == Mode::RdOam);
+        if line && !self.stat_line {
+            *if_ |= Interrupt::LCDStat as u8;
         }
+        self.stat_line = line;
     }
 
     fn update_tileset(&mut self) {
+        self.tileset_rebuilds += 1;
 
         let tiles = &mut *self.tiles;
         let iter = tiles.to_update.iter_mut();
@@ -448,9 +1197,8 @@ impl Gpu {
             //      byte 1 : 01101010
             //
             // The colors are [0, 2, 2, 1, 3, 0, 3, 1]
-            // println!("-- memory addr: {:#0X}   {:#0X}", ((i % NUM_TILES) * 16) + 0x8000, self.vrambank[((i % NUM_TILES) * 16)]);
             for j in 0..8 {
-                let addr = ((i % NUM_TILES) * 16) + j * 2;
+                let addr = i * 16 + j * 2;
 
                 // println!("memory addr: {:#0X}", addr + 0x8000);
                 // All tiles are located 0x8000-0x97ff => 0x0000-0x17ff in VRAM
@@ -484,22 +1232,71 @@ impl Gpu {
         if self.bgmap {0x1c00} else {0x1800}
     }
 
+    // LCDC as the CPU reads it
+    fn lcdc(&self) -> u8 {
+        ((self.lcdon as u8)    << 7) |
+        ((self.winmap as u8)   << 6) |
+        ((self.winon as u8)    << 5) |
+        ((self.tiledata as u8) << 4) |
+        ((self.bgmap as u8)    << 3) |
+        ((self.objsize as u8)  << 2) |
+        ((self.objon as u8)    << 1) |
+        ((self.bgon as u8)     << 0)
+    }
+
+    // Everything the current line is drawn from
+    fn line_inputs(&self) -> LineInputs {
+        LineInputs {
+            lcdc: self.lcdc(),
+            opri: self.opri,
+            scroll: (self.scx, self.scy),
+            window: (self.wx, self.wy, self.win_line),
+            dmg_pal: (self.bgp, self.obp0, self.obp1),
+            changes: self.changes,
+        }
+    }
+
     fn render_line(&mut self) {
         if !self.lcdon { return }
 
+        // Nothing it's drawn from changed since this line was drawn last
+        // frame, it would come out the same. Only the window moves on.
+        // Lines with writes during mode 3 are always drawn
+        let inputs = self.line_inputs();
+        let ly = self.ly as usize;
+        let writes = ::std::mem::replace(&mut self.mid_line, Vec::new());
+        if writes.is_empty() && self.drawn_lines[ly] == Some(inputs) {
+            if self.window_on_line() {
+                self.win_line += 1;
+            }
+            return;
+        }
+        self.drawn_lines[ly] = if writes.is_empty() {Some(inputs)} else {None};
+
         let mut scanline = [0u8; WIDTH];
 
+        if let Some(ref mut sources) = self.tile_sources {
+            let start = self.ly as usize * WIDTH;
+            for src in sources[start..start + WIDTH].iter_mut() {
+                *src = None;
+            }
+        }
+
         if self.tiles.need_update {
             self.update_tileset();
             self.tiles.need_update = false;
         }
 
-        if self.bgon {
-            self.render_background(&mut scanline);
-        }
-        if self.winon {
-            //self.render_window(&mut scanline);
+        // LCDC bit 0 only turns the background (and window) off on the DMG,
+        // on the CGB it's the master priority, see render_sprites
+        if self.bgon || self.is_cgb {
+            if writes.is_empty() {
+                self.render_background(&mut scanline, 0, WIDTH);
+            } else {
+                self.render_background_split(&mut scanline, &writes);
+            }
         }
+        self.render_window(&mut scanline);
         if self.objon {
             self.render_sprites(&mut scanline);
         }
@@ -515,7 +1312,35 @@ impl Gpu {
         }
     }
 
-    fn render_background(&mut self, scanline: &mut [u8; WIDTH]) {
+    // Draw the background from the registers as mode 3 started, switching to
+    // the values written at the pixels they were written at. They end up
+    // the same as the registers are now
+    fn render_background_split(&mut self, scanline: &mut [u8; WIDTH], writes: &[(usize, u8, u8)]) {
+        let (scx, scy, bgp) = self.line_start;
+        self.scx = scx;
+        self.scy = scy;
+        self.bgp = bgp;
+        update_pal(&mut self.pal.bg, &self.base_pal.bg, bgp);
+
+        let mut from = 0;
+        for &(x, reg, val) in writes.iter() {
+            if x > from {
+                self.render_background(scanline, from, x);
+                from = x;
+            }
+            match reg {
+                0x42 => self.scy = val,
+                0x43 => self.scx = val,
+                _ => { self.bgp = val; update_pal(&mut self.pal.bg, &self.base_pal.bg, val); }
+            }
+        }
+        if from < WIDTH {
+            self.render_background(scanline, from, WIDTH);
+        }
+    }
+
+    // Pixels `from` up to `to` of the line
+    fn render_background(&mut self, scanline: &mut [u8; WIDTH], from: usize, to: usize) {
         let mapbase = self.bgbase();
         let line = self.ly as usize + self.scy as usize;
 
@@ -527,17 +1352,17 @@ impl Gpu {
 
         // X and Y location inside the tile itself to paint
         let y = (self.ly.wrapping_add(self.scy)) % 8;
-        let mut x = self.scx % 8;
+        let mut x = (from as u8).wrapping_add(self.scx) % 8;
 
         // Offset into the canvas to draw. line * width * 4 colors
-        let mut coff = (self.ly as usize) * WIDTH * 4;
+        let mut coff = ((self.ly as usize) * WIDTH + from) * 4;
 
-        let mut i = 0;
+        let mut i = from as u8;
         // this.tiledata is a flag to determine which tile data table to use
         // 0=8800-97FF, 1=8000-8FFF. For some odd reason, if tiledata = 0, then
         // (&tiles[0]) == 0x9000, where if tiledata = 1, (&tiles[0]) = 0x8000.
         // This implies that the indices are treated as signed numbers.
-        // TODO: should this be 0x800 ?
+        // Tile 256 is at 0x9000, so signed indices land on 128-383
         let tilebase = if !self.tiledata {256} else {0};
 
         //info!("render background. mapbase:{:x} scx:{} scy:{}", mapbase, self.scx, self.scy);
@@ -550,27 +1375,46 @@ impl Gpu {
             let mapoff = ((i as usize + self.scx as usize) % 256) >> 3;
             let tilei = self.vrambank[mapbase + mapoff];
             // bg_tiles[loop_c] = tilei;
-            // tiledata = 0 => tilei is a signed byte, so fix it here
-            let tilebase = tilei%192;//self.add_tilei(tilebase, tilei);
-            // println!("tilebase: {}", tilebase);
+            let tile = self.add_tilei(tilebase, tilei);
 
             let row;
             let bgpri;
             let hflip;
             let bgp;
 
-            row = self.tiles.data[tilebase as usize][y as usize];
-            bgpri = false;
-            hflip = false;
-            bgp = self.pal.bg;
+            if self.is_cgb {
+                let attrs = self.vrambank1[mapbase + mapoff];
+                let ty = if attrs & 0x40 != 0 {7 - y} else {y};
+                row = self.tile_row(attrs, self.bg_tile_addr(tilei) + ty as usize * 2);
+                bgpri = attrs & 0x80 != 0;
+                hflip = attrs & 0x20 != 0;
+                bgp = self.cgb_pal.bg[(attrs & 7) as usize];
+            } else {
+                row = self.tiles.data[tile][y as usize];
+                bgpri = false;
+                hflip = false;
+                bgp = self.pal.bg;
+            }
+            let hash = match self.tile_sources {
+                Some(_) => self.tile_hash(tile, self.bgp),
+                None => 0,
+            };
 
-            while x < 8 && i < WIDTH as u8 {
-                let colori = row[if hflip {7 - x} else {x} as usize];
+            while x < 8 && (i as usize) < to {
+                let tx = if hflip {7 - x} else {x};
+                let colori = row[tx as usize];
 
-                // To indicate bg priority, list a color >= 4
-                scanline[i as usize] = if bgpri {4} else {colori};
+                if let Some(ref mut sources) = self.tile_sources {
+                    sources[self.ly as usize * WIDTH + i as usize] =
+                        Some(TileSource { hash: hash, x: tx, y: y });
+                }
 
-                set_pixel_index(&mut self.image_data, coff, colori as usize, &bgp);
+                // To indicate bg priority, list a color >= 4. Color 0 never
+                // has priority
+                scanline[i as usize] = if bgpri && colori != 0 {4 | colori} else {colori};
+
+                let index = self.color_index(self.bgp, colori);
+                set_pixel_index(&mut self.image_data, &mut self.indices, coff, colori as usize, index, &bgp);
 
                 x += 1;
                 i += 1;
@@ -579,24 +1423,83 @@ impl Gpu {
 
             x = 0;
             // loop_c += 1;
-            if i >= WIDTH as u8 { break }
+            if i as usize >= to { break }
         }
 
         // Dump bg tiles
         // println!("LINE: {:03} | LY: {:03} | {:?}", line, self.ly, bg_tiles);
     }
 
+    pub fn winbase(&self) -> usize {
+        if self.winmap {0x1c00} else {0x1800}
+    }
+
+    // Offset in VRAM of a BG/window tile. tiledata = 0 => tilei is a signed
+    // index from 0x9000
+    pub fn bg_tile_addr(&self, tilei: u8) -> usize {
+        if self.tiledata {
+            tilei as usize * 16
+        } else {
+            (0x1000 + tilei as i8 as isize * 16) as usize
+        }
+    }
+
+    // Whether the window shows up on the current line
+    fn window_on_line(&self) -> bool {
+        // On the DMG, turning the background off hides the window too
+        (self.bgon || self.is_cgb) && self.winon && self.ly >= self.wy && self.wx <= 166
+    }
+
+    // The window is a second, non-scrolling background drawn over the first
+    // from (WX - 7, WY) to the bottom right of the screen
     fn render_window(&mut self, scanline: &mut [u8; WIDTH]) {
-        // TODO: Window rendering
+        if !self.window_on_line() {
+            return;
+        }
+        let line = self.win_line as usize;
+        let mapbase = self.winbase() + (line >> 3 << 5);
+        let y = line % 8;
+        let left = self.wx as isize - 7;
+
+        for i in left.max(0) as usize..WIDTH {
+            let wx = (i as isize - left) as usize;
+            let tilei = self.vrambank[mapbase + (wx >> 3)];
+            // Attributes work like the background's on the CGB
+            let attrs = if self.is_cgb {self.vrambank1[mapbase + (wx >> 3)]} else {0};
+            let ty = if attrs & 0x40 != 0 {7 - y} else {y};
+            let tx = if attrs & 0x20 != 0 {7 - wx % 8} else {wx % 8};
+            let addr = self.bg_tile_addr(tilei) + ty * 2;
+            let colori = self.tile_row(attrs, addr)[tx];
+            let bgp = if self.is_cgb {self.cgb_pal.bg[(attrs & 7) as usize]} else {self.pal.bg};
+
+            // Sprites behind the background check this, the window counts
+            // as background
+            scanline[i] = if attrs & 0x80 != 0 && colori != 0 {4 | colori} else {colori};
+            let index = self.color_index(self.bgp, colori);
+            set_pixel_index(&mut self.image_data, &mut self.indices, (self.ly as usize * WIDTH + i) * 4,
+                colori as usize, index, &bgp);
+
+            if self.tile_sources.is_some() {
+                let hash = self.tile_hash_at(addr - ty * 2, self.bgp);
+                let sources = self.tile_sources.as_mut().unwrap();
+                sources[self.ly as usize * WIDTH + i] =
+                    Some(TileSource { hash: hash, x: tx as u8, y: ty as u8 });
+            }
+        }
+        self.win_line += 1;
     }
 
     fn render_sprites(&mut self, scanline: &mut [u8; WIDTH]) {
         let line = self.ly as i32;
         let ysize = if self.objsize {16} else {8};
 
-        // All sprits are located in OAM
-        // There are 40 sprites in total, each is 4 bytes wide
-        for sprite in self.oam.chunks(4) {
+        // Sprites are drawn from highest to lowest priority, as the OAM scan
+        // sorted them. A pixel belongs to the first sprite with a visible
+        // color there, even if that sprite ends up behind the background.
+        let mut taken = [false; WIDTH];
+        for n in 0..self.line_objs.len() {
+            let i = self.line_objs[n];
+            let sprite = &self.oam[i * OAM_ENTRY_SIZE..(i + 1) * OAM_ENTRY_SIZE];
             let mut yoff = (sprite[0] as i32) - 16;
             let xoff = (sprite[1] as i32) - 8;
             let mut tile = sprite[2] as usize;
@@ -632,54 +1535,309 @@ impl Gpu {
             // different bank. Otherwise, we just use the tile index as a raw
             // index.
             // bit4 is the palette number. 0 = obp0, 1 = obp1
-           let pal = if flags & 0x10 != 0 {self.pal.obp1} else {self.pal.obp0};
-           let tiled = self.tiles.data[tile as usize];
-
+            // CGB: bit0-2 is the palette number, bit3 the VRAM bank
+           let pal = if self.is_cgb {
+               self.cgb_pal.obj[(flags & 7) as usize]
+           } else if flags & 0x10 != 0 {self.pal.obp1} else {self.pal.obp0};
+           let hash = match self.tile_sources {
+               Some(_) => self.tile_hash(tile, if flags & 0x10 != 0 {self.obp1} else {self.obp0}),
+               None => 0,
+           };
 
             // bit6 is the vertical flip bit
-            let row = if flags & 0x40 != 0 {
-                tiled[(7 - (line - yoff)) as usize]
+            let ty = if flags & 0x40 != 0 {7 - (line - yoff)} else {line - yoff};
+            let row = if self.is_cgb {
+                self.tile_row(flags, tile * 16 + ty as usize * 2)
             } else {
-                tiled[(line - yoff) as usize]
+                self.tiles.data[tile as usize][ty as usize]
             };
 
             for x in 0..8 {
                 coff += 4;
 
                 // If these pixels are off screen, don't bother drawing
-                // anything. Also, if the background tile at this pixel has
-                // priority, don't render this sprite at all.
-                if xoff + x < 0 || xoff + x >= WIDTH as i32 ||
-                   scanline[(x + xoff) as usize] > 3 {
+                // anything
+                if xoff + x < 0 || xoff + x >= WIDTH as i32 {
                     continue
                 }
                 // bit5 is the horizontal flip flag
-                let colori = row[if flags & 0x20 != 0 {7-x} else {x} as usize];
+                let tx = if flags & 0x20 != 0 {7-x} else {x};
+                let colori = row[tx as usize];
 
                 // A color index of 0 for sprites means transparent
                 if colori == 0 { continue }
 
-                // bit7 0=OBJ Above BG, 1=OBJ Behind BG color 1-3. So if this
-                // sprite has this flag set and the data at this location
-                // already contains data (nonzero), then don't render this
-                // sprite
-                if flags & 0x80 != 0 && scanline[(xoff + x) as usize] != 0 {
-                    continue
+                // A sprite with more priority already has this pixel
+                if taken[(xoff + x) as usize] { continue }
+                taken[(xoff + x) as usize] = true;
+
+                // On the CGB, LCDC bit 0 clear puts every sprite over the
+                // background whatever the priority bits say. On the DMG the
+                // background isn't drawn then, so it's all color 0 anyway
+                if self.bgon {
+                    // If the background tile at this pixel has priority, don't
+                    // render this sprite at all
+                    if scanline[(x + xoff) as usize] > 3 { continue }
+
+                    // bit7 0=OBJ Above BG, 1=OBJ Behind BG color 1-3. So if
+                    // this sprite has this flag set and the data at this
+                    // location already contains data (nonzero), then don't
+                    // render this sprite
+                    if flags & 0x80 != 0 && scanline[(xoff + x) as usize] != 0 {
+                        continue
+                    }
                 }
 
-                let color = pal[colori as usize];
-
-                let palette = if flags & 0x10 != 0 {self.pal.obp0} else {self.pal.obp1};
-                set_pixel_index(&mut self.image_data, coff as usize - 4, colori as usize, &palette);
+                let index = self.color_index(if flags & 0x10 != 0 {self.obp1} else {self.obp0}, colori);
+                set_pixel_index(&mut self.image_data, &mut self.indices, coff as usize - 4, colori as usize, index, &pal);
+                if let Some(ref mut sources) = self.tile_sources {
+                    sources[(coff as usize - 4) / 4] =
+                        Some(TileSource { hash: hash, x: tx as u8, y: ty as u8 });
+                }
             }
         }
     }
 
+    // Mode 2, done at its end: pick the sprites on this line and sort them
+    // by priority once for mode 3 and the renderer. The CGB goes by OAM
+    // order, the DMG (and OPRI = 1) favours the lowest X coordinate, then
+    // OAM order
+    fn oam_scan(&mut self) {
+        let mut objs = self.line_sprites(self.ly as i32, self.sprite_height());
+        if self.opri & 1 != 0 {
+            let oam = &self.oam;
+            objs.sort_by_key(|&i| oam[i * OAM_ENTRY_SIZE + 1]);
+        }
+        self.line_objs = objs;
+    }
+
+    // The sprites on a line, picked during the OAM scan. Only the first
+    // MAX_LINE_SPRITES in OAM are drawn, whether they're on screen horizontally
+    // or not
+    pub fn line_sprites(&self, line: i32, ysize: i32) -> Vec<usize> {
+        (0..OBJ_COUNT).filter(|&i| {
+            let yoff = self.oam[i * OAM_ENTRY_SIZE] as i32 - 16;
+            yoff <= line && line < yoff + ysize
+        }).take(MAX_LINE_SPRITES).collect()
+    }
+
+    // A row of a tile straight from VRAM, at the offset of its first byte.
+    // Bit 3 of the CGB attributes picks the bank
+    pub fn tile_row(&self, attrs: u8, addr: usize) -> [u8; 8] {
+        let bank = if attrs & 0x08 != 0 {&self.vrambank1} else {&self.vrambank};
+        let (lsb, msb) = (bank[addr], bank[addr + 1]);
+        let mut row = [0; 8];
+        for (x, colori) in row.iter_mut().enumerate() {
+            let bit = 7 - x;
+            *colori = (msb >> bit & 1) << 1 | lsb >> bit & 1;
+        }
+        row
+    }
+
+    // What frame_indices() has for a color drawn through a DMG palette
+    // register
+    fn color_index(&self, reg: u8, colori: u8) -> u8 {
+        if self.is_cgb {colori} else {reg >> (colori * 2) & 3}
+    }
+
+    // Identifies a tile and the palette it's drawn with in HD packs: FNV-1a
+    // of its 16 bytes in VRAM followed by the palette register
+    pub fn tile_hash(&self, tile: usize, pal: u8) -> u32 {
+        self.tile_hash_at((tile % NUM_TILES) * 16, pal)
+    }
+
+    // Same, for the tile at an offset in VRAM
+    fn tile_hash_at(&self, start: usize, pal: u8) -> u32 {
+        fnv1a(fnv1a(FNV_OFFSET, &self.vrambank[start..start + 16]), &[pal])
+    }
+
+    // Fill the screen with the lightest color, what the LCD shows while off
+    fn blank_screen(&mut self) {
+        let color = if self.is_cgb {[255; 4]} else {self.base_pal.bg[0]};
+        for p in self.image_data.chunks_mut(4) {
+            p.copy_from_slice(&color);
+        }
+        *self.indices = [0; WIDTH * HEIGHT];
+        self.finished_indices.copy_from_slice(&self.indices[..]);
+        self.frames = self.frames.wrapping_add(1);
+        *self.drawn_lines = [None; HEIGHT];
+        // Nothing's drawn from tiles on a blank screen
+        if let Some(ref mut finished) = self.finished_sources {
+            *finished = vec![None; WIDTH * HEIGHT];
+        }
+        // Shows up right away, there's no VBlank with the LCD off
+        match self.sgb {
+            Some(ref sgb) => sgb.colorize(&self.indices, &mut self.finished),
+            None => self.finished.copy_from_slice(&self.image_data[..]),
+        }
+        // Not faded out, the screen just goes blank
+        if let Some(ref mut blend) = self.blend {
+            blend.prev = *self.image_data;
+            blend.shown = *self.image_data;
+        }
+    }
+
+    // Keep track of the tile behind every pixel from now on
+    pub fn track_tile_sources(&mut self) {
+        self.tile_sources = Some(vec![None; WIDTH * HEIGHT]);
+        self.finished_sources = Some(vec![None; WIDTH * HEIGHT]);
+        // Lines drawn so far didn't keep them
+        *self.drawn_lines = [None; HEIGHT];
+    }
+
+    // Leave KEY0/OPRI the way the CGB boot ROM would. DMG games run in
+    // compatibility mode, which also keeps the DMG sprite priority and
+    // monochrome palettes
+    pub fn init_cgb_mode(&mut self, dmg_compat: bool) {
+        self.is_cgb_hw = true;
+        self.is_cgb = !dmg_compat;
+        self.key0 = if dmg_compat {0x04} else {0x80};
+        self.opri = if dmg_compat {1} else {0};
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    // A CGB running a DMG game
+    pub fn dmg_compat(&self) -> bool {
+        self.is_cgb_hw && self.key0 & 0x04 != 0
+    }
+
+    // Colors the background shades map to
+    pub fn base_bg_palette(&self) -> Palette {
+        self.base_pal.bg
+    }
+
+    // Colors a background tile is drawn with, through BGP or the CGB
+    // palette in its attributes
+    pub fn bg_tile_palette(&self, attrs: u8) -> Palette {
+        if self.is_cgb {self.cgb_pal.bg[(attrs & 7) as usize]} else {self.pal.bg}
+    }
+
+    // Colors a sprite is drawn with, through OBP0/OBP1 or the CGB palette
+    // in its flags
+    pub fn obj_palette(&self, flags: u8) -> Palette {
+        if self.is_cgb {
+            self.cgb_pal.obj[(flags & 7) as usize]
+        } else if flags & 0x10 != 0 {self.pal.obp1} else {self.pal.obp0}
+    }
+
+    // BGP, OBP0 and OBP1 with their register values and the colors they
+    // compile to
+    pub fn dmg_palettes(&self) -> [(&'static str, u8, Palette); 3] {
+        [("BGP", self.bgp, self.pal.bg), ("OBP0", self.obp0, self.pal.obp0), ("OBP1", self.obp1, self.pal.obp1)]
+    }
+
+    // CGB background or sprite palette RAM, and the colors compiled from it
+    pub fn cgb_palettes(&self, obj: bool) -> (&[u8; 64], &[Palette; 8]) {
+        if obj {
+            (&self.cgb_pal.obj_ram, &self.cgb_pal.obj)
+        } else {
+            (&self.cgb_pal.bg_ram, &self.cgb_pal.bg)
+        }
+    }
+
+    // 8 or 16
+    pub fn sprite_height(&self) -> i32 {
+        if self.objsize {16} else {8}
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    // 0-3, as in STAT
+    pub fn mode(&self) -> u8 {
+        self.mode as u8
+    }
+
+    // LY as the CPU and the LYC compare see it. Line 153 only reads 153 for
+    // its first 4 dots, then 0 until line 0 actually starts, so LYC = 0
+    // matches (and interrupts) a line early
+    fn ly_register(&self) -> u8 {
+        if self.ly == 153 && self.clock >= 4 {0} else {self.ly}
+    }
+
+    // SCX, SCY
+    pub fn scroll(&self) -> (u8, u8) {
+        (self.scx, self.scy)
+    }
+
+    // WX, WY and whether the window is shown
+    pub fn window(&self) -> (u8, u8, bool) {
+        (self.wx, self.wy, (self.bgon || self.is_cgb) && self.winon)
+    }
+
+    // Which of `palettes` every layer is using, None after a colorization
+    pub fn palette_index(&self) -> Option<usize> {
+        self.palette_sel
+    }
+
+    pub fn palette_name(&self) -> Option<&str> {
+        self.palette_sel.map(|i| &self.palettes[i].0[..])
+    }
+
+    // Switch every layer to the next palette in `palettes`, returns its name
+    pub fn cycle_palette(&mut self) -> &str {
+        let next = match self.palette_sel {
+            Some(i) => (i + 1) % self.palettes.len(),
+            None => 0,
+        };
+        let pal = self.palettes[next].1;
+        self.set_base_palettes(pal, pal, pal);
+        self.palette_sel = Some(next);
+        &self.palettes[next].0
+    }
+
+    // Switch every layer to one of `palettes` by name
+    pub fn select_palette(&mut self, name: &str) -> Result<(), String> {
+        let name = name.to_lowercase();
+        let i = try!(self.palettes.iter().position(|&(ref n, _)| *n == name)
+            .ok_or(format!("No palette named \"{}\"", name)));
+        let pal = self.palettes[i].1;
+        self.set_base_palettes(pal, pal, pal);
+        self.palette_sel = Some(i);
+        Ok(())
+    }
+
+    // SGB games are colored by the palettes they send, see sgb.rs. Until
+    // they do it's the usual DMG shades
+    pub fn init_sgb_mode(&mut self) {
+        self.is_sgb = true;
+        self.sgb = Some(Box::new(SgbColors::new(self.base_pal.bg)));
+    }
+
+    // Replace the colors the DMG shades map to, one palette per layer
+    pub fn set_base_palettes(&mut self, bg: Palette, obp0: Palette, obp1: Palette) {
+        self.palette_sel = None;
+        self.base_pal.bg = bg;
+        self.base_pal.obp0 = obp0;
+        self.base_pal.obp1 = obp1;
+
+        update_pal(&mut self.pal.bg, &self.base_pal.bg, self.bgp);
+        update_pal(&mut self.pal.obp0, &self.base_pal.obp0, self.obp0);
+        update_pal(&mut self.pal.obp1, &self.base_pal.obp1, self.obp1);
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    // VRAM (bank 1 too on the CGB) and OAM byte for byte, to
+    // <prefix>_vram0.bin, <prefix>_vram1.bin and <prefix>_oam.bin for other
+    // tools to look at. Returns the files written
+    pub fn dump_memory(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut dumps: Vec<(String, &[u8])> = vec![(format!("{}_vram0.bin", prefix), &self.vrambank[..])];
+        if self.is_cgb {
+            dumps.push((format!("{}_vram1.bin", prefix), &self.vrambank1[..]));
+        }
+        dumps.push((format!("{}_oam.bin", prefix), &self.oam[..]));
+        for &(ref path, data) in dumps.iter() {
+            try!(File::create(path).and_then(|mut f| f.write_all(data)));
+        }
+        Ok(dumps.into_iter().map(|(path, _)| path).collect())
+    }
+
     pub fn dump_tiles(&self) {
         use image::{ImageBuffer, RgbaImage, Rgba};
 
         static TILE_SIZE_X: u32 = 16 * 8;
-        static TILE_SIZE_Y: u32 = 12 * 8;
+        static TILE_SIZE_Y: u32 = 24 * 8;
 
         let mut img: RgbaImage = ImageBuffer::new(TILE_SIZE_X, TILE_SIZE_Y);
 
@@ -718,21 +1876,841 @@ fn set_pixel(image_data: &mut ScreenData, x: usize, y: usize, r: u8, g: u8, b: u
 }
 
 #[inline]
-fn set_pixel_index(image_data: &mut ScreenData, first_byte: usize, colori: usize, pal: &Palette) {
+fn set_pixel_index(image_data: &mut ScreenData, indices: &mut IndexData, first_byte: usize, colori: usize,
+                   index: u8, pal: &Palette) {
+    indices[first_byte / 4] = index;
     image_data[first_byte] = pal[colori][0];    // R
     image_data[first_byte+1] = pal[colori][1];  // G
     image_data[first_byte+2] = pal[colori][2];  // B
-    image_data[first_byte+3] = pal[colori][0];  // A
+    image_data[first_byte+3] = pal[colori][3];  // A
 }
 
 // Update the cached palettes for BG/OBP0/OBP1. This should be called whenever
 // these registers are modified
-fn update_pal(pal: &mut Palette, val: u8) {
+fn update_pal(pal: &mut Palette, base: &Palette, val: u8) {
     // These registers are indices into the actual palette. See
     // http://problemkaputt.de/pandocs.htm#lcdmonochromepalettes
-    pal[0] = PALETTE[((val >> 0) & 0x3) as usize];
-    pal[1] = PALETTE[((val >> 2) & 0x3) as usize];
-    pal[2] = PALETTE[((val >> 4) & 0x3) as usize];
-    pal[3] = PALETTE[((val >> 6) & 0x3) as usize];
+    pal[0] = base[((val >> 0) & 0x3) as usize];
+    pal[1] = base[((val >> 2) & 0x3) as usize];
+    pal[2] = base[((val >> 4) & 0x3) as usize];
+    pal[3] = base[((val >> 6) & 0x3) as usize];
     info!("BG Color: {:?} val {:02X}", pal, val);
-}
\ No newline at end of file
+}
+
+impl FrameBlend {
+    // A frame is done, mix it into what's shown
+    fn push(&mut self, frame: &ScreenData) {
+        match self.mode {
+            BlendMode::Average => {
+                for i in 0..frame.len() {
+                    self.shown[i] = ((frame[i] as u16 + self.prev[i] as u16 + 1) / 2) as u8;
+                }
+            }
+            BlendMode::Ghosting => {
+                // Exponential decay towards the new shade, rounded away from
+                // the old one so it gets all the way there
+                for i in 0..frame.len() {
+                    let diff = frame[i] as i32 - self.shown[i] as i32;
+                    let step = (diff * (256 - GHOSTING_KEEP) + diff.signum() * 255) / 256;
+                    self.shown[i] = (self.shown[i] as i32 + step) as u8;
+                }
+            }
+        }
+        self.prev.copy_from_slice(frame);
+    }
+}
+
+impl CgbPalettes {
+    // Write through BCPD
+    fn write_bg(&mut self, val: u8) {
+        let i = (self.bcps & 0x3f) as usize;
+        self.bg_ram[i] = val;
+        self.bg[i / 8][i % 8 / 2] = ram_color(&self.bg_ram, i);
+        self.bcps = next_index(self.bcps);
+    }
+
+    // Write through OCPD
+    fn write_obj(&mut self, val: u8) {
+        let i = (self.ocps & 0x3f) as usize;
+        self.obj_ram[i] = val;
+        self.obj[i / 8][i % 8 / 2] = ram_color(&self.obj_ram, i);
+        self.ocps = next_index(self.ocps);
+    }
+}
+
+// The color a byte of palette RAM is part of
+fn ram_color(ram: &[u8; 64], i: usize) -> Color {
+    let lo = i & !1;
+    rgb555(ram[lo] as u16 | (ram[lo + 1] as u16) << 8)
+}
+
+// BCPS/OCPS after a write to the data register, only moves on with bit 7 set
+fn next_index(ps: u8) -> u8 {
+    if ps & 0x80 != 0 { 0x80 | (ps + 1) & 0x3f } else { ps }
+}
+
+// Convert a CGB color (xBBBBBGGGGGRRRRR) to RGBA
+pub fn rgb555(c: u16) -> Color {
+    let scale = |v: u16| ((v & 0x1F) * 255 / 0x1F) as u8;
+    [scale(c), scale(c >> 5), scale(c >> 10), 255]
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod gpu_tests {
+    use super::*;
+
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * WIDTH + x) * 4;
+        [gpu.image_data[i], gpu.image_data[i + 1], gpu.image_data[i + 2], gpu.image_data[i + 3]]
+    }
+
+    #[test]
+    fn window() {
+        let mut gpu = Gpu::new();
+        // LCD, window (map at 0x9C00), tiles at 0x8000 and BG on
+        gpu.wb(0xFF40, 0xF1);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF4A, 1);
+        gpu.wb(0xFF4B, 7 + 100);
+        // Tile 1 is all color 3, the window map is full of it
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in 0x9C00..0xA000 {
+            gpu.wb_vram(addr, 1);
+        }
+        let black = gpu.base_bg_palette()[3];
+        let white = gpu.base_bg_palette()[0];
+
+        // Above WY there's only background
+        gpu.ly = 0;
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 120, 0), white);
+
+        gpu.ly = 1;
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 99, 1), white);
+        assert_eq!(pixel(&gpu, 100, 1), black);
+        assert_eq!(gpu.win_line, 1);
+
+        // Signed tile indices count from 0x9000
+        gpu.wb(0xFF40, 0xE1);
+        assert_eq!(gpu.bg_tile_addr(0xFF), 0x0FF0);
+        assert_eq!(gpu.bg_tile_addr(0x01), 0x1010);
+    }
+
+    #[test]
+    fn signed_tiles() {
+        let mut gpu = Gpu::new();
+        // LCD and BG on, signed tile indices, map at 0x9800
+        gpu.wb(0xFF40, 0x81);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.skip_frame = false;
+        // Tile 0x80 is at 0x8800 (all color 3), tile 0x7F at 0x97F0 (color
+        // 1), the last of the 384
+        for addr in 0x8800..0x8810 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in (0x97F0..0x9800).filter(|a| a % 2 == 0) {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        gpu.wb_vram(0x9800, 0x80);
+        gpu.wb_vram(0x9801, 0x7F);
+        gpu.wb_vram(0x9802, 0x00);
+
+        gpu.ly = 0;
+        gpu.render_line();
+        let pal = gpu.base_bg_palette();
+        assert_eq!(pixel(&gpu, 0, 0), pal[3]);
+        assert_eq!(pixel(&gpu, 8, 0), pal[1]);
+        assert_eq!(pixel(&gpu, 16, 0), pal[0]);
+
+        // Unsigned, tile 0x80 is at 0x8800 too and 0x7F at 0x87F0
+        gpu.wb(0xFF40, 0x91);
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 0, 0), pal[3]);
+        assert_eq!(pixel(&gpu, 8, 0), pal[0]);
+    }
+
+    #[test]
+    fn memory_dumps() {
+        use std::env;
+        use std::fs;
+
+        let dir = env::temp_dir().join("rustboy_gpu_tests");
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("dump").to_string_lossy().into_owned();
+
+        let mut gpu = Gpu::new();
+        gpu.vrambank[0x1FFF] = 0xAB;
+        gpu.oam[0] = 0x10;
+        let files = gpu.dump_memory(&prefix).unwrap();
+        assert_eq!(files, vec![format!("{}_vram0.bin", prefix), format!("{}_oam.bin", prefix)]);
+        let vram = fs::read(&files[0]).unwrap();
+        assert_eq!((vram.len(), vram[0x1FFF]), (0x2000, 0xAB));
+        assert_eq!(fs::read(&files[1]).unwrap()[..2], [0x10, 0]);
+
+        // Both banks on the CGB
+        gpu.init_cgb_mode(false);
+        assert_eq!(gpu.dump_memory(&prefix).unwrap().len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn palette_switching() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF47, 0xE4);
+        assert_eq!(gpu.pal.bg[3], PALETTE_GREEN[3]);
+
+        assert_eq!(gpu.cycle_palette(), "bw");
+        // The compiled palettes change right away
+        assert_eq!(gpu.pal.bg[3], PALETTE_BW[3]);
+        assert_eq!(gpu.pal.obp1[0], PALETTE_BW[0]);
+        assert_eq!(gpu.cycle_palette(), "puke-green");
+        assert_eq!(gpu.cycle_palette(), "pocket");
+        assert_eq!(gpu.cycle_palette(), "light");
+        assert_eq!(gpu.cycle_palette(), "green");
+
+        // After a colorization, cycling starts over
+        gpu.set_base_palettes(PALETTE_BW, PALETTE_BW, PALETTE_GREEN);
+        assert_eq!(gpu.cycle_palette(), "green");
+
+        gpu.palettes.push((String::from("mine"), PALETTE_BW));
+        gpu.select_palette("Mine").unwrap();
+        assert_eq!(gpu.palette_name(), Some("mine"));
+        assert_eq!(gpu.cycle_palette(), "green");
+        assert!(gpu.select_palette("nope").is_err());
+    }
+
+    #[test]
+    fn cgb() {
+        let mut gpu = Gpu::new();
+        gpu.init_cgb_mode(false);
+        // LCD, tiles at 0x8000 and BG on
+        gpu.wb(0xFF40, 0x91);
+
+        // BG palette 2, color 1 pure red. Auto increment from 0x12
+        gpu.wb(0xFF68, 0x80 | 0x12);
+        gpu.wb(0xFF69, 0x1F);
+        gpu.wb(0xFF69, 0x00);
+        assert_eq!(gpu.rb(0xFF68), 0xC0 | 0x14);
+        gpu.wb(0xFF68, 0x13);
+        assert_eq!(gpu.rb(0xFF69), 0x00);
+
+        // Tile 1 in bank 1 has color 1 in its leftmost column only
+        gpu.wb(0xFF4F, 1);
+        for row in 0..8 {
+            gpu.wb_vram(0x8010 + row * 2, 0x80);
+        }
+        // First tile of the map uses it with palette 2, flipped horizontally
+        gpu.wb_vram(0x9800, 0x08 | 0x20 | 2);
+        gpu.wb(0xFF4F, 0);
+        gpu.wb_vram(0x9800, 1);
+        assert_eq!(gpu.rb_vram(0x9800), 1);
+        assert_eq!(gpu.rb_vram(0x8010), 0);
+
+        gpu.ly = 0;
+        gpu.render_line();
+        // Palette 2 color 0 is still white
+        assert_eq!(pixel(&gpu, 0, 0), [255, 255, 255, 255]);
+        assert_eq!(pixel(&gpu, 7, 0), [255, 0, 0, 255]);
+
+        // DMG games don't see any of it
+        let mut gpu = Gpu::new();
+        gpu.init_cgb_mode(true);
+        gpu.wb(0xFF4F, 1);
+        assert_eq!(gpu.rb(0xFF4F), 0xFF);
+        assert_eq!(gpu.rb(0xFF68), 0xFF);
+    }
+
+    #[test]
+    fn cgb_bg_priority() {
+        let mut gpu = Gpu::new();
+        gpu.init_cgb_mode(false);
+        // LCD, tiles at 0x8000, sprites and BG on
+        gpu.wb(0xFF40, 0x93);
+        // BG palette 0, color 1 pure red
+        gpu.wb(0xFF68, 0x80 | 0x02);
+        gpu.wb(0xFF69, 0x1F);
+        gpu.wb(0xFF69, 0x00);
+        // Tile 1 is color 1 on the left half, color 0 on the right
+        for row in 0..8 {
+            gpu.wb_vram(0x8010 + row * 2, 0xF0);
+        }
+        // The first two map tiles are tile 1, the first one with BG priority
+        gpu.wb(0xFF4F, 1);
+        gpu.wb_vram(0x9800, 0x80);
+        gpu.wb(0xFF4F, 0);
+        gpu.wb_vram(0x9800, 1);
+        gpu.wb_vram(0x9801, 1);
+        // A sprite over the first one, all color 3
+        for addr in 0x8020..0x8030 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        gpu.oam[..4].copy_from_slice(&[16, 8, 2, 0]);
+        let obj = gpu.cgb_pal.obj[0][3];
+        let red = [255, 0, 0, 255];
+
+        gpu.ly = 0;
+        gpu.oam_scan();
+        gpu.render_line();
+        // BG priority only covers the colors other than 0
+        assert_eq!(pixel(&gpu, 0, 0), red);
+        assert_eq!(pixel(&gpu, 4, 0), obj);
+
+        // LCDC bit 0 off puts the sprite over everything, the background is
+        // still drawn
+        gpu.wb(0xFF40, 0x92);
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 0, 0), obj);
+        assert_eq!(pixel(&gpu, 8, 0), red);
+    }
+
+    #[test]
+    fn sprites_per_line() {
+        let mut gpu = Gpu::new();
+        // LCD and sprites on
+        gpu.wb(0xFF40, 0x82);
+        gpu.wb(0xFF48, 0xE4);
+        // Tile 1 is all color 3
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        // 12 sprites side by side on line 0, the first one off screen
+        for i in 0..12 {
+            gpu.oam[i * 4] = 16;
+            gpu.oam[i * 4 + 1] = if i == 0 {0} else {8 + i as u8 * 8};
+            gpu.oam[i * 4 + 2] = 1;
+        }
+        let black = gpu.pal.obp0[3];
+
+        gpu.ly = 0;
+        gpu.oam_scan();
+        gpu.render_line();
+        assert_eq!(gpu.line_sprites(0, 8), (0..10).collect::<Vec<_>>());
+        assert_eq!(pixel(&gpu, 72, 0), black);
+        assert!(pixel(&gpu, 80, 0) != black);
+        assert!(pixel(&gpu, 88, 0) != black);
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let mut gpu = Gpu::new();
+        // LCD, tiles at 0x8000, sprites and BG on
+        gpu.wb(0xFF40, 0x93);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0xFF49, 0x1B);
+        // Tile 1 is all color 3, the background is made of it
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            gpu.wb_vram(addr, 1);
+        }
+        // Sprite 0 (OBP1) overlaps sprite 1 (OBP0) from the right, sprite 2
+        // (OBP1) is left of sprite 1 and behind the background
+        let sprites = [[16, 12, 1, 0x10], [16, 8, 1, 0x00], [16, 5, 1, 0x90]];
+        for (i, sprite) in sprites.iter().enumerate() {
+            gpu.oam[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        let obp0 = gpu.pal.obp0[3];
+        let bg = gpu.pal.bg[3];
+
+        gpu.ly = 0;
+        gpu.oam_scan();
+        gpu.render_line();
+        // The smaller X wins over OAM order
+        assert_eq!(pixel(&gpu, 5, 0), obp0);
+        assert_eq!(pixel(&gpu, 8, 0), gpu.pal.obp1[3]);
+        // Sprite 2 wins where it's drawn, but hides behind the background
+        // instead of letting sprite 1 through
+        assert_eq!(pixel(&gpu, 1, 0), bg);
+
+        // The CGB goes by OAM order alone
+        gpu.opri = 0;
+        gpu.oam_scan();
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 5, 0), gpu.pal.obp1[3]);
+    }
+
+    #[test]
+    fn key0_opri() {
+        // Not there on the DMG
+        let mut gpu = Gpu::new();
+        assert_eq!(gpu.rb(0xFF4C), 0xFF);
+        assert_eq!(gpu.rb(0xFF6C), 0xFF);
+        assert!(!gpu.dmg_compat());
+
+        // CGB games go by OAM order, and the boot ROM locked both registers
+        gpu.init_cgb_mode(false);
+        assert!(!gpu.dmg_compat());
+        assert_eq!(gpu.rb(0xFF4C), 0xFF);
+        assert_eq!(gpu.rb(0xFF6C), 0xFE);
+        gpu.wb(0xFF6C, 0x01);
+        gpu.wb(0xFF4C, 0x04);
+        assert_eq!(gpu.rb(0xFF6C), 0xFE);
+        assert!(!gpu.dmg_compat());
+
+        // DMG games keep the X priority, and OPRI is hidden
+        let mut gpu = Gpu::new();
+        gpu.init_cgb_mode(true);
+        assert!(gpu.dmg_compat());
+        assert_eq!(gpu.opri, 1);
+        assert_eq!(gpu.rb(0xFF6C), 0xFF);
+    }
+
+    #[test]
+    fn pixel_fifo() {
+        let mut gpu = Gpu::new();
+        gpu.use_pixel_fifo();
+        // LCD, tiles at 0x8000 and BG on
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF47, 0xE4);
+        // Tile 1 is all color 3, the background is made of it
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            gpu.wb_vram(addr, 1);
+        }
+        let mut if_ = 0;
+        let black = gpu.pal.bg[3];
+        // Show the first frame after turning the LCD on
+        gpu.skip_frame = false;
+
+        // Mode 3 length of line 0, the palette changes half way through
+        let mut mode3 = |gpu: &mut Gpu| {
+            gpu.ly = 153;
+            gpu.clock = 455;
+            gpu.mode = Mode::VBlank;
+            gpu.step(82, &mut if_);
+            assert_eq!(gpu.mode, Mode::RdVram);
+            gpu.step(90, &mut if_);
+            gpu.wb(0xFF47, 0x1B);
+            let mut dots = 90;
+            while gpu.mode == Mode::RdVram {
+                gpu.step(1, &mut if_);
+                dots += 1;
+            }
+            dots
+        };
+
+        let dots = mode3(&mut gpu);
+        assert_eq!(pixel(&gpu, 0, 0), black);
+        assert!(pixel(&gpu, 159, 0) != black);
+
+        // Fine scrolling makes mode 3 longer
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF43, 3);
+        assert_eq!(mode3(&mut gpu), dots + 3);
+    }
+
+    #[test]
+    fn frame_tile_sources() {
+        let mut gpu = Gpu::new();
+        gpu.track_tile_sources();
+        // LCD, tiles at 0x8000 and BG on, the background is made of tile 1
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF47, 0xE4);
+        for addr in 0x8010..0x8020 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            gpu.wb_vram(addr, 1);
+        }
+        gpu.skip_frame = false;
+        let mut if_ = 0;
+        while gpu.mode != Mode::VBlank {
+            gpu.step(4, &mut if_);
+        }
+        let first = gpu.frame_tile_sources().unwrap()[0];
+        assert!(first.is_some());
+
+        // The next frame is drawn from tile 2, the finished one keeps its
+        // sources until VBlank
+        for addr in 0x8020..0x8030 {
+            gpu.wb_vram(addr, 0x0F);
+        }
+        for addr in 0x9800..0x9C00 {
+            gpu.wb_vram(addr, 2);
+        }
+        while gpu.ly != 1 {
+            gpu.step(4, &mut if_);
+        }
+        assert!(gpu.tile_sources.as_ref().unwrap()[0] != first);
+        assert_eq!(gpu.frame_tile_sources().unwrap()[0], first);
+    }
+
+    #[test]
+    fn mode3_length() {
+        let mut gpu = Gpu::new();
+        // LCD, sprites and BG on
+        gpu.wb(0xFF40, 0x83);
+        let mut if_ = 0;
+        let mut mode3 = |gpu: &mut Gpu| {
+            gpu.ly = 0;
+            gpu.clock = 0;
+            gpu.mode = Mode::RdOam;
+            let mut dots = 0;
+            gpu.step(81, &mut if_);
+            while gpu.mode == Mode::RdVram {
+                gpu.step(1, &mut if_);
+                dots += 1;
+            }
+            dots
+        };
+        assert_eq!(mode3(&mut gpu), 172);
+
+        gpu.wb(0xFF43, 2);
+        assert_eq!(mode3(&mut gpu), 174);
+
+        // A sprite on the line, X + SCX lands 2 pixels into a tile
+        gpu.oam[0] = 16;
+        gpu.oam[1] = 8;
+        assert_eq!(mode3(&mut gpu), 174 + 9);
+        // HBlank gets what's left of the line
+        assert_eq!(gpu.mode, Mode::HBlank);
+    }
+
+    #[test]
+    fn stat_line() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x80);
+        let mut if_ = 0;
+        gpu.ly = 0;
+        gpu.clock = 0;
+        gpu.mode = Mode::RdOam;
+
+        // HBlank and OAM interrupts, LY = LYC on line 1
+        gpu.wb(0xFF41, 0x68);
+        gpu.wb(0xFF45, 1);
+        gpu.step(300, &mut if_);
+        assert_eq!(gpu.mode, Mode::HBlank);
+        assert_eq!(if_ & Interrupt::LCDStat as u8, Interrupt::LCDStat as u8);
+
+        // Line 1 matches LYC right as HBlank ends, then mode 2 starts: the
+        // line stays high the whole time
+        if_ = 0;
+        gpu.step(200, &mut if_);
+        assert_eq!((gpu.ly, gpu.mode), (1, Mode::RdOam));
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0x04);
+        assert_eq!(if_ & Interrupt::LCDStat as u8, 0);
+
+        // LY = LYC holds it through mode 3 into HBlank
+        gpu.step(300, &mut if_);
+        assert_eq!(gpu.mode, Mode::HBlank);
+        assert_eq!(if_ & Interrupt::LCDStat as u8, 0);
+
+        // Without it, mode 3 drops the line and HBlank raises it again
+        for _ in 0..456 / 4 {
+            gpu.step(4, &mut if_);
+        }
+        assert_eq!((gpu.ly, gpu.mode), (2, Mode::HBlank));
+        assert_eq!(if_ & Interrupt::LCDStat as u8, Interrupt::LCDStat as u8);
+    }
+
+    #[test]
+    fn lyc_compare() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x80);
+        let mut if_ = 0;
+        gpu.ly = 152;
+        gpu.clock = 0;
+        gpu.mode = Mode::VBlank;
+
+        // Only the LYC interrupt, every line is compared
+        gpu.wb(0xFF41, 0x40);
+        gpu.wb(0xFF45, 153);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0);
+        gpu.step(456, &mut if_);
+        assert_eq!(gpu.rb(0xFF44), 153);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0x04);
+        assert_eq!(if_ & Interrupt::LCDStat as u8, Interrupt::LCDStat as u8);
+
+        // 4 dots into line 153 LY reads 0, LYC = 153 stops matching
+        if_ = 0;
+        gpu.step(4, &mut if_);
+        assert_eq!((gpu.ly, gpu.rb(0xFF44)), (153, 0));
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0);
+
+        // LYC = 0 matches from there, through line 0, with one interrupt
+        gpu.wb(0xFF45, 0);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0x04);
+        gpu.step(4, &mut if_);
+        assert_eq!(if_ & Interrupt::LCDStat as u8, Interrupt::LCDStat as u8);
+        if_ = 0;
+        gpu.step(448, &mut if_);
+        assert_eq!((gpu.ly, gpu.rb(0xFF44)), (0, 0));
+        assert_eq!(if_ & Interrupt::LCDStat as u8, 0);
+        gpu.step(456, &mut if_);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0);
+
+        // The flag holds its last value with the LCD off
+        gpu.wb(0xFF45, 1);
+        gpu.wb(0xFF40, 0x00);
+        gpu.wb(0xFF45, 0);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn frame_blend() {
+        let mut gpu = Gpu::new();
+        gpu.blend_frames(Some(BlendMode::Average));
+        // A sprite pixel showing every other frame
+        for frame in 0..4 {
+            let shade = if frame % 2 == 0 {0} else {200};
+            gpu.image_data[..4].copy_from_slice(&[shade, shade, shade, 255]);
+            gpu.finish_frame();
+        }
+        assert_eq!(&gpu.screen()[..4], &[100, 100, 100, 255]);
+        // The frame being drawn doesn't show until it's done
+        gpu.image_data[..4].copy_from_slice(&[0, 0, 0, 255]);
+        assert_eq!(&gpu.screen()[..4], &[100, 100, 100, 255]);
+
+        // Without blending it's the last finished frame, still not that one
+        gpu.blend_frames(None);
+        assert_eq!(&gpu.screen()[..4], &[200, 200, 200, 255]);
+        gpu.finish_frame();
+        assert_eq!(&gpu.screen()[..4], &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn ghosting() {
+        let mut gpu = Gpu::new();
+        gpu.image_data[..4].copy_from_slice(&[255, 255, 255, 255]);
+        gpu.finish_frame();
+        gpu.blend_frames(Some(BlendMode::Ghosting));
+        // Going black fades out over a few frames, and gets there
+        gpu.image_data[..4].copy_from_slice(&[0, 0, 0, 255]);
+        let mut shades = Vec::new();
+        for _ in 0..10 {
+            gpu.finish_frame();
+            shades.push(gpu.screen()[0]);
+        }
+        assert_eq!(&shades[..3], &[127, 63, 31]);
+        assert_eq!(shades[9], 0);
+        assert_eq!(gpu.screen()[3], 255);
+    }
+
+    #[test]
+    fn pixel_formats() {
+        let mut gpu = Gpu::new();
+        // LCD, tiles at 0x8000, BG on, BGP backwards
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF47, 0x1B);
+        // The first tile is color 3 on the left half, color 0 on the right
+        for addr in 0x8000..0x8010 {
+            gpu.wb_vram(addr, 0xF0);
+        }
+        gpu.ly = 0;
+        gpu.render_line();
+        gpu.finish_frame();
+
+        // Color 3 is the lightest shade through that BGP
+        assert_eq!(&gpu.frame_indices()[..8], &[0, 0, 0, 0, 3, 3, 3, 3]);
+        let rgba = pixel(&gpu, 4, 0);
+        let rgb565 = (rgba[0] as u16 >> 3) << 11 | (rgba[1] as u16 >> 2) << 5 | rgba[2] as u16 >> 3;
+        assert_eq!(gpu.frame_rgb565()[4], rgb565);
+        assert_eq!(&gpu.frame()[16..20], &rgba);
+        assert_eq!(rgba[3], 255);
+    }
+
+    #[test]
+    fn unchanged_lines() {
+        let mut gpu = Gpu::new();
+        // LCD, BG and window on, the window from line 0
+        gpu.wb(0xFF40, 0xB1);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF4B, 7);
+        gpu.ly = 0;
+        gpu.render_line();
+        let drawn = pixel(&gpu, 0, 0);
+        assert_eq!(gpu.win_line, 1);
+
+        // Same inputs, the line is left alone but the window moves on
+        gpu.image_data[..4].copy_from_slice(&[1, 2, 3, 4]);
+        gpu.win_line = 0;
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 0, 0), [1, 2, 3, 4]);
+        assert_eq!(gpu.win_line, 1);
+
+        // A VRAM write draws it again
+        gpu.win_line = 0;
+        gpu.wb_vram(0x9C00, 0);
+        gpu.render_line();
+        assert_eq!(pixel(&gpu, 0, 0), drawn);
+    }
+
+    #[test]
+    fn mid_line_writes() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF47, 0xE4);
+        // Drawn even though the LCD was just turned on
+        gpu.skip_frame = false;
+        let mut if_ = 0;
+        gpu.ly = 0;
+        gpu.clock = 0;
+        gpu.mode = Mode::RdOam;
+        gpu.step(81, &mut if_);
+        assert_eq!(gpu.mode, Mode::RdVram);
+
+        // 172 dots of mode 3, pixel 80 comes out 92 dots in
+        gpu.step(91, &mut if_);
+        gpu.wb(0xFF47, 0xE7);
+        while gpu.mode == Mode::RdVram {
+            gpu.step(1, &mut if_);
+        }
+        assert_eq!(gpu.indices[79], 0);
+        assert_eq!(gpu.indices[80], 3);
+        // Not kept, the next frame may not write at the same place
+        assert_eq!(gpu.drawn_lines[0], None);
+    }
+
+    #[test]
+    fn frame_skip() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x91);
+        gpu.frame_skip = Some((1, 2));
+        let mut if_ = 0;
+        // Every other VBlank shows a new frame, once the first one decided
+        // about the next
+        let mut shown = Vec::new();
+        for _ in 0..5 {
+            let before = gpu.frames();
+            for _ in 0..70224 / 4 {
+                gpu.step(4, &mut if_);
+            }
+            shown.push(gpu.frames() != before);
+        }
+        assert_eq!(shown[1..], [true, false, true, false]);
+    }
+
+    #[test]
+    fn lcd_off() {
+        let mut gpu = Gpu::new();
+        // LCD and BG on, tile 0 all color 3
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF47, 0xE4);
+        for addr in 0x8000..0x8010 {
+            gpu.wb_vram(addr, 0xFF);
+        }
+        let mut if_ = 0;
+        for _ in 0..10 {
+            gpu.step(456, &mut if_);
+        }
+        assert_eq!(gpu.ly, 10);
+
+        gpu.wb(0xFF40, 0x11);
+        gpu.step(456, &mut if_);
+        assert_eq!(gpu.rb(0xFF44), 0);
+        assert_eq!(gpu.rb(0xFF41) & 3, 0);
+        assert_eq!(pixel(&gpu, 0, 0), gpu.base_bg_palette()[0]);
+
+        // Nothing shows until the second frame
+        gpu.wb(0xFF40, 0x91);
+        for _ in 0..154 * 456 / 4 {
+            gpu.step(4, &mut if_);
+        }
+        assert_eq!(pixel(&gpu, 0, 0), gpu.base_bg_palette()[0]);
+        for _ in 0..154 * 456 / 4 {
+            gpu.step(4, &mut if_);
+        }
+        assert_eq!(pixel(&gpu, 0, 0), gpu.pal.bg[3]);
+    }
+
+    #[test]
+    fn access_by_mode() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x80);
+        gpu.mode = Mode::HBlank;
+        assert!(!gpu.vram_locked() && !gpu.oam_locked());
+
+        gpu.mode = Mode::RdOam;
+        assert!(!gpu.vram_locked() && gpu.oam_locked());
+
+        gpu.mode = Mode::RdVram;
+        assert!(gpu.vram_locked() && gpu.oam_locked());
+
+        gpu.restrict_access = false;
+        assert!(!gpu.vram_locked() && !gpu.oam_locked());
+
+        // Nothing's locked with the LCD off
+        gpu.restrict_access = true;
+        gpu.wb(0xFF40, 0x00);
+        assert!(!gpu.vram_locked() && !gpu.oam_locked());
+    }
+
+    #[test]
+    fn oam_bug() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF40, 0x80);
+        gpu.mode = Mode::RdOam;
+        for (i, b) in gpu.oam.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let before = gpu.oam;
+
+        // Off by default
+        gpu.clock = 8;
+        gpu.corrupt_oam(0xFE10, OamAccess::Write);
+        assert_eq!(&gpu.oam[..], &before[..]);
+
+        // 8 dots in the PPU is on row 2, mixed with row 1
+        gpu.oam_bug = true;
+        gpu.corrupt_oam(0xFE10, OamAccess::Write);
+        let (a, b, c) = (0x1110u16, 0x0908u16, 0x0D0Cu16);
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        assert_eq!(&gpu.oam[16..18], &[first as u8, (first >> 8) as u8]);
+        assert_eq!(&gpu.oam[18..24], &before[10..16]);
+        assert_eq!(&gpu.oam[..16], &before[..16]);
+        assert_eq!(&gpu.oam[24..], &before[24..]);
+
+        // Reads have their own formula
+        gpu.oam = before;
+        gpu.corrupt_oam(0xFEFF, OamAccess::Read);
+        let first = b | (a & c);
+        assert_eq!(&gpu.oam[16..18], &[first as u8, (first >> 8) as u8]);
+
+        // With an INC/DEC it's the same before row 4
+        gpu.oam = before;
+        gpu.corrupt_oam(0xFE00, OamAccess::ReadIncDec);
+        assert_eq!(&gpu.oam[16..18], &[first as u8, (first >> 8) as u8]);
+        assert_eq!(&gpu.oam[..16], &before[..16]);
+
+        // From there row 4 gets mixed and copied over rows 3 and 5 before
+        // the read corrupts row 5, which doesn't change it anymore
+        gpu.oam = before;
+        gpu.clock = 20;
+        gpu.corrupt_oam(0xFE00, OamAccess::ReadIncDec);
+        let (a, b, c, d) = (0x1918u16, 0x2120u16, 0x2928u16, 0x1D1Cu16);
+        let mixed = (b & (a | c | d)) | (a & c & d);
+        let mut row = [mixed as u8, (mixed >> 8) as u8, 0, 0, 0, 0, 0, 0];
+        row[2..].copy_from_slice(&before[34..40]);
+        assert_eq!(&gpu.oam[24..32], &row[..]);
+        assert_eq!(&gpu.oam[32..40], &row[..]);
+        assert_eq!(&gpu.oam[40..48], &row[..]);
+        assert_eq!(&gpu.oam[..24], &before[..24]);
+        assert_eq!(&gpu.oam[48..], &before[48..]);
+        gpu.clock = 8;
+
+        // Not on row 0, outside OAM, outside mode 2 or on the CGB
+        gpu.oam = before;
+        gpu.clock = 2;
+        gpu.corrupt_oam(0xFE00, OamAccess::Write);
+        gpu.clock = 8;
+        gpu.corrupt_oam(0xFF00, OamAccess::Write);
+        gpu.mode = Mode::HBlank;
+        gpu.corrupt_oam(0xFE00, OamAccess::Write);
+        gpu.mode = Mode::RdOam;
+        gpu.is_cgb = true;
+        gpu.corrupt_oam(0xFE00, OamAccess::Write);
+        assert_eq!(&gpu.oam[..], &before[..]);
+    }
+}
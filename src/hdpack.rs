@@ -0,0 +1,188 @@
+//
+//      HD tile replacement packs
+//
+
+// A pack is a directory of PNGs named after the tile they replace, e.g.
+// 1a2b3c4d.png, see Gpu::tile_hash. Every replacement is the same size, a
+// multiple of 8 pixels square, and that multiple is the scale the frame is
+// composited at. Pixels from tiles without a replacement are just enlarged.
+//
+// The tiles on screen can be dumped into the pack's dump/ directory as a
+// starting point for drawing replacements.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{self, ImageBuffer, RgbaImage, Rgba};
+
+use gpu::{TileSource, ScreenData, WIDTH, HEIGHT};
+
+pub struct HdPack {
+    dir: PathBuf,
+    pub scale: usize,
+    // RGBA, scale * 8 pixels square
+    tiles: HashMap<u32, Vec<u8>>,
+}
+
+impl HdPack {
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<HdPack, String> {
+        let dir = dir.as_ref();
+        let entries = try!(fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e)));
+
+        let mut pack = HdPack { dir: dir.to_path_buf(), scale: 0, tiles: HashMap::new() };
+        for entry in entries {
+            let path = try!(entry.map_err(|e| e.to_string())).path();
+            let hash = match parse_name(&path) {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            let img = try!(image::open(&path).map_err(|e| format!("{}: {}", path.display(), e))).to_rgba();
+            let (w, h) = (img.width() as usize, img.height() as usize);
+            if w != h || w % 8 != 0 || w == 0 {
+                return Err(format!("{}: replacements must be square and a multiple of 8 pixels", path.display()));
+            }
+            if pack.scale == 0 {
+                pack.scale = w / 8;
+            } else if w / 8 != pack.scale {
+                return Err(format!("{}: is {}x{}, the other replacements are {}x{}",
+                    path.display(), w, h, pack.scale * 8, pack.scale * 8));
+            }
+            pack.tiles.insert(hash, img.into_raw());
+        }
+
+        if pack.tiles.is_empty() {
+            // Nothing to replace yet, but tiles can still be dumped
+            pack.scale = 1;
+        }
+        info!("Loaded {} HD tiles at {}x from {}", pack.tiles.len(), pack.scale, dir.display());
+        Ok(pack)
+    }
+
+    pub fn dims(&self) -> [u32; 2] {
+        [(WIDTH * self.scale) as u32, (HEIGHT * self.scale) as u32]
+    }
+
+    // Draw the frame at `scale` times the resolution into `out`. Returns false
+    // (leaving `out` alone) if no pixel has a replacement.
+    pub fn compose(&self, frame: &ScreenData, sources: &[Option<TileSource>], out: &mut Vec<u8>) -> bool {
+        let has_replacements = sources.iter().any(|src| match *src {
+            Some(ref src) => self.tiles.contains_key(&src.hash),
+            None => false,
+        });
+        if !has_replacements {
+            return false;
+        }
+
+        let scale = self.scale;
+        let out_width = WIDTH * scale;
+        out.resize(out_width * HEIGHT * scale * 4, 0);
+
+        for (i, src) in sources.iter().enumerate() {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            let native = &frame[i * 4..i * 4 + 4];
+            let tile = src.as_ref().and_then(|src| self.tiles.get(&src.hash).map(|t| (src, t)));
+
+            for sy in 0..scale {
+                for sx in 0..scale {
+                    let dst = ((y * scale + sy) * out_width + x * scale + sx) * 4;
+                    let pixel = match tile {
+                        Some((src, data)) => {
+                            let tx = src.x as usize * scale + sx;
+                            let ty = src.y as usize * scale + sy;
+                            let p = (ty * 8 * scale + tx) * 4;
+                            // Transparent parts show the original pixel
+                            if data[p + 3] == 0 { native } else { &data[p..p + 4] }
+                        }
+                        None => native,
+                    };
+                    out[dst..dst + 4].copy_from_slice(pixel);
+                }
+            }
+        }
+        true
+    }
+
+    // Save the tiles on screen without a replacement to dump/, at their
+    // original size
+    pub fn dump_tiles(&self, frame: &ScreenData, sources: &[Option<TileSource>]) -> Result<usize, String> {
+        let dump_dir = self.dir.join("dump");
+        try!(fs::create_dir_all(&dump_dir).map_err(|e| e.to_string()));
+
+        let mut tiles: HashMap<u32, RgbaImage> = HashMap::new();
+        for (i, src) in sources.iter().enumerate() {
+            let src = match *src {
+                Some(ref src) if !self.tiles.contains_key(&src.hash) => src,
+                _ => continue,
+            };
+            let img = tiles.entry(src.hash).or_insert_with(|| ImageBuffer::new(8, 8));
+            let p = &frame[i * 4..i * 4 + 4];
+            img.put_pixel(src.x as u32, src.y as u32, Rgba { data: [p[0], p[1], p[2], p[3]] });
+        }
+
+        for (hash, img) in tiles.iter() {
+            let path = dump_dir.join(format!("{:08x}.png", hash));
+            try!(img.save(&path).map_err(|e| format!("{}: {}", path.display(), e)));
+        }
+        Ok(tiles.len())
+    }
+}
+
+// 1a2b3c4d.png -> 0x1a2b3c4d
+fn parse_name(path: &Path) -> Option<u32> {
+    if path.extension().and_then(|e| e.to_str()) != Some("png") {
+        return None;
+    }
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    if stem.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(stem, 16).ok()
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod hdpack_tests {
+    use super::*;
+
+    #[test]
+    fn replacement_names() {
+        assert_eq!(parse_name(Path::new("pack/1a2b3c4d.png")), Some(0x1a2b3c4d));
+        assert_eq!(parse_name(Path::new("pack/1a2b3c4d.bmp")), None);
+        assert_eq!(parse_name(Path::new("pack/readme.png")), None);
+    }
+
+    #[test]
+    fn compose_at_scale() {
+        let mut tiles = HashMap::new();
+        // 2x, pixel (1, 0) of the tile is red in the replacement
+        let mut data = vec![0u8; 16 * 16 * 4];
+        for p in data.chunks_mut(4) {
+            p.copy_from_slice(&[0, 0, 255, 255]);
+        }
+        for &(x, y) in [(2, 0), (3, 0), (2, 1), (3, 1)].iter() {
+            let p = (y * 16 + x) * 4;
+            data[p..p + 4].copy_from_slice(&[255, 0, 0, 255]);
+        }
+        tiles.insert(7, data);
+        let pack = HdPack { dir: PathBuf::new(), scale: 2, tiles: tiles };
+
+        let frame = [9u8; WIDTH * HEIGHT * 4];
+        let mut sources = vec![None; WIDTH * HEIGHT];
+        let mut out = Vec::new();
+        assert!(!pack.compose(&frame, &sources, &mut out));
+
+        sources[0] = Some(TileSource { hash: 7, x: 1, y: 0 });
+        sources[1] = Some(TileSource { hash: 8, x: 0, y: 0 });
+        assert!(pack.compose(&frame, &sources, &mut out));
+        assert_eq!(out.len(), WIDTH * HEIGHT * 16);
+        let px = |x: usize, y: usize| &out[(y * WIDTH * 2 + x) * 4..(y * WIDTH * 2 + x) * 4 + 4];
+        assert_eq!(px(0, 0), &[255, 0, 0, 255]);
+        assert_eq!(px(1, 1), &[255, 0, 0, 255]);
+        assert_eq!(px(2, 0), &[9, 9, 9, 9]);   // no replacement for that tile
+    }
+}
@@ -0,0 +1,125 @@
+//
+//      Memory access heatmap
+//
+
+// Counts reads, writes and executed opcodes at every address, and exports
+// them as a 256x256 image: one pixel per address, a row per 256 bytes
+// (so 0xC000 starts at row 0xC0). Writes are red, reads green and execution
+// blue, each on a log scale so rarely touched addresses still show up.
+
+use std::path::Path;
+
+use image::{ImageBuffer, RgbaImage, Rgba};
+
+const NUM_ADDRS: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+}
+
+pub struct Heatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+    execs: Vec<u32>,
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        Heatmap {
+            reads: vec![0; NUM_ADDRS],
+            writes: vec![0; NUM_ADDRS],
+            execs: vec![0; NUM_ADDRS],
+        }
+    }
+
+    #[inline]
+    pub fn count(&mut self, access: Access, addr: u16) {
+        let counts = self.counts_mut(access);
+        counts[addr as usize] = counts[addr as usize].saturating_add(1);
+    }
+
+    pub fn get(&self, access: Access, addr: u16) -> u32 {
+        self.counts(access)[addr as usize]
+    }
+
+    // The `count` most accessed addresses, most accessed first
+    pub fn hottest(&self, access: Access, count: usize) -> Vec<(u16, u32)> {
+        let mut addrs: Vec<(u16, u32)> = self.counts(access).iter().enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(addr, &n)| (addr as u16, n))
+            .collect();
+        addrs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addrs.truncate(count);
+        addrs
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        let max = |counts: &[u32]| *counts.iter().max().unwrap_or(&0);
+        let (max_w, max_r, max_x) = (max(&self.writes), max(&self.reads), max(&self.execs));
+
+        let mut img: RgbaImage = ImageBuffer::new(256, 256);
+        for addr in 0..NUM_ADDRS {
+            let r = intensity(self.writes[addr], max_w);
+            let g = intensity(self.reads[addr], max_r);
+            let b = intensity(self.execs[addr], max_x);
+            img.put_pixel(addr as u32 % 256, addr as u32 / 256, Rgba { data: [r, g, b, 255] });
+        }
+        img
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        self.to_image().save(path).map_err(|e| e.to_string())
+    }
+
+    fn counts(&self, access: Access) -> &[u32] {
+        match access {
+            Access::Read => &self.reads,
+            Access::Write => &self.writes,
+            Access::Exec => &self.execs,
+        }
+    }
+
+    fn counts_mut(&mut self, access: Access) -> &mut [u32] {
+        match access {
+            Access::Read => &mut self.reads,
+            Access::Write => &mut self.writes,
+            Access::Exec => &mut self.execs,
+        }
+    }
+}
+
+// Log scale from 0 to 255
+fn intensity(count: u32, max: u32) -> u8 {
+    if max == 0 {
+        return 0;
+    }
+    ((count as f64 + 1.).ln() / (max as f64 + 1.).ln() * 255.) as u8
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod heatmap_tests {
+    use super::*;
+
+    #[test]
+    fn hottest_addresses() {
+        let mut heat = Heatmap::new();
+        for _ in 0..3 { heat.count(Access::Write, 0xC0A0); }
+        heat.count(Access::Write, 0xFF40);
+        heat.count(Access::Read, 0xC0A0);
+
+        assert_eq!(heat.hottest(Access::Write, 5), vec![(0xC0A0, 3), (0xFF40, 1)]);
+        assert_eq!(heat.hottest(Access::Exec, 5), vec![]);
+
+        assert_eq!(intensity(3, 3), 255);
+        assert_eq!(intensity(1, 3), 127);
+        assert_eq!(intensity(0, 3), 0);
+        assert_eq!(intensity(0, 0), 0);
+    }
+}
@@ -1,58 +1,200 @@
-use piston::input::Key;
+use std::cell::Cell;
+use piston::input::{Key, ControllerButton};
+
+// Up to 4 controllers can be connected through the SGB multiplayer adapter
+pub const MAX_PLAYERS: usize = 4;
+
+// Gamepad button numbers, in GLFW's gamepad layout
+const PAD_A: u8 = 0;
+const PAD_B: u8 = 1;
+const PAD_BACK: u8 = 6;
+const PAD_START: u8 = 7;
+const PAD_UP: u8 = 11;
+const PAD_RIGHT: u8 = 12;
+const PAD_DOWN: u8 = 13;
+const PAD_LEFT: u8 = 14;
+
+// Buttons held on a controller
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Joypad {
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
 
 pub struct Input {
-    rows: [u8; 2],
+    // Button and direction rows of every controller
+    rows: [[u8; 2]; MAX_PLAYERS],
+    // Buttons held on the touch screen, for the first controller. Kept apart
+    // so letting go of one doesn't release it while a key still holds it
+    touch: [u8; 2],
     column: u8,
+
+    // SGB MLT_REQ: number of controllers enabled and the one being read
+    players: u8,
+    player: u8,
+
+    // The game read a pressed button, for latency measurements
+    press_read: Cell<bool>,
 }
 
 impl Input {
     pub fn new() -> Self {
-        Input {rows: [0x0F, 0x0F], column: 0}
+        Input {
+            rows: [[0x0F, 0x0F]; MAX_PLAYERS], touch: [0x0F, 0x0F], column: 0, players: 1, player: 0,
+            press_read: Cell::new(false),
+        }
     }
 
     pub fn reset(&mut self) {
-        self.rows = [0x0F, 0x0F];
+        self.rows = [[0x0F, 0x0F]; MAX_PLAYERS];
+        self.touch = [0x0F, 0x0F];
         self.column = 0;
+        self.players = 1;
+        self.player = 0;
+    }
+
+    // The current controller's rows, with the touch screen's buttons on the
+    // first one
+    fn current_rows(&self) -> [u8; 2] {
+        let rows = self.rows[self.player as usize];
+        if self.player == 0 {
+            [rows[0] & self.touch[0], rows[1] & self.touch[1]]
+        } else {
+            rows
+        }
     }
 
     pub fn rb(&self) -> u8 {
+        let rows = self.current_rows();
         match self.column {
-            0x10 => self.rows[0],
-            0x20 => self.rows[1],
-            _ => unreachable!("Invalid input column {:#X}", self.column)
+            0x10 => self.read_row(rows[0]),
+            0x20 => self.read_row(rows[1]),
+            // Nothing selected, the SGB reports which controller is current
+            0x30 => 0x0F - self.player,
+            _ => self.read_row(rows[0] & rows[1]),
         }
     }
 
+    fn read_row(&self, row: u8) -> u8 {
+        if row != 0x0F {
+            self.press_read.set(true);
+        }
+        row
+    }
+
+    // Any button held on the current controller, wakes the CPU from STOP
+    pub fn any_pressed(&self) -> bool {
+        let rows = self.current_rows();
+        rows[0] & rows[1] != 0x0F
+    }
+
+    // Whether a pressed button was read since the last call
+    pub fn take_press_read(&self) -> bool {
+        self.press_read.replace(false)
+    }
+
     pub fn wb(&mut self, data: u8) {
-        self.column = data & 0x30;
+        let column = data & 0x30;
+
+        // With multiplayer enabled the SGB switches to the next controller
+        // every time P15 goes from LOW to HIGH
+        if self.players > 1 && self.column & 0x20 == 0 && column & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.column = column;
+    }
+
+    // Called for MLT_REQ, valid values are 1, 2 and 4
+    pub fn set_players(&mut self, players: u8) {
+        info!("SGB multiplayer: {} players", players);
+        self.players = players;
+        self.player = 0;
+    }
+
+    // Set every button of a controller at once
+    pub fn set_joypad(&mut self, player: usize, pad: &Joypad) {
+        self.rows[player] = joypad_rows(pad);
+    }
+
+    // Everything held on the touch screen, buttons held some other way stay
+    // held whatever it has
+    pub fn set_touch(&mut self, pad: &Joypad) {
+        self.touch = joypad_rows(pad);
     }
 
-    pub fn key_press(&mut self, key: &Key) {
+    // Returns whether the key is mapped to a button
+    pub fn key_press(&mut self, key: &Key) -> bool {
         debug!("{:?} pressed", key);
         match *key {
-            Key::Return => {self.rows[0] &= 0x7}
-            Key::Space =>  {self.rows[0] &= 0xB}
-            Key::Left =>   {self.rows[1] &= 0xD}
-            Key::Up =>     {self.rows[1] &= 0xB}
-            Key::Right =>  {self.rows[1] &= 0xE}
-            Key::Down =>   {self.rows[1] &= 0x7}
-            Key::X =>      {self.rows[0] &= 0xD}
-            Key::Z =>      {self.rows[0] &= 0xE}
-            _ => {}
+            Key::Return => {self.rows[0][0] &= 0x7}
+            Key::Space =>  {self.rows[0][0] &= 0xB}
+            Key::Left =>   {self.rows[0][1] &= 0xD}
+            Key::Up =>     {self.rows[0][1] &= 0xB}
+            Key::Right =>  {self.rows[0][1] &= 0xE}
+            Key::Down =>   {self.rows[0][1] &= 0x7}
+            Key::X =>      {self.rows[0][0] &= 0xD}
+            Key::Z =>      {self.rows[0][0] &= 0xE}
+            _ => return false,
         }
+        true
     }
     pub fn key_release(&mut self, key: &Key) {
         debug!("{:?} released", key);
         match *key {
-            Key::Return => {self.rows[0] |= 0x8}
-            Key::Space =>  {self.rows[0] |= 0x4}
-            Key::Left =>   {self.rows[1] |= 0x2}
-            Key::Up =>     {self.rows[1] |= 0x4}
-            Key::Right =>  {self.rows[1] |= 0x1}
-            Key::Down =>   {self.rows[1] |= 0x8}
-            Key::X =>      {self.rows[0] |= 0x2}
-            Key::Z =>      {self.rows[0] |= 0x1}
-            _ => {}     
-        }
-    }
-}
\ No newline at end of file
+            Key::Return => {self.rows[0][0] |= 0x8}
+            Key::Space =>  {self.rows[0][0] |= 0x4}
+            Key::Left =>   {self.rows[0][1] |= 0x2}
+            Key::Up =>     {self.rows[0][1] |= 0x4}
+            Key::Right =>  {self.rows[0][1] |= 0x1}
+            Key::Down =>   {self.rows[0][1] |= 0x8}
+            Key::X =>      {self.rows[0][0] |= 0x2}
+            Key::Z =>      {self.rows[0][0] |= 0x1}
+            _ => {}
+        }
+    }
+
+    // Gamepad N drives controller N, so the first gamepad and the keyboard
+    // share the first controller and extra gamepads are players 2-4
+    pub fn controller_press(&mut self, button: &ControllerButton) {
+        if let Some((player, row, bit)) = controller_bit(button) {
+            self.rows[player][row] &= !bit;
+        }
+    }
+    pub fn controller_release(&mut self, button: &ControllerButton) {
+        if let Some((player, row, bit)) = controller_bit(button) {
+            self.rows[player][row] |= bit;
+        }
+    }
+}
+
+fn joypad_rows(pad: &Joypad) -> [u8; 2] {
+    let bits = |pressed: [bool; 4]| {
+        pressed.iter().enumerate()
+            .fold(0x0F, |row, (i, &p)| if p { row & !(1 << i) } else { row })
+    };
+    [bits([pad.a, pad.b, pad.select, pad.start]), bits([pad.right, pad.left, pad.up, pad.down])]
+}
+
+fn controller_bit(button: &ControllerButton) -> Option<(usize, usize, u8)> {
+    if button.id < 0 || button.id as usize >= MAX_PLAYERS {
+        return None;
+    }
+    let (row, bit) = match button.button {
+        PAD_START => (0, 0x8),
+        PAD_BACK =>  (0, 0x4),
+        PAD_A =>     (0, 0x2),
+        PAD_B =>     (0, 0x1),
+        PAD_DOWN =>  (1, 0x8),
+        PAD_UP =>    (1, 0x4),
+        PAD_LEFT =>  (1, 0x2),
+        PAD_RIGHT => (1, 0x1),
+        _ => return None,
+    };
+    Some((button.id as usize, row, bit))
+}
@@ -0,0 +1,101 @@
+//
+//      Input latency measurement
+//
+
+// Follows a key press from the host event to the first frame where the game
+// read it from the joypad register, and from there to the first time that
+// frame was drawn. The CPU runs a whole frame at a time, so the joypad read is
+// timestamped at the end of the frame it happened in.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub to_joypad: Duration,   // press -> frame that read it
+    pub to_screen: Duration,   // press -> that frame presented
+    pub frames: u32,           // emulated frames between the two
+}
+
+enum State {
+    Idle,
+    // Waiting for the game to read the press
+    Pressed { at: Instant, frame: u32 },
+    // Waiting for the frame to be presented
+    Read { at: Instant, read: Instant, frames: u32 },
+}
+
+pub struct LatencyProbe {
+    state: State,
+    measurements: Vec<Measurement>,
+}
+
+impl LatencyProbe {
+    pub fn new() -> LatencyProbe {
+        LatencyProbe { state: State::Idle, measurements: Vec::new() }
+    }
+
+    // A game key was pressed on the host. Presses during a measurement are
+    // ignored, they'd only muddy it.
+    pub fn key_pressed(&mut self, now: Instant, frame: u32) {
+        if let State::Idle = self.state {
+            self.state = State::Pressed { at: now, frame: frame };
+        }
+    }
+
+    // A frame finished, `read` tells if the game saw a pressed button in it
+    pub fn frame_done(&mut self, now: Instant, frame: u32, read: bool) {
+        if let State::Pressed { at, frame: pressed_frame } = self.state {
+            if read {
+                self.state = State::Read { at: at, read: now, frames: frame - pressed_frame };
+            }
+        }
+    }
+
+    // The current frame was drawn. Returns the finished measurement, if any.
+    pub fn presented(&mut self, now: Instant) -> Option<Measurement> {
+        if let State::Read { at, read, frames } = self.state {
+            self.state = State::Idle;
+            let m = Measurement { to_joypad: read - at, to_screen: now - at, frames: frames };
+            self.measurements.push(m);
+            return Some(m);
+        }
+        None
+    }
+
+    // Average end-to-end latency
+    pub fn average(&self) -> Option<Duration> {
+        if self.measurements.is_empty() {
+            return None;
+        }
+        let total = self.measurements.iter().fold(Duration::new(0, 0), |t, m| t + m.to_screen);
+        Some(total / self.measurements.len() as u32)
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod latency_tests {
+    use super::*;
+
+    #[test]
+    fn measure_press() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut probe = LatencyProbe::new();
+
+        probe.key_pressed(start, 10);
+        probe.frame_done(ms(16), 11, false);
+        probe.key_pressed(ms(20), 11);  // ignored
+        probe.frame_done(ms(33), 12, true);
+        let m = probe.presented(ms(40)).unwrap();
+
+        assert_eq!(m.to_joypad, Duration::from_millis(33));
+        assert_eq!(m.to_screen, Duration::from_millis(40));
+        assert_eq!(m.frames, 2);
+        assert_eq!(probe.presented(ms(50)), None);
+        assert_eq!(probe.average(), Some(Duration::from_millis(40)));
+    }
+}
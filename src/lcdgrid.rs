@@ -0,0 +1,59 @@
+//
+//      LCD grid
+//
+
+// Drawn over the upscaled screen so it looks like the DMG's LCD instead of
+// flat squares: a thin gap around every pixel, and each line a little darker
+// at the bottom like a scanline. Shown with --lcd-grid, G toggles it.
+
+use gpu::{WIDTH, HEIGHT};
+
+// Alpha of the black gap between pixels, and of the scanline shading
+const GAP_ALPHA: u8 = 70;
+const SCANLINE_ALPHA: u8 = 30;
+
+// RGBA overlay for the screen drawn scale times bigger, transparent except
+// for the grid. Needs a scale of 3 or more to leave something of each pixel
+pub fn pixel_grid(scale: usize) -> Vec<u8> {
+    let (w, h) = (WIDTH * scale, HEIGHT * scale);
+    let mut pixels = vec![0; w * h * 4];
+    if scale < 3 {
+        return pixels;
+    }
+    for y in 0..h {
+        for x in 0..w {
+            let alpha = if x % scale == scale - 1 || y % scale == scale - 1 {
+                GAP_ALPHA
+            } else if y % scale == scale - 2 {
+                SCANLINE_ALPHA
+            } else {
+                continue
+            };
+            pixels[(y * w + x) * 4 + 3] = alpha;
+        }
+    }
+    pixels
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod lcdgrid_tests {
+    use super::*;
+
+    #[test]
+    fn grid() {
+        let grid = pixel_grid(4);
+        let alpha = |x: usize, y: usize| grid[(y * WIDTH * 4 + x) * 4 + 3];
+        assert_eq!(grid.len(), WIDTH * HEIGHT * 16 * 4);
+        assert_eq!(alpha(0, 0), 0);
+        assert_eq!(alpha(3, 0), GAP_ALPHA);
+        assert_eq!(alpha(1, 3), GAP_ALPHA);
+        assert_eq!(alpha(1, 2), SCANLINE_ALPHA);
+        assert_eq!(alpha(5, 5), 0);
+        // Too small to see anything through
+        assert!(pixel_grid(2).iter().all(|&b| b == 0));
+    }
+}
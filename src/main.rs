@@ -18,15 +18,24 @@ extern crate gfx_graphics;
 extern crate gfx_device_gl;
 extern crate gfx_text;
 extern crate fps_counter;
+#[cfg(feature = "discord")]
+extern crate discord_rpc_client;
+#[cfg(feature = "winit-frontend")]
+extern crate winit;
+#[cfg(feature = "winit-frontend")]
+extern crate pixels;
 
 use std::env;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use env_logger::LogBuilder;
 use log::LogRecord;
 use colored::*;
 
 use piston_window::{OpenGL, PistonWindow, WindowSettings, Texture};
 use glfw_window::GlfwWindow;
-use piston::window::AdvancedWindow;
+use piston::window::{AdvancedWindow, Window};
 use piston::event_loop::EventLoop;
 use piston::input::*;
 use fps_counter::FPSCounter;
@@ -40,25 +49,125 @@ mod mmu;
 mod cartridge;
 mod emulator;
 mod timer;
+mod serial;
 mod input;
+mod sgb;
+mod config;
+mod colorize;
+mod palettes;
+mod gamedb;
+mod saves;
+mod perf;
+mod latency;
+mod bench;
+mod framehash;
+mod debugger;
+mod breakpoints;
+mod gdb;
+mod disasm;
+mod heatmap;
+mod watch;
+mod hdpack;
+mod gif;
+mod replay;
+mod lcdgrid;
+mod profiler;
+mod symbols;
+mod overlay;
+mod filter;
+mod display;
+mod recording;
+mod screenshot;
+mod camera;
+mod playlist;
+mod mobile;
+mod splash;
+mod touch;
+mod terminal;
+mod tileview;
+mod mapview;
+mod oamview;
+mod palview;
+mod palmenu;
+#[cfg(feature = "discord")]
+mod discord;
+#[cfg(feature = "winit-frontend")]
+mod winit_frontend;
+#[cfg(feature = "winit-frontend")]
+mod screen_shader;
 
 const OPENGL: OpenGL = OpenGL::V3_2;
 static DEFAULT_LOG_LEVEL: &'static str = "debug";
+static BENCH_LOG_LEVEL: &'static str = "warn";
 static DEFAULT_LOG_LEVELS: &'static str = "gfx_device_gl=warn,cargo=error";
 static WINDOW_TITLE: &'static str = "Rust Boy Emulator";
 
-const SCREEN_MULT: u32 = 4;
+// Window size in multiples of the screen, unless --scale says otherwise
+const DEFAULT_SCALE: u32 = 4;
 const BG_COLOR: [f32; 4] = [5./255., 36./255., 5./255., 1.0];
 const TEXT_COLOR: [f32; 4] = [255./255., 255./255., 255./255., 1.0];
 const TEXT_TITLE_COLOR: [f32; 4] = [210./255., 210./255., 210./255., 1.0];
 
 const NATIVE_DIMS: [u32; 2] = [160, 144];
-const SCREEN_DIMS: [u32; 2] = [NATIVE_DIMS[0] * SCREEN_MULT,
-                               NATIVE_DIMS[1] * SCREEN_MULT];
-const FONT_SIZE: u8 = (1. + SCREEN_MULT as f32 * 4.5) as u8;
+// Tile and sprite viewers are drawn this much bigger, in the top right
+// corner
+const TILE_VIEW_SCALE: f64 = 2.;
+const OAM_VIEW_SCALE: f64 = 4.;
+
+// Debug overlays over the screen, one at a time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DebugView {
+    Tiles,
+    Map,
+    Oam,
+    Palettes,
+}
+
+fn screen_dims(scale: u32) -> [u32; 2] {
+    [NATIVE_DIMS[0] * scale, NATIVE_DIMS[1] * scale]
+}
+
+// The window fits the screen at a scale, the way it's turned
+fn window_dims(scale: u32, orientation: display::Orientation) -> [u32; 2] {
+    let size = orientation.size();
+    [size[0] as u32 * scale, size[1] as u32 * scale]
+}
+
+// Draw something of size dims over the screen's rect in the window
+fn fill_rect(transform: graphics::types::Matrix2d, rect: [f64; 4], dims: [u32; 2]) -> graphics::types::Matrix2d {
+    use graphics::Transformed;
+    transform.trans(rect[0], rect[1]).scale(rect[2] / dims[0] as f64, rect[3] / dims[1] as f64)
+}
+
+// Same for the screen and what lines up with its pixels, turned and mirrored
+fn fill_screen(transform: graphics::types::Matrix2d, rect: [f64; 4], dims: [u32; 2],
+               orientation: display::Orientation) -> graphics::types::Matrix2d {
+    use graphics::Transformed;
+    transform.append_transform(orientation.transform(rect, dims))
+}
+
+fn font_size(scale: u32) -> u8 {
+    (1. + scale as f32 * 4.5) as u8
+}
 
 
+// Play with the winit frontend if it was built in, see winit_frontend.rs
+#[cfg(feature = "winit-frontend")]
+fn run_winit_frontend(config: &config::Config) -> bool {
+    if let Err(err) = winit_frontend::run(config, WINDOW_TITLE, config.scale.unwrap_or(DEFAULT_SCALE)) {
+        error!("{}", err);
+    }
+    true
+}
+
+#[cfg(not(feature = "winit-frontend"))]
+fn run_winit_frontend(_: &config::Config) -> bool {
+    false
+}
+
 fn main() {
+    let args: Vec<_> = env::args().collect();
+    let is_bench = args.len() > 1 && args[1] == "bench";
 
     // Logging stuff
     let format = |record: &LogRecord| {
@@ -78,58 +187,197 @@ fn main() {
     };
     let mut builder = LogBuilder::new();
     builder.format(format);
-    builder.parse(&format!("{},{}", DEFAULT_LOG_LEVEL, DEFAULT_LOG_LEVELS));
+    // Logging everything would be most of what the benchmark measures
+    let log_level = if is_bench { BENCH_LOG_LEVEL } else { DEFAULT_LOG_LEVEL };
+    builder.parse(&format!("{},{}", log_level, DEFAULT_LOG_LEVELS));
     builder.init().unwrap();
 
-    // Argument parsing
-    let args: Vec<_> = env::args().collect();
-    let rom_path: &String;
+    if is_bench {
+        if let Err(err) = bench::run(&args[2..]) {
+            error!("{}\n{}", err, bench::USAGE);
+        }
+        return;
+    }
+    if args.len() > 1 && args[1] == "camera" {
+        if let Err(err) = camera::run(&args[2..]) {
+            error!("{}\n{}", err, camera::USAGE);
+        }
+        return;
+    }
 
-    match args.len() {
-        2 => rom_path = &args[1],
-        _ => {
-            error!("No arguments provided.\nUSAGE: rustboy-emu <path/to/rom>");
+    // Argument parsing
+    let config = match config::Config::from_args(&args[1..]) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}\n{}", err, config::USAGE);
             return;
         },
+    };
+    if let Some(frames) = config.run_frames {
+        framehash::run_or_exit(&config, frames);
+        return;
+    }
+    if let Some(mode) = config.terminal {
+        if let Err(err) = terminal::run(&config, mode) {
+            error!("{}", err);
+        }
+        return;
+    }
+    if run_winit_frontend(&config) {
+        return;
     }
 
     // Window creation
+    let mut scale = config.scale.unwrap_or(DEFAULT_SCALE);
     let mut window: PistonWindow<GlfwWindow> =
         WindowSettings::new(
             WINDOW_TITLE,
-            SCREEN_DIMS,
+            window_dims(scale, config.orientation),
         )
         .opengl(OPENGL)
-        .resizable(false)
+        .resizable(true)
         .build()
         .unwrap();
     window.set_ups(60);
 
     // Initialize emulator
-    let mut emu = emulator::Emulator::new(&window, rom_path);
+    let mut emu = emulator::Emulator::new(&config);
+    let mut playlist = playlist::Playlist::new(&config);
 
     // Append game name to title
     window.set_title(
-       String::from(format!("{} - {}", WINDOW_TITLE, emu.rom_header.get_game_title()))
+       String::from(format!("{} - {}", WINDOW_TITLE, emu.game_title()))
     );
 
+    // Discord Rich Presence
+    #[cfg(feature = "discord")]
+    let mut presence = config.discord_app_id.map(|id|
+        discord::Presence::new(id, &emu.game_title()));
+    #[cfg(not(feature = "discord"))]
+    {
+        if config.discord_app_id.is_some() {
+            warn!("Built without the discord feature, --discord is ignored");
+        }
+    }
+
+    // Frame time statistics
+    let mut frame_times = if config.frame_stats { Some(perf::FrameTimes::new()) } else { None };
+    // Input latency measurements
+    let mut latency = if config.measure_latency { Some(latency::LatencyProbe::new()) } else { None };
+    // The last 30 seconds, for R to save
+    let mut replay = replay::ReplayBuffer::new();
+    // Shown instead of the game until it's over
+    let mut splash = splash::Splash::new(&config.boot_splash,
+        emu.mem.rom_loaded.get(0x104..0x134).unwrap_or(&[]), emu.mem.gpu.base_bg_palette());
+    // On-screen buttons
+    let mut touch_controls = if config.touch_controls {
+        let layout = match config.touch_layout {
+            Some(ref path) => touch::load_layout(path).unwrap_or_else(|err| {
+                error!("Couldn't load touch layout {}: {}", path, err);
+                touch::default_layout()
+            }),
+            None => touch::default_layout(),
+        };
+        Some(touch::TouchControls::new(layout, config.touch_opacity.unwrap_or(touch::DEFAULT_OPACITY)))
+    } else {
+        None
+    };
+    // Debug console on the terminal
+    let mut console = if config.console { Some(debugger::Debugger::new()) } else { None };
+    // And the gdb stub
+    let mut gdb = config.gdb_port.and_then(|port| match gdb::GdbStub::listen(port) {
+        Ok(stub) => Some(stub),
+        Err(err) => {
+            error!("Couldn't listen for gdb on port {}: {}", port, err);
+            None
+        }
+    });
+
     let output_color = window.output_color.clone();
 
     // Initialize text renderers
     macro_rules! make_font(
     () => (gfx_text::new(window.factory.clone())
-            .with_size(FONT_SIZE)
+            .with_size(font_size(scale))
             .with_font("resources/fonts/joystix monospace.ttf")
             .build().unwrap();
     ));
     let mut text = make_font!();
     let mut text_shadow = make_font!();
 
-    // Set up framebuffer
+    // Set up framebuffer. The textures stay around and are only updated
+    // when the Gpu has finished a new frame, every render event draws them
     let ts = TextureSettings::new().filter(texture::Filter::Nearest).compress(false).generate_mipmap(false);
     let mut framebuffer =
         Texture::create(&mut window.factory, Format::Rgba8, &*emu.mem.gpu.image_data, NATIVE_DIMS, &ts)
         .expect("Couldn't create framebuffer texture");
+    let screen_img = graphics::Image::new().src_rect([0., 0., NATIVE_DIMS[0] as f64, NATIVE_DIMS[1] as f64]);
+    // The frame after --filter, in its own texture when there's a filter
+    let factor = config.filter.factor() as u32;
+    let filtered_dims = [NATIVE_DIMS[0] * factor, NATIVE_DIMS[1] * factor];
+    let mut filtered = vec![0u8; (filtered_dims[0] * filtered_dims[1] * 4) as usize];
+    let mut filtered_texture = if config.filter != filter::Filter::None {
+        Some(Texture::create(&mut window.factory, Format::Rgba8, &filtered, filtered_dims, &ts)
+            .expect("Couldn't create filtered framebuffer texture"))
+    } else {
+        None
+    };
+
+    // Pixel grid drawn over the screen
+    let mut show_grid = config.lcd_grid;
+    macro_rules! make_grid(
+    () => (Texture::create(&mut window.factory, Format::Rgba8, &lcdgrid::pixel_grid(scale as usize),
+            screen_dims(scale), &ts)
+            .expect("Couldn't create grid texture");
+    ));
+    let mut grid_texture = make_grid!();
+
+    // Register overlay in the bottom left corner of the screen
+    let mut show_overlay = config.cpu_overlay;
+
+    // HD tile replacements, composited into their own larger texture
+    let hd_pack = config.hd_pack.as_ref().and_then(|dir| match hdpack::HdPack::load(dir) {
+        Ok(pack) => Some(pack),
+        Err(err) => { error!("Couldn't load HD pack: {}", err); None }
+    });
+    let mut hd_frame = Vec::new();
+    let mut hd_framebuffer = None;
+    if let Some(ref pack) = hd_pack {
+        emu.mem.gpu.track_tile_sources();
+        hd_frame = vec![0u8; (pack.dims()[0] * pack.dims()[1] * 4) as usize];
+        hd_framebuffer = Some(Texture::create(&mut window.factory, Format::Rgba8, &hd_frame, pack.dims(), &ts)
+            .expect("Couldn't create HD framebuffer texture"));
+    }
+    // Gpu::frames() when the textures were last updated, None for the splash
+    // screen which is always uploaded
+    let mut uploaded_frame = None;
+    // Whether the last frame had HD tiles, shown instead of the framebuffer
+    let mut hd_shown = false;
+
+    // V shows every tile in VRAM, B the background maps, O the sprites, L the palettes
+    let mut debug_view = None;
+    let mut tile_viewer = tileview::TileViewer::new();
+    let mut tile_texture =
+        Texture::create(&mut window.factory, Format::Rgba8, &tile_viewer.pixels, tileview::DIMS, &ts)
+        .expect("Couldn't create tile viewer texture");
+    let mut map_viewer = mapview::MapViewer::new();
+    let mut map_texture =
+        Texture::create(&mut window.factory, Format::Rgba8, &map_viewer.pixels, mapview::DIMS, &ts)
+        .expect("Couldn't create map viewer texture");
+    let mut oam_viewer = oamview::OamViewer::new();
+    let mut oam_texture =
+        Texture::create(&mut window.factory, Format::Rgba8, &oam_viewer.pixels, oamview::DIMS, &ts)
+        .expect("Couldn't create sprite viewer texture");
+    let mut pal_viewer = palview::PaletteViewer::new();
+    let mut pal_texture =
+        Texture::create(&mut window.factory, Format::Rgba8, &pal_viewer.pixels, palview::DIMS, &ts)
+        .expect("Couldn't create palette viewer texture");
+    // K opens the palette menu, with the frame previewed in the highlighted one
+    let mut pal_menu = palmenu::PaletteMenu::new();
+    let mut pal_menu_texture =
+        Texture::create(&mut window.factory, Format::Rgba8, &pal_menu.pixels, palmenu::DIMS, &ts)
+        .expect("Couldn't create palette menu texture");
+    let mut cursor = [0f64; 2];
 
     // Set up framerate counter
     let mut fps = FPSCounter::new();
@@ -138,51 +386,427 @@ fn main() {
     while let Some(evt) = window.next() {
         //debug!("EVENT: {:?}", evt);
 
+        if let Some(ref mut console) = console {
+            console.poll(&mut emu);
+        }
+        if let Some(ref mut gdb) = gdb {
+            gdb.poll(&mut emu);
+        }
+
         // Space to pause/unpause emulation
         if let Some(Button::Keyboard(Key::Space)) = evt.press_args() {
             emu.toggle_running();
         }
 
+        // F7 to run until the current routine returns, F8 to run to the
+        // address the console's until went to again
+        if let Some(Button::Keyboard(Key::F7)) = evt.press_args() {
+            if let Err(err) = emu.step_out() {
+                warn!("{}", err);
+            }
+        }
+        if let Some(Button::Keyboard(Key::F8)) = evt.press_args() {
+            if let Err(err) = emu.run_to_cursor() {
+                warn!("{}", err);
+            }
+        }
+
         // D to enable/disable debugging text
         if let Some(Button::Keyboard(Key::D)) = evt.press_args() {
             emu.toggle_debugging();
         }
 
+        // U to turn frame pacing off/on
+        if let Some(Button::Keyboard(Key::U)) = evt.press_args() {
+            emu.toggle_uncapped();
+        }
+
+        // H to dump the frame time statistics to a csv
+        if let Some(Button::Keyboard(Key::H)) = evt.press_args() {
+            if let Some(ref times) = frame_times {
+                match times.dump_csv("frame_times.csv") {
+                    Ok(_) => info!("Frame times dumped to frame_times.csv"),
+                    Err(err) => error!("Couldn't dump frame times: {}", err),
+                }
+            }
+        }
+
+        // V/B/O/L to show/hide the tile, map, sprite and palette viewers
+        let views = [(Key::V, DebugView::Tiles), (Key::B, DebugView::Map), (Key::O, DebugView::Oam),
+            (Key::L, DebugView::Palettes)];
+        for &(key, view) in views.iter() {
+            if evt.press_args() == Some(Button::Keyboard(key)) {
+                debug_view = if debug_view == Some(view) {None} else {Some(view)};
+            }
+        }
+        if let Some(pos) = evt.mouse_cursor_args() {
+            cursor = pos;
+        }
+
         // T to dump all tiles to a png
         if let Some(Button::Keyboard(Key::T)) = evt.press_args() {
             emu.mem.gpu.dump_tiles();
         }
 
+        // F10 to dump VRAM and OAM to binary files, see the console's dump
+        if let Some(Button::Keyboard(Key::F10)) = evt.press_args() {
+            match emu.mem.gpu.dump_memory(&format!("dump_{}", emu.frame_count)) {
+                Ok(files) => info!("Dumped {}", files.join(", ")),
+                Err(err) => error!("Couldn't dump VRAM and OAM: {}", err),
+            }
+        }
+
+        // P to dump the tiles on screen for making HD replacements
+        if let Some(Button::Keyboard(Key::P)) = evt.press_args() {
+            if let (Some(pack), Some(sources)) = (hd_pack.as_ref(), emu.mem.gpu.frame_tile_sources()) {
+                match pack.dump_tiles(emu.mem.gpu.frame(), sources) {
+                    Ok(n) => info!("Dumped {} tiles for the HD pack", n),
+                    Err(err) => error!("Couldn't dump tiles: {}", err),
+                }
+            }
+        }
+
+        // Tab to switch to the next rom
+        if let Some(Button::Keyboard(Key::Tab)) = evt.press_args() {
+            if playlist.len() > 1 {
+                playlist.next(&mut emu);
+                // Its frame count has nothing to do with the last game's
+                uploaded_frame = None;
+                pal_menu.open = false;
+                if hd_pack.is_some() && emu.mem.gpu.tile_sources.is_none() {
+                    emu.mem.gpu.track_tile_sources();
+                }
+                window.set_title(format!("{} - {}", WINDOW_TITLE, emu.game_title()));
+                #[cfg(feature = "discord")]
+                {
+                    if let Some(ref mut presence) = presence {
+                        presence.set_game(&emu.game_title());
+                    }
+                }
+            }
+        }
+
+        // - and = to make the window smaller/bigger
+        let new_scale = match evt.press_args() {
+            Some(Button::Keyboard(Key::Minus)) if scale > config::MIN_SCALE => scale - 1,
+            Some(Button::Keyboard(Key::Equals)) if scale < config::MAX_SCALE => scale + 1,
+            _ => scale,
+        };
+        if new_scale != scale {
+            scale = new_scale;
+            window.set_size(window_dims(scale, config.orientation));
+            grid_texture = make_grid!();
+            text = make_font!();
+            text_shadow = make_font!();
+            info!("Scale: {}x", scale);
+        }
+
+        // G to show/hide the pixel grid
+        if let Some(Button::Keyboard(Key::G)) = evt.press_args() {
+            show_grid = !show_grid;
+        }
+
+        // I to show/hide the register overlay
+        if let Some(Button::Keyboard(Key::I)) = evt.press_args() {
+            show_overlay = !show_overlay;
+        }
+
+        // C to switch to the next palette
+        if let Some(Button::Keyboard(Key::C)) = evt.press_args() {
+            info!("Palette: {}", emu.mem.gpu.cycle_palette());
+        }
+
+        // K to open/close the palette menu. While it's open Up, Down, Enter
+        // and Escape go to it instead of the game
+        let mut menu_key = false;
+        if let Some(Button::Keyboard(key)) = evt.press_args() {
+            if key == Key::K {
+                pal_menu.toggle(&emu.mem.gpu);
+                menu_key = true;
+            } else if pal_menu.open {
+                menu_key = true;
+                match key {
+                    Key::Up => pal_menu.move_by(&emu.mem.gpu, -1),
+                    Key::Down => pal_menu.move_by(&emu.mem.gpu, 1),
+                    Key::Return => {
+                        let name = pal_menu.selected_name(&emu.mem.gpu).to_string();
+                        if let Err(err) = emu.pick_palette(&name) {
+                            error!("{}", err);
+                        }
+                        pal_menu.open = false;
+                    }
+                    Key::Escape => pal_menu.open = false,
+                    _ => menu_key = false,
+                }
+            }
+        }
+
+        // R to save the last 30 seconds as a GIF, in the background
+        if let Some(Button::Keyboard(Key::R)) = evt.press_args() {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let path = format!("replay_{}.gif", secs);
+            let frames = replay.snapshot();
+            info!("Saving {} frames to {}", frames.len(), path);
+            thread::spawn(move || {
+                match replay::write_gif(&path, &frames) {
+                    Ok(_) => info!("Replay saved to {}", path),
+                    Err(err) => error!("Couldn't save the replay: {}", err),
+                }
+            });
+        }
+
+        // F9 to start/stop recording a video
+        if let Some(Button::Keyboard(Key::F9)) = evt.press_args() {
+            match emu.recorder.take() {
+                Some(rec) => {
+                    let (path, frames) = (rec.path.clone(), rec.frames);
+                    match rec.finish() {
+                        Ok(_) => info!("Recorded {} frames to {}", frames, path),
+                        Err(err) => error!("Couldn't finish the recording: {}", err),
+                    }
+                }
+                None => {
+                    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    match recording::Recorder::start(config.record_format, secs) {
+                        Ok(rec) => {
+                            info!("Recording to {}", rec.path);
+                            emu.recorder = Some(rec);
+                        }
+                        Err(err) => error!("Couldn't start recording: {}", err),
+                    }
+                }
+            }
+        }
+
+        // F12 to save a screenshot
+        if let Some(Button::Keyboard(Key::F12)) = evt.press_args() {
+            let mult = if config.scaled_screenshots {scale as usize} else {1};
+            match screenshot::save(&emu.mem.gpu.screen()[..], mult) {
+                Ok(path) => info!("Screenshot saved to {}", path),
+                Err(err) => error!("Couldn't save the screenshot: {}", err),
+            }
+        }
+
+        // M to save the memory access heatmap to a png
+        if let Some(Button::Keyboard(Key::M)) = evt.press_args() {
+            if let Some(ref heat) = emu.mem.heatmap {
+                match heat.save("heatmap.png") {
+                    Ok(_) => info!("Memory heatmap saved to heatmap.png"),
+                    Err(err) => error!("Couldn't save the memory heatmap: {}", err),
+                }
+            }
+        }
+
         // If any other button was pressed, let emulator handle it
         if let Some(Button::Keyboard(key)) = evt.press_args() {
-            emu.mem.input.key_press(&key);
+            if !menu_key && emu.mem.input.key_press(&key) {
+                if let Some(ref mut probe) = latency {
+                    emu.mem.input.take_press_read();
+                    probe.key_pressed(Instant::now(), emu.frame_count);
+                }
+            }
         }
         // If any other button was released, let emulator handle it
         if let Some(Button::Keyboard(key)) = evt.release_args() {
             emu.mem.input.key_release(&key);
         }
+        // Gamepads
+        if let Some(Button::Controller(button)) = evt.press_args() {
+            emu.mem.input.controller_press(&button);
+        }
+        if let Some(Button::Controller(button)) = evt.release_args() {
+            emu.mem.input.controller_release(&button);
+        }
+        // Touchscreens
+        if let (Some(args), Some(controls)) = (evt.touch_args(), touch_controls.as_mut()) {
+            controls.touch(&args, &mut emu.mem.input);
+        }
 
         if let Event::Render(_) = evt {
             //println!("RENDER: {}", emu.frame_count);
+            let render_start = Instant::now();
+
             // Draw BG
             window.draw_2d(&evt, |c, g| {
                 clear(BG_COLOR, g);
             });
+            // Where the screen goes, everything drawn over it is scaled from
+            // its own size to this
+            let win_size = window.size();
+            let win_dims = [win_size.width as f64, win_size.height as f64];
+            let rect = config.display.rect_for(win_dims, config.orientation.size());
+
+            // Upload the frame, once
+            let frame = if splash.is_some() { None } else { Some(emu.mem.gpu.frames()) };
+            if frame.is_none() || frame != uploaded_frame {
+                uploaded_frame = frame;
+                let screen = match splash {
+                    Some(ref splash) => &splash.screen,
+                    None => emu.mem.gpu.screen(),
+                };
+                // Composite the HD frame if any tile on screen has a replacement
+                hd_shown = false;
+                let sources = emu.mem.gpu.frame_tile_sources();
+                if let (Some(pack), Some(hd_tex), None, Some(sources)) =
+                    (hd_pack.as_ref(), hd_framebuffer.as_mut(), splash.as_ref(), sources) {
+                    if pack.compose(emu.mem.gpu.frame(), sources, &mut hd_frame) {
+                        UpdateTexture::update(hd_tex, &mut window.encoder, Format::Rgba8,
+                            &hd_frame, [0,0], pack.dims()).unwrap();
+                        hd_shown = true;
+                    }
+                }
+                if !hd_shown {
+                    match filtered_texture {
+                        // Filtered, then the rest of the way to the window scale
+                        Some(ref mut texture) => {
+                            config.filter.apply(&screen[..], &mut filtered);
+                            UpdateTexture::update(texture, &mut window.encoder, Format::Rgba8,
+                                &filtered, [0,0], filtered_dims).unwrap();
+                        }
+                        None => {
+                            UpdateTexture::update(&mut framebuffer, &mut window.encoder, Format::Rgba8,
+                                &screen[..], [0,0], NATIVE_DIMS).unwrap();
+                        }
+                    }
+                }
+            }
 
-            // Emulator rendering (does nothing for now, look below)
-            //emu.render(&r, &mut window, &mut framebuffer, &evt);
-
-            // TODO: Move these to the above call
-            // Update the framebuffer
-            UpdateTexture::update(&mut framebuffer, &mut window.encoder, Format::Rgba8,
-                &*emu.mem.gpu.image_data, [0,0], NATIVE_DIMS).unwrap();
             // Draw the screen
-            window.draw_2d(&evt, |c, g| {
-                use graphics::Transformed;
+            match (hd_shown, hd_framebuffer.as_ref(), hd_pack.as_ref(), filtered_texture.as_ref()) {
+                (true, Some(hd_tex), Some(pack), _) => {
+                    let dims = pack.dims();
+                    window.draw_2d(&evt, |c, g| {
+                        graphics::Image::new().draw(hd_tex, &c.draw_state,
+                            fill_screen(c.transform, rect, dims, config.orientation), g);
+                    });
+                }
+                (_, _, _, Some(texture)) => {
+                    window.draw_2d(&evt, |c, g| {
+                        graphics::Image::new().draw(texture, &c.draw_state,
+                            fill_screen(c.transform, rect, filtered_dims, config.orientation), g);
+                    });
+                }
+                _ => {
+                    window.draw_2d(&evt, |c, g| {
+                        screen_img.draw(&framebuffer, &c.draw_state,
+                            fill_screen(c.transform, rect, NATIVE_DIMS, config.orientation), g);
+                    });
+                }
+            }
+            if show_grid && splash.is_none() {
+                window.draw_2d(&evt, |c, g| {
+                    graphics::Image::new().draw(&grid_texture, &c.draw_state,
+                        fill_screen(c.transform, rect, screen_dims(scale), config.orientation), g);
+                });
+            }
 
-                emu.mem.gpu.img.draw(&framebuffer, &c.draw_state,
-                    c.transform.scale(SCREEN_MULT as f64, SCREEN_MULT as f64), g);
-            });
+            // Touch controls over the screen, brighter while held
+            if let Some(ref controls) = touch_controls {
+                window.draw_2d(&evt, |c, g| {
+                    let (w, h) = (win_dims[0], win_dims[1]);
+                    for control in controls.controls.iter() {
+                        let shade = if controls.is_held(control) { 1.0 } else { 0.6 };
+                        let color = [shade, shade, shade, controls.opacity];
+                        let rect = [control.x * w, control.y * h, control.w * w, control.h * h];
+                        match control.button {
+                            touch::TouchButton::A | touch::TouchButton::B =>
+                                graphics::ellipse(color, rect, c.transform, g),
+                            _ => graphics::rectangle(color, rect, c.transform, g),
+                        }
+                    }
+                });
+            }
+
+            // Debug overlay in the top right corner, with what's under the mouse
+            if let Some(view) = debug_view {
+                let gpu = &emu.mem.gpu;
+                let (pixels, dims, texture, view_scale) = match view {
+                    DebugView::Tiles => {
+                        tile_viewer.update(gpu);
+                        (&tile_viewer.pixels, tileview::DIMS, &mut tile_texture, TILE_VIEW_SCALE)
+                    }
+                    DebugView::Map => {
+                        map_viewer.update(gpu);
+                        (&map_viewer.pixels, mapview::DIMS, &mut map_texture, 1.)
+                    }
+                    DebugView::Oam => {
+                        oam_viewer.update(gpu);
+                        (&oam_viewer.pixels, oamview::DIMS, &mut oam_texture, OAM_VIEW_SCALE)
+                    }
+                    DebugView::Palettes => {
+                        pal_viewer.update(gpu);
+                        (&pal_viewer.pixels, palview::DIMS, &mut pal_texture, TILE_VIEW_SCALE)
+                    }
+                };
+                let pos = [win_dims[0] - dims[0] as f64 * view_scale - 8., 8.];
+                UpdateTexture::update(texture, &mut window.encoder, Format::Rgba8,
+                    pixels, [0,0], dims).unwrap();
+                window.draw_2d(&evt, |c, g| {
+                    use graphics::Transformed;
+
+                    graphics::Image::new().draw(&*texture, &c.draw_state,
+                        c.transform.trans(pos[0], pos[1]).scale(view_scale, view_scale), g);
+                });
+
+                let (x, y) = ((cursor[0] - pos[0]) / view_scale, (cursor[1] - pos[1]) / view_scale);
+                let hovered = match view {
+                    DebugView::Tiles => tile_viewer.tile_at(x, y).map(|tile| tile.describe()),
+                    DebugView::Map => map_viewer.describe_at(gpu, x, y),
+                    DebugView::Oam => oam_viewer.entry_at(x, y).map(|i| oamview::describe(gpu, i)),
+                    DebugView::Palettes => pal_viewer.describe_at(gpu, x, y),
+                };
+                if let Some(desc) = hovered {
+                    let text_pos = [pos[0] as i32, (pos[1] + dims[1] as f64 * view_scale) as i32 + 4];
+                    text_shadow.add(&desc, [text_pos[0] + 2, text_pos[1] + 2], [0., 0., 0., 1.]);
+                    text.add(&desc, text_pos, TEXT_COLOR);
+                    window.draw_2d(&evt, |c, g| {
+                        text_shadow.draw(&mut g.encoder, &output_color).unwrap();
+                        text.draw(&mut g.encoder, &output_color).unwrap();
+                    });
+                }
+            }
+
+            // Palette menu on the right half of the screen, the preview over
+            // the list
+            if pal_menu.open {
+                pal_menu.update(&emu.mem.gpu);
+                UpdateTexture::update(&mut pal_menu_texture, &mut window.encoder, Format::Rgba8,
+                    &pal_menu.pixels, [0,0], palmenu::DIMS).unwrap();
+                let half = [rect[0] + rect[2] / 2., rect[1], rect[2] / 2., rect[3] / 2.];
+                window.draw_2d(&evt, |c, g| {
+                    graphics::rectangle([0., 0., 0., 0.75], [half[0], half[1], half[2], rect[3]], c.transform, g);
+                    graphics::Image::new().draw(&pal_menu_texture, &c.draw_state,
+                        fill_rect(c.transform, half, palmenu::DIMS), g);
+                });
+                for (line_n, line) in pal_menu.lines(&emu.mem.gpu).iter().enumerate() {
+                    let pos = [half[0] as i32 + 4, (half[1] + half[3]) as i32 + 4 + line_n as i32 * font_size(scale) as i32];
+                    text_shadow.add(line, [pos[0] + 2, pos[1] + 2], [0., 0., 0., 1.]);
+                    text.add(line, pos, TEXT_COLOR);
+                }
+                window.draw_2d(&evt, |c, g| {
+                    text_shadow.draw(&mut g.encoder, &output_color).unwrap();
+                    text.draw(&mut g.encoder, &output_color).unwrap();
+                });
+            }
+
+            if show_overlay {
+                let lines = overlay::lines(&emu);
+                let line_height = font_size(scale) as f64;
+                let height = lines.len() as f64 * line_height + 8.;
+                let top = rect[1] + rect[3] - height;
+                window.draw_2d(&evt, |c, g| {
+                    graphics::rectangle([0., 0., 0., 0.6], [rect[0], top, rect[2], height], c.transform, g);
+                });
+                for (line_n, line) in lines.iter().enumerate() {
+                    let pos = [rect[0] as i32 + 4, (top + 4. + line_n as f64 * line_height) as i32];
+                    text_shadow.add(line, [pos[0] + 2, pos[1] + 2], [0., 0., 0., 1.]);
+                    text.add(line, pos, TEXT_COLOR);
+                }
+                window.draw_2d(&evt, |c, g| {
+                    text_shadow.draw(&mut g.encoder, &output_color).unwrap();
+                    text.draw(&mut g.encoder, &output_color).unwrap();
+                });
+            }
 
             // TODO: Move to seperate module (debugger.rs)
             // Debugger rendering
@@ -191,13 +815,26 @@ fn main() {
                 dbg_string.push_str(&format!("\tRegisters\n{:?}\n\n", emu.cpu.get_regs()));
                 dbg_string.push_str(&format!("\tFlags\n{:?}\n\n", emu.cpu.get_flags()));
                 dbg_string.push_str(&format!("\tTimers\n{:?}\n\n", emu.mem.get_timers()));
+                if !emu.watches.watches.is_empty() {
+                    dbg_string.push_str("\tWatches\n");
+                    for watch in emu.watches.watches.iter() {
+                        dbg_string.push_str(&format!(" {}\n", watch.display()));
+                    }
+                }
+                let calls = emu.cpu.calls.backtrace(&emu.symbols);
+                if !calls.is_empty() {
+                    dbg_string.push_str("\tCall stack\n");
+                    for line in calls.iter().take(8) {
+                        dbg_string.push_str(&format!(" {}\n", line));
+                    }
+                }
 
                 // Split lines and place them appropriately
                 let dbg_lines = dbg_string.split('\n');
                 for (line_n, line) in dbg_lines.enumerate() {
                     text.add(
                         line,
-                        [10, 10 + line_n as i32 * FONT_SIZE as i32 + 1],
+                        [10, 10 + line_n as i32 * font_size(scale) as i32 + 1],
                         if line.len() !=0 && line.as_bytes()[0] == '\t' as u8 {  // sorry
                             TEXT_TITLE_COLOR
                         } else {
@@ -206,7 +843,7 @@ fn main() {
                     );
                     text_shadow.add(
                         line,
-                        [10 + 2, 10 + 2 + line_n as i32 * FONT_SIZE as i32 + 1],
+                        [10 + 2, 10 + 2 + line_n as i32 * font_size(scale) as i32 + 1],
                         [0., 0., 0., 1.]
                     );
                 }
@@ -215,14 +852,70 @@ fn main() {
                     text.draw(&mut g.encoder, &output_color).unwrap();
                 });
             }
+
+            if let Some(ref mut times) = frame_times {
+                times.presented(render_start.elapsed());
+            }
+            if let Some(ref mut probe) = latency {
+                if let Some(m) = probe.presented(Instant::now()) {
+                    info!("Input latency: {:?} to joypad read, {:?} to screen ({} frames), average {:?}",
+                        m.to_joypad, m.to_screen, m.frames, probe.average().unwrap());
+                }
+            }
+        }
+
+        #[cfg(feature = "discord")]
+        {
+            if let Some(ref mut presence) = presence {
+                presence.update(emu.is_running(), emu.recorder.is_some());
+            }
         }
 
         if let Some(u) = evt.update_args() {
             //println!("UPDATE: {}", emu.frame_count);
-            if emu.is_running() {
+            let splash_over = splash.as_mut().map_or(false, |s| !s.step());
+            if splash_over {
+                splash = None;
+            }
+            // The game starts once the splash is over
+            if splash.is_none() && emu.is_running() {
                 debug!("FRAME START: {}", emu.frame_count);
-                emu.update(&u);
+                let update_start = Instant::now();
+                let frame_before = emu.frame_count;
+                let rebuilt = if emu.is_uncapped() {
+                    // Use up the whole update interval instead of one frame
+                    let budget = Duration::from_millis((u.dt * 1000.) as u64);
+                    emu.update_uncapped(budget)
+                } else {
+                    emu.update().tileset_rebuilt
+                };
+                if emu.frame_count != frame_before {
+                    replay.push(emu.mem.gpu.frame());
+                }
+
+                if let Some(ref mut times) = frame_times {
+                    let mut causes = Vec::new();
+                    if rebuilt {
+                        causes.push("tileset rebuild");
+                    }
+                    times.emulated(emu.frame_count, update_start.elapsed(), causes);
+                }
+                if let Some(ref mut probe) = latency {
+                    probe.frame_done(Instant::now(), emu.frame_count, emu.mem.input.take_press_read());
+                }
             }
         }
     }
+
+    // Window closed, finish the recording and keep the battery saves
+    if let Some(rec) = emu.recorder.take() {
+        let (path, frames) = (rec.path.clone(), rec.frames);
+        match rec.finish() {
+            Ok(_) => info!("Recorded {} frames to {}", frames, path),
+            Err(err) => error!("Couldn't finish the recording: {}", err),
+        }
+    }
+    emu.flush_save();
+    emu.finish_profile();
+    playlist.flush_saves();
 }
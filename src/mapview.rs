@@ -0,0 +1,134 @@
+//
+//      Background map viewer
+//
+
+// Both 32x32 tile maps, 0x9800 on the left and 0x9C00 on the right, drawn
+// like the background would be and refreshed every frame, shown over the
+// screen with B. The part of the background on screen is outlined in red,
+// wrapping around the edges the way scrolling does, and the part of the
+// window on screen in blue.
+
+use gpu::{Color, Gpu, WIDTH, HEIGHT};
+
+const MAP_SIZE: usize = 256;
+const VIEWPORT_COLOR: Color = [255, 0, 0, 255];
+const WINDOW_COLOR: Color = [0, 96, 255, 255];
+
+pub const DIMS: [u32; 2] = [MAP_SIZE as u32 * 2, MAP_SIZE as u32];
+
+pub struct MapViewer {
+    // RGBA, DIMS in size
+    pub pixels: Vec<u8>,
+}
+
+impl MapViewer {
+    pub fn new() -> MapViewer {
+        MapViewer { pixels: vec![0; (DIMS[0] * DIMS[1] * 4) as usize] }
+    }
+
+    pub fn update(&mut self, gpu: &Gpu) {
+        for map in 0..2 {
+            for tile in 0..32 * 32 {
+                let mapaddr = map_base(map) + tile;
+                let tilei = gpu.vrambank[mapaddr];
+                let attrs = if gpu.is_cgb {gpu.vrambank1[mapaddr]} else {0};
+                let pal = gpu.bg_tile_palette(attrs);
+                for y in 0..8 {
+                    let ty = if attrs & 0x40 != 0 {7 - y} else {y};
+                    let mut row = gpu.tile_row(attrs, gpu.bg_tile_addr(tilei) + ty * 2);
+                    if attrs & 0x20 != 0 {
+                        row.reverse();
+                    }
+                    for (x, &colori) in row.iter().enumerate() {
+                        self.set(map, tile % 32 * 8 + x, tile / 32 * 8 + y, pal[colori as usize]);
+                    }
+                }
+            }
+        }
+
+        let (scx, scy) = gpu.scroll();
+        let bgmap = if gpu.bgbase() == map_base(1) {1} else {0};
+        self.outline(bgmap, scx as usize, scy as usize, WIDTH, HEIGHT, VIEWPORT_COLOR);
+
+        // The window's top left is at (WX - 7, WY), anything left of the
+        // screen is cut off
+        let (wx, wy, shown) = gpu.window();
+        if shown && wx <= 166 && (wy as usize) < HEIGHT {
+            let winmap = if gpu.winbase() == map_base(1) {1} else {0};
+            let left = wx as isize - 7;
+            let skipped = (-left).max(0) as usize;
+            let w = WIDTH - left.max(0) as usize;
+            let h = HEIGHT - wy as usize;
+            self.outline(winmap, skipped, 0, w, h, WINDOW_COLOR);
+        }
+    }
+
+    // What's at a point of the view, in its pixels
+    pub fn describe_at(&self, gpu: &Gpu, x: f64, y: f64) -> Option<String> {
+        if x < 0. || y < 0. || x >= DIMS[0] as f64 || y >= DIMS[1] as f64 {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let mapaddr = map_base(x / MAP_SIZE) + y / 8 * 32 + x % MAP_SIZE / 8;
+        let mut desc = format!("{:04X}  tile {:02X}", 0x8000 + mapaddr, gpu.vrambank[mapaddr]);
+        if gpu.is_cgb {
+            desc.push_str(&format!("  attr {:02X}", gpu.vrambank1[mapaddr]));
+        }
+        Some(desc)
+    }
+
+    fn set(&mut self, map: usize, x: usize, y: usize, color: Color) {
+        let i = (y % MAP_SIZE * DIMS[0] as usize + map * MAP_SIZE + x % MAP_SIZE) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+
+    // A rectangle on a map, wrapping around its edges
+    fn outline(&mut self, map: usize, x: usize, y: usize, w: usize, h: usize, color: Color) {
+        for i in 0..w {
+            self.set(map, x + i, y, color);
+            self.set(map, x + i, y + h - 1, color);
+        }
+        for j in 0..h {
+            self.set(map, x, y + j, color);
+            self.set(map, x + w - 1, y + j, color);
+        }
+    }
+}
+
+// Offset of a map in VRAM
+fn map_base(map: usize) -> usize {
+    if map == 0 {0x1800} else {0x1c00}
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mapview_tests {
+    use super::*;
+
+    fn pixel(viewer: &MapViewer, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * DIMS[0] as usize + x) * 4;
+        [viewer.pixels[i], viewer.pixels[i + 1], viewer.pixels[i + 2], viewer.pixels[i + 3]]
+    }
+
+    #[test]
+    fn viewport() {
+        let mut gpu = Gpu::new();
+        // LCD, BG on, BG map at 0x9C00
+        gpu.wb(0xFF40, 0x99);
+        gpu.wb(0xFF42, 200);
+        gpu.wb(0xFF43, 250);
+        let mut viewer = MapViewer::new();
+        viewer.update(&gpu);
+
+        // The top left corner, then the right edge wrapped around
+        assert_eq!(pixel(&viewer, 256 + 250, 200), VIEWPORT_COLOR);
+        assert_eq!(pixel(&viewer, 256 + (250 + 159) % 256, 210), VIEWPORT_COLOR);
+        assert!(pixel(&viewer, 250, 200) != VIEWPORT_COLOR);
+
+        gpu.vrambank[0x1c00 + 33] = 0x42;
+        assert_eq!(viewer.describe_at(&gpu, 256. + 9., 8.).unwrap(), "9C21  tile 42");
+    }
+}
@@ -20,13 +20,16 @@
 
 #![allow(dead_code)]
 
-use piston::window::Window;
-use piston_window::PistonWindow;
-
 use timer::Timer;
-use gpu::Gpu;
+use serial::Serial;
+use gpu::{Gpu, OamAccess};
 use gpu;
 use input::Input;
+use sgb::{self, Sgb};
+use emulator::Model;
+use heatmap::{Heatmap, Access};
+use breakpoints::{Watchpoints, Watchpoint, WatchKind, WatchHit};
+use emulator::Allowed;
 
 #[derive(PartialEq, Eq, Debug)]
 enum Mbc {
@@ -52,28 +55,77 @@ pub struct Memory {
     pub rom_loaded: Vec<u8>,
 
     pub timer: Box<Timer>,
+    pub serial: Serial,
     pub gpu: Box<Gpu>,
     pub input: Input,
+    pub sgb: Sgb,
+
+    // Some registers only exist on some models
+    pub model: Model,
+
+    // 0xFF72-0xFF75 - Undocumented CGB registers, no known purpose
+    cgb_undoc: [u8; 4],
+
+    // Set while the CPU runs an instruction, every access it makes is a
+    // machine cycle the rest of the hardware is stepped through first, see
+    // tick()
+    pub access_timing: bool,
+    // Cycles stepped that way since take_ticked()
+    ticked: u32,
+    // Cycles the CPU sat still on top of the instruction's own since
+    // take_stalled(), for a general purpose DMA
+    stalled: u32,
+
+    // KEY1 (0xFF4D), CGB only: the speed the CPU runs at (bit 7) and whether
+    // the next STOP switches it (bit 0)
+    pub double_speed: bool,
+    speed_armed: bool,
+    // Odd cycle left over from halving the PPU's share in double speed
+    half_cycle: u32,
 
     mbc: Mbc,
     cart_type: u8,
-    enable_ext_ram: bool,
+    // External (cartridge) RAM, sized from the header. Smaller sizes are
+    // mirrored over the whole 0xA000-0xBFFF window
+    ext_ram: Vec<u8>,
+    // Set by writing 0x0A to 0x0000-0x1FFF, disabled RAM reads 0xFF and
+    // ignores writes
+    ram_enabled: bool,
     is_ram_mode: bool,   // true -> RAM expansion mode, else ROM mode
     rom_bank: u8,
     rom_offset: u16,
     ram_bank: u8,
     ram_offset: u16,
 
+    // Serial bytes sent since the last take_serial()
+    serial_out: Vec<u8>,
+
+    // Access counts, only kept when enabled
+    pub heatmap: Option<Heatmap>,
+
+    // Addresses that stop execution when the CPU touches them, only added
+    // through Emulator::add_watchpoint
+    watchpoints: Watchpoints,
+
     // OAM DMA stuff
     pub is_dma: bool,
     dma_left: usize,
     dma_value: u8,
+    // Cycles towards the next byte copied
+    dma_clock: u32,
+
+    // CGB VRAM DMA (HDMA1-5). Source and destination of the next 16 byte
+    // block, blocks left and whether they're copied one per HBlank
+    hdma_src: u16,
+    hdma_dst: u16,
+    hdma_blocks: u8,
+    hdma_hblank: bool,
 }
 
 impl Memory {
     // Allocate a 64k byte array and zero initialize it
     // This is all the system's RAM
-    pub fn new<W: Window>(window: &PistonWindow<W>) -> Memory {
+    pub fn new() -> Memory {
         let mut mem = Memory {
             if_: 1u8,
             ie_: 0u8,
@@ -81,21 +133,43 @@ impl Memory {
             rom_loaded: Vec::new(),
 
             timer: Box::new(Timer::new()),
-            gpu: Box::new(Gpu::new(window)),
+            serial: Serial::new(),
+            gpu: Box::new(Gpu::new()),
             input: Input::new(),
+            sgb: Sgb::new(),
+
+            model: Model::Dmg,
+            cgb_undoc: [0; 4],
+            access_timing: false,
+            ticked: 0,
+            stalled: 0,
+            double_speed: false,
+            speed_armed: false,
+            half_cycle: 0,
 
             mbc: Mbc::Unknown,
             cart_type: 0,
-            enable_ext_ram: false,
+            ext_ram: Vec::new(),
+            ram_enabled: false,
             is_ram_mode: false,
             rom_bank: 0,
             rom_offset: 0x4000,
             ram_bank: 0,
             ram_offset: 0x0000,
 
+            serial_out: Vec::new(),
+
+            heatmap: None,
+            watchpoints: Watchpoints::new(),
+
             is_dma: false,
             dma_left: 0,
             dma_value: 0,
+            dma_clock: 0,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_blocks: 0,
+            hdma_hblank: false,
         };
         mem.power_on();
         mem.timer.reset_bios_skip();
@@ -163,6 +237,57 @@ impl Memory {
     //  self.rom_header = Some(header);
     // }
 
+    // Cartridges that keep their RAM when turned off
+    pub fn has_battery(&self) -> bool {
+        match self.cart_type {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 |
+            0x17 | 0x1B | 0x1E | 0xFF => true,
+            _ => false,
+        }
+    }
+
+    pub fn take_serial(&mut self) -> Vec<u8> {
+        ::std::mem::replace(&mut self.serial_out, Vec::new())
+    }
+
+    // ROM bank the CPU sees at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> u8 {
+        (self.rom_offset / 0x4000) as u8
+    }
+
+    // A byte of a ROM bank whether it's mapped or not, 0xFF past the end of
+    // the rom. Bank 0 is 0x0000-0x3FFF, the others go at 0x4000-0x7FFF
+    pub fn rom_byte(&self, bank: u8, addr: u16) -> u8 {
+        let i = bank as usize * 0x4000 + (addr & 0x3FFF) as usize;
+        self.rom_loaded.get(i).cloned().unwrap_or(0xFF)
+    }
+
+    // Work RAM (0xC000-0xDFFF)
+    pub fn wram(&self) -> &[u8] {
+        &self.raw_mem[0xC000..0xE000]
+    }
+
+    // External (cartridge) RAM, for battery saves
+    pub fn ext_ram(&self) -> &[u8] {
+        &self.ext_ram
+    }
+    pub fn load_ext_ram(&mut self, data: &[u8]) {
+        if data.len() != self.ext_ram.len() {
+            warn!("Save is {} bytes, cartridge RAM is {} bytes", data.len(), self.ext_ram.len());
+        }
+        let len = data.len().min(self.ext_ram.len());
+        self.ext_ram[..len].copy_from_slice(&data[..len]);
+    }
+    pub fn set_ext_ram_size(&mut self, size: usize) {
+        info!("External RAM: {} bytes", size);
+        self.ext_ram = vec![0; size];
+    }
+
+    // Index into ext_ram for an address in 0xA000-0xBFFF
+    fn ext_ram_index(&self, addr: u16) -> usize {
+        (self.ram_offset as usize + (addr & 0x1FFF) as usize) % self.ext_ram.len()
+    }
+
     pub fn get_timers(&self) -> &Timer {
         &self.timer.as_ref()
     }
@@ -206,10 +331,45 @@ impl Memory {
     // Public members
 
     // Read Byte
-    // TODO: add 4 to total_cycles for cycle accuracy (not that simple)
     pub fn rb(&mut self, addr: u16) -> u8 {
+        self.read(addr, OamAccess::Read)
+    }
+
+    // ld a,(hl+) and ld a,(hl-), which garble OAM differently
+    pub fn rb_inc_dec(&mut self, addr: u16) -> u8 {
+        self.read(addr, OamAccess::ReadIncDec)
+    }
+
+    fn read(&mut self, addr: u16, access: OamAccess) -> u8 {
         //self.debug_print_addr(addr, true);
-        //self.timer.step(4, &mut self.if_);
+        self.access_tick();
+        if let Some(ref mut heat) = self.heatmap {
+            heat.count(Access::Read, addr);
+        }
+        // The DMA has the bus, only HRAM (and the I/O registers) can be used
+        if self.is_dma && addr < 0xFF00 {
+            return 0xFF;
+        }
+        self.gpu.corrupt_oam(addr, access);
+        let val = if self.locked(addr) { 0xFF } else { self.peek(addr) };
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.access(addr, false, val, val);
+        }
+        val
+    }
+
+    // The PPU has VRAM to itself in mode 3 and OAM in modes 2 and 3. Only
+    // the CPU is locked out, peek() and poke() get through
+    fn locked(&self, addr: u16) -> bool {
+        match addr {
+            0x8000 ... 0x9FFF => self.gpu.vram_locked(),
+            0xFE00 ... 0xFE9F => self.gpu.oam_locked(),
+            _ => false,
+        }
+    }
+
+    // Read a byte without counting it as an access
+    pub fn peek(&self, addr: u16) -> u8 {
         match addr {
             // ROM (switched bank)
             0x4000 ... 0x7FFF => {
@@ -221,13 +381,14 @@ impl Memory {
             // VRAM so let the gpu handle it
             0x8000 ... 0x9FFF => self.gpu.rb_vram(addr),
             // External RAM
-            0xA000 ... 0xBFFF => if self.enable_ext_ram {
-                self.read_byte_raw(self.ram_offset + (addr & 0x1FFF))
+            0xA000 ... 0xBFFF => if self.ram_enabled && !self.ext_ram.is_empty() {
+                self.ext_ram[self.ext_ram_index(addr)]
                 } else {
                     0xFF
                 },
             // Mirrored memory
             0xE000 ... 0xFDFF => self.read_byte_raw(addr - 0x2000),
+            0xFE00 ... 0xFE9F => self.gpu.rb_oam(addr),
             0xFEA0 ... 0xFEFF => 0xFF, // { warn!("Unusable memory accessed"); 0xFF },
             0xFF00 ... 0xFF79 => self.ioreg_rb(addr),
 
@@ -245,21 +406,37 @@ impl Memory {
 
     // Read word
     pub fn rw(&mut self, addr: u16) -> u16 {
-        assert!(addr <= 0xFFFF - 1,
-         "Invalid memory read: {:04X}", addr);
-
         (self.rb(addr) as u16) |
-        (self.rb(addr + 1) as u16) << 8
+        (self.rb(addr.wrapping_add(1)) as u16) << 8
     }
 
     // Write byte
     pub fn wb(&mut self, addr: u16, data: u8) {
         //self.debug_print_addr(addr, false);
-        //self.timer.step(4, &mut self.if_);
+        self.access_tick();
+        if let Some(ref mut heat) = self.heatmap {
+            heat.count(Access::Write, addr);
+        }
+        if self.is_dma && addr < 0xFF00 {
+            return;
+        }
+        if !self.watchpoints.is_empty() {
+            let old = self.peek(addr);
+            self.watchpoints.access(addr, true, old, data);
+        }
+        self.gpu.corrupt_oam(addr, OamAccess::Write);
+        if self.locked(addr) {
+            return;
+        }
+        self.poke(addr, data);
+    }
+
+    // Write a byte without counting it as an access
+    pub fn poke(&mut self, addr: u16, data: u8) {
         match addr {
             // Enable external RAM if 0x0A was writtten. Disable it otherwise
-            0x0000 ... 0x1FFF => if self.cart_type == 2 || self.cart_type == 3 {
-                self.enable_ext_ram = data & 0x0F == 0x0A;
+            0x0000 ... 0x1FFF => if self.mbc != Mbc::RomOnly {
+                self.ram_enabled = data & 0x0F == 0x0A;
             },
             // Switch ROM bank
             0x2000 ... 0x3FFF => {
@@ -303,11 +480,13 @@ impl Memory {
             // 0: ROM mode (no RAM banks, up to 2MB ROM)
             // 1: RAM mode (4 RAM banks, up to 512kB ROM)
             0x6000 ... 0x7FFF => self.is_ram_mode = data & 1 == 1,
-            0xA000 ... 0xBFFF => if self.enable_ext_ram {
-                self.write_byte_raw(addr, data);
+            0xA000 ... 0xBFFF => if self.ram_enabled && !self.ext_ram.is_empty() {
+                let i = self.ext_ram_index(addr);
+                self.ext_ram[i] = data;
             },
             // Mirrored memory
             0xE000 ... 0xFDFF => self.write_byte_raw(addr - 0x2000, data),
+            0xFE00 ... 0xFE9F => self.gpu.wb_oam(addr, data),
             0xFEA0 ... 0xFEFF => debug!("Unusable memory written to"),
             // VRAM so let the gpu handle it
             0x8000 ... 0x9FFF => self.gpu.wb_vram(addr, data),
@@ -325,13 +504,63 @@ impl Memory {
         }
     }
 
+    fn has_key1(&self) -> bool {
+        self.model == Model::Cgb && !self.gpu.dmg_compat()
+    }
+
+    // STOP was executed, switch speed if KEY1 was armed. Returns whether it
+    // did
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.has_key1() || !self.speed_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_armed = false;
+        info!("Switched to {} speed", if self.double_speed {"double"} else {"normal"});
+        true
+    }
+
     // Write word
     pub fn ww(&mut self, addr: u16, data: u16) {
-        assert!(addr <= 0xFFFF - 1,
-         "Invalid memory write: {:04X}", addr);
-
         self.wb(addr, data as u8);
-        self.wb(addr + 1, (data >> 8) as u8);
+        self.wb(addr.wrapping_add(1), (data >> 8) as u8);
+    }
+
+    // 16 bit INC and DEC put the register on the address bus, which is
+    // enough for the OAM bug
+    pub fn inc_dec_16(&mut self, addr: u16) {
+        self.gpu.corrupt_oam(addr, OamAccess::Write);
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints.list
+    }
+
+    // Hardcore mode rules watchpoints out, the check comes first
+    pub fn add_watchpoint(&mut self, _: Allowed, kind: WatchKind, start: u16, end: u16) -> Result<usize, String> {
+        self.watchpoints.add(kind, start, end)
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(index)
+    }
+
+    // The instruction at pc is done, returns whether it set a watchpoint off
+    pub fn retire_watchpoints(&mut self, pc: u16) -> bool {
+        self.watchpoints.retire(pc)
+    }
+
+    pub fn watch_hit(&self) -> Option<&WatchHit> {
+        self.watchpoints.peek_hit()
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watchpoints.take_hit()
+    }
+
+    // A machine cycle the CPU spends without touching the bus
+    pub fn idle(&mut self) {
+        self.access_tick();
     }
 
     /// Reads a value from a known IO type register
@@ -343,6 +572,8 @@ impl Memory {
                 match addr & 0xF {
                     // TODO: Input
                     0x0 => self.input.rb(),
+                    0x1 => self.serial.sb,
+                    0x2 => self.serial.rb_sc(),
                     0x4 => (self.timer.div >> 8) as u8,
                     0x5 => self.timer.tima,
                     0x6 => self.timer.tma,
@@ -355,13 +586,51 @@ impl Memory {
             // Video I/O Registers (0xFF4x)
             0x4 => {
                 match addr & 0xF {
-                    0...5 | 7...0xB | 0xF => {
+                    0...5 | 7...0xC | 0xF => {
                         //debug!("gpu_rb {:x}", addr);
                         self.gpu.rb(addr)
                     },
+                    // DMA reads back the last source written
+                    6 => self.dma_value,
+                    0xD if self.has_key1() => {
+                        (self.double_speed as u8) << 7 | 0x7E | self.speed_armed as u8
+                    }
                     _ => 0xFF//self.read_byte_raw(addr),
                 }
             }
+            // VRAM DMA (0xFF5x)
+            0x5 => {
+                match addr & 0xF {
+                    0x5 if self.gpu.is_cgb => self.rb_hdma5(),
+                    _ => 0xFF,
+                }
+            }
+            // CGB registers (0xFF6x)
+            0x6 => {
+                match addr & 0xF {
+                    // BCPS/BCPD/OCPS/OCPD, OPRI
+                    0x8...0xC => self.gpu.rb(addr),
+                    _ => 0xFF,
+                }
+            }
+            // CGB registers (0xFF7x)
+            0x7 => {
+                if self.model != Model::Cgb {
+                    return 0xFF;
+                }
+                match addr & 0xF {
+                    0x2 | 0x3 => self.cgb_undoc[(addr & 0xF) as usize - 2],
+                    // Only available outside of DMG compatibility mode
+                    0x4 if !self.gpu.dmg_compat() => self.cgb_undoc[2],
+                    // Only bits 4-6 can be used
+                    0x5 => self.cgb_undoc[3] | 0x8F,
+                    // PCM12/PCM34 - Current 4-bit output of channels 1/2 and
+                    // 3/4 (low/high nibble). There's no APU yet, so every
+                    // channel is silent
+                    0x6 | 0x7 => 0x00,
+                    _ => 0xFF,
+                }
+            }
             _ => 0xFF//self.read_byte_raw(addr),
         }
     }
@@ -377,11 +646,23 @@ impl Memory {
             // I/O Ports (0xFF0x)
             0x0 => {
                 match addr & 0xF {
-                    0x0 => self.input.wb(data),
+                    0x0 => {
+                        self.input.wb(data);
+                        if self.sgb.enabled {
+                            if let Some(cmd) = self.sgb.wb(data) {
+                                self.sgb_command(&cmd);
+                            }
+                        }
+                    }
                     0x1 => {
+                        self.serial.sb = data;
+                        // Test roms print through here, a device talks its own protocol
+                        if self.serial.device.is_some() {
+                            return;
+                        }
                         info!("Serial data transfer in address {:04X}, data {}", addr, data as char);
 
-                        // TODO: Maybe open at constructor like trace_log.txt
+                        // TODO: Maybe open it once in the constructor
                         // Open a file in write-only mode, returns `io::Result<File>`
                         let mut file = OpenOptions::new()
                             .append(true)
@@ -390,8 +671,9 @@ impl Memory {
                             .unwrap();
 
                         file.write(&[data]).unwrap();
+                        self.serial_out.push(data);
                     }
-                    0x2 => {/* Serial transfer start */}
+                    0x2 => { self.serial.wb_sc(data); }
                     0x4 => { self.timer.div = 0; }
                     0x5 => { self.timer.tima = data; }
                     0x6 => { self.timer.tma = data; }
@@ -409,7 +691,7 @@ impl Memory {
             // Video I/O Registers (0xFF4x)
             0x4 => {
                 match addr & 0xF {
-                    0...3 | 5 | 7...0xB => {
+                    0...3 | 5 | 7...0xC | 0xF => {
                         let dt = self.gpu.wb(addr, data);
                         //debug!("gpu_wb {:x} {:x}", addr, data);
                         dt
@@ -417,33 +699,67 @@ impl Memory {
                     // Write to LY normally resets it, but it leads
                     // to challenging timings so just do nothing
                     4 => {},
-                    6 => {
-                            self.start_dma_transfer(data);
-                            self.timer.step(4, &mut self.if_);
-                        }
+                    6 => self.start_dma_transfer(data),
+                    0xD if self.has_key1() => self.speed_armed = data & 1 != 0,
                     _ => self.write_byte_raw(addr, data)
                 }
             }
+            // VRAM DMA (0xFF5x)
+            0x5 if self.gpu.is_cgb => {
+                match addr & 0xF {
+                    0x1 => self.hdma_src = (data as u16) << 8 | self.hdma_src & 0xFF,
+                    0x2 => self.hdma_src = self.hdma_src & 0xFF00 | (data & 0xF0) as u16,
+                    0x3 => self.hdma_dst = ((data & 0x1F) as u16) << 8 | self.hdma_dst & 0xFF,
+                    0x4 => self.hdma_dst = self.hdma_dst & 0xFF00 | (data & 0xF0) as u16,
+                    0x5 => self.wb_hdma5(data),
+                    _ => self.write_byte_raw(addr, data),
+                }
+            }
+            // CGB registers (0xFF6x)
+            0x6 if addr & 0xF >= 0x8 && addr & 0xF <= 0xC => self.gpu.wb(addr, data),
+            // CGB registers (0xFF7x)
+            0x7 if self.model == Model::Cgb => {
+                match addr & 0xF {
+                    0x2 | 0x3 => self.cgb_undoc[(addr & 0xF) as usize - 2] = data,
+                    0x4 if !self.gpu.dmg_compat() => self.cgb_undoc[2] = data,
+                    0x5 => self.cgb_undoc[3] = data & 0x70,
+                    // PCM12/PCM34 are read-only
+                    0x4 | 0x6 | 0x7 => {},
+                    _ => self.write_byte_raw(addr, data),
+                }
+            }
             _ => {
                 self.write_byte_raw(addr, data);
             }
         }
     }
 
+    fn sgb_command(&mut self, cmd: &[u8]) {
+        // VRAM transfers pick up 4KB of tile data from the screen
+        let start = if self.gpu.tiledata {0} else {0x800};
+        if let Some(ref mut colors) = self.gpu.sgb {
+            if self.sgb.color_command(cmd, &self.gpu.vrambank[start..start + 0x1000], colors) {
+                return;
+            }
+        }
+        match cmd[0] >> 3 {
+            sgb::MLT_REQ => {
+                let players = match cmd[1] & 3 { 1 => 2, 3 => 4, _ => 1 };
+                self.input.set_players(players);
+            }
+            sgb::SOUND => self.sgb.sound(cmd),
+            sgb::SOU_TRN => {
+                self.sgb.sou_trn(&self.gpu.vrambank[start..start + 0x1000]);
+            }
+            code => debug!("Unhandled SGB command {:02X}", code),
+        }
+    }
+
     pub fn find_mbc(&mut self, cartridge_type: u8) {
         self.cart_type = cartridge_type;
 
-        match cartridge_type {
-            2 | 3 | 8 | 9 | 0xC | 0xD | 0x10 |
-            0x12 | 0x13 | 0x16 | 0x17 | 0x1A |
-            0x1B | 0x1D | 0x1E | 0xFF => {
-                self.enable_ext_ram = true;
-            }
-            _ => {}
-        };
-
         self.mbc = match cartridge_type {
-            0x00 => Mbc::RomOnly,
+            0x00 | 0x08 | 0x09 => Mbc::RomOnly,
             0x01 ... 0x03 => Mbc::Mbc1,
             0x05 ... 0x06 => Mbc::Mbc2,
             0x0F ... 0x13 => Mbc::Mbc3,
@@ -458,7 +774,20 @@ impl Memory {
             Mbc::RomOnly | Mbc::Mbc1 => {},
             _ => panic!("Unsupported MBC: {:?}", self.mbc),
         };
-        info!("Mbc: {:?}. External RAM: {}", self.mbc, self.enable_ext_ram);
+
+        // Without an MBC there's nothing to enable the RAM with
+        self.ram_enabled = self.mbc == Mbc::RomOnly;
+        info!("Mbc: {:?}. External RAM: {}", self.mbc, self.cart_has_ram());
+    }
+
+    // Cartridge types that come with external RAM
+    pub fn cart_has_ram(&self) -> bool {
+        match self.cart_type {
+            2 | 3 | 8 | 9 | 0xC | 0xD | 0x10 |
+            0x12 | 0x13 | 0x16 | 0x17 | 0x1A |
+            0x1B | 0x1D | 0x1E | 0xFF => true,
+            _ => false,
+        }
     }
 
 
@@ -492,25 +821,138 @@ impl Memory {
         self.is_dma = true;
         self.dma_left = gpu::OAM_SIZE;
         self.dma_value = val;
+        self.dma_clock = 0;
+    }
 
-        self.timer.step(4, &mut self.if_);
+    // HDMA5 - blocks left minus one, bit 7 set when no HBlank DMA is running.
+    // 0xFF once everything was copied
+    fn rb_hdma5(&self) -> u8 {
+        let left = self.hdma_blocks.wrapping_sub(1) & 0x7F;
+        if self.hdma_hblank { left } else { 0x80 | left }
     }
 
-    pub fn handle_dma_transfer(&mut self) {
-        self.dma_left -= 1;
-        if self.dma_left == 0 {
-            self.is_dma = false;
+    // Bit 7 clear copies all the blocks right away (general purpose DMA), set
+    // copies one every HBlank. Clearing it while an HBlank DMA runs stops it
+    fn wb_hdma5(&mut self, data: u8) {
+        if self.hdma_hblank && data & 0x80 == 0 {
+            self.hdma_hblank = false;
+            return;
+        }
+        self.hdma_blocks = (data & 0x7F) + 1;
+        self.hdma_hblank = data & 0x80 != 0;
+        debug!("VRAM DMA of {} bytes from {:04X} to {:04X}{}", self.hdma_blocks as u16 * 16,
+            self.hdma_src, 0x8000 | self.hdma_dst, if self.hdma_hblank {" during HBlank"} else {""});
+        // The CPU is stopped while it's copying, 8 machine cycles a block,
+        // twice as many of the faster ones in double speed
+        let block_cycles = if self.double_speed {16} else {8};
+        while !self.hdma_hblank && self.hdma_blocks > 0 {
+            self.hdma_block();
+            for _ in 0..block_cycles {
+                self.access_tick();
+            }
+            self.stalled += block_cycles * 4;
         }
-        // TODO: OPTIMIZE (compute above)
-        let high_byte = (self.dma_value as u16) << 8;
-        let low_byte = gpu::OAM_SIZE - self.dma_left -1;
+    }
 
-        self.gpu.oam[low_byte] = self.rb(high_byte | low_byte as u16 );
+    // Copy the next 16 bytes of a VRAM DMA to the selected VRAM bank
+    fn hdma_block(&mut self) {
+        for i in 0..16 {
+            let data = self.peek(self.hdma_src.wrapping_add(i));
+            self.gpu.wb_vram(0x8000 | (self.hdma_dst + i) & 0x1FFF, data);
+        }
+        self.hdma_src = self.hdma_src.wrapping_add(16);
+        self.hdma_dst = (self.hdma_dst + 16) & 0x1FF0;
+        self.hdma_blocks -= 1;
+        if self.hdma_blocks == 0 {
+            self.hdma_hblank = false;
+        }
+    }
+
+    // Step everything but the CPU
+    pub fn tick(&mut self, cycles: u32) {
+        self.tick_timers(cycles);
+        self.tick_video(cycles);
+    }
+
+    // Split in two so the emulator's profile can time them apart
+    pub fn tick_timers(&mut self, cycles: u32) {
+        self.timer.step(cycles, &mut self.if_);
+        self.serial.step(cycles, &mut self.if_);
+        self.step_dma(cycles);
+    }
+
+    // An M-cycle at a time, the PPU only moves on a line per step and a long
+    // stall like the speed switch would skip lines otherwise
+    pub fn tick_video(&mut self, cycles: u32) {
+        let mut left = cycles;
+        while left > 0 {
+            let chunk = left.min(4);
+            left -= chunk;
+            let ppu = self.ppu_cycles(chunk);
+            self.gpu.step(ppu, &mut self.if_);
+            self.step_hdma();
+        }
+    }
 
-        self.timer.step(4, &mut self.if_);
+    // The timer, serial port and OAM DMA run off the CPU clock and speed up
+    // with it in double speed, the PPU keeps its own rate and sees half the
+    // cycles
+    pub fn ppu_cycles(&mut self, cycles: u32) -> u32 {
+        if !self.double_speed {
+            return cycles;
+        }
+        let total = cycles + self.half_cycle;
+        self.half_cycle = total & 1;
+        total / 2
+    }
 
-        // println!("{:04X} becomes {:02X}",
-        // high_byte | low_byte as u16, self.rb(high_byte | low_byte as u16));
+    fn access_tick(&mut self) {
+        if self.access_timing {
+            self.tick(4);
+            self.ticked += 4;
+        }
+    }
+
+    // Cycles of the instruction already stepped through by its accesses,
+    // the rest are internal ones still to step
+    pub fn take_ticked(&mut self) -> u32 {
+        let ticked = self.ticked;
+        self.ticked = 0;
+        ticked
+    }
+
+    // Cycles of the instruction the CPU was stalled for, already stepped
+    // through like accesses
+    pub fn take_stalled(&mut self) -> u32 {
+        let stalled = self.stalled;
+        self.stalled = 0;
+        stalled
+    }
+
+    // Called after the GPU steps, an HBlank DMA copies a block every HBlank
+    pub fn step_hdma(&mut self) {
+        if self.gpu.take_hblank() && self.hdma_hblank {
+            self.hdma_block();
+        }
+    }
+
+    // Copies a byte from XX00-XX9F to OAM every machine cycle, 160 cycles
+    // for all of it
+    pub fn step_dma(&mut self, cycles: u32) {
+        if !self.is_dma {
+            return;
+        }
+        self.dma_clock += cycles;
+        while self.dma_clock >= 4 && self.is_dma {
+            self.dma_clock -= 4;
+            let i = gpu::OAM_SIZE - self.dma_left;
+            let val = self.peek((self.dma_value as u16) << 8 | i as u16);
+            self.gpu.dma_oam(i, val);
+            self.dma_left -= 1;
+            if self.dma_left == 0 {
+                self.is_dma = false;
+            }
+        }
     }
 }
 
@@ -534,4 +976,246 @@ mod mem_tests {
         assert_eq!(mem.read_byte_raw(0x8006), 0x56);
         assert_eq!(mem.read_byte_raw(0x8007), 0x78);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn access_tick() {
+        let mut mem: Memory = Memory::new();
+        mem.timer.div = 0x00FC;
+        assert_eq!(mem.rb(0xFF04), 0);
+
+        // The machine cycle of the read happens before it
+        mem.access_timing = true;
+        assert_eq!(mem.rb(0xFF04), 1);
+        mem.wb(0xC000, 0);
+        assert_eq!(mem.take_ticked(), 8);
+        assert_eq!(mem.take_ticked(), 0);
+    }
+
+    #[test]
+    fn cartridge_ram() {
+        let mut mem: Memory = Memory::new();
+        mem.find_mbc(0x03);
+        mem.set_ext_ram_size(0x8000);
+
+        // Off until 0x0A is written to 0000-1FFF
+        mem.wb(0xA000, 0x11);
+        assert_eq!(mem.rb(0xA000), 0xFF);
+        mem.wb(0x0000, 0x0A);
+        assert_eq!(mem.rb(0xA000), 0x00);
+        mem.wb(0xA000, 0x11);
+        assert_eq!(mem.rb(0xA000), 0x11);
+        mem.wb(0x1FFF, 0x00);
+        assert_eq!(mem.rb(0xA000), 0xFF);
+        mem.wb(0x0000, 0x0A);
+        mem.wb(0x0000, 0x0B);
+        assert_eq!(mem.rb(0xA000), 0xFF);
+
+        // Banks in RAM mode
+        mem.wb(0x0000, 0x0A);
+        mem.wb(0x6000, 0x01);
+        mem.wb(0x4000, 0x02);
+        assert_eq!(mem.rb(0xA000), 0x00);
+        mem.wb(0xA000, 0x22);
+        mem.wb(0x4000, 0x00);
+        assert_eq!(mem.rb(0xA000), 0x11);
+        assert_eq!(mem.ext_ram()[0x4000], 0x22);
+
+        // 2KB carts repeat it over the 8KB window
+        let mut mem: Memory = Memory::new();
+        mem.find_mbc(0x03);
+        mem.set_ext_ram_size(0x800);
+        mem.wb(0x0000, 0x0A);
+        mem.wb(0xA001, 0x33);
+        assert_eq!(mem.rb(0xA801), 0x33);
+        assert_eq!(mem.rb(0xB801), 0x33);
+        mem.wb(0xBFFF, 0x44);
+        assert_eq!(mem.ext_ram()[0x7FF], 0x44);
+    }
+
+    #[test]
+    fn key1() {
+        let mut mem: Memory = Memory::new();
+        // Not on the DMG
+        mem.wb(0xFF4D, 0x01);
+        assert_eq!(mem.rb(0xFF4D), 0xFF);
+        assert!(!mem.switch_speed());
+
+        mem.model = Model::Cgb;
+        assert_eq!(mem.rb(0xFF4D), 0x7E);
+        mem.wb(0xFF4D, 0x01);
+        assert_eq!(mem.rb(0xFF4D), 0x7F);
+        assert!(mem.switch_speed());
+        assert_eq!(mem.rb(0xFF4D), 0xFE);
+        // Only once per write
+        assert!(!mem.switch_speed());
+    }
+
+    #[test]
+    fn pcm() {
+        let mut mem: Memory = Memory::new();
+        // CGB only
+        assert_eq!(mem.rb(0xFF76), 0xFF);
+        assert_eq!(mem.rb(0xFF77), 0xFF);
+
+        // Silent channels, and writes don't stick
+        mem.model = Model::Cgb;
+        assert_eq!(mem.rb(0xFF76), 0x00);
+        assert_eq!(mem.rb(0xFF77), 0x00);
+        mem.wb(0xFF76, 0x12);
+        mem.wb(0xFF77, 0x34);
+        assert_eq!(mem.rb(0xFF76), 0x00);
+        assert_eq!(mem.rb(0xFF77), 0x00);
+    }
+
+    #[test]
+    fn cgb_undocumented() {
+        let mut mem: Memory = Memory::new();
+        // Nothing on the DMG
+        mem.wb(0xFF72, 0x12);
+        assert_eq!(mem.rb(0xFF72), 0xFF);
+
+        mem.model = Model::Cgb;
+        mem.gpu.init_cgb_mode(false);
+        for addr in 0xFF72..0xFF76 {
+            mem.wb(addr, 0xFF);
+        }
+        assert_eq!(mem.rb(0xFF72), 0xFF);
+        assert_eq!(mem.rb(0xFF73), 0xFF);
+        assert_eq!(mem.rb(0xFF74), 0xFF);
+        assert_eq!(mem.rb(0xFF75), 0xFF);
+        for addr in 0xFF72..0xFF76 {
+            mem.wb(addr, 0x00);
+        }
+        assert_eq!(mem.rb(0xFF72), 0x00);
+        assert_eq!(mem.rb(0xFF73), 0x00);
+        assert_eq!(mem.rb(0xFF74), 0x00);
+        // Only bits 4-6 are there
+        assert_eq!(mem.rb(0xFF75), 0x8F);
+        mem.wb(0xFF75, 0x5A);
+        assert_eq!(mem.rb(0xFF75), 0xDF);
+
+        // FF74 is locked in DMG compatibility mode
+        let mut mem: Memory = Memory::new();
+        mem.model = Model::Cgb;
+        mem.gpu.init_cgb_mode(true);
+        mem.wb(0xFF74, 0x00);
+        assert_eq!(mem.rb(0xFF74), 0xFF);
+        mem.wb(0xFF72, 0x00);
+        assert_eq!(mem.rb(0xFF72), 0x00);
+    }
+
+    #[test]
+    fn vram_locked_in_mode3() {
+        let mut mem: Memory = Memory::new();
+        mem.wb(0x8000, 1);
+        mem.wb(0xFE00, 2);
+        mem.wb(0xFF40, 0x80);
+        while mem.peek(0xFF41) & 0x03 != 3 {
+            mem.tick(4);
+        }
+
+        // The CPU is locked out of both
+        assert_eq!((mem.rb(0x8000), mem.rb(0xFE00)), (0xFF, 0xFF));
+        mem.wb(0x8000, 3);
+        mem.wb(0xFE00, 4);
+
+        // The debugger isn't
+        assert_eq!((mem.peek(0x8000), mem.peek(0xFE00)), (1, 2));
+        mem.poke(0x8001, 5);
+        mem.poke(0xFE01, 6);
+        assert_eq!((mem.peek(0x8001), mem.peek(0xFE01)), (5, 6));
+    }
+
+    #[test]
+    fn double_speed() {
+        let mut mem: Memory = Memory::new();
+        mem.model = Model::Cgb;
+        let (clock, div) = (mem.gpu.clock, mem.timer.div);
+        mem.tick(20);
+        assert_eq!(mem.gpu.clock - clock, 20);
+        assert_eq!(mem.timer.div.wrapping_sub(div), 20);
+
+        mem.wb(0xFF4D, 0x01);
+        assert!(mem.switch_speed());
+        let (clock, div) = (mem.gpu.clock, mem.timer.div);
+        mem.tick(20);
+        assert_eq!(mem.gpu.clock - clock, 10);
+        assert_eq!(mem.timer.div.wrapping_sub(div), 20);
+        // Odd cycles carry over
+        mem.tick(3);
+        mem.tick(3);
+        assert_eq!(mem.gpu.clock - clock, 13);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut mem: Memory = Memory::new();
+        for i in 0..0xA0 {
+            mem.wb(0xC100 + i, i as u8 + 1);
+        }
+        mem.wb(0xFF80, 0x42);
+
+        mem.wb(0xFF46, 0xC1);
+        assert_eq!(mem.rb(0xFF46), 0xC1);
+        // Only HRAM is reachable until it's done
+        assert_eq!(mem.rb(0xC100), 0xFF);
+        assert_eq!(mem.rb(0xFF80), 0x42);
+        // The debugger isn't held up by it, nor does it step anything
+        mem.access_timing = true;
+        mem.poke(0xC200, 0x99);
+        assert_eq!(mem.peek(0xC200), 0x99);
+        assert_eq!(mem.take_ticked(), 0);
+        mem.access_timing = false;
+
+        mem.step_dma(4 * 10);
+        assert_eq!(mem.gpu.oam[9], 10);
+        assert_eq!(mem.gpu.oam[10], 0);
+        mem.step_dma(4 * 150);
+        assert!(!mem.is_dma);
+        assert_eq!(mem.gpu.oam[0x9F], 0xA0);
+        assert_eq!(mem.rb(0xC100), 1);
+    }
+
+    #[test]
+    fn vram_dma() {
+        let mut mem: Memory = Memory::new();
+        mem.gpu.init_cgb_mode(false);
+        for i in 0..0x40 {
+            mem.wb(0xC000 + i, i as u8 + 1);
+        }
+        mem.wb(0xFF51, 0xC0);
+        mem.wb(0xFF52, 0x00);
+        mem.wb(0xFF53, 0x01);
+        mem.wb(0xFF54, 0x00);
+
+        // General purpose, 2 blocks to bank 1 at once. The CPU waits for it
+        mem.wb(0xFF4F, 1);
+        mem.access_timing = true;
+        let div = mem.timer.div;
+        mem.wb(0xFF55, 0x01);
+        mem.access_timing = false;
+        assert_eq!(mem.take_stalled(), 2 * 32);
+        assert_eq!(mem.take_ticked(), 4 + 2 * 32);
+        assert_eq!(mem.timer.div.wrapping_sub(div), 4 + 2 * 32);
+        assert_eq!(mem.rb(0x8100), 1);
+        assert_eq!(mem.rb(0x811F), 0x20);
+        assert_eq!(mem.rb(0xFF55), 0xFF);
+        mem.wb(0xFF4F, 0);
+        assert_eq!(mem.rb(0x8100), 0);
+
+        // HBlank, carries on where the last one stopped
+        mem.wb(0xFF55, 0x81);
+        assert_eq!(mem.rb(0xFF55), 0x01);
+        let mut if_ = 0;
+        for _ in 0..456 / 4 {
+            mem.gpu.step(4, &mut if_);
+            mem.step_hdma();
+        }
+        assert_eq!(mem.rb(0xFF55), 0x00);
+        assert_eq!(mem.rb(0x8120), 0x21);
+        assert_eq!(mem.rb(0x8130), 0);
+        // Stopped with a block left
+        mem.wb(0xFF55, 0x00);
+        assert_eq!(mem.rb(0xFF55), 0x80);
+    }
+}
@@ -0,0 +1,721 @@
+//
+//      Mobile Adapter GB
+//
+
+// The cable that connected a Game Boy to a Japanese mobile phone, used by
+// Pokémon Crystal JP and a handful of other games to go online. Games speak a
+// packet protocol over the serial port, the adapter did the dialing and the
+// PPP/TCP part itself. Here the TCP connections games open are real ones and
+// DNS queries go to the system resolver, or all to one server when given, as
+// the Nintendo servers are long gone and community ones stand in for them.
+// HTTP, POP and SMTP are spoken by the games themselves over those connections.
+//
+// Packets, both ways:
+//   0x99 0x66, command, 0x00, 0x00, data length, data, 16 bit sum of the
+//   header and data, then the sender sends its device id and the receiver
+//   its own, then the sender 0x00 and the receiver command ^ 0x80 as ack.
+// The adapter answers every command with a packet of the same command | 0x80,
+// or 0x6E (error) with the command and an error code. While idle each side
+// sends a filler byte, 0x4B from the Game Boy and 0xD2 from the adapter.
+//
+// Only the 8 bit serial mode and calls to the ISP are emulated, calls to
+// another Game Boy connect nowhere.
+//
+// Each connection's socket lives on a thread of its own so connecting and
+// sending never hold up the emulation, and so does each DNS lookup. The game
+// waits for the reply to opening a connection or to a query anyway, the
+// adapter just keeps sending filler until then.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use saves;
+use serial::SerialDevice;
+
+const CONFIG_SIZE: usize = 0xC0;
+
+const MAGIC: [u8; 2] = [0x99, 0x66];
+// The blue adapter, the one for PDC phones
+const ADAPTER_ID: u8 = 0x88;
+const IDLE: u8 = 0xD2;
+const MAX_DATA: usize = 254;
+const MAX_CONNECTIONS: usize = 2;
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+// How long a connection's thread waits for data before checking for some
+// to send
+const POLL_MS: u64 = 10;
+
+// Connection id of the telephone line itself
+const LINE: u8 = 0xFF;
+
+const ACK_BAD_CHECKSUM: u8 = 0xF1;
+const ACK_UNKNOWN_COMMAND: u8 = 0xF0;
+
+const BEGIN_SESSION: u8 = 0x10;
+const END_SESSION: u8 = 0x11;
+const DIAL: u8 = 0x12;
+const HANG_UP: u8 = 0x13;
+const TRANSFER: u8 = 0x15;
+const TELEPHONE_STATUS: u8 = 0x17;
+const SIO32_MODE: u8 = 0x18;
+const READ_CONFIG: u8 = 0x19;
+const WRITE_CONFIG: u8 = 0x1A;
+const CONNECTION_CLOSED: u8 = 0x1F;
+const ISP_LOGIN: u8 = 0x21;
+const ISP_LOGOUT: u8 = 0x22;
+const OPEN_TCP: u8 = 0x23;
+const CLOSE_TCP: u8 = 0x24;
+const DNS_QUERY: u8 = 0x28;
+const ERROR: u8 = 0x6E;
+
+// Where the packet being received is at
+#[derive(Debug, PartialEq)]
+enum State {
+    Idle,
+    Magic,
+    Header,
+    Data,
+    Checksum,
+    DeviceId,
+    Ack,
+    Reply,
+}
+
+// From a connection's thread
+enum Event {
+    Connected,
+    Data(Vec<u8>),
+    Closed,
+    Failed(io::Error),
+}
+
+// A reply that waits on a thread
+enum Pending {
+    // Connection being opened
+    Open(u8),
+    // Name being looked up
+    Lookup(String, Receiver<Option<Ipv4Addr>>),
+}
+
+struct Connection {
+    to_server: Sender<Vec<u8>>,
+    events: Receiver<Event>,
+    // Arrived but not handed to the game yet
+    received: Vec<u8>,
+    closed: bool,
+}
+
+impl Connection {
+    fn open(addr: SocketAddr) -> Connection {
+        let (to_server, outgoing) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        thread::spawn(move || run_connection(addr, outgoing, events_tx));
+        Connection { to_server: to_server, events: events, received: Vec::new(), closed: false }
+    }
+
+    // None while it's still connecting
+    fn connected(&mut self) -> Option<io::Result<()>> {
+        match self.events.try_recv() {
+            Ok(Event::Connected) => Some(Ok(())),
+            Ok(Event::Failed(err)) => Some(Err(err)),
+            Ok(_) | Err(TryRecvError::Disconnected) =>
+                Some(Err(io::Error::new(io::ErrorKind::Other, "connection thread stopped"))),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    // Queues the data to send and returns what arrived without waiting for
+    // more, the game keeps polling. None once the other side closed it and
+    // everything it sent was handed over
+    fn exchange(&mut self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if !data.is_empty() && !self.closed {
+            let _ = self.to_server.send(data.to_vec());
+        }
+        loop {
+            match self.events.try_recv() {
+                Ok(Event::Data(data)) => self.received.extend(data),
+                Ok(Event::Failed(err)) => return Err(err),
+                Ok(Event::Connected) => {}
+                Ok(Event::Closed) | Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+        if self.closed && self.received.is_empty() {
+            return Ok(None);
+        }
+        // One byte of the reply is the connection id
+        let len = self.received.len().min(MAX_DATA - 1);
+        Ok(Some(self.received.drain(..len).collect()))
+    }
+}
+
+pub struct MobileAdapter {
+    state: State,
+    // What goes out on the next transfer
+    next_out: u8,
+    header: Vec<u8>,
+    data: Vec<u8>,
+    checksum: Vec<u8>,
+    reply: VecDeque<u8>,
+
+    config: Vec<u8>,
+    config_path: PathBuf,
+    // Community server to send every DNS query to
+    server: Option<String>,
+    in_call: bool,
+    logged_in: bool,
+    connections: Vec<Option<Connection>>,
+    // Connection being opened or name being looked up, the reply waits for it
+    pending: Option<Pending>,
+}
+
+// tetris.gb -> tetris.mobile, the adapter's settings for that game
+pub fn config_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("mobile")
+}
+
+impl MobileAdapter {
+    pub fn new(server: Option<String>, config_path: PathBuf) -> MobileAdapter {
+        let mut config = saves::read(&config_path).unwrap_or_default();
+        config.resize(CONFIG_SIZE, 0);
+
+        MobileAdapter {
+            state: State::Idle,
+            next_out: IDLE,
+            header: Vec::new(),
+            data: Vec::new(),
+            checksum: Vec::new(),
+            reply: VecDeque::new(),
+            config: config,
+            config_path: config_path,
+            server: server,
+            in_call: false,
+            logged_in: false,
+            connections: (0..MAX_CONNECTIONS).map(|_| None).collect(),
+            pending: None,
+        }
+    }
+
+    // Takes a byte from the Game Boy, returns the one to send next
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            State::Idle => {
+                if byte == MAGIC[0] {
+                    self.state = State::Magic;
+                }
+                IDLE
+            }
+            State::Magic => {
+                self.state = if byte == MAGIC[1] { State::Header } else { State::Idle };
+                self.header.clear();
+                self.data.clear();
+                self.checksum.clear();
+                IDLE
+            }
+            State::Header => {
+                self.header.push(byte);
+                if self.header.len() == 4 {
+                    self.state = if byte == 0 { State::Checksum } else { State::Data };
+                }
+                IDLE
+            }
+            State::Data => {
+                self.data.push(byte);
+                if self.data.len() == self.header[3] as usize {
+                    self.state = State::Checksum;
+                }
+                IDLE
+            }
+            State::Checksum => {
+                self.checksum.push(byte);
+                if self.checksum.len() < 2 {
+                    return IDLE;
+                }
+                self.state = State::DeviceId;
+                ADAPTER_ID
+            }
+            State::DeviceId => {
+                self.state = State::Ack;
+                let command = self.header[0];
+                if checksum(&self.header, &self.data) != (self.checksum[0] as u16) << 8 | self.checksum[1] as u16 {
+                    warn!("Mobile adapter: bad checksum on command {:02X}", command);
+                    return ACK_BAD_CHECKSUM;
+                }
+                let data = self.data.clone();
+                match self.command(command, &data) {
+                    Some(reply) => {
+                        self.reply = reply.into_iter().collect();
+                        command ^ 0x80
+                    }
+                    None => {
+                        warn!("Mobile adapter: unknown command {:02X}", command);
+                        ACK_UNKNOWN_COMMAND
+                    }
+                }
+            }
+            State::Ack | State::Reply => {
+                if self.pending.is_some() {
+                    match self.pending_reply() {
+                        Some(reply) => {
+                            self.pending = None;
+                            self.reply = reply.into_iter().collect();
+                        }
+                        None => return IDLE,
+                    }
+                }
+                match self.reply.pop_front() {
+                    Some(out) => {
+                        self.state = State::Reply;
+                        out
+                    }
+                    None => {
+                        self.state = State::Idle;
+                        IDLE
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs a command, returns the packet to reply with or None if unknown
+    fn command(&mut self, command: u8, data: &[u8]) -> Option<Vec<u8>> {
+        debug!("Mobile adapter: command {:02X} {:?}", command, data);
+        let reply = match command {
+            BEGIN_SESSION => {
+                info!("Mobile adapter: session started");
+                packet(command, data)
+            }
+            END_SESSION => {
+                self.hang_up();
+                info!("Mobile adapter: session ended");
+                packet(command, &[])
+            }
+            DIAL => {
+                if self.in_call {
+                    return Some(error(command, 1));
+                }
+                let number = String::from_utf8_lossy(data.get(1..).unwrap_or(&[])).into_owned();
+                info!("Mobile adapter: dialing {}", number);
+                self.in_call = true;
+                packet(command, &[])
+            }
+            HANG_UP => {
+                self.hang_up();
+                packet(command, &[])
+            }
+            TRANSFER => {
+                if data.is_empty() {
+                    return Some(error(command, 2));
+                }
+                self.transfer(data[0], &data[1..])
+            }
+            TELEPHONE_STATUS => {
+                let status = match (self.in_call, self.logged_in) {
+                    (_, true) => 5,
+                    (true, false) => 4,
+                    _ => 0,
+                };
+                packet(command, &[status])
+            }
+            SIO32_MODE => {
+                warn!("Mobile adapter: 32 bit mode requested, staying in 8 bit mode");
+                packet(command, &[])
+            }
+            READ_CONFIG => {
+                let (offset, len) = match data {
+                    &[offset, len] => (offset as usize, len as usize),
+                    _ => return Some(error(command, 2)),
+                };
+                if offset + len > CONFIG_SIZE {
+                    return Some(error(command, 2));
+                }
+                let mut reply = vec![offset as u8];
+                reply.extend_from_slice(&self.config[offset..offset + len]);
+                packet(command, &reply)
+            }
+            WRITE_CONFIG => {
+                if data.is_empty() || data[0] as usize + data.len() - 1 > CONFIG_SIZE {
+                    return Some(error(command, 2));
+                }
+                let offset = data[0] as usize;
+                self.config[offset..offset + data.len() - 1].copy_from_slice(&data[1..]);
+                if let Err(err) = saves::write_atomic(&self.config_path, &self.config) {
+                    warn!("Couldn't write {}: {}", self.config_path.display(), err);
+                }
+                packet(command, &[])
+            }
+            ISP_LOGIN => {
+                if !self.in_call {
+                    return Some(error(command, 1));
+                }
+                // User id and password don't matter to anyone, the two DNS
+                // servers after them are given back
+                let id_len = *data.get(0).unwrap_or(&0) as usize;
+                let pass_len = *data.get(1 + id_len).unwrap_or(&0) as usize;
+                let dns = data.get(2 + id_len + pass_len..).unwrap_or(&[]);
+                let mut reply = vec![127, 0, 0, 1];
+                reply.extend_from_slice(dns);
+                reply.resize(12, 0);
+                self.logged_in = true;
+                info!("Mobile adapter: logged in");
+                packet(command, &reply)
+            }
+            ISP_LOGOUT => {
+                self.close_connections();
+                self.logged_in = false;
+                packet(command, &[])
+            }
+            OPEN_TCP => {
+                let (ip, port) = match data {
+                    &[a, b, c, d, hi, lo] => (Ipv4Addr::new(a, b, c, d), (hi as u16) << 8 | lo as u16),
+                    _ => return Some(error(command, 2)),
+                };
+                let id = match self.connections.iter().position(|c| c.is_none()) {
+                    Some(id) if self.logged_in => id,
+                    _ => return Some(error(command, 0)),
+                };
+                info!("Mobile adapter: connecting to {}:{}", ip, port);
+                self.connections[id] = Some(Connection::open(SocketAddr::new(IpAddr::V4(ip), port)));
+                self.pending = Some(Pending::Open(id as u8));
+                // Replied to once it's through, see open_result()
+                Vec::new()
+            }
+            CLOSE_TCP => {
+                match data.get(0) {
+                    Some(&id) if (id as usize) < MAX_CONNECTIONS => {
+                        self.connections[id as usize] = None;
+                        packet(command, &[id])
+                    }
+                    _ => error(command, 2),
+                }
+            }
+            DNS_QUERY => {
+                let name = String::from_utf8_lossy(data).into_owned();
+                let host = self.server.clone().unwrap_or_else(|| name.clone());
+                let (result_tx, result) = mpsc::channel();
+                thread::spawn(move || { let _ = result_tx.send(resolve(&host)); });
+                self.pending = Some(Pending::Lookup(name, result));
+                // Replied to once it's answered, see pending_reply()
+                Vec::new()
+            }
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    // Sends data over a connection and replies with whatever arrived
+    fn transfer(&mut self, id: u8, data: &[u8]) -> Vec<u8> {
+        if id == LINE {
+            // Nobody on the other end of a call
+            return if self.in_call { packet(TRANSFER, &[id]) } else { error(TRANSFER, 1) };
+        }
+
+        let result = match self.connections.get_mut(id as usize) {
+            Some(&mut Some(ref mut conn)) => conn.exchange(data),
+            _ => return error(TRANSFER, 1),
+        };
+        match result {
+            Ok(Some(received)) => {
+                let mut reply = vec![id];
+                reply.extend(received);
+                packet(TRANSFER, &reply)
+            }
+            Ok(None) => {
+                info!("Mobile adapter: connection {} closed by the server", id);
+                self.connections[id as usize] = None;
+                packet(CONNECTION_CLOSED, &[id])
+            }
+            Err(err) => {
+                warn!("Mobile adapter: connection {} failed: {}", id, err);
+                self.connections[id as usize] = None;
+                error(TRANSFER, 2)
+            }
+        }
+    }
+
+    // The reply to what's pending, None until its thread is done
+    fn pending_reply(&mut self) -> Option<Vec<u8>> {
+        if let Some(Pending::Open(id)) = self.pending {
+            return self.open_result(id);
+        }
+        let (name, result) = match self.pending {
+            Some(Pending::Lookup(ref name, ref result)) => (name, result),
+            _ => return None,
+        };
+        match result.try_recv() {
+            Ok(Some(ip)) => {
+                info!("Mobile adapter: {} is {}", name, ip);
+                Some(packet(DNS_QUERY, &ip.octets()))
+            }
+            Ok(None) | Err(TryRecvError::Disconnected) => {
+                warn!("Mobile adapter: couldn't resolve {}", name);
+                Some(error(DNS_QUERY, 2))
+            }
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    // The reply to opening connection id, None until it's connected or
+    // failed to
+    fn open_result(&mut self, id: u8) -> Option<Vec<u8>> {
+        let result = match self.connections[id as usize] {
+            Some(ref mut conn) => match conn.connected() {
+                Some(result) => result,
+                None => return None,
+            },
+            None => return Some(error(OPEN_TCP, 3)),
+        };
+        match result {
+            Ok(()) => {
+                info!("Mobile adapter: connection {} open", id);
+                Some(packet(OPEN_TCP, &[id]))
+            }
+            Err(err) => {
+                warn!("Mobile adapter: couldn't open connection {}: {}", id, err);
+                self.connections[id as usize] = None;
+                Some(error(OPEN_TCP, 3))
+            }
+        }
+    }
+
+    fn close_connections(&mut self) {
+        for conn in self.connections.iter_mut() {
+            *conn = None;
+        }
+    }
+
+    fn hang_up(&mut self) {
+        self.close_connections();
+        self.pending = None;
+        self.logged_in = false;
+        self.in_call = false;
+    }
+}
+
+impl SerialDevice for MobileAdapter {
+    fn exchange(&mut self, sent: u8) -> u8 {
+        let out = self.next_out;
+        self.next_out = self.receive(sent);
+        out
+    }
+}
+
+fn checksum(header: &[u8], data: &[u8]) -> u16 {
+    header.iter().chain(data.iter()).fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+}
+
+// A reply packet as sent, acknowledgement bytes included
+fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+    let header = [command | 0x80, 0, 0, data.len() as u8];
+    let sum = checksum(&header, data);
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&[(sum >> 8) as u8, sum as u8, ADAPTER_ID, 0x00]);
+    out
+}
+
+fn error(command: u8, code: u8) -> Vec<u8> {
+    packet(ERROR, &[command, code])
+}
+
+// A lookup's thread, the system resolver can take its time
+fn resolve(host: &str) -> Option<Ipv4Addr> {
+    let addrs = match (host, 0).to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return None,
+    };
+    for addr in addrs {
+        if let IpAddr::V4(ip) = addr.ip() {
+            return Some(ip);
+        }
+    }
+    None
+}
+
+// A connection's thread: connects, then sends what the game queues and
+// passes on what arrives until either side closes it
+fn run_connection(addr: SocketAddr, outgoing: Receiver<Vec<u8>>, events: Sender<Event>) {
+    let mut stream = match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_SECS))
+        .and_then(|stream| stream.set_read_timeout(Some(Duration::from_millis(POLL_MS))).map(|_| stream)) {
+        Ok(stream) => stream,
+        Err(err) => {
+            let _ = events.send(Event::Failed(err));
+            return;
+        }
+    };
+    if events.send(Event::Connected).is_err() {
+        return;
+    }
+
+    let mut buf = [0u8; 1024];
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(data) => if let Err(err) = stream.write_all(&data) {
+                    let _ = events.send(Event::Failed(err));
+                    return;
+                },
+                Err(TryRecvError::Empty) => break,
+                // Closed by the game
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        let event = match stream.read(&mut buf) {
+            Ok(0) => Event::Closed,
+            Ok(n) => Event::Data(buf[..n].to_vec()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => Event::Failed(err),
+        };
+        let done = match event { Event::Data(_) => false, _ => true };
+        if events.send(event).is_err() || done {
+            return;
+        }
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod mobile_tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+
+    fn adapter(name: &str) -> MobileAdapter {
+        let path = env::temp_dir().join(format!("rustboy_{}.mobile", name));
+        let _ = fs::remove_file(&path);
+        MobileAdapter::new(None, path)
+    }
+
+    // Sends a command packet the way a game does, returns the reply packet
+    fn send(adapter: &mut MobileAdapter, command: u8, data: &[u8]) -> Vec<u8> {
+        let header = [command, 0, 0, data.len() as u8];
+        let sum = checksum(&header, data);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&[(sum >> 8) as u8, sum as u8]);
+        for &b in bytes.iter() {
+            assert_eq!(adapter.exchange(b), IDLE);
+        }
+        assert_eq!(adapter.exchange(0x81), ADAPTER_ID);
+        assert_eq!(adapter.exchange(0x00), command ^ 0x80);
+
+        // Poll until the adapter goes idle again, skipping the filler before
+        // the reply
+        let mut reply = Vec::new();
+        loop {
+            let b = adapter.exchange(0x4B);
+            if b == IDLE && adapter.state == State::Idle {
+                return reply;
+            }
+            if b != IDLE || !reply.is_empty() {
+                reply.push(b);
+            }
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut adapter = adapter("session");
+
+        assert_eq!(send(&mut adapter, BEGIN_SESSION, b"NINTENDO"), packet(BEGIN_SESSION, b"NINTENDO"));
+        assert_eq!(&packet(BEGIN_SESSION, b"NINTENDO")[..6], &[0x99, 0x66, 0x90, 0, 0, 8]);
+
+        adapter.config[0x10..0x12].copy_from_slice(&[0x4D, 0x41]);
+        assert_eq!(send(&mut adapter, READ_CONFIG, &[0x10, 2]), packet(READ_CONFIG, &[0x10, 0x4D, 0x41]));
+        assert_eq!(send(&mut adapter, READ_CONFIG, &[0xBF, 2]), error(READ_CONFIG, 2));
+
+        // Written to the file for next time
+        assert_eq!(send(&mut adapter, WRITE_CONFIG, &[0x20, 0x12, 0x34]), packet(WRITE_CONFIG, &[]));
+        let saved = MobileAdapter::new(None, adapter.config_path.clone());
+        assert_eq!(&saved.config[0x20..0x22], &[0x12, 0x34]);
+        let _ = fs::remove_file(&adapter.config_path);
+
+        // Logging in needs a call to the ISP first
+        assert_eq!(send(&mut adapter, ISP_LOGIN, &[0, 0]), error(ISP_LOGIN, 1));
+        assert_eq!(send(&mut adapter, DIAL, b"\x00#9677"), packet(DIAL, &[]));
+        assert_eq!(send(&mut adapter, TELEPHONE_STATUS, &[]), packet(TELEPHONE_STATUS, &[4]));
+        let login = send(&mut adapter, ISP_LOGIN, &[1, b'g', 1, b'p', 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(login, packet(ISP_LOGIN, &[127, 0, 0, 1, 1, 2, 3, 4, 5, 6, 7, 8]));
+
+        assert_eq!(send(&mut adapter, END_SESSION, &[]), packet(END_SESSION, &[]));
+        assert!(!adapter.in_call && !adapter.logged_in);
+    }
+
+    #[test]
+    fn tcp() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut adapter = adapter("tcp");
+        send(&mut adapter, DIAL, b"\x00#9677");
+        send(&mut adapter, ISP_LOGIN, &[0, 0]);
+
+        // Filler until it's connected
+        let open = send(&mut adapter, OPEN_TCP, &[127, 0, 0, 1, (port >> 8) as u8, port as u8]);
+        assert_eq!(open, packet(OPEN_TCP, &[0]));
+        let (mut client, _) = server.accept().unwrap();
+
+        assert_eq!(send(&mut adapter, TRANSFER, &[0, b'H', b'I']), packet(TRANSFER, &[0]));
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"HI");
+
+        client.write_all(b"OK").unwrap();
+        drop(client);
+        let mut received = Vec::new();
+        loop {
+            let reply = send(&mut adapter, TRANSFER, &[0]);
+            if reply == packet(CONNECTION_CLOSED, &[0]) {
+                break;
+            }
+            received.extend_from_slice(&reply[7..reply.len() - 4]);
+        }
+        assert_eq!(received, b"OK");
+        assert!(adapter.connections[0].is_none());
+    }
+
+    #[test]
+    fn dns() {
+        // Every name goes to the server when there's one, filler until the
+        // lookup's thread answers
+        let path = env::temp_dir().join("rustboy_dns.mobile");
+        let mut adapter = MobileAdapter::new(Some("127.0.0.1".to_string()), path);
+        assert_eq!(send(&mut adapter, DNS_QUERY, b"gameboy.datacenter.ne.jp"),
+            packet(DNS_QUERY, &[127, 0, 0, 1]));
+        assert!(adapter.pending.is_none());
+    }
+
+    #[test]
+    fn bad_packets() {
+        let mut adapter = adapter("bad_packets");
+        // Unknown command
+        let header = [0x7F, 0, 0, 0];
+        for &b in MAGIC.iter().chain(header.iter()).chain([0x00, 0x7F].iter()) {
+            adapter.exchange(b);
+        }
+        assert_eq!(adapter.exchange(0x81), ADAPTER_ID);
+        assert_eq!(adapter.exchange(0x00), ACK_UNKNOWN_COMMAND);
+        assert_eq!(adapter.exchange(0x4B), IDLE);
+
+        // Wrong checksum
+        for &b in MAGIC.iter().chain([0x17, 0, 0, 0, 0x12, 0x34].iter()) {
+            adapter.exchange(b);
+        }
+        adapter.exchange(0x81);
+        assert_eq!(adapter.exchange(0x00), ACK_BAD_CHECKSUM);
+    }
+}
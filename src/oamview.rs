@@ -0,0 +1,133 @@
+//
+//      Sprite inspector
+//
+
+// The 40 sprites in OAM, 8 to a row, drawn through their palettes and
+// refreshed every frame, shown over the screen with O. The ones on the line
+// being drawn get a yellow frame. Pointing at one decodes its entry, the
+// console's `oam` command lists all of them.
+
+use gpu::{Color, Gpu, OAM_SIZE};
+
+const SPRITES: usize = OAM_SIZE / 4;
+const COLUMNS: usize = 8;
+// A sprite of up to 8x16 with a 2 pixel frame
+const CELL_WIDTH: usize = 12;
+const CELL_HEIGHT: usize = 20;
+const FRAME_COLOR: Color = [90, 90, 90, 255];
+const ON_LINE_COLOR: Color = [255, 220, 0, 255];
+// Shows through transparent pixels
+const BACKDROP: Color = [40, 40, 40, 255];
+
+pub const DIMS: [u32; 2] = [(CELL_WIDTH * COLUMNS) as u32, (CELL_HEIGHT * SPRITES / COLUMNS) as u32];
+
+// A line about OAM entry i: Y, X, tile and flags as they are, then what the
+// flags mean
+pub fn describe(gpu: &Gpu, i: usize) -> String {
+    let e = &gpu.oam[i * 4..i * 4 + 4];
+    let flags = e[3];
+    let mut desc = format!("#{:02} Y {:02X} X {:02X} tile {:02X} flags {:02X}", i, e[0], e[1], e[2], flags);
+    if gpu.is_cgb {
+        desc.push_str(&format!("  pal {} bank {}", flags & 7, flags >> 3 & 1));
+    } else {
+        desc.push_str(if flags & 0x10 != 0 {"  OBP1"} else {"  OBP0"});
+    }
+    if flags & 0x20 != 0 { desc.push_str(" xflip") }
+    if flags & 0x40 != 0 { desc.push_str(" yflip") }
+    if flags & 0x80 != 0 { desc.push_str(" behind") }
+    desc
+}
+
+pub struct OamViewer {
+    // RGBA, DIMS in size
+    pub pixels: Vec<u8>,
+}
+
+impl OamViewer {
+    pub fn new() -> OamViewer {
+        OamViewer { pixels: vec![0; (DIMS[0] * DIMS[1] * 4) as usize] }
+    }
+
+    pub fn update(&mut self, gpu: &Gpu) {
+        let height = gpu.sprite_height();
+        let on_line = gpu.line_sprites(gpu.ly() as i32, height);
+
+        for i in 0..SPRITES {
+            let (left, top) = (i % COLUMNS * CELL_WIDTH, i / COLUMNS * CELL_HEIGHT);
+            let frame = if on_line.contains(&i) {ON_LINE_COLOR} else {FRAME_COLOR};
+            for y in 0..CELL_HEIGHT {
+                for x in 0..CELL_WIDTH {
+                    let edge = x == 0 || y == 0 || x == CELL_WIDTH - 1 || y == CELL_HEIGHT - 1;
+                    self.set(left + x, top + y, if edge {frame} else {BACKDROP});
+                }
+            }
+
+            let e = &gpu.oam[i * 4..i * 4 + 4];
+            let flags = e[3];
+            let tile = if height == 16 {e[2] & 0xfe} else {e[2]} as usize;
+            let pal = gpu.obj_palette(flags);
+            // Only the CGB has a second bank to take tiles from
+            let attrs = if gpu.is_cgb {flags} else {0};
+            for y in 0..height as usize {
+                let ty = if flags & 0x40 != 0 {height as usize - 1 - y} else {y};
+                let row = gpu.tile_row(attrs, tile * 16 + ty * 2);
+                for x in 0..8 {
+                    let colori = row[if flags & 0x20 != 0 {7 - x} else {x}];
+                    if colori != 0 {
+                        self.set(left + 2 + x, top + 2 + y, pal[colori as usize]);
+                    }
+                }
+            }
+        }
+    }
+
+    // The OAM entry at a point of the view, in its pixels
+    pub fn entry_at(&self, x: f64, y: f64) -> Option<usize> {
+        if x < 0. || y < 0. || x >= DIMS[0] as f64 || y >= DIMS[1] as f64 {
+            return None;
+        }
+        Some(y as usize / CELL_HEIGHT * COLUMNS + x as usize / CELL_WIDTH)
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        let i = (y * DIMS[0] as usize + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod oamview_tests {
+    use super::*;
+
+    fn pixel(viewer: &OamViewer, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * DIMS[0] as usize + x) * 4;
+        [viewer.pixels[i], viewer.pixels[i + 1], viewer.pixels[i + 2], viewer.pixels[i + 3]]
+    }
+
+    #[test]
+    fn sprites() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF48, 0xE4);
+        // Tile 1 has color 3 in its top left pixel
+        gpu.vrambank[0x10] = 0x80;
+        gpu.vrambank[0x11] = 0x80;
+        // Sprite 9 uses it flipped horizontally, on line 0
+        gpu.oam[9 * 4..9 * 4 + 4].copy_from_slice(&[16, 8, 1, 0x20]);
+
+        let mut viewer = OamViewer::new();
+        viewer.update(&gpu);
+        // Second row, second cell
+        let (left, top) = (CELL_WIDTH, CELL_HEIGHT);
+        assert_eq!(pixel(&viewer, left + 2 + 7, top + 2), gpu.obj_palette(0)[3]);
+        assert_eq!(pixel(&viewer, left + 2, top + 2), BACKDROP);
+        assert_eq!(pixel(&viewer, left, top), ON_LINE_COLOR);
+        assert_eq!(pixel(&viewer, 0, 0), FRAME_COLOR);
+
+        assert_eq!(viewer.entry_at((left + 3) as f64, (top + 3) as f64), Some(9));
+        assert_eq!(describe(&gpu, 9), "#09 Y 10 X 08 tile 01 flags 20  OBP0 xflip");
+    }
+}
@@ -0,0 +1,57 @@
+//
+//      Register overlay
+//
+
+// A few lines of CPU and PPU state drawn in a corner of the game screen
+// (I toggles it), for watching what a game does without the whole debug
+// panel in the way:
+//   AF 01B0 BC 0013 DE 00D8
+//   HL 014D SP FFFE PC 0150
+//   IME 1  PPU mode 2 LY 144
+//   Frame 1234
+
+use emulator::Emulator;
+
+const MODES: [&'static str; 4] = ["0 HBlank", "1 VBlank", "2 OAM", "3 Draw"];
+
+pub fn lines(emu: &Emulator) -> Vec<String> {
+    let r = emu.cpu.get_regs();
+    let gpu = &emu.mem.gpu;
+    let ppu = if gpu.lcdon {
+        format!("PPU {:<8} LY {:3}", MODES[gpu.mode() as usize & 3], gpu.ly())
+    } else {
+        String::from("PPU off")
+    };
+    vec![
+        format!("AF {:04X} BC {:04X} DE {:04X}", r.af(), r.bc(), r.de()),
+        format!("HL {:04X} SP {:04X} PC {:04X}", r.hl(), r.sp(), r.pc()),
+        format!("IME {}  {}", r.ime as u8, ppu),
+        format!("Frame {}", emu.frame_count),
+    ]
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod overlay_tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn state() {
+        let mut emu = Emulator::with_rom(vec![0; 0x8000], &Config::default());
+        emu.cpu.get_regs_mut().af_set(0x01B0);
+        emu.cpu.get_regs_mut().pc_set(0x0150);
+        emu.cpu.get_regs_mut().ime = true;
+        emu.frame_count = 1234;
+
+        let lines = lines(&emu);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("AF 01B0 "));
+        assert!(lines[1].ends_with(" PC 0150"));
+        assert!(lines[2].starts_with("IME 1  PPU"));
+        assert_eq!(lines[3], "Frame 1234");
+    }
+}
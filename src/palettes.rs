@@ -0,0 +1,125 @@
+//
+//      User palettes
+//
+
+// Extra 4 color schemes for DMG games, loaded with --palettes and picked with
+// --palette (or C while playing). One palette per line, lightest color first:
+//
+//   name | #E0F8D0 | #88C070 | #346856 | #081820
+//
+// Lines starting with '#' are comments.
+//
+// The palette picked for a game in the palette menu (see palmenu.rs) is kept
+// by name in a .palette file next to the rom, like its .sav.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use gpu::Palette;
+use saves;
+
+pub fn preset_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("palette")
+}
+
+// The palette name saved for a game, if there is one
+pub fn load_preset(path: &Path) -> Option<String> {
+    let mut text = String::new();
+    if File::open(path).and_then(|mut f| f.read_to_string(&mut text)).is_err() {
+        return None;
+    }
+    let name = text.trim();
+    if name.is_empty() {None} else {Some(name.to_lowercase())}
+}
+
+pub fn save_preset(path: &Path, name: &str) -> io::Result<()> {
+    saves::write_atomic(path, format!("{}\n", name).as_bytes())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<(String, Palette)>> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    Ok(parse(&text))
+}
+
+// Bad lines are skipped with a warning
+pub fn parse(text: &str) -> Vec<(String, Palette)> {
+    let mut palettes = Vec::new();
+    for (line_n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(palette) => palettes.push(palette),
+            Err(err) => warn!("Palette file line {}: {}", line_n + 1, err),
+        }
+    }
+    palettes
+}
+
+fn parse_line(line: &str) -> Result<(String, Palette), String> {
+    let fields: Vec<&str> = line.split('|').map(|f| f.trim()).collect();
+    if fields.len() != 5 {
+        return Err(format!("expected a name and 4 colors, got {} fields", fields.len()));
+    }
+    if fields[0].is_empty() {
+        return Err(String::from("missing name"));
+    }
+
+    let mut palette = [[0u8; 4]; 4];
+    for (color, field) in palette.iter_mut().zip(fields[1..].iter()) {
+        *color = try!(parse_color(field));
+    }
+    Ok((fields[0].to_lowercase(), palette))
+}
+
+// #RRGGBB
+fn parse_color(s: &str) -> Result<[u8; 4], String> {
+    let hex = s.trim_left_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("invalid color \"{}\"", s));
+    }
+    let rgb = try!(u32::from_str_radix(hex, 16).map_err(|_| format!("invalid color \"{}\"", s)));
+    Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255])
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod palettes_tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn parse_file() {
+        let palettes = parse("
+# Pocket
+Pocket | #C4CFA1 | #8B956D | #4D533C | #1F1F1F
+broken | #C4CFA1 | #8B956D
+bad color | #C4CFA1 | #8B956D | #4D533C | 1F1F
+");
+        assert_eq!(palettes.len(), 1);
+        assert_eq!(palettes[0].0, "pocket");
+        assert_eq!(palettes[0].1[0], [0xC4, 0xCF, 0xA1, 255]);
+        assert_eq!(palettes[0].1[3], [0x1F, 0x1F, 0x1F, 255]);
+    }
+
+    #[test]
+    fn preset_per_game() {
+        let dir = env::temp_dir().join("rustboy_palettes_tests");
+        fs::create_dir_all(&dir).unwrap();
+        let path = preset_path(dir.join("tetris.gb"));
+        assert_eq!(path, dir.join("tetris.palette"));
+
+        assert_eq!(load_preset(&path), None);
+        save_preset(&path, "pocket").unwrap();
+        assert_eq!(load_preset(&path), Some(String::from("pocket")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
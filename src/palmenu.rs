@@ -0,0 +1,103 @@
+//
+//      Palette menu
+//
+
+// K opens a list of the palettes C cycles through (the built in ones and any
+// from --palettes) with the frame on screen recolored in the highlighted one,
+// live. Up and Down move through the list, Enter switches to it and keeps it
+// for the game (see Emulator::pick_palette), K or Escape closes the menu
+// without changing anything.
+
+use gpu::{Gpu, IndexData, Palette, HEIGHT, WIDTH};
+
+pub const DIMS: [u32; 2] = [WIDTH as u32, HEIGHT as u32];
+
+pub struct PaletteMenu {
+    pub open: bool,
+    // Index into Gpu::palettes
+    pub selected: usize,
+    // RGBA, DIMS in size, the preview
+    pub pixels: Vec<u8>,
+}
+
+impl PaletteMenu {
+    pub fn new() -> PaletteMenu {
+        PaletteMenu {
+            open: false,
+            selected: 0,
+            pixels: vec![0; (DIMS[0] * DIMS[1] * 4) as usize],
+        }
+    }
+
+    // Opens on the palette in use
+    pub fn toggle(&mut self, gpu: &Gpu) {
+        self.open = !self.open;
+        self.selected = gpu.palette_index().unwrap_or(0);
+    }
+
+    // Up (-1) or down (1) the list, wrapping around
+    pub fn move_by(&mut self, gpu: &Gpu, delta: isize) {
+        let len = gpu.palettes.len() as isize;
+        self.selected = ((self.selected as isize + delta) % len + len) as usize % len as usize;
+    }
+
+    pub fn selected_name<'a>(&self, gpu: &'a Gpu) -> &'a str {
+        &gpu.palettes[self.selected].0
+    }
+
+    pub fn update(&mut self, gpu: &Gpu) {
+        recolor(gpu.frame_indices(), &gpu.palettes[self.selected].1, &mut self.pixels);
+    }
+
+    // The list, the highlighted palette marked with > and the one in use
+    // with *
+    pub fn lines(&self, gpu: &Gpu) -> Vec<String> {
+        gpu.palettes.iter().enumerate().map(|(i, &(ref name, _))| {
+            format!("{}{} {}", if i == self.selected {">"} else {" "},
+                if Some(i) == gpu.palette_index() {"*"} else {" "}, name)
+        }).collect()
+    }
+}
+
+// The DMG shades of a frame in other colors
+pub fn recolor(indices: &IndexData, pal: &Palette, out: &mut [u8]) {
+    for (p, &index) in out.chunks_mut(4).zip(indices.iter()) {
+        p.copy_from_slice(&pal[index as usize & 3]);
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod palmenu_tests {
+    use super::*;
+
+    #[test]
+    fn browse() {
+        let mut gpu = Gpu::new();
+        let mut menu = PaletteMenu::new();
+        gpu.select_palette("pocket").unwrap();
+        menu.toggle(&gpu);
+        assert!(menu.open);
+        assert_eq!(menu.selected_name(&gpu), "pocket");
+
+        menu.move_by(&gpu, -1);
+        assert_eq!(menu.selected_name(&gpu), "puke-green");
+        menu.move_by(&gpu, 3);
+        assert_eq!(menu.selected_name(&gpu), "green");
+        let lines = menu.lines(&gpu);
+        assert_eq!(lines[0], ">  green");
+        assert_eq!(lines[3], " * pocket");
+
+        // Shades map straight to the palette's colors
+        let mut indices = [0u8; WIDTH * HEIGHT];
+        indices[1] = 3;
+        let pal = gpu.palettes[1].1;
+        let mut out = [0u8; 8];
+        recolor(&indices, &pal, &mut out);
+        assert_eq!(&out[..4], &pal[0]);
+        assert_eq!(&out[4..], &pal[3]);
+    }
+}
@@ -0,0 +1,128 @@
+//
+//      Palette viewer
+//
+
+// The palettes as they are right now, one row of 4 colors each, refreshed
+// every frame and shown over the screen with L. BGP, OBP0 and OBP1 come
+// first, then on the CGB the 8 background and 8 sprite palettes from palette
+// RAM. Pointing at a color tells where it comes from, the console's `pal`
+// command lists them all.
+
+use gpu::{Gpu, Palette};
+
+const SWATCH: usize = 12;
+const DMG_ROWS: usize = 3;
+const CGB_ROWS: usize = 16;
+
+pub const DIMS: [u32; 2] = [(SWATCH * 4) as u32, (SWATCH * (DMG_ROWS + CGB_ROWS)) as u32];
+
+fn hex(color: [u8; 4]) -> String {
+    format!("#{:02X}{:02X}{:02X}", color[0], color[1], color[2])
+}
+
+// A line about palette row i of the view
+pub fn describe(gpu: &Gpu, row: usize) -> String {
+    let (name, raw, pal) = palette(gpu, row);
+    let colors: Vec<String> = pal.iter().map(|&c| hex(c)).collect();
+    format!("{:<5} {}  {}", name, raw, colors.join(" "))
+}
+
+// Name, raw value and colors of palette row i, CGB colors are the 15-bit
+// values from palette RAM
+fn palette(gpu: &Gpu, row: usize) -> (String, String, Palette) {
+    if row < DMG_ROWS {
+        let (name, raw, pal) = gpu.dmg_palettes()[row];
+        return (name.to_string(), format!("{:02X}", raw), pal);
+    }
+    let (obj, i) = ((row - DMG_ROWS) / 8 == 1, (row - DMG_ROWS) % 8);
+    let (ram, pals) = gpu.cgb_palettes(obj);
+    let raw: Vec<String> = (0..4).map(|c| {
+        let lo = i * 8 + c * 2;
+        format!("{:04X}", ram[lo] as u16 | (ram[lo + 1] as u16) << 8)
+    }).collect();
+    (format!("{}{}", if obj {"OBJ"} else {"BG"}, i), raw.join(" "), pals[i])
+}
+
+pub struct PaletteViewer {
+    // RGBA, DIMS in size. The CGB rows are left transparent on the DMG
+    pub pixels: Vec<u8>,
+    rows: usize,
+}
+
+impl PaletteViewer {
+    pub fn new() -> PaletteViewer {
+        PaletteViewer {
+            pixels: vec![0; (DIMS[0] * DIMS[1] * 4) as usize],
+            rows: DMG_ROWS,
+        }
+    }
+
+    pub fn rows(gpu: &Gpu) -> usize {
+        if gpu.is_cgb {DMG_ROWS + CGB_ROWS} else {DMG_ROWS}
+    }
+
+    pub fn update(&mut self, gpu: &Gpu) {
+        self.rows = PaletteViewer::rows(gpu);
+        for row in 0..self.rows {
+            let (_, _, pal) = palette(gpu, row);
+            for y in row * SWATCH..(row + 1) * SWATCH {
+                for x in 0..DIMS[0] as usize {
+                    let i = (y * DIMS[0] as usize + x) * 4;
+                    self.pixels[i..i + 4].copy_from_slice(&pal[x / SWATCH]);
+                }
+            }
+        }
+    }
+
+    // What's at a point of the view, in its pixels
+    pub fn describe_at(&self, gpu: &Gpu, x: f64, y: f64) -> Option<String> {
+        if x < 0. || y < 0. || x >= DIMS[0] as f64 || y >= (self.rows * SWATCH) as f64 {
+            return None;
+        }
+        let (row, color) = (y as usize / SWATCH, x as usize / SWATCH);
+        let (name, raw, pal) = palette(gpu, row);
+        let source = if row < DMG_ROWS {
+            let shade = gpu.dmg_palettes()[row].1 >> (color * 2) & 3;
+            format!("={}  color {} -> shade {}", raw, color, shade)
+        } else {
+            format!("  color {} = {}", color, &raw[color * 5..color * 5 + 4])
+        };
+        Some(format!("{}{}  {}", name, source, hex(pal[color])))
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod palview_tests {
+    use super::*;
+
+    #[test]
+    fn live_registers() {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF48, 0x1B);
+        let mut viewer = PaletteViewer::new();
+        viewer.update(&gpu);
+
+        // OBP0 is the second row, its color 0 shows shade 3
+        let i = (SWATCH * DIMS[0] as usize) * 4;
+        assert_eq!(&viewer.pixels[i..i + 4], &gpu.dmg_palettes()[1].2[0]);
+        let desc = viewer.describe_at(&gpu, 1., SWATCH as f64 + 1.).unwrap();
+        assert!(desc.starts_with("OBP0=1B  color 0 -> shade 3  #"), desc);
+        // No CGB rows on the DMG
+        assert_eq!(viewer.describe_at(&gpu, 1., (SWATCH * DMG_ROWS) as f64), None);
+
+        gpu.is_cgb = true;
+        // OBJ palette 1, color 2 to pure red
+        gpu.wb(0xFF6A, 0x8C);
+        gpu.wb(0xFF6B, 0x1F);
+        gpu.wb(0xFF6B, 0x00);
+        viewer.update(&gpu);
+        let y = (SWATCH * (DMG_ROWS + 9)) as f64;
+        assert_eq!(viewer.describe_at(&gpu, SWATCH as f64 * 2., y).unwrap(),
+            "OBJ1  color 2 = 001F  #FF0000");
+        assert!(describe(&gpu, DMG_ROWS + 9).starts_with("OBJ1  0000 0000 001F 0000  #"));
+    }
+}
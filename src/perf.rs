@@ -0,0 +1,175 @@
+//
+//      Frame time statistics
+//
+
+// Keeps a histogram of how long frames take to emulate and present, logs the
+// ones slower than the 99th percentile along with what else happened in them,
+// and can dump the recent frames to a CSV file.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+// Histogram buckets are 1ms wide, the last one holds everything slower
+const NUM_BUCKETS: usize = 50;
+// Frames kept for the CSV dump, a minute at 60 FPS
+const MAX_SAMPLES: usize = 3600;
+// Don't report spikes before there's enough data for a percentile
+const MIN_FRAMES: u64 = 120;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameSample {
+    pub frame: u32,
+    pub emulation_ms: f64,
+    pub presentation_ms: f64,
+    // Work done in this frame that could explain a spike
+    pub causes: Vec<&'static str>,
+}
+
+impl FrameSample {
+    pub fn total_ms(&self) -> f64 {
+        self.emulation_ms + self.presentation_ms
+    }
+}
+
+pub struct FrameTimes {
+    buckets: [u64; NUM_BUCKETS],
+    frames: u64,
+    samples: VecDeque<FrameSample>,
+    // Emulated, but not presented yet
+    current: Option<FrameSample>,
+}
+
+impl FrameTimes {
+    pub fn new() -> FrameTimes {
+        FrameTimes {
+            buckets: [0; NUM_BUCKETS],
+            frames: 0,
+            samples: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    // A frame was emulated
+    pub fn emulated(&mut self, frame: u32, time: Duration, causes: Vec<&'static str>) {
+        // The last frame was never shown
+        if let Some(sample) = self.current.take() {
+            self.record(sample);
+        }
+        self.current = Some(FrameSample {
+            frame: frame,
+            emulation_ms: to_ms(time),
+            presentation_ms: 0.,
+            causes: causes,
+        });
+    }
+
+    // The last emulated frame was drawn
+    pub fn presented(&mut self, time: Duration) {
+        if let Some(mut sample) = self.current.take() {
+            sample.presentation_ms = to_ms(time);
+            self.record(sample);
+        }
+    }
+
+    fn record(&mut self, sample: FrameSample) {
+        let total = sample.total_ms();
+
+        if self.frames >= MIN_FRAMES {
+            let p99 = self.percentile(0.99);
+            if total > p99 {
+                warn!("Frame {} took {:.2}ms (emulation {:.2}ms, presentation {:.2}ms, p99 {:.0}ms){}",
+                    sample.frame, total, sample.emulation_ms, sample.presentation_ms, p99,
+                    if sample.causes.is_empty() { String::new() }
+                    else { format!(": {}", sample.causes.join(", ")) });
+            }
+        }
+
+        let bucket = (total as usize).min(NUM_BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.frames += 1;
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    // Upper bound (in ms) of the bucket the given fraction of frames fit in
+    pub fn percentile(&self, fraction: f64) -> f64 {
+        let target = (self.frames as f64 * fraction).ceil() as u64;
+        let mut seen = 0;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return (i + 1) as f64;
+            }
+        }
+        NUM_BUCKETS as f64
+    }
+
+    pub fn dump_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        try!(writeln!(file, "frame,emulation_ms,presentation_ms,total_ms,causes"));
+        for s in self.samples.iter() {
+            try!(writeln!(file, "{},{:.3},{:.3},{:.3},{}",
+                s.frame, s.emulation_ms, s.presentation_ms, s.total_ms(), s.causes.join(";")));
+        }
+        Ok(())
+    }
+}
+
+// Time spent in each part of the emulator, see Emulator::profile
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SubsystemTimes {
+    pub cpu: Duration,
+    pub timer: Duration,
+    pub gpu: Duration,      // stepping and rendering scanlines
+    pub frame_end: Duration, // copying out the frame and the state checksum
+}
+
+impl SubsystemTimes {
+    pub fn total(&self) -> Duration {
+        self.cpu + self.timer + self.gpu + self.frame_end
+    }
+}
+
+pub fn to_ms(time: Duration) -> f64 {
+    time.as_secs() as f64 * 1000. + time.subsec_nanos() as f64 / 1_000_000.
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod perf_tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut times = FrameTimes::new();
+        for i in 0..100 {
+            let ms = if i == 99 { 30 } else { 2 };
+            times.emulated(i, Duration::from_millis(ms), vec![]);
+            times.presented(Duration::from_millis(1));
+        }
+        assert_eq!(times.percentile(0.5), 4.);
+        assert_eq!(times.percentile(0.99), 4.);
+        assert_eq!(times.percentile(1.0), 32.);
+    }
+
+    #[test]
+    fn unpresented_frames_are_kept() {
+        let mut times = FrameTimes::new();
+        times.emulated(0, Duration::from_millis(5), vec!["tileset rebuild"]);
+        times.emulated(1, Duration::from_millis(5), vec![]);
+        times.presented(Duration::from_millis(1));
+        assert_eq!(times.samples.len(), 2);
+        assert_eq!(times.samples[0].presentation_ms, 0.);
+        assert_eq!(times.samples[0].causes, vec!["tileset rebuild"]);
+        assert_eq!(times.samples[1].total_ms(), 6.);
+    }
+}
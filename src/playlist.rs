@@ -0,0 +1,71 @@
+//
+//      ROM playlist
+//
+
+// Switches between several roms without restarting. Every game keeps its own
+// emulator, so switching back continues where it was left; the battery save
+// of the game being left is written out on the way.
+
+use std::mem;
+
+use config::Config;
+use emulator::Emulator;
+
+pub struct Playlist {
+    config: Config,
+    // Emulators of the games not being played, None until first started
+    parked: Vec<Option<Emulator>>,
+    pub current: usize,
+}
+
+impl Playlist {
+    pub fn new(config: &Config) -> Playlist {
+        let mut config = config.clone();
+        // Only the first game gets to write the watch log, the trace and
+        // the profile, another one would truncate them
+        config.watch_csv = None;
+        config.trace = None;
+        config.profile_game = None;
+        // The others have their own .sym files
+        config.sym_file = None;
+
+        Playlist {
+            parked: config.roms.iter().map(|_| None).collect(),
+            config: config,
+            current: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    // Park the running emulator and bring out the next game's
+    pub fn next(&mut self, emu: &mut Emulator) {
+        if self.len() < 2 {
+            return;
+        }
+        emu.flush_save();
+
+        let next = (self.current + 1) % self.len();
+        let incoming = match self.parked[next].take() {
+            Some(parked) => parked,
+            None => {
+                self.config.rom_path = self.config.roms[next].clone();
+                Emulator::new(&self.config)
+            }
+        };
+        self.parked[self.current] = Some(mem::replace(emu, incoming));
+        self.current = next;
+        info!("Switched to {} ({}/{})", self.config.roms[next], next + 1, self.len());
+    }
+
+    // Write the saves of all the parked games
+    // The first game's profile too, if it was left for another one
+    pub fn flush_saves(&mut self) {
+        for emu in self.parked.iter_mut().filter_map(|e| e.as_mut()) {
+            emu.flush_save();
+            emu.finish_profile();
+        }
+    }
+}
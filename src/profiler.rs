@@ -0,0 +1,188 @@
+//
+//      Game code profiler
+//
+
+// Counts the cycles every instruction of the game takes, together with the
+// call stack it ran under (see cpu/callstack.rs), to find where a game
+// spends its time rather than where the emulator does. Functions are the
+// labels of the .sym file when there is one, or the addresses calls went to.
+// save() writes folded stacks, a line per stack with its cycles:
+//   Main;UpdateActors;CheckCollision 123456
+// which flamegraph.pl, inferno-flamegraph and speedscope all read.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use cpu::callstack::Frame;
+use symbols::{self, Symbols};
+
+pub struct Profiler {
+    // Cycles per call stack, the frames' entry addresses outermost first
+    // and the instruction's address last, see key()
+    stacks: HashMap<Vec<u32>, u64>,
+    // Reused for every instruction
+    current: Vec<u32>,
+    pub total: u64,
+}
+
+// ROM addresses with the bank they were in, 0x4000-0x7FFF is the same
+// address in another bank
+fn key(addr: u16, rom_bank: u8) -> u32 {
+    match addr {
+        0x4000 ... 0x7FFF => (rom_bank as u32) << 16 | addr as u32,
+        _ => addr as u32,
+    }
+}
+
+fn bank_addr(key: u32) -> (Option<u8>, u16) {
+    let addr = key as u16;
+    (symbols::bank_of(addr, (key >> 16) as u8), addr)
+}
+
+// "02:4567", or "C000" outside of ROM
+fn location(key: u32) -> String {
+    match bank_addr(key) {
+        (Some(bank), addr) => format!("{:02X}:{:04X}", bank, addr),
+        (None, addr) => format!("{:04X}", addr),
+    }
+}
+
+// The function key is in: the label before it without its local part
+// (".loop"), or None without one
+fn function(symbols: &Symbols, key: u32) -> Option<String> {
+    let (bank, addr) = bank_addr(key);
+    symbols.containing(bank, addr)
+        .map(|(name, _)| String::from(name.split('.').next().unwrap_or(name)))
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler { stacks: HashMap::new(), current: Vec::new(), total: 0 }
+    }
+
+    // Before the instruction at pc runs, inside the calls of `frames`
+    pub fn start(&mut self, frames: &[Frame], pc: u16, rom_bank: u8) {
+        self.current.clear();
+        self.current.extend(frames.iter().map(|frame| key(frame.addr, frame.rom_bank)));
+        self.current.push(key(pc, rom_bank));
+    }
+
+    // It took `cycles`. An interrupt dispatched instead counts towards the
+    // instruction it held up
+    pub fn finish(&mut self, cycles: u32) {
+        let cycles = cycles as u64;
+        self.total += cycles;
+        if let Some(total) = self.stacks.get_mut(&self.current[..]) {
+            *total += cycles;
+            return;
+        }
+        self.stacks.insert(self.current.clone(), cycles);
+    }
+
+    // The `count` instruction addresses that took the most cycles
+    pub fn hottest(&self, count: usize) -> Vec<(String, u64)> {
+        let mut addrs: HashMap<u32, u64> = HashMap::new();
+        for (stack, &cycles) in self.stacks.iter() {
+            *addrs.entry(*stack.last().unwrap()).or_insert(0) += cycles;
+        }
+        let mut addrs: Vec<(u32, u64)> = addrs.into_iter().collect();
+        addrs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addrs.into_iter().take(count).map(|(key, cycles)| (location(key), cycles)).collect()
+    }
+
+    // The stacks by name, outermost first. Calls are named after the label
+    // they went to, or its address. The function the instruction is in
+    // goes on the end when it isn't the innermost call, like code reached
+    // with a jp or the main loop outside of any call
+    fn named_stacks(&self, symbols: &Symbols) -> HashMap<Vec<String>, u64> {
+        let mut named: HashMap<Vec<String>, u64> = HashMap::new();
+        for (stack, &cycles) in self.stacks.iter() {
+            let (&pc, calls) = stack.split_last().unwrap();
+            let mut names: Vec<String> = calls.iter()
+                .map(|&key| function(symbols, key).unwrap_or_else(|| location(key)))
+                .collect();
+            match function(symbols, pc) {
+                Some(name) => if names.last() != Some(&name) {
+                    names.push(name);
+                },
+                None => if names.is_empty() {
+                    names.push(String::from("(top level)"));
+                },
+            }
+            *named.entry(names).or_insert(0) += cycles;
+        }
+        named
+    }
+
+    // Cycles spent in each function itself, not counting what it called,
+    // the `count` highest
+    pub fn functions(&self, symbols: &Symbols, count: usize) -> Vec<(String, u64)> {
+        let mut funcs: HashMap<String, u64> = HashMap::new();
+        for (mut names, cycles) in self.named_stacks(symbols) {
+            *funcs.entry(names.pop().unwrap()).or_insert(0) += cycles;
+        }
+        let mut funcs: Vec<(String, u64)> = funcs.into_iter().collect();
+        funcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        funcs.truncate(count);
+        funcs
+    }
+
+    pub fn folded(&self, symbols: &Symbols) -> Vec<String> {
+        let mut lines: Vec<String> = self.named_stacks(symbols).into_iter()
+            .map(|(names, cycles)| format!("{} {}", names.join(";"), cycles))
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    pub fn save(&self, path: &str, symbols: &Symbols) -> Result<(), String> {
+        let mut file = try!(File::create(path).map_err(|e| format!("{}: {}", path, e)));
+        for line in self.folded(symbols) {
+            try!(writeln!(file, "{}", line).map_err(|e| format!("{}: {}", path, e)));
+        }
+        Ok(())
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod profiler_tests {
+    use super::*;
+    use cpu::callstack::FrameKind;
+
+    fn frame(addr: u16, rom_bank: u8) -> Frame {
+        Frame { kind: FrameKind::Call, addr: addr, ret: 0x0153, rom_bank: rom_bank, sp: 0xFFFC }
+    }
+
+    #[test]
+    fn stacks() {
+        let mut prof = Profiler::new();
+        let runs = [(vec![], 0x0150, 16), (vec![], 0x0150, 16),
+            (vec![frame(0x4000, 2)], 0x4003, 12), (vec![frame(0x4000, 2)], 0x0200, 8)];
+        for &(ref frames, pc, cycles) in runs.iter() {
+            prof.start(frames, pc, 2);
+            prof.finish(cycles);
+        }
+        assert_eq!(prof.total, 52);
+        assert_eq!(prof.hottest(2), vec![(String::from("00:0150"), 32), (String::from("02:4003"), 12)]);
+
+        // Without symbols the calls are the functions
+        let none = Symbols::new();
+        assert_eq!(prof.folded(&none), vec![
+            String::from("(top level) 32"),
+            String::from("02:4000 20"),
+        ]);
+
+        let symbols = Symbols::parse("00:0150 Main\n00:0200 Memcpy\n02:4000 Draw\n02:4002 Draw.loop\n");
+        assert_eq!(prof.folded(&symbols), vec![
+            String::from("Draw 12"),
+            String::from("Draw;Memcpy 8"),
+            String::from("Main 32"),
+        ]);
+        assert_eq!(prof.functions(&symbols, 2), vec![(String::from("Main"), 32), (String::from("Draw"), 12)]);
+    }
+}
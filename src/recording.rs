@@ -0,0 +1,164 @@
+//
+//      Video recording
+//
+
+// F9 starts and stops recording every emulated frame, taken at VBlank so the
+// video runs at exactly the Game Boy's 59.73 FPS however the window was
+// keeping up. By default it's a YUV4MPEG2 file that ffmpeg reads
+// as is (ffmpeg -i recording_<time>.y4m out.mp4), or a directory of PNGs
+// with --record-format png.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use image::{self, ColorType};
+use gpu::{ScreenData, WIDTH, HEIGHT};
+
+// 4194304 Hz / 70224 cycles a frame
+const FRAME_RATE: &'static str = "F4194304:70224";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    Y4m,
+    Png,
+}
+
+impl Default for RecordFormat {
+    fn default() -> RecordFormat {
+        RecordFormat::Y4m
+    }
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RecordFormat, String> {
+        match s {
+            "y4m" => Ok(RecordFormat::Y4m),
+            "png" => Ok(RecordFormat::Png),
+            _ => Err(format!("Unknown recording format \"{}\", expected y4m or png", s)),
+        }
+    }
+}
+
+enum Output {
+    Y4m(Box<Write>),
+    // Directory the numbered frames go in
+    Png(PathBuf),
+}
+
+pub struct Recorder {
+    out: Output,
+    pub path: String,
+    pub frames: u32,
+}
+
+impl Recorder {
+    // Start a recording named after the time
+    pub fn start(format: RecordFormat, time: u64) -> Result<Recorder, String> {
+        match format {
+            RecordFormat::Y4m => {
+                let path = format!("recording_{}.y4m", time);
+                let file = try!(File::create(&path).map_err(|e| format!("{}: {}", path, e)));
+                Recorder::y4m(BufWriter::new(file), path)
+            }
+            RecordFormat::Png => {
+                let path = format!("recording_{}", time);
+                try!(fs::create_dir_all(&path).map_err(|e| format!("{}: {}", path, e)));
+                Ok(Recorder { out: Output::Png(PathBuf::from(&path)), path: path, frames: 0 })
+            }
+        }
+    }
+
+    pub fn y4m<W: Write + 'static>(mut out: W, path: String) -> Result<Recorder, String> {
+        try!(write!(out, "YUV4MPEG2 W{} H{} {} Ip A1:1 C444\n", WIDTH, HEIGHT, FRAME_RATE)
+            .map_err(|e| format!("{}: {}", path, e)));
+        Ok(Recorder { out: Output::Y4m(Box::new(out)), path: path, frames: 0 })
+    }
+
+    // Called with every emulated frame
+    pub fn push(&mut self, image: &ScreenData) -> Result<(), String> {
+        let res = match self.out {
+            Output::Y4m(ref mut out) => {
+                out.write_all(b"FRAME\n").and_then(|_| out.write_all(&to_yuv444(image)))
+                    .map_err(|e| e.to_string())
+            }
+            Output::Png(ref dir) => {
+                let path = dir.join(format!("{:06}.png", self.frames));
+                image::save_buffer(&path, &image[..], WIDTH as u32, HEIGHT as u32, ColorType::RGBA(8))
+                    .map_err(|e| e.to_string())
+            }
+        };
+        try!(res.map_err(|e| format!("{}: {}", self.path, e)));
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Output::Y4m(ref mut out) = self.out {
+            try!(out.flush().map_err(|e| format!("{}: {}", self.path, e)));
+        }
+        Ok(())
+    }
+}
+
+// RGBA to the Y, U and V planes, BT.601 studio range like ffmpeg expects
+fn to_yuv444(image: &ScreenData) -> Vec<u8> {
+    let n = WIDTH * HEIGHT;
+    let mut planes = vec![0; n * 3];
+    for (i, p) in image.chunks(4).enumerate() {
+        let (r, g, b) = (p[0] as i32, p[1] as i32, p[2] as i32);
+        planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        planes[n + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        planes[2 * n + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+    planes
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod recording_tests {
+    use super::*;
+    use std::io;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    // Keeps what's written where the test can see it
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn y4m() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let mut rec = Recorder::y4m(Shared(written.clone()), String::from("test.y4m")).unwrap();
+        let mut image = [255u8; WIDTH * HEIGHT * 4];
+        image[..4].copy_from_slice(&[0, 0, 0, 255]);
+        rec.push(&image).unwrap();
+        rec.push(&image).unwrap();
+        assert_eq!(rec.frames, 2);
+        rec.finish().unwrap();
+
+        let header = "YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\n";
+        let data = written.borrow();
+        let frame = 6 + WIDTH * HEIGHT * 3;
+        assert_eq!(data.len(), header.len() + frame * 2);
+        assert!(data.starts_with(header.as_bytes()));
+        let y = &data[header.len() + 6..];
+        // Black and white in studio range, no color
+        assert_eq!(&y[..2], &[16, 235]);
+        assert_eq!(y[WIDTH * HEIGHT], 128);
+        assert_eq!(y[WIDTH * HEIGHT * 2 + 1], 128);
+    }
+}
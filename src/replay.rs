@@ -0,0 +1,114 @@
+//
+//      Instant replay
+//
+
+// Always keeps the last REPLAY_SECS seconds of frames, so something that just
+// happened can be saved as a GIF without having been recording. Only every
+// FRAME_STEP-th frame is kept (GIF delays are in hundredths of a second, so
+// 60 FPS can't be played back exactly anyway), stored as palette indices to
+// keep the buffer small.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use gif::{self, Frame};
+use gpu::{ScreenData, WIDTH, HEIGHT};
+
+const REPLAY_SECS: usize = 30;
+const FRAME_STEP: u32 = 3;
+// 20 FPS
+const FRAME_DELAY: u16 = 5;
+const MAX_FRAMES: usize = REPLAY_SECS * 60 / FRAME_STEP as usize;
+
+pub struct ReplayBuffer {
+    frames: VecDeque<Frame>,
+    skipped: u32,
+}
+
+impl ReplayBuffer {
+    pub fn new() -> ReplayBuffer {
+        ReplayBuffer { frames: VecDeque::new(), skipped: 0 }
+    }
+
+    // Called with every emulated frame
+    pub fn push(&mut self, image: &ScreenData) {
+        self.skipped += 1;
+        if self.skipped < FRAME_STEP {
+            return;
+        }
+        self.skipped = 0;
+
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(index_colors(image));
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    // The frames to export, so the buffer can keep going meanwhile
+    pub fn snapshot(&self) -> Vec<Frame> {
+        self.frames.iter().cloned().collect()
+    }
+}
+
+pub fn write_gif<P: AsRef<Path>>(path: P, frames: &[Frame]) -> Result<(), String> {
+    let file = try!(File::create(path).map_err(|e| e.to_string()));
+    let mut enc = try!(gif::Encoder::new(BufWriter::new(file), WIDTH as u16, HEIGHT as u16, FRAME_DELAY)
+        .map_err(|e| e.to_string()));
+    for frame in frames {
+        try!(enc.write_frame(frame).map_err(|e| e.to_string()));
+    }
+    try!(enc.finish().map_err(|e| e.to_string()));
+    Ok(())
+}
+
+// A frame only uses the colors of a few palettes, more than 256 can't
+// happen, but they'd fall back to the first one
+fn index_colors(image: &ScreenData) -> Frame {
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT);
+
+    for p in image.chunks(4) {
+        let color = [p[0], p[1], p[2]];
+        let index = match palette.iter().position(|c| *c == color) {
+            Some(i) => i,
+            None if palette.len() < 256 => {
+                palette.push(color);
+                palette.len() - 1
+            }
+            None => 0,
+        };
+        pixels.push(index as u8);
+    }
+    Frame { palette: palette, pixels: pixels }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+
+    #[test]
+    fn rolling_buffer() {
+        let mut image = [0u8; WIDTH * HEIGHT * 4];
+        image[4..8].copy_from_slice(&[255, 255, 255, 255]);
+
+        let mut replay = ReplayBuffer::new();
+        for _ in 0..(MAX_FRAMES + 5) * FRAME_STEP as usize {
+            replay.push(&image);
+        }
+        assert_eq!(replay.len(), MAX_FRAMES);
+
+        let frame = &replay.snapshot()[0];
+        assert_eq!(frame.palette, vec![[0, 0, 0], [255, 255, 255]]);
+        assert_eq!(&frame.pixels[..3], &[0, 1, 0]);
+    }
+}
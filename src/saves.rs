@@ -0,0 +1,153 @@
+//
+//      Battery saves
+//
+
+// Cartridge RAM of games with a battery is kept in a .sav file next to the
+// rom. Saves are written to a temporary file and renamed over the old one, so
+// a post-save hook (e.g. syncing to a Dropbox/Syncthing folder) only ever
+// sees complete files.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// What to do after a save has been written
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostSaveHook {
+    // Run through the shell, with the save's path in $RUSTBOY_SAVE
+    Command(String),
+    // Copy the save into this directory
+    CopyTo(PathBuf),
+}
+
+impl PostSaveHook {
+    pub fn run(&self, save: &Path) -> io::Result<()> {
+        match *self {
+            PostSaveHook::Command(ref cmd) => {
+                let status = try!(Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .env("RUSTBOY_SAVE", save)
+                    .status());
+                if !status.success() {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                        format!("\"{}\" exited with {}", cmd, status)));
+                }
+            }
+            PostSaveHook::CopyTo(ref dir) => {
+                let name = try!(save.file_name().ok_or(
+                    io::Error::new(io::ErrorKind::InvalidInput, "Save path has no file name")));
+                try!(write_atomic(&dir.join(name), &try!(read(save))));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Other emulators append the MBC3 clock to the save, VBA and BGB in 48 bytes,
+// older versions in 44
+const RTC_FOOTER_SIZES: [usize; 2] = [48, 44];
+
+// tetris.gb -> tetris.sav
+pub fn save_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("sav")
+}
+
+// The .sav next to the rom, or a .srm (RetroArch) to import. Saves are always
+// written back as .sav.
+pub fn find_save<P: AsRef<Path>>(rom_path: P) -> Option<PathBuf> {
+    ["sav", "srm"].iter()
+        .map(|ext| rom_path.as_ref().with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+// Fit a save from another emulator to the cartridge RAM size: known footers
+// are stripped, then it's truncated or padded. Returns what was changed.
+pub fn normalize(data: &[u8], ram_size: usize) -> (Vec<u8>, Vec<String>) {
+    let mut data = data.to_vec();
+    let mut changes = Vec::new();
+
+    if let Some(&footer) = RTC_FOOTER_SIZES.iter().find(|&&n| data.len() == ram_size + n) {
+        data.truncate(ram_size);
+        changes.push(format!("stripped a {} byte clock footer", footer));
+    }
+    if data.len() > ram_size {
+        let extra = data.len() - ram_size;
+        let nonzero = data[ram_size..].iter().any(|&b| b != 0);
+        data.truncate(ram_size);
+        changes.push(format!("dropped {} extra bytes{}", extra,
+            if nonzero { "" } else { " (all zero)" }));
+    } else if data.len() < ram_size {
+        changes.push(format!("padded {} missing bytes with zeros", ram_size - data.len()));
+        data.resize(ram_size, 0);
+    }
+    (data, changes)
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
+// Write to a temporary file next to the target and rename it over it
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("sav.tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(data));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp, path)
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod saves_tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn sav_next_to_rom() {
+        assert_eq!(save_path("roms/tetris.gb"), PathBuf::from("roms/tetris.sav"));
+    }
+
+    #[test]
+    fn normalize_sizes() {
+        let save = vec![7u8; 0x2000];
+        assert_eq!(normalize(&save, 0x2000), (save.clone(), vec![]));
+
+        let mut with_rtc = save.clone();
+        with_rtc.extend_from_slice(&[1; 48]);
+        let (data, changes) = normalize(&with_rtc, 0x2000);
+        assert_eq!(data, save);
+        assert_eq!(changes, vec![String::from("stripped a 48 byte clock footer")]);
+
+        let (data, changes) = normalize(&save, 0x8000);
+        assert_eq!(data.len(), 0x8000);
+        assert_eq!(&data[..0x2000], &save[..]);
+        assert_eq!(changes.len(), 1);
+
+        let (data, changes) = normalize(&vec![0u8; 0x8000], 0x2000);
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(changes, vec![String::from("dropped 24576 extra bytes (all zero)")]);
+    }
+
+    #[test]
+    fn copy_hook() {
+        let dir = env::temp_dir().join("rustboy_saves_tests");
+        let sync = dir.join("sync");
+        fs::create_dir_all(&sync).unwrap();
+
+        let save = dir.join("game.sav");
+        write_atomic(&save, &[1, 2, 3]).unwrap();
+        PostSaveHook::CopyTo(sync.clone()).run(&save).unwrap();
+        assert_eq!(read(sync.join("game.sav")).unwrap(), vec![1, 2, 3]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+// Draws the 160x144 frame over the part of the window pixels gives it. This
+// part has the bindings and the vertex stage, the fragment stage comes after
+// it: screen_fs.wgsl, or a custom shader picked with --shader. See
+// screen_shader.rs
+
+struct Uniforms {
+    // Size of the area drawn to, in window pixels
+    output_size: vec2<f32>,
+    // 1.0 when on
+    grid: f32,
+    crt: f32,
+};
+
+@group(0) @binding(0) var screen: texture_2d<f32>;
+@group(0) @binding(1) var screen_sampler: sampler;
+@group(0) @binding(2) var<uniform> u: Uniforms;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// One triangle big enough to cover the viewport, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) i: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+const SCREEN: vec2<f32> = vec2<f32>(160.0, 144.0);
+const PI: f32 = 3.14159265;
@@ -0,0 +1,42 @@
+// The built-in fragment stage, with the post-processing the uniforms turn
+// on. Goes after screen.wgsl
+
+@fragment
+fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    var uv = vertex.uv;
+    // From the middle, -1 to 1
+    let c = uv * 2.0 - 1.0;
+    if (u.crt > 0.5) {
+        // Bulge out like a curved tube, with black past the edges
+        let bent = c * (1.0 + c.yx * c.yx * vec2<f32>(0.06, 0.08));
+        uv = bent * 0.5 + 0.5;
+    }
+    // textureSampleLevel since what's below depends on the pixel
+    var color = textureSampleLevel(screen, screen_sampler, uv, 0.0).rgb;
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+        color = vec3<f32>(0.0);
+    }
+
+    // Where in its Game Boy pixel this is, and how big those are on screen
+    let cell = fract(uv * SCREEN);
+    let cell_size = u.output_size / SCREEN;
+
+    if (u.grid > 0.5 && cell_size.x >= 3.0) {
+        // A window pixel wide gap on the right and bottom of every pixel,
+        // and a little shading above the bottom one, like lcdgrid.rs
+        let edge = vec2<f32>(1.0) - vec2<f32>(1.0) / cell_size;
+        if (cell.x >= edge.x || cell.y >= edge.y) {
+            color = color * 0.73;
+        } else if (cell.y >= edge.y - 1.0 / cell_size.y) {
+            color = color * 0.88;
+        }
+    }
+
+    if (u.crt > 0.5) {
+        // Scanlines, darkest between the Game Boy's lines
+        color = color * (0.8 + 0.2 * cos((cell.y - 0.5) * 2.0 * PI));
+        // Darker towards the corners
+        color = color * (1.0 - 0.125 * dot(c, c));
+    }
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,312 @@
+//
+//      Screen shader
+//
+
+// Post-processing for the winit frontend, done on the GPU in screen.wgsl
+// instead of per pixel on the CPU. Instead of pixels' own scaling pass this
+// draws its texture (the 160x144 frame, already uploaded) straight to the
+// window with a shader that scales it and adds the LCD grid (--lcd-grid) or
+// a CRT look (--crt): a curved picture, scanlines and darker corners.
+// Frame blending and ghosting still happen in the Gpu, they need the
+// previous frames.
+//
+// --shader replaces the fragment stage with one of your own, a WGSL file
+// with an fs_main that gets everything declared in screen.wgsl (the frame,
+// its sampler and the uniforms). The file is checked for changes while
+// playing and loaded again when saved. One that doesn't compile is logged
+// and the last one that did stays on screen.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use pixels::{Pixels, PixelsContext};
+use pixels::wgpu;
+
+// Bytes in the Uniforms struct of screen.wgsl
+const UNIFORMS_SIZE: u64 = 16;
+// Where --shader looks for shaders given by name
+pub const SHADER_DIR: &'static str = "resources/shaders";
+
+pub struct ScreenShader {
+    pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+    uniforms: wgpu::Buffer,
+    grid: bool,
+    crt: bool,
+    // To build the pipeline again for a changed custom shader
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    custom: Option<CustomShader>,
+    // wgpu errors land here instead of panicking, see build_pipeline
+    errors: Arc<Mutex<Option<String>>>,
+}
+
+// A fragment stage from --shader, and when the file was last loaded
+struct CustomShader {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ScreenShader {
+    pub fn new(pixels: &Pixels, grid: bool, crt: bool, custom: Option<&str>) -> ScreenShader {
+        let device = pixels.device();
+        let errors = Arc::new(Mutex::new(None));
+        {
+            let errors = errors.clone();
+            device.on_uncaptured_error(Box::new(move |err| {
+                *errors.lock().unwrap() = Some(err.to_string());
+            }));
+        }
+
+        // The frame pixels keeps in a texture
+        let frame = pixels.context().texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Sharp pixels, the shader does any smoothing
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("screen sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let uniforms = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("screen uniforms"),
+            size: UNIFORMS_SIZE,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("screen bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("screen bind group"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&frame) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&sampler) },
+                wgpu::BindGroupEntry { binding: 2, resource: uniforms.as_entire_binding() },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("screen pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let format = pixels.render_texture_format();
+        let pipeline = build_pipeline(device, &pipeline_layout, format, &errors, include_str!("screen_fs.wgsl"))
+            .expect("Couldn't build the screen shader");
+
+        let custom = custom.map(|name| {
+            let path = find_shader(name);
+            if !path.is_file() {
+                error!("Couldn't find shader {}", path.display());
+            }
+            CustomShader { path: path, modified: None }
+        });
+        let mut shader = ScreenShader {
+            pipeline: pipeline,
+            bind_group: bind_group,
+            uniforms: uniforms,
+            grid: grid,
+            crt: crt,
+            pipeline_layout: pipeline_layout,
+            format: format,
+            custom: custom,
+            errors: errors,
+        };
+        shader.reload(pixels);
+        shader
+    }
+
+    // Load the custom shader again if its file changed since the last time,
+    // true if the picture changed
+    pub fn reload(&mut self, pixels: &Pixels) -> bool {
+        let path = match self.custom {
+            Some(ref mut custom) => {
+                let modified = fs::metadata(&custom.path).and_then(|m| m.modified()).ok();
+                if modified.is_none() || modified == custom.modified {
+                    return false;
+                }
+                // Whatever happens, wait for the next save before trying again
+                custom.modified = modified;
+                custom.path.clone()
+            }
+            None => return false,
+        };
+
+        let mut fragment = String::new();
+        if let Err(err) = File::open(&path).and_then(|mut f| f.read_to_string(&mut fragment)) {
+            error!("Couldn't read shader {}: {}", path.display(), err);
+            return false;
+        }
+        match build_pipeline(pixels.device(), &self.pipeline_layout, self.format, &self.errors, &fragment) {
+            Ok(pipeline) => {
+                self.pipeline = pipeline;
+                info!("Loaded shader {}", path.display());
+                true
+            }
+            Err(err) => {
+                error!("Shader {} doesn't compile, keeping the last one: {}", path.display(), err);
+                false
+            }
+        }
+    }
+
+    // Draw the frame where pixels would have, the integer scaled area in
+    // the middle of the window
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView, context: &PixelsContext) {
+        if let Some(err) = self.errors.lock().unwrap().take() {
+            error!("wgpu: {}", err);
+        }
+        let (x, y, w, h) = context.scaling_renderer.clip_rect();
+        context.queue.write_buffer(&self.uniforms, 0, &uniform_bytes(w, h, self.grid, self.crt));
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("screen pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_scissor_rect(x, y, w, h);
+        pass.set_viewport(x as f32, y as f32, w as f32, h as f32, 0., 1.);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+// The pipeline drawing with the fragment stage in `fragment`. wgpu checks
+// shaders as they're created and tells the error handler set up in new(), so
+// a broken one comes back as an error
+fn build_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, format: wgpu::TextureFormat,
+                  errors: &Mutex<Option<String>>, fragment: &str) -> Result<wgpu::RenderPipeline, String> {
+    errors.lock().unwrap().take();
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("screen shader"),
+        source: wgpu::ShaderSource::Wgsl(shader_source(fragment).into()),
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("screen pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        multiview: None,
+    });
+    match errors.lock().unwrap().take() {
+        Some(err) => Err(err),
+        None => Ok(pipeline),
+    }
+}
+
+// The bindings and vertex stage, then the fragment stage
+fn shader_source(fragment: &str) -> String {
+    format!("{}\n{}", include_str!("screen.wgsl"), fragment)
+}
+
+// --shader takes a file, or the name of one in SHADER_DIR
+fn find_shader(name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.is_file() {
+        return path.to_path_buf();
+    }
+    let path = Path::new(SHADER_DIR).join(name);
+    if path.extension().is_some() {path} else {path.with_extension("wgsl")}
+}
+
+// The Uniforms struct as the GPU reads it, little endian f32s
+fn uniform_bytes(w: u32, h: u32, grid: bool, crt: bool) -> Vec<u8> {
+    let flag = |on: bool| if on {1.} else {0.};
+    let values = [w as f32, h as f32, flag(grid), flag(crt)];
+    let mut bytes = Vec::with_capacity(UNIFORMS_SIZE as usize);
+    for v in values.iter() {
+        let bits = v.to_bits();
+        bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+    }
+    bytes
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod screen_shader_tests {
+    use super::*;
+
+    #[test]
+    fn uniforms() {
+        let bytes = uniform_bytes(640, 576, true, false);
+        assert_eq!(bytes.len() as u64, UNIFORMS_SIZE);
+        // 640.0, 1.0, 0.0
+        assert_eq!(&bytes[..4], &[0x00, 0x00, 0x20, 0x44]);
+        assert_eq!(&bytes[8..12], &[0x00, 0x00, 0x80, 0x3f]);
+        assert_eq!(&bytes[12..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn custom_shaders() {
+        assert_eq!(find_shader("grayscale"), Path::new(SHADER_DIR).join("grayscale.wgsl"));
+        assert_eq!(find_shader("mine.wgsl"), Path::new(SHADER_DIR).join("mine.wgsl"));
+        assert_eq!(find_shader("src/screen_fs.wgsl"), Path::new("src/screen_fs.wgsl"));
+
+        let source = shader_source("@fragment fn fs_main() {}");
+        assert!(source.starts_with(include_str!("screen.wgsl")));
+        assert!(source.ends_with("@fragment fn fs_main() {}"));
+    }
+}
@@ -0,0 +1,62 @@
+//
+//      Screenshots
+//
+
+// F12 saves the frame on screen to screenshot_<time>.png, at the Game Boy's
+// resolution or blown up by a whole number like the window does, so the
+// pixels stay sharp.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::{self, ColorType};
+use gpu::{WIDTH, HEIGHT};
+
+// The frame with every pixel made scale x scale
+pub fn scale_frame(pixels: &[u8], scale: usize) -> Vec<u8> {
+    let width = WIDTH * scale;
+    let mut scaled = vec![0; width * HEIGHT * scale * 4];
+    for y in 0..HEIGHT * scale {
+        for x in 0..width {
+            let src = ((y / scale) * WIDTH + x / scale) * 4;
+            let dst = (y * width + x) * 4;
+            scaled[dst..dst + 4].copy_from_slice(&pixels[src..src + 4]);
+        }
+    }
+    scaled
+}
+
+// Save RGBA frame data, returns where it went
+pub fn save(pixels: &[u8], scale: usize) -> Result<String, String> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| format!("{}_{:03}", d.as_secs(), d.subsec_nanos() / 1_000_000)).unwrap_or_default();
+    let path = format!("screenshot_{}.png", time);
+    let scaled = if scale > 1 {scale_frame(pixels, scale)} else {pixels.to_vec()};
+    try!(image::save_buffer(&path, &scaled, (WIDTH * scale) as u32, (HEIGHT * scale) as u32,
+        ColorType::RGBA(8)).map_err(|e| format!("{}: {}", path, e)));
+    Ok(path)
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod screenshot_tests {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        let mut pixels = vec![0; WIDTH * HEIGHT * 4];
+        // Pixel (1, 1) red
+        let i = (WIDTH + 1) * 4;
+        pixels[i..i + 4].copy_from_slice(&[255, 0, 0, 255]);
+
+        let scaled = scale_frame(&pixels, 3);
+        assert_eq!(scaled.len(), pixels.len() * 9);
+        let at = |x: usize, y: usize| { let i = (y * WIDTH * 3 + x) * 4; &scaled[i..i + 4] };
+        assert_eq!(at(3, 3), &[255, 0, 0, 255]);
+        assert_eq!(at(5, 5), &[255, 0, 0, 255]);
+        assert_eq!(at(6, 5), &[0, 0, 0, 0]);
+        assert_eq!(at(2, 3), &[0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,100 @@
+//
+//      Serial port
+//
+
+// Writing 0x81 to SC (0xFF02) shifts SB (0xFF01) out at 8192 Hz using the
+// internal clock, while a byte from the other side is shifted in. Once the 8
+// bits are done, SC bit 7 clears and a serial interrupt is requested. With
+// nothing plugged in, 0xFF comes back.
+// Transfers clocked by the other side (SC = 0x80) never finish, no device
+// here drives the clock.
+
+use cpu::Interrupt;
+
+// 4194304 Hz / 8192 Hz per bit * 8 bits
+const TRANSFER_CYCLES: u32 = 4096;
+
+// Something on the other end of the link cable
+pub trait SerialDevice {
+    // Both sides exchange a byte, returns the one the device sent
+    fn exchange(&mut self, sent: u8) -> u8;
+}
+
+pub struct Serial {
+    pub sb: u8,
+    pub sc: u8,
+    // Cycles until the transfer in progress finishes
+    cycles_left: u32,
+    pub device: Option<Box<SerialDevice>>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial { sb: 0, sc: 0, cycles_left: 0, device: None }
+    }
+
+    pub fn rb_sc(&self) -> u8 {
+        self.sc | 0x7E
+    }
+
+    pub fn wb_sc(&mut self, data: u8) {
+        self.sc = data;
+        if data & 0x81 == 0x81 {
+            self.cycles_left = TRANSFER_CYCLES;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32, if_: &mut u8) {
+        if self.cycles_left == 0 {
+            return;
+        }
+        if cycles < self.cycles_left {
+            self.cycles_left -= cycles;
+            return;
+        }
+
+        self.cycles_left = 0;
+        self.sb = match self.device {
+            Some(ref mut device) => device.exchange(self.sb),
+            None => 0xFF,
+        };
+        self.sc &= 0x7F;
+        *if_ |= Interrupt::Serial as u8;
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod serial_tests {
+    use super::*;
+
+    struct Echo;
+    impl SerialDevice for Echo {
+        fn exchange(&mut self, sent: u8) -> u8 { !sent }
+    }
+
+    #[test]
+    fn internal_clock_transfer() {
+        let mut serial = Serial::new();
+        let mut if_ = 0;
+        serial.sb = 0x0F;
+        serial.device = Some(Box::new(Echo));
+        serial.wb_sc(0x81);
+
+        serial.step(TRANSFER_CYCLES - 4, &mut if_);
+        assert_eq!((serial.sb, if_), (0x0F, 0));
+        serial.step(4, &mut if_);
+        assert_eq!(serial.sb, 0xF0);
+        assert_eq!(serial.rb_sc(), 0x7F);
+        assert_eq!(if_, Interrupt::Serial as u8);
+
+        // External clock, nobody drives it
+        if_ = 0;
+        serial.wb_sc(0x80);
+        serial.step(TRANSFER_CYCLES * 2, &mut if_);
+        assert_eq!(if_, 0);
+    }
+}
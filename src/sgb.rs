@@ -0,0 +1,605 @@
+//
+//      Super Game Boy
+//
+
+// Commands are sent to the SGB one bit at a time through the joypad register
+// (P1), see http://problemkaputt.de/pandocs.htm#sgbcommandpackettransfers
+//
+// A transfer starts with a reset pulse (P14 and P15 both LOW), then every bit
+// is sent by pulling one of the lines LOW (P14 = 0, P15 = 1) and releasing both
+// lines again. 128 bits (16 bytes, LSB first) make up a packet, followed by a
+// stop bit (0). The low 3 bits of the first byte tell how many packets the
+// whole command takes.
+
+//
+// Colorization: the SGB has four palettes of four colors, color 0 shared by
+// all of them, and picks one for every 8x8 cell of the screen from a 20x18
+// attribute map. The DMG shade of each pixel (after BGP/OBP0/OBP1) selects
+// the color within the palette, sprites included.
+
+use gpu::{rgb555, Color, IndexData, Palette, ScreenData, HEIGHT, WIDTH};
+
+// Command codes (upper 5 bits of the first byte of a command)
+pub const PAL01: u8 = 0x00;
+pub const PAL23: u8 = 0x01;
+pub const PAL03: u8 = 0x02;
+pub const PAL12: u8 = 0x03;
+pub const ATTR_BLK: u8 = 0x04;
+pub const ATTR_LIN: u8 = 0x05;
+pub const ATTR_DIV: u8 = 0x06;
+pub const ATTR_CHR: u8 = 0x07;
+pub const SOUND: u8 = 0x08;
+pub const SOU_TRN: u8 = 0x09;
+pub const PAL_SET: u8 = 0x0A;
+pub const PAL_TRN: u8 = 0x0B;
+pub const MLT_REQ: u8 = 0x11;
+pub const ATTR_TRN: u8 = 0x15;
+pub const ATTR_SET: u8 = 0x16;
+pub const MASK_EN: u8 = 0x17;
+
+// The attribute map is one palette number per 8x8 cell
+pub const CELLS_X: usize = WIDTH / 8;
+pub const CELLS_Y: usize = HEIGHT / 8;
+pub type AttrMap = [u8; CELLS_X * CELLS_Y];
+
+// PAL_TRN sends 512 palettes, ATTR_TRN 45 attribute files of 90 bytes
+const SYSTEM_PALETTES: usize = 512;
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+
+const PACKET_SIZE: usize = 16;
+
+pub struct Sgb {
+    // SGB functions are only available if the cartridge asks for them
+    pub enabled: bool,
+
+    packet: [u8; PACKET_SIZE],
+    bit: usize,         // bits received of the current packet
+    receiving: bool,    // a reset pulse started a transfer
+    released: bool,     // both lines went HIGH since the last bit
+
+    command: Vec<u8>,
+    packets_left: u8,
+
+    // The last SOUND command. Nothing is played, the SNES side isn't emulated
+    pub sound: Option<SoundCommand>,
+    // SNES sound CPU memory, as uploaded by SOU_TRN
+    pub spc_ram: Vec<u8>,
+
+    // Palettes and attribute maps kept on the SNES side, uploaded by
+    // PAL_TRN and ATTR_TRN and picked from by PAL_SET and ATTR_SET
+    system_palettes: Vec<Palette>,
+    attr_files: Vec<AttrMap>,
+}
+
+// What MASK_EN shows instead of the Game Boy screen, while a game sets up
+// VRAM for a transfer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mask {
+    Off,
+    Freeze,     // keep showing the last frame
+    Black,
+    Color0,     // all color 0
+}
+
+// The colors the SGB draws the screen with. The Gpu keeps these and applies
+// them to every finished frame, see colorize
+pub struct SgbColors {
+    pub palettes: [Palette; 4],
+    pub attrs: AttrMap,
+    pub mask: Mask,
+}
+
+impl SgbColors {
+    // Until a game sends its own, every palette is `pal`
+    pub fn new(pal: Palette) -> SgbColors {
+        SgbColors { palettes: [pal; 4], attrs: [0; CELLS_X * CELLS_Y], mask: Mask::Off }
+    }
+
+    // Color `indices` (DMG shades) into `out`, RGBA8
+    pub fn colorize(&self, indices: &IndexData, out: &mut ScreenData) {
+        let fill = match self.mask {
+            Mask::Off => None,
+            Mask::Freeze => return,
+            Mask::Black => Some([0, 0, 0, 255]),
+            Mask::Color0 => Some(self.palettes[0][0]),
+        };
+        for (i, p) in out.chunks_mut(4).enumerate() {
+            let color = fill.unwrap_or_else(|| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                let pal = self.attrs[(y / 8) * CELLS_X + x / 8] as usize;
+                self.palettes[pal & 3][indices[i] as usize & 3]
+            });
+            p.copy_from_slice(&color);
+        }
+    }
+
+    fn set_color0(&mut self, color: Color) {
+        for pal in self.palettes.iter_mut() {
+            pal[0] = color;
+        }
+    }
+
+    fn fill(&mut self, x1: usize, y1: usize, x2: usize, y2: usize, pal: u8) {
+        for y in y1..(y2 + 1).min(CELLS_Y) {
+            for x in x1..(x2 + 1).min(CELLS_X) {
+                self.attrs[y * CELLS_X + x] = pal & 3;
+            }
+        }
+    }
+}
+
+// Sound effects are played on two ports, A and B, each with its own table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Keep,           // 00, don't change what the port is playing
+    Stop,           // 80
+    Play { code: u8, pitch: u8, volume: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundCommand {
+    pub a: Effect,
+    pub b: Effect,
+    pub score: u8,      // music score to play, from SOU_TRN data
+}
+
+const EFFECTS_A: [&'static str; 0x30] = [
+    "Nintendo", "Game Over", "Drop", "OK A", "OK B", "Select A", "Select B",
+    "Select C", "Mistake buzzer", "Catch item", "Gate squeaks", "Small explosion",
+    "Medium explosion", "Large explosion", "Attacked A", "Attacked B", "Punch A",
+    "Punch B", "Breath in air", "Rocket projectile A", "Rocket projectile B",
+    "Escaping bubble", "Jump", "Fast jump", "Jet takeoff", "Jet landing",
+    "Cup breaking", "Glass breaking", "Level up", "Insert air", "Sword swing",
+    "Water falling", "Fire", "Wall collapsing", "Cancel", "Walking",
+    "Blocking strike", "Picture floats on and off", "Fade in", "Fade out",
+    "Window opening", "Window closing", "Big laser", "Stone gate", "Teleportation",
+    "Lightning", "Earthquake", "Small laser",
+];
+
+const EFFECTS_B: [&'static str; 0x19] = [
+    "Applause (small group)", "Applause (medium group)", "Applause (large group)",
+    "Wind", "Rain", "Storm", "Storm with wind and thunder", "Lightning",
+    "Earthquake", "Avalanche", "Wave", "River", "Waterfall",
+    "Small character running", "Horse running", "Warning sound", "Approaching car",
+    "Jet flying", "UFO flying", "Electromagnetic waves", "Score up", "Fire",
+    "Camera shutter", "Write", "Show up title",
+];
+
+impl Effect {
+    fn from_packet(code: u8, attributes: u8) -> Effect {
+        match code {
+            0x00 => Effect::Keep,
+            0x80 => Effect::Stop,
+            _ => Effect::Play { code: code, pitch: attributes & 3, volume: (attributes >> 2) & 3 },
+        }
+    }
+
+    fn describe(&self, names: &[&str]) -> String {
+        match *self {
+            Effect::Keep => String::from("-"),
+            Effect::Stop => String::from("stop"),
+            Effect::Play { code, pitch, volume } => format!("{} (pitch {}, volume {})",
+                names.get(code as usize - 1).cloned().unwrap_or("unknown"), pitch, volume),
+        }
+    }
+}
+
+impl SoundCommand {
+    pub fn from_packet(cmd: &[u8]) -> SoundCommand {
+        SoundCommand {
+            a: Effect::from_packet(cmd[1], cmd[3]),
+            b: Effect::from_packet(cmd[2], cmd[3] >> 4),
+            score: cmd[4],
+        }
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            enabled: false,
+            packet: [0; PACKET_SIZE],
+            bit: 0,
+            receiving: false,
+            released: false,
+            command: Vec::new(),
+            packets_left: 0,
+            sound: None,
+            spc_ram: vec![0; 0x10000],
+            system_palettes: vec![[[0, 0, 0, 255]; 4]; SYSTEM_PALETTES],
+            attr_files: vec![[0; CELLS_X * CELLS_Y]; ATTR_FILES],
+        }
+    }
+
+    // Handle the palette and attribute commands. `data` is the 4KB of VRAM
+    // for PAL_TRN and ATTR_TRN. Returns false for other commands
+    pub fn color_command(&mut self, cmd: &[u8], data: &[u8], colors: &mut SgbColors) -> bool {
+        let word = |i: usize| cmd[i] as u16 | (cmd[i + 1] as u16) << 8;
+        let code = cmd[0] >> 3;
+        match code {
+            PAL01 | PAL23 | PAL03 | PAL12 => {
+                let (a, b) = match code { PAL01 => (0, 1), PAL23 => (2, 3), PAL03 => (0, 3), _ => (1, 2) };
+                colors.set_color0(rgb555(word(1)));
+                for i in 0..3 {
+                    colors.palettes[a][i + 1] = rgb555(word(3 + i * 2));
+                    colors.palettes[b][i + 1] = rgb555(word(9 + i * 2));
+                }
+            }
+            ATTR_BLK => {
+                let sets = (cmd[1] as usize).min((cmd.len() - 2) / 6);
+                for set in cmd[2..2 + sets * 6].chunks(6) {
+                    attr_block(colors, set);
+                }
+            }
+            ATTR_LIN => {
+                let lines = (cmd[1] as usize).min(cmd.len() - 2);
+                for &line in cmd[2..2 + lines].iter() {
+                    let (n, pal) = ((line & 0x1F) as usize, (line >> 5) & 3);
+                    if line & 0x80 != 0 {
+                        colors.fill(0, n, CELLS_X - 1, n, pal);
+                    } else {
+                        colors.fill(n, 0, n, CELLS_Y - 1, pal);
+                    }
+                }
+            }
+            ATTR_DIV => {
+                let (after, before, on) = (cmd[1] & 3, (cmd[1] >> 2) & 3, (cmd[1] >> 4) & 3);
+                let n = cmd[2] as usize;
+                if cmd[1] & 0x40 != 0 {
+                    // A horizontal line at row n
+                    colors.fill(0, 0, CELLS_X - 1, CELLS_Y - 1, after);
+                    if n > 0 {
+                        colors.fill(0, 0, CELLS_X - 1, n - 1, before);
+                    }
+                    colors.fill(0, n, CELLS_X - 1, n, on);
+                } else {
+                    colors.fill(0, 0, CELLS_X - 1, CELLS_Y - 1, after);
+                    if n > 0 {
+                        colors.fill(0, 0, n - 1, CELLS_Y - 1, before);
+                    }
+                    colors.fill(n, 0, n, CELLS_Y - 1, on);
+                }
+            }
+            ATTR_CHR => {
+                let (mut x, mut y) = (cmd[1] as usize, cmd[2] as usize);
+                let count = (word(3) as usize).min((cmd.len() - 6) * 4);
+                let vertical = cmd[5] & 1 != 0;
+                for i in 0..count {
+                    if x >= CELLS_X || y >= CELLS_Y {
+                        break;
+                    }
+                    colors.attrs[y * CELLS_X + x] = cmd[6 + i / 4] >> (6 - (i % 4) * 2) & 3;
+                    if vertical {
+                        y += 1;
+                        if y == CELLS_Y { y = 0; x += 1; }
+                    } else {
+                        x += 1;
+                        if x == CELLS_X { x = 0; y += 1; }
+                    }
+                }
+            }
+            PAL_SET => {
+                for i in 0..4 {
+                    colors.palettes[i] = self.system_palettes[word(1 + i * 2) as usize % SYSTEM_PALETTES];
+                }
+                // Color 0 of the first palette is used for all of them
+                let color0 = colors.palettes[0][0];
+                colors.set_color0(color0);
+                if cmd[9] & 0x80 != 0 {
+                    self.attr_set(cmd[9], colors);
+                }
+            }
+            PAL_TRN => {
+                for (pal, bytes) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (i, c) in bytes.chunks(2).enumerate() {
+                        pal[i] = rgb555(c[0] as u16 | (c[1] as u16) << 8);
+                    }
+                }
+                info!("SGB palettes received");
+            }
+            ATTR_TRN => {
+                for (file, bytes) in self.attr_files.iter_mut().zip(data.chunks(ATTR_FILE_SIZE)) {
+                    for (i, attr) in file.iter_mut().enumerate() {
+                        *attr = bytes[i / 4] >> (6 - (i % 4) * 2) & 3;
+                    }
+                }
+                info!("SGB attribute files received");
+            }
+            ATTR_SET => self.attr_set(cmd[1], colors),
+            MASK_EN => {
+                colors.mask = match cmd[1] & 3 {
+                    0 => Mask::Off, 1 => Mask::Freeze, 2 => Mask::Black, _ => Mask::Color0,
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Bits 0-5 pick the attribute file, bit 6 turns the mask off
+    fn attr_set(&self, arg: u8, colors: &mut SgbColors) {
+        colors.attrs = self.attr_files[(arg & 0x3F) as usize % ATTR_FILES];
+        if arg & 0x40 != 0 {
+            colors.mask = Mask::Off;
+        }
+    }
+
+    pub fn sound(&mut self, cmd: &[u8]) {
+        let sound = SoundCommand::from_packet(cmd);
+        info!("SGB sound, A: {}, B: {}, score: {:02X}",
+            sound.a.describe(&EFFECTS_A), sound.b.describe(&EFFECTS_B), sound.score);
+        self.sound = Some(sound);
+    }
+
+    // `data` is the 4KB of VRAM the SGB picks up from the screen
+    pub fn sou_trn(&mut self, data: &[u8]) {
+        match parse_spc_blocks(data) {
+            Ok((blocks, entry)) => {
+                for &(addr, ref bytes) in blocks.iter() {
+                    for (i, &b) in bytes.iter().enumerate() {
+                        self.spc_ram[(addr as usize + i) & 0xFFFF] = b;
+                    }
+                }
+                info!("SGB sound data: {} blocks, {} bytes, entry point {:04X}", blocks.len(),
+                    blocks.iter().map(|b| b.1.len()).sum::<usize>(), entry);
+            }
+            Err(err) => warn!("Invalid SGB sound data: {}", err),
+        }
+    }
+
+    // Feed a write to P1 (0xFF00). Returns the whole command once its last
+    // packet has been received.
+    pub fn wb(&mut self, data: u8) -> Option<Vec<u8>> {
+        match data & 0x30 {
+            // Reset pulse
+            0x00 => {
+                self.receiving = true;
+                self.released = false;
+                self.bit = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            // Both lines released, ready for the next bit
+            0x30 => self.released = true,
+            // P14 LOW: 0 bit, P15 LOW: 1 bit
+            lines => {
+                if !self.receiving || !self.released {
+                    return None;
+                }
+                self.released = false;
+
+                if self.bit == PACKET_SIZE * 8 {
+                    // Stop bit
+                    self.receiving = false;
+                    return self.packet_done();
+                }
+                if lines == 0x10 {
+                    self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                }
+                self.bit += 1;
+            }
+        }
+        None
+    }
+
+    fn packet_done(&mut self) -> Option<Vec<u8>> {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = match self.packet[0] & 7 { 0 => 1, n => n };
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+
+        if self.packets_left == 0 {
+            debug!("SGB command {:02X} received ({} bytes)",
+                self.command[0] >> 3, self.command.len());
+            Some(self.command.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// One ATTR_BLK data set: which parts to change, their palettes and the
+// block's corners in cells. With only the inside or only the outside to
+// change, the border goes with it.
+fn attr_block(colors: &mut SgbColors, set: &[u8]) {
+    let (ctrl, pals) = (set[0] & 7, set[1]);
+    let (x1, y1, x2, y2) = (set[2] as usize & 0x1F, set[3] as usize & 0x1F,
+                            set[4] as usize & 0x1F, set[5] as usize & 0x1F);
+    let (inside, outside) = (pals & 3, (pals >> 4) & 3);
+    let border = match ctrl {
+        1 => Some(inside),
+        4 => Some(outside),
+        _ if ctrl & 2 != 0 => Some((pals >> 2) & 3),
+        _ => None,
+    };
+    for y in 0..CELLS_Y {
+        for x in 0..CELLS_X {
+            let pal = if x < x1 || x > x2 || y < y1 || y > y2 {
+                if ctrl & 4 != 0 { Some(outside) } else { None }
+            } else if x == x1 || x == x2 || y == y1 || y == y2 {
+                border
+            } else if ctrl & 1 != 0 {
+                Some(inside)
+            } else {
+                None
+            };
+            if let Some(pal) = pal {
+                colors.attrs[y * CELLS_X + x] = pal;
+            }
+        }
+    }
+}
+
+// SOU_TRN data is a list of blocks: a 16 bit length, the SPC RAM address to
+// copy to and the bytes. A block of length 0 ends it, its address is where the
+// sound CPU jumps to.
+fn parse_spc_blocks(data: &[u8]) -> Result<(Vec<(u16, Vec<u8>)>, u16), String> {
+    let word = |i: usize| data[i] as u16 | (data[i + 1] as u16) << 8;
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+
+    while pos + 4 <= data.len() {
+        let (len, addr) = (word(pos) as usize, word(pos + 2));
+        pos += 4;
+        if len == 0 {
+            return Ok((blocks, addr));
+        }
+        if pos + len > data.len() {
+            return Err(format!("block at {:04X} goes past the end of the transfer", addr));
+        }
+        blocks.push((addr, data[pos..pos + len].to_vec()));
+        pos += len;
+    }
+    Err(String::from("no end block"))
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod sgb_tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) -> Option<Vec<u8>> {
+        sgb.wb(0x00);
+        sgb.wb(0x30);
+        for i in 0..PACKET_SIZE * 8 {
+            let bit = (packet[i / 8] >> (i % 8)) & 1;
+            assert_eq!(sgb.wb(if bit == 1 {0x10} else {0x20}), None);
+            sgb.wb(0x30);
+        }
+        sgb.wb(0x20)
+    }
+
+    #[test]
+    fn receive_mlt_req() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (MLT_REQ << 3) | 1;
+        packet[1] = 0x03;
+
+        let cmd = send_packet(&mut sgb, &packet).unwrap();
+        assert_eq!(cmd.len(), PACKET_SIZE);
+        assert_eq!(cmd[0] >> 3, MLT_REQ);
+        assert_eq!(cmd[1], 0x03);
+    }
+
+    #[test]
+    fn receive_multi_packet_command() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (0x04 << 3) | 2;
+
+        assert_eq!(send_packet(&mut sgb, &packet), None);
+        packet[0] = 0xAA;
+        let cmd = send_packet(&mut sgb, &packet).unwrap();
+        assert_eq!(cmd.len(), PACKET_SIZE * 2);
+        assert_eq!(cmd[PACKET_SIZE], 0xAA);
+    }
+
+    #[test]
+    fn sound_packet() {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (SOUND << 3) | 1;
+        packet[1] = 0x17;
+        packet[2] = 0x80;
+        packet[3] = 0b0000_1011;
+
+        let sound = SoundCommand::from_packet(&packet);
+        assert_eq!(sound.a, Effect::Play { code: 0x17, pitch: 3, volume: 2 });
+        assert_eq!(sound.b, Effect::Stop);
+        assert_eq!(sound.a.describe(&EFFECTS_A), "Jump (pitch 3, volume 2)");
+    }
+
+    #[test]
+    fn spc_blocks() {
+        let mut data = vec![0u8; 0x1000];
+        data[..7].copy_from_slice(&[3, 0, 0x00, 0x20, 0xAA, 0xBB, 0xCC]);
+        data[7..11].copy_from_slice(&[0, 0, 0x00, 0x04]);
+
+        let mut sgb = Sgb::new();
+        sgb.sou_trn(&data);
+        assert_eq!(&sgb.spc_ram[0x2000..0x2003], &[0xAA, 0xBB, 0xCC]);
+        assert_eq!(parse_spc_blocks(&data).unwrap().1, 0x0400);
+        assert!(parse_spc_blocks(&[0xFF, 0x00, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn palettes_and_blocks() {
+        let mut sgb = Sgb::new();
+        let mut colors = SgbColors::new([[255; 4]; 4]);
+
+        // Color 0 white, palette 0 all red, palette 1 all blue
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (PAL01 << 3) | 1;
+        packet[1..3].copy_from_slice(&[0xFF, 0x7F]);
+        for i in 0..3 {
+            packet[3 + i * 2..5 + i * 2].copy_from_slice(&[0x1F, 0x00]);
+            packet[9 + i * 2..11 + i * 2].copy_from_slice(&[0x00, 0x7C]);
+        }
+        let cmd = send_packet(&mut sgb, &packet).unwrap();
+        assert!(sgb.color_command(&cmd, &[], &mut colors));
+        assert_eq!(colors.palettes[1], [[255; 4], [0, 0, 255, 255], [0, 0, 255, 255], [0, 0, 255, 255]]);
+        assert_eq!(colors.palettes[3][0], [255; 4]);
+
+        // Palette 1 inside cells 2-5, 2 on the border and 3 outside
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (ATTR_BLK << 3) | 1;
+        packet[1] = 1;
+        packet[2..8].copy_from_slice(&[0b111, 0b11_10_01, 2, 2, 5, 5]);
+        let cmd = send_packet(&mut sgb, &packet).unwrap();
+        assert!(sgb.color_command(&cmd, &[], &mut colors));
+        assert_eq!(colors.attrs[3 * CELLS_X + 3], 1);
+        assert_eq!(colors.attrs[2 * CELLS_X + 4], 2);
+        assert_eq!(colors.attrs[0], 3);
+
+        // Only the inside: the border goes with it, outside is left alone
+        colors.attrs = [0; CELLS_X * CELLS_Y];
+        attr_block(&mut colors, &[0b001, 0b11_10_01, 2, 2, 5, 5]);
+        assert_eq!(colors.attrs[2 * CELLS_X + 4], 1);
+        assert_eq!(colors.attrs[0], 0);
+
+        let mut indices = [0u8; WIDTH * HEIGHT];
+        indices[3 * 8 * WIDTH + 3 * 8] = 2;
+        let mut out = [0u8; WIDTH * HEIGHT * 4];
+        colors.colorize(&indices, &mut out);
+        let i = (3 * 8 * WIDTH + 3 * 8) * 4;
+        assert_eq!(&out[i..i + 4], &[0, 0, 255, 255]);
+        assert_eq!(&out[..4], &[255; 4]);
+    }
+
+    #[test]
+    fn attribute_files_and_mask() {
+        let mut sgb = Sgb::new();
+        let mut colors = SgbColors::new([[255; 4]; 4]);
+
+        // File 1 is all palette 2
+        let mut data = vec![0u8; 0x1000];
+        for b in data[ATTR_FILE_SIZE..ATTR_FILE_SIZE * 2].iter_mut() {
+            *b = 0b10_10_10_10;
+        }
+        let mut cmd = [0u8; PACKET_SIZE];
+        cmd[0] = (ATTR_TRN << 3) | 1;
+        assert!(sgb.color_command(&cmd, &data, &mut colors));
+
+        cmd = [0u8; PACKET_SIZE];
+        cmd[0] = (MASK_EN << 3) | 1;
+        cmd[1] = 2;
+        assert!(sgb.color_command(&cmd, &[], &mut colors));
+        assert_eq!(colors.mask, Mask::Black);
+
+        // Picking the file and canceling the mask together
+        cmd = [0u8; PACKET_SIZE];
+        cmd[0] = (ATTR_SET << 3) | 1;
+        cmd[1] = 0x41;
+        assert!(sgb.color_command(&cmd, &[], &mut colors));
+        assert_eq!(colors.mask, Mask::Off);
+        assert!(colors.attrs.iter().all(|&a| a == 2));
+
+        cmd[0] = (SOUND << 3) | 1;
+        assert!(!sgb.color_command(&cmd, &[], &mut colors));
+    }
+}
@@ -0,0 +1,225 @@
+//
+//      Boot splash
+//
+
+// The boot ROM isn't run, games start right where it would hand over. This
+// puts something on screen before that: the scrolling logo recreated from the
+// one in the cartridge header (like the real boot ROM, so hacked logos show
+// too), or an image of your own.
+
+use std::str::FromStr;
+
+use image;
+
+use gpu::{Palette, ScreenData, WIDTH, HEIGHT};
+
+// Frames to hold the logo (or image) still before the game starts
+const HOLD_FRAMES: u32 = 60;
+const LOGO_WIDTH: usize = 48;
+const LOGO_HEIGHT: usize = 8;
+// The logo is drawn at twice its size, where the boot ROM leaves it
+const LOGO_X: usize = 32;
+const LOGO_Y: usize = 64;
+// The ® the boot ROM puts right of the logo
+const REGISTERED: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplashKind {
+    // Straight into the game, what the emulator has always done
+    Skip,
+    // The scrolling logo
+    Logo,
+    // An image file, centered
+    Image(String),
+}
+
+impl Default for SplashKind {
+    fn default() -> SplashKind {
+        SplashKind::Skip
+    }
+}
+
+impl FromStr for SplashKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SplashKind, String> {
+        match s {
+            "skip" => Ok(SplashKind::Skip),
+            "logo" => Ok(SplashKind::Logo),
+            "" => Err(String::from("Expected logo, skip or an image")),
+            path => Ok(SplashKind::Image(String::from(path))),
+        }
+    }
+}
+
+pub struct Splash {
+    // The logo's pixels, or the whole image
+    bitmap: Vec<bool>,
+    image: Option<Box<ScreenData>>,
+    colors: Palette,
+    frame: u32,
+    pub screen: Box<ScreenData>,
+}
+
+impl Splash {
+    // None when there's nothing to show. `header_logo` is 0x104-0x133 of the
+    // rom, `colors` the ones the game will start with
+    pub fn new(kind: &SplashKind, header_logo: &[u8], colors: Palette) -> Option<Splash> {
+        let mut splash = Splash {
+            bitmap: Vec::new(),
+            image: None,
+            colors: colors,
+            frame: 0,
+            screen: Box::new([0; WIDTH * HEIGHT * 4]),
+        };
+        match *kind {
+            SplashKind::Skip => return None,
+            SplashKind::Logo => splash.bitmap = decode_logo(header_logo),
+            SplashKind::Image(ref path) => match load_image(path, colors[0]) {
+                Ok(image) => splash.image = Some(image),
+                Err(err) => {
+                    warn!("Couldn't load boot splash {}: {}", path, err);
+                    return None;
+                }
+            },
+        }
+        splash.draw();
+        Some(splash)
+    }
+
+    // Advance a frame, false once it's over and the game should start
+    pub fn step(&mut self) -> bool {
+        self.frame += 1;
+        self.draw();
+        self.frame < self.scroll_frames() + HOLD_FRAMES
+    }
+
+    // The logo comes down from above the screen a line per frame
+    fn scroll_frames(&self) -> u32 {
+        if self.image.is_some() { 0 } else { (LOGO_Y + LOGO_HEIGHT * 2) as u32 }
+    }
+
+    fn draw(&mut self) {
+        if let Some(ref image) = self.image {
+            self.screen.copy_from_slice(&image[..]);
+            return;
+        }
+
+        for p in self.screen.chunks_mut(4) {
+            p.copy_from_slice(&self.colors[0]);
+        }
+        let top = self.frame.min(self.scroll_frames()) as isize - (LOGO_HEIGHT * 2) as isize;
+        let dark = self.colors[3];
+        for y in 0..LOGO_HEIGHT * 2 {
+            for x in 0..LOGO_WIDTH * 2 {
+                if self.bitmap[(y / 2) * LOGO_WIDTH + x / 2] {
+                    set_pixel(&mut self.screen, LOGO_X + x, top + y as isize, dark);
+                }
+            }
+        }
+        for (y, row) in REGISTERED.iter().enumerate() {
+            for x in 0..8 {
+                if row & (0x80 >> x) != 0 {
+                    set_pixel(&mut self.screen, LOGO_X + LOGO_WIDTH * 2 + x, top + y as isize, dark);
+                }
+            }
+        }
+    }
+}
+
+fn set_pixel(screen: &mut ScreenData, x: usize, y: isize, color: [u8; 4]) {
+    if y < 0 || y as usize >= HEIGHT || x >= WIDTH {
+        return;
+    }
+    let i = (y as usize * WIDTH + x) * 4;
+    screen[i..i + 4].copy_from_slice(&color);
+}
+
+// The header logo is 48x8 pixels in 4x4 blocks, two bytes each: the top row
+// of blocks first, then the bottom one. Every nibble is a row of a block
+pub fn decode_logo(logo: &[u8]) -> Vec<bool> {
+    let mut bitmap = vec![false; LOGO_WIDTH * LOGO_HEIGHT];
+    for (i, &byte) in logo.iter().take(48).enumerate() {
+        let block = (i % 24) / 2;
+        for half in 0..2 {
+            let nibble = if half == 0 { byte >> 4 } else { byte & 0xF };
+            let y = (i / 24) * 4 + (i % 2) * 2 + half;
+            for bit in 0..4 {
+                bitmap[y * LOGO_WIDTH + block * 4 + bit] = nibble & (0x8 >> bit) != 0;
+            }
+        }
+    }
+    bitmap
+}
+
+// Centers the image on a screen of the background color, cropping what
+// doesn't fit
+fn load_image(path: &str, background: [u8; 4]) -> Result<Box<ScreenData>, String> {
+    let img = try!(image::open(path).map_err(|e| e.to_string())).to_rgba();
+    let (w, h) = (img.width() as usize, img.height() as usize);
+
+    let mut screen = Box::new([0u8; WIDTH * HEIGHT * 4]);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            // Where the screen pixel lands in the image
+            let ix = (x + w / 2) as isize - (WIDTH / 2) as isize;
+            let iy = (y + h / 2) as isize - (HEIGHT / 2) as isize;
+            let color = if ix >= 0 && iy >= 0 && (ix as usize) < w && (iy as usize) < h {
+                img.get_pixel(ix as u32, iy as u32).data
+            } else {
+                background
+            };
+            let i = (y * WIDTH + x) * 4;
+            screen[i..i + 4].copy_from_slice(&color);
+        }
+    }
+    Ok(screen)
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod splash_tests {
+    use super::*;
+
+    const NINTENDO_LOGO: [u8; 48] = [
+        0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+        0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+        0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+        0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+    ];
+
+    fn row(bitmap: &[bool], y: usize) -> String {
+        bitmap[y * LOGO_WIDTH..(y + 1) * LOGO_WIDTH].iter().map(|&b| if b { '#' } else { '.' }).collect()
+    }
+
+    #[test]
+    fn logo() {
+        let bitmap = decode_logo(&NINTENDO_LOGO);
+        // The i's dot, the t and the d's stem, then the bottom row
+        assert_eq!(row(&bitmap, 1), "###..##.##........##...................##.......");
+        assert_eq!(row(&bitmap, 7), "##...##.##.##..##.##..#####.##..##..#####..####.");
+    }
+
+    #[test]
+    fn scrolls_then_holds() {
+        let colors = [[255, 255, 255, 255], [170, 170, 170, 255], [85, 85, 85, 255], [0, 0, 0, 255]];
+        assert!(Splash::new(&SplashKind::Skip, &NINTENDO_LOGO, colors).is_none());
+
+        let mut splash = Splash::new(&SplashKind::Logo, &NINTENDO_LOGO, colors).unwrap();
+        let mut frames = 1;
+        while splash.step() {
+            frames += 1;
+        }
+        assert_eq!(frames, (LOGO_Y + LOGO_HEIGHT * 2) as u32 + HOLD_FRAMES);
+        // Top left pixel of the N, where the logo comes to rest
+        let i = (LOGO_Y * WIDTH + LOGO_X) * 4;
+        assert_eq!(&splash.screen[i..i + 4], &[0, 0, 0, 255]);
+        assert_eq!(&splash.screen[..4], &[255, 255, 255, 255]);
+
+        assert_eq!("logo".parse(), Ok(SplashKind::Logo));
+        assert_eq!("boot.png".parse(), Ok(SplashKind::Image(String::from("boot.png"))));
+    }
+}
@@ -0,0 +1,202 @@
+//
+//      Symbols
+//
+
+// Labels from the .sym file RGBDS (rgblink -n) and most other toolchains
+// write next to the rom, a line per label:
+//   01:4000 Main.loop
+// Comments start with ';'. WLA-DX's files split into sections and only the
+// [labels] one has labels, with 4 digit banks. The bank only means something
+// for ROM in 0x4000-0x7FFF, everything else is looked up by address alone.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct Symbols {
+    by_addr: BTreeMap<(u8, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols { by_addr: BTreeMap::new() }
+    }
+
+    // Lines that aren't labels are skipped, some tools put other things in
+    pub fn parse(src: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        let mut in_labels = true;
+        for line in src.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.starts_with('[') {
+                in_labels = line == "[labels]";
+                continue;
+            }
+            if !in_labels {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (loc, name) = match (words.next(), words.next()) {
+                (Some(loc), Some(name)) => (loc, name),
+                _ => continue,
+            };
+            let mut parts = loc.splitn(2, ':');
+            let bank = parts.next().and_then(|b| u8::from_str_radix(b, 16).ok());
+            let addr = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.by_addr.insert((bank, addr), String::from(name));
+            }
+        }
+        symbols
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Symbols, String> {
+        let mut src = String::new();
+        try!(File::open(path.as_ref())
+            .and_then(|mut file| file.read_to_string(&mut src))
+            .map_err(|e| format!("{}: {}", path.as_ref().display(), e)));
+        Ok(Symbols::parse(&src))
+    }
+
+    // The .sym file next to the rom, if there's one
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P) -> Option<Symbols> {
+        let path = rom_path.as_ref().with_extension("sym");
+        if !path.exists() {
+            return None;
+        }
+        match Symbols::load(&path) {
+            Ok(symbols) => {
+                info!("Loaded {} symbols from {}", symbols.len(), path.display());
+                Some(symbols)
+            }
+            Err(err) => {
+                warn!("Couldn't load symbols: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    // Where the label called `name` is
+    pub fn find(&self, name: &str) -> Option<(u8, u16)> {
+        self.by_addr.iter().find(|&(_, n)| n == name).map(|(&loc, _)| loc)
+    }
+
+    // The label right at addr
+    pub fn label_at(&self, bank: Option<u8>, addr: u16) -> Option<&str> {
+        match self.containing(bank, addr) {
+            Some((name, 0)) => Some(name),
+            _ => None,
+        }
+    }
+
+    // addr as the label before it and how far past it, like Main.loop+$3
+    pub fn describe(&self, bank: Option<u8>, addr: u16) -> Option<String> {
+        match self.containing(bank, addr) {
+            Some((name, 0)) => Some(String::from(name)),
+            Some((name, offset)) => Some(format!("{}+${:X}", name, offset)),
+            None => None,
+        }
+    }
+
+    // The last label at or before addr, in the same bank and memory region,
+    // and how far past it addr is
+    pub fn containing(&self, bank: Option<u8>, addr: u16) -> Option<(&str, u16)> {
+        let found = match bank {
+            Some(bank) => match self.by_addr.get(&(bank, addr)) {
+                Some(name) => Some((addr, name)),
+                None => self.by_addr.range((bank, 0)..(bank, addr))
+                    .next_back()
+                    .map(|(&(_, a), name)| (a, name)),
+            },
+            None => self.by_addr.iter()
+                .filter(|&(&(_, a), _)| a <= addr)
+                .max_by_key(|&(&(_, a), _)| a)
+                .map(|(&(_, a), name)| (a, name)),
+        };
+        match found {
+            Some((a, name)) if region(a) == region(addr) => Some((name.as_str(), addr - a)),
+            _ => None,
+        }
+    }
+}
+
+// Which part of the memory map addr is in, labels don't reach past their own
+fn region(addr: u16) -> u8 {
+    match addr {
+        0x0000 ... 0x3FFF => 0,
+        0x4000 ... 0x7FFF => 1,
+        0x8000 ... 0x9FFF => 2,
+        0xA000 ... 0xBFFF => 3,
+        0xC000 ... 0xFDFF => 4,
+        0xFE00 ... 0xFF7F => 5,
+        _ => 6,
+    }
+}
+
+// The bank symbols for addr are looked up in, with rom_bank mapped at
+// 0x4000-0x7FFF
+pub fn bank_of(addr: u16, rom_bank: u8) -> Option<u8> {
+    match addr {
+        0x0000 ... 0x3FFF => Some(0),
+        0x4000 ... 0x7FFF => Some(rom_bank),
+        _ => None,
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::parse("; File generated by rgblink
+00:0150 Start
+00:0160 Start.loop
+01:4000 Update
+02:4000 Draw   ; comment
+00:C000 wPlayerX
+SECTION stuff
+");
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.containing(Some(0), 0x0150), Some(("Start", 0)));
+        assert_eq!(symbols.containing(Some(0), 0x0165), Some(("Start.loop", 5)));
+        assert_eq!(symbols.containing(Some(0), 0x0100), None);
+        assert_eq!(symbols.containing(Some(2), 0x4010), Some(("Draw", 0x10)));
+        assert_eq!(symbols.containing(Some(3), 0x4010), None);
+        assert_eq!(symbols.containing(None, 0xC001), Some(("wPlayerX", 1)));
+        // ROM0 labels don't run on into WRAM
+        assert_eq!(symbols.containing(None, 0x8000), None);
+
+        assert_eq!(symbols.find("Draw"), Some((2, 0x4000)));
+        assert_eq!(symbols.label_at(Some(0), 0x0165), None);
+        assert_eq!(symbols.describe(Some(0), 0x016A), Some(String::from("Start.loop+$A")));
+    }
+
+    #[test]
+    fn wla_dx() {
+        let symbols = Symbols::parse("[labels]
+0000:0150 main
+0001:4000 update
+[definitions]
+00000010 _sizeof_update
+[addr-to-line mapping]
+0000:0150 0000:0000002d
+");
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.label_at(Some(1), 0x4000), Some("update"));
+    }
+}
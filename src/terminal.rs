@@ -0,0 +1,383 @@
+//
+//      Terminal frontend
+//
+
+// Plays in the terminal, for SSH sessions and quick checks without a window
+// (--terminal <mode>). Every new frame is written over the last one:
+//
+// blocks  - ▀ half blocks in 24-bit color, two Game Boy pixels a character,
+//           160x72 characters
+// braille - braille dots, 2x4 pixels a character and 80x36 characters, only
+//           light or dark
+// sixel   - real pixels, for terminals that do sixel graphics (xterm -ti 340,
+//           mlterm, foot, WezTerm...)
+//
+// Terminals only tell when a key is typed, not when it's let go, so a key
+// holds its button for a few frames. Z/X are A/B, Space is Select, Enter is
+// Start, the arrows are the d-pad and Q quits. Logging goes to stderr, best
+// sent somewhere else (2>rustboy.log).
+
+use std::io::{self, Read, Write};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use emulator::Emulator;
+use gpu::{ScreenData, WIDTH, HEIGHT};
+use input::Joypad;
+
+// 70224 cycles a frame at 4194304 Hz
+const FRAME_TIME: Duration = Duration::from_nanos(16_742_706);
+// Terminals can't keep up with 60 big frames a second
+const DRAW_TIME: Duration = Duration::from_millis(33);
+// How long a typed key holds its button
+const HOLD_FRAMES: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminalMode {
+    Blocks,
+    Braille,
+    Sixel,
+}
+
+impl FromStr for TerminalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TerminalMode, String> {
+        match s {
+            "blocks" => Ok(TerminalMode::Blocks),
+            "braille" => Ok(TerminalMode::Braille),
+            "sixel" => Ok(TerminalMode::Sixel),
+            _ => Err(format!("Unknown terminal mode \"{}\", expected blocks, braille or sixel", s)),
+        }
+    }
+}
+
+impl TerminalMode {
+    // The frame as text to print with the cursor at the top left
+    pub fn draw(&self, frame: &ScreenData) -> String {
+        match *self {
+            TerminalMode::Blocks => half_blocks(frame),
+            TerminalMode::Braille => braille(frame),
+            TerminalMode::Sixel => sixel(frame),
+        }
+    }
+}
+
+fn pixel(frame: &ScreenData, x: usize, y: usize) -> [u8; 3] {
+    let i = (y * WIDTH + x) * 4;
+    [frame[i], frame[i + 1], frame[i + 2]]
+}
+
+// Each character is the pixel above in the foreground color and the one below
+// in the background color. Colors are only sent when they change
+pub fn half_blocks(frame: &ScreenData) -> String {
+    let mut out = String::new();
+    for y in (0..HEIGHT).step_by(2) {
+        let mut last = None;
+        for x in 0..WIDTH {
+            let colors = (pixel(frame, x, y), pixel(frame, x, y + 1));
+            if last != Some(colors) {
+                let (top, bottom) = colors;
+                out.push_str(&format!("\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]));
+                last = Some(colors);
+            }
+            out.push('▀');
+        }
+        out.push_str("\x1b[0m\r\n");
+    }
+    out
+}
+
+// Darker than the middle gray is a dot
+fn is_dark(color: [u8; 3]) -> bool {
+    (color[0] as u32 * 299 + color[1] as u32 * 587 + color[2] as u32 * 114) / 1000 < 128
+}
+
+// Braille characters have 8 dots, two columns of 4, each a bit from U+2800
+pub fn braille(frame: &ScreenData) -> String {
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    let mut out = String::new();
+    for y in (0..HEIGHT).step_by(4) {
+        for x in (0..WIDTH).step_by(2) {
+            let mut bits = 0;
+            for dy in 0..4 {
+                for dx in 0..2 {
+                    if is_dark(pixel(frame, x + dx, y + dy)) {
+                        bits |= DOTS[dy][dx];
+                    }
+                }
+            }
+            out.push(::std::char::from_u32(0x2800 + bits).unwrap());
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+// A sixel image: the colors first, then bands 6 pixels high, each one a row
+// of characters per color with a bit for every pixel of that column in it.
+// Runs of the same character are sent as !<count><char>
+pub fn sixel(frame: &ScreenData) -> String {
+    // Up to 256 registers, a frame from a CGB game could have more colors
+    // than that, those get the closest one already in
+    let mut colors: Vec<[u8; 3]> = Vec::new();
+    let mut indices = vec![0u8; WIDTH * HEIGHT];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let color = pixel(frame, x, y);
+            let i = match colors.iter().position(|&c| c == color) {
+                Some(i) => i,
+                None if colors.len() < 256 => {
+                    colors.push(color);
+                    colors.len() - 1
+                }
+                None => closest(&colors, color),
+            };
+            indices[y * WIDTH + x] = i as u8;
+        }
+    }
+
+    let mut out = String::from("\x1bPq");
+    out.push_str(&format!("\"1;1;{};{}", WIDTH, HEIGHT));
+    for (i, c) in colors.iter().enumerate() {
+        let pct = |v: u8| v as u32 * 100 / 255;
+        out.push_str(&format!("#{};2;{};{};{}", i, pct(c[0]), pct(c[1]), pct(c[2])));
+    }
+    for band in (0..HEIGHT).step_by(6) {
+        for color in 0..colors.len() {
+            let row: Vec<u8> = (0..WIDTH).map(|x| {
+                (0..6).filter(|&dy| band + dy < HEIGHT && indices[(band + dy) * WIDTH + x] as usize == color)
+                    .fold(0, |bits, dy| bits | 1 << dy)
+            }).collect();
+            if row.iter().all(|&bits| bits == 0) {
+                continue;
+            }
+            out.push_str(&format!("#{}", color));
+            let mut x = 0;
+            while x < WIDTH {
+                let run = row[x..].iter().take_while(|&&bits| bits == row[x]).count();
+                let c = (63 + row[x]) as char;
+                if run > 3 {
+                    out.push_str(&format!("!{}{}", run, c));
+                } else {
+                    for _ in 0..run {
+                        out.push(c);
+                    }
+                }
+                x += run;
+            }
+            // Back to the start of the band for the next color
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+fn closest(colors: &[[u8; 3]], color: [u8; 3]) -> usize {
+    let dist = |c: &[u8; 3]| (0..3).map(|i| (c[i] as i32 - color[i] as i32).pow(2)).sum::<i32>();
+    (0..colors.len()).min_by_key(|&i| dist(&colors[i])).unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TermKey {
+    A, B, Select, Start, Up, Down, Left, Right, Quit,
+}
+
+// Keys in what the terminal sent, arrows come as ESC [ A to D
+pub fn parse_keys(bytes: &[u8]) -> Vec<TermKey> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let key = match bytes[i] {
+            0x1b if bytes.get(i + 1) == Some(&b'[') && i + 2 < bytes.len() => {
+                i += 2;
+                match bytes[i] {
+                    b'A' => Some(TermKey::Up),
+                    b'B' => Some(TermKey::Down),
+                    b'C' => Some(TermKey::Right),
+                    b'D' => Some(TermKey::Left),
+                    _ => None,
+                }
+            }
+            b'z' | b'Z' => Some(TermKey::A),
+            b'x' | b'X' => Some(TermKey::B),
+            b' ' => Some(TermKey::Select),
+            b'\r' | b'\n' => Some(TermKey::Start),
+            b'q' | b'Q' => Some(TermKey::Quit),
+            _ => None,
+        };
+        keys.extend(key);
+        i += 1;
+    }
+    keys
+}
+
+// Frames each button is still held for
+#[derive(Default)]
+struct HeldKeys {
+    frames: [u32; 8],
+}
+
+impl HeldKeys {
+    fn press(&mut self, key: TermKey) {
+        let i = match key {
+            TermKey::A => 0, TermKey::B => 1, TermKey::Select => 2, TermKey::Start => 3,
+            TermKey::Up => 4, TermKey::Down => 5, TermKey::Left => 6, TermKey::Right => 7,
+            TermKey::Quit => return,
+        };
+        self.frames[i] = HOLD_FRAMES;
+    }
+
+    // The buttons for the next frame
+    fn next(&mut self) -> Joypad {
+        let f = self.frames;
+        for n in self.frames.iter_mut() {
+            *n = n.saturating_sub(1);
+        }
+        Joypad {
+            a: f[0] > 0, b: f[1] > 0, select: f[2] > 0, start: f[3] > 0,
+            up: f[4] > 0, down: f[5] > 0, left: f[6] > 0, right: f[7] > 0,
+        }
+    }
+}
+
+// Bytes typed, read on their own thread since reading blocks
+fn read_stdin() -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Keys as soon as they're typed and not echoed, through stty
+fn raw_mode(on: bool) {
+    let args = if on {"-icanon -echo min 1"} else {"icanon echo"};
+    if let Err(err) = Command::new("sh").arg("-c").arg(format!("stty {} < /dev/tty", args)).status() {
+        warn!("Couldn't set up the terminal: {}", err);
+    }
+}
+
+pub fn run(config: &Config, mode: TerminalMode) -> Result<(), String> {
+    let mut emu = Emulator::new(config);
+    let keys = read_stdin();
+    let mut held = HeldKeys::default();
+
+    raw_mode(true);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    // Clear, hide the cursor
+    try!(write!(out, "\x1b[2J\x1b[?25l").map_err(|e| e.to_string()));
+
+    let mut next_frame = Instant::now();
+    let mut last_draw = Instant::now() - DRAW_TIME;
+    let mut drawn_frame = None;
+    'running: loop {
+        while let Ok(bytes) = keys.try_recv() {
+            for key in parse_keys(&bytes) {
+                if key == TermKey::Quit {
+                    break 'running;
+                }
+                held.press(key);
+            }
+        }
+
+        // Don't try to make up for a long stall
+        if Instant::now() > next_frame + FRAME_TIME * 5 {
+            next_frame = Instant::now();
+        }
+        while Instant::now() >= next_frame {
+            emu.step_frame(held.next());
+            next_frame += FRAME_TIME;
+        }
+
+        let frame = emu.mem.gpu.frames();
+        if drawn_frame != Some(frame) && last_draw.elapsed() >= DRAW_TIME {
+            let text = mode.draw(emu.mem.gpu.screen());
+            if write!(out, "\x1b[H{}", text).and_then(|_| out.flush()).is_err() {
+                break;
+            }
+            drawn_frame = Some(frame);
+            last_draw = Instant::now();
+        }
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        }
+    }
+
+    let _ = write!(out, "\x1b[0m\x1b[?25h\r\n");
+    raw_mode(false);
+    emu.flush_save();
+    emu.finish_profile();
+    Ok(())
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod terminal_tests {
+    use super::*;
+
+    fn frame<F: Fn(usize, usize) -> [u8; 4]>(f: F) -> ScreenData {
+        let mut frame = [0u8; WIDTH * HEIGHT * 4];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let i = (y * WIDTH + x) * 4;
+                frame[i..i + 4].copy_from_slice(&f(x, y));
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn renderers() {
+        // White on top of black stripes
+        let stripes = frame(|_, y| if y % 2 == 0 {[255; 4]} else {[0, 0, 0, 255]});
+        let text = half_blocks(&stripes);
+        assert!(text.starts_with("\x1b[38;2;255;255;255;48;2;0;0;0m▀▀"));
+        // One color change a line
+        assert_eq!(text.matches("\x1b[38;2").count(), HEIGHT / 2);
+        assert_eq!(text.lines().count(), HEIGHT / 2);
+
+        // Dark on the left half of each character
+        let columns = frame(|x, _| if x % 2 == 0 {[0, 0, 0, 255]} else {[255; 4]});
+        let text = braille(&columns);
+        assert!(text.starts_with("⡇⡇"));
+        assert_eq!(text.lines().next().unwrap().chars().count(), WIDTH / 2);
+
+        let text = sixel(&stripes);
+        assert!(text.starts_with("\x1bPq\"1;1;160;144#0;2;100;100;100#1;2;0;0;0#0!160"));
+        assert!(text.ends_with("-\x1b\\"));
+        assert_eq!("braille".parse(), Ok(TerminalMode::Braille));
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_keys(b"z\x1b[Ax\rq"),
+            vec![TermKey::A, TermKey::Up, TermKey::B, TermKey::Start, TermKey::Quit]);
+        let mut held = HeldKeys::default();
+        held.press(TermKey::Left);
+        assert!(held.next().left);
+        for _ in 1..HOLD_FRAMES {
+            held.next();
+        }
+        assert!(!held.next().left);
+    }
+}
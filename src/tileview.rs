@@ -0,0 +1,128 @@
+//
+//      Tile viewer
+//
+
+// All the tiles in VRAM (0x8000-0x97FF) in rows of 16, refreshed every frame
+// and shown over the screen with V. With LCDC bit 4 set tiles are numbered
+// from the top (0x8000), with it clear from the middle third (0x9000) and the
+// ones above it count as negative. On the CGB, bank 1 goes to the right of
+// bank 0. Pointing at a tile tells its address and both indices.
+
+use gpu::Gpu;
+
+const TILES_WIDE: usize = 16;
+const TILE_COUNT: usize = 384;
+const BANK_WIDTH: usize = TILES_WIDE * 8;
+const SHEET_HEIGHT: usize = TILE_COUNT / TILES_WIDE * 8;
+
+// Size of the sheet with room for both banks
+pub const DIMS: [u32; 2] = [BANK_WIDTH as u32 * 2, SHEET_HEIGHT as u32];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileInfo {
+    pub bank: u8,
+    // Tile number from 0x8000
+    pub tile: usize,
+}
+
+impl TileInfo {
+    pub fn addr(&self) -> u16 {
+        0x8000 + self.tile as u16 * 16
+    }
+
+    // The index that picks the tile with LCDC bit 4 set, if any
+    pub fn index_8000(&self) -> Option<u8> {
+        if self.tile < 256 { Some(self.tile as u8) } else { None }
+    }
+
+    // The signed index that picks the tile with LCDC bit 4 clear, if any
+    pub fn index_8800(&self) -> Option<u8> {
+        if self.tile >= 128 { Some(self.tile as u8) } else { None }
+    }
+
+    pub fn describe(&self) -> String {
+        let index = |i: Option<u8>| i.map_or(String::from("--"), |i| format!("{:02X}", i));
+        format!("{:04X}:{}  tile {} / {}", self.addr(), self.bank,
+            index(self.index_8000()), index(self.index_8800()))
+    }
+}
+
+pub struct TileViewer {
+    // RGBA, DIMS in size. Bank 1 is left transparent on the DMG
+    pub pixels: Vec<u8>,
+    banks: usize,
+}
+
+impl TileViewer {
+    pub fn new() -> TileViewer {
+        TileViewer {
+            pixels: vec![0; (DIMS[0] * DIMS[1] * 4) as usize],
+            banks: 1,
+        }
+    }
+
+    // Redraw the tiles from VRAM, in the shades of the current palette
+    pub fn update(&mut self, gpu: &Gpu) {
+        self.banks = if gpu.is_cgb {2} else {1};
+        let colors = gpu.base_bg_palette();
+        for bank in 0..self.banks {
+            let vram = if bank == 0 {&gpu.vrambank} else {&gpu.vrambank1};
+            for tile in 0..TILE_COUNT {
+                for y in 0..8 {
+                    let (lsb, msb) = (vram[tile * 16 + y * 2], vram[tile * 16 + y * 2 + 1]);
+                    for x in 0..8 {
+                        let colori = (msb >> (7 - x) & 1) << 1 | lsb >> (7 - x) & 1;
+                        let px = bank * BANK_WIDTH + tile % TILES_WIDE * 8 + x;
+                        let py = tile / TILES_WIDE * 8 + y;
+                        let i = (py * DIMS[0] as usize + px) * 4;
+                        self.pixels[i..i + 4].copy_from_slice(&colors[colori as usize]);
+                    }
+                }
+            }
+        }
+    }
+
+    // The tile at a point of the sheet, in its pixels
+    pub fn tile_at(&self, x: f64, y: f64) -> Option<TileInfo> {
+        if x < 0. || y < 0. || x >= (BANK_WIDTH * self.banks) as f64 || y >= SHEET_HEIGHT as f64 {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        Some(TileInfo {
+            bank: (x / BANK_WIDTH) as u8,
+            tile: y / 8 * TILES_WIDE + x % BANK_WIDTH / 8,
+        })
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod tileview_tests {
+    use super::*;
+
+    #[test]
+    fn hover() {
+        let mut gpu = Gpu::new();
+        // Tile 0x81 all color 3
+        for i in 0..16 {
+            gpu.vrambank[0x810 + i] = 0xFF;
+        }
+        let mut viewer = TileViewer::new();
+        viewer.update(&gpu);
+
+        let tile = viewer.tile_at(8. + 3., 64. + 7.).unwrap();
+        assert_eq!(tile, TileInfo { bank: 0, tile: 0x81 });
+        assert_eq!(tile.describe(), "8810:0  tile 81 / 81");
+        let i = (71 * DIMS[0] as usize + 11) * 4;
+        assert_eq!(&viewer.pixels[i..i + 4], &gpu.base_bg_palette()[3]);
+
+        let last = viewer.tile_at(127., 191.).unwrap();
+        assert_eq!(last.describe(), "97F0:0  tile -- / 7F");
+        assert_eq!(viewer.tile_at(0., 0.).unwrap().index_8800(), None);
+        // No bank 1 on the DMG
+        assert_eq!(viewer.tile_at(130., 0.), None);
+    }
+}
@@ -10,6 +10,7 @@ const DIV_AFTER_BIOS: u16 = 0xABCC;
 pub struct Timer {
     // This register is incremented at rate of 16384Hz
     // Writing any value to this register resets it to 00h
+    // It's the top byte of this, which counts every clock
     pub div: u16,
     // This timer is incremented by a clock frequency specified by the TAC register ($FF07)
     // When the value overflows (gets bigger than FFh) then it will be reset to the
@@ -45,17 +46,23 @@ impl Timer {
     }
 
     pub fn step(&mut self, ticks: u32, if_: &mut u8) {
-        self.div = self.div.wrapping_add(1);
-        if (self.tac & 0b100) != 0 {
-            // Check for 8-bit overflow
-            if ((self.tima as u16) + 1) & 0xFF == 0 {
-                self.tima = self.tma;
-
-                // Fire Timer interrupt
-                *if_ |= Interrupt::Timer as u8;
-            } else {
-                // Increment TIMA
-                self.tima += 1;
+        // TIMA counts when the bit of the DIV counter that toggles at its
+        // rate (tima_speed is in machine cycles) goes from 1 to 0
+        let bit = (self.tima_speed * 4 / 2) as u16;
+        for _ in 0..ticks {
+            let before = self.div;
+            self.div = self.div.wrapping_add(1);
+            if (self.tac & 0b100) != 0 && before & bit != 0 && self.div & bit == 0 {
+                // Check for 8-bit overflow
+                if self.tima == 0xFF {
+                    self.tima = self.tma;
+
+                    // Fire Timer interrupt
+                    *if_ |= Interrupt::Timer as u8;
+                } else {
+                    // Increment TIMA
+                    self.tima += 1;
+                }
             }
         }
     }
@@ -77,4 +84,48 @@ impl fmt::Debug for Timer {
             self.tima_speed,
             )
     }
-}
\ No newline at end of file
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod timer_tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        let mut timer = Timer::new();
+        let mut if_ = 0;
+        timer.div = 0;
+        timer.step(256, &mut if_);
+        assert_eq!(timer.div >> 8, 1);
+
+        // 262144 Hz, every 16 clocks, however they're split up
+        timer.div = 0;
+        timer.tac = 0b101;
+        timer.update();
+        timer.step(12, &mut if_);
+        assert_eq!(timer.tima, 0);
+        timer.step(4, &mut if_);
+        timer.step(160, &mut if_);
+        assert_eq!(timer.tima, 11);
+
+        // Overflow reloads TMA and interrupts
+        timer.tima = 0xFF;
+        timer.tma = 0x42;
+        timer.step(16, &mut if_);
+        assert_eq!((timer.tima, if_), (0x42, Interrupt::Timer as u8));
+
+        // 4096 Hz
+        timer.tac = 0b100;
+        timer.update();
+        timer.div = 0;
+        timer.tima = 0;
+        timer.step(1020, &mut if_);
+        assert_eq!(timer.tima, 0);
+        timer.step(4, &mut if_);
+        assert_eq!(timer.tima, 1);
+    }
+}
@@ -0,0 +1,238 @@
+//
+//      On-screen touch controls
+//
+
+// A d-pad and A/B/Start/Select drawn over the screen for touchscreens. Every
+// finger holds what's under it and can slide onto other buttons, the d-pad
+// picks the direction (or diagonal) from where it's touched relative to its
+// center. The buttons held change the first controller like keys do, so a
+// keyboard or gamepad keeps working alongside.
+//
+// Layout files have a control per line, in fractions of the screen:
+//   <dpad|a|b|start|select> <x> <y> <width> <height>
+// and `#` comments.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use piston::input::{Touch, TouchArgs};
+
+use input::{Input, Joypad};
+
+pub const DEFAULT_OPACITY: f32 = 0.4;
+// Distance from the d-pad's center, relative to its size, under which no
+// direction is held
+const DPAD_DEAD_ZONE: f64 = 0.15;
+// A direction counts along with the other one when it's at least this much of
+// it, so touching between two directions holds both
+const DIAGONAL_RATIO: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TouchButton {
+    DPad,
+    A,
+    B,
+    Start,
+    Select,
+}
+
+// A control's area, in fractions of the screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Control {
+    pub button: TouchButton,
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+}
+
+impl Control {
+    fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x < self.x + self.w && y >= self.y && y < self.y + self.h
+    }
+
+    // Holds the button(s) for a touch at x, y inside the control
+    fn press(&self, x: f64, y: f64, pad: &mut Joypad) {
+        match self.button {
+            TouchButton::A => pad.a = true,
+            TouchButton::B => pad.b = true,
+            TouchButton::Start => pad.start = true,
+            TouchButton::Select => pad.select = true,
+            TouchButton::DPad => {
+                // -1 to 1 from the center
+                let dx = (x - self.x) / self.w * 2. - 1.;
+                let dy = (y - self.y) / self.h * 2. - 1.;
+                let major = dx.abs().max(dy.abs());
+                if major < DPAD_DEAD_ZONE * 2. {
+                    return;
+                }
+                if dx.abs() >= major * DIAGONAL_RATIO {
+                    if dx < 0. { pad.left = true } else { pad.right = true }
+                }
+                if dy.abs() >= major * DIAGONAL_RATIO {
+                    if dy < 0. { pad.up = true } else { pad.down = true }
+                }
+            }
+        }
+    }
+}
+
+pub fn default_layout() -> Vec<Control> {
+    vec![
+        Control { button: TouchButton::DPad,   x: 0.03, y: 0.55, w: 0.30, h: 0.33 },
+        Control { button: TouchButton::B,      x: 0.62, y: 0.66, w: 0.15, h: 0.17 },
+        Control { button: TouchButton::A,      x: 0.80, y: 0.57, w: 0.15, h: 0.17 },
+        Control { button: TouchButton::Select, x: 0.33, y: 0.91, w: 0.15, h: 0.07 },
+        Control { button: TouchButton::Start,  x: 0.52, y: 0.91, w: 0.15, h: 0.07 },
+    ]
+}
+
+pub fn parse_layout(src: &str) -> Result<Vec<Control>, String> {
+    let mut controls = Vec::new();
+    for (n, line) in src.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 5 {
+            return Err(format!("Line {}: expected <button> <x> <y> <width> <height>", n + 1));
+        }
+        let button = match words[0].to_lowercase().as_str() {
+            "dpad" => TouchButton::DPad,
+            "a" => TouchButton::A,
+            "b" => TouchButton::B,
+            "start" => TouchButton::Start,
+            "select" => TouchButton::Select,
+            other => return Err(format!("Line {}: unknown button \"{}\"", n + 1, other)),
+        };
+        let mut nums = [0f64; 4];
+        for (num, word) in nums.iter_mut().zip(words[1..].iter()) {
+            *num = try!(word.parse().map_err(|_| format!("Line {}: invalid number \"{}\"", n + 1, word)));
+        }
+        controls.push(Control { button: button, x: nums[0], y: nums[1], w: nums[2], h: nums[3] });
+    }
+    Ok(controls)
+}
+
+pub fn load_layout(path: &str) -> Result<Vec<Control>, String> {
+    let mut src = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut src)).map_err(|e| e.to_string()));
+    parse_layout(&src)
+}
+
+pub struct TouchControls {
+    pub controls: Vec<Control>,
+    pub opacity: f32,
+    // What every finger on the screen holds
+    fingers: HashMap<i64, Joypad>,
+    held: Joypad,
+}
+
+impl TouchControls {
+    pub fn new(controls: Vec<Control>, opacity: f32) -> TouchControls {
+        TouchControls {
+            controls: controls,
+            opacity: opacity,
+            fingers: HashMap::new(),
+            held: Joypad::default(),
+        }
+    }
+
+    // Touch positions are normalized to the window
+    pub fn touch(&mut self, args: &TouchArgs, input: &mut Input) {
+        match args.touch {
+            Touch::Start | Touch::Move => {
+                let mut pad = Joypad::default();
+                for control in self.controls.iter().filter(|c| c.contains(args.x, args.y)) {
+                    control.press(args.x, args.y, &mut pad);
+                }
+                self.fingers.insert(args.id, pad);
+            }
+            Touch::End | Touch::Cancel => {
+                self.fingers.remove(&args.id);
+            }
+        }
+
+        let held = self.fingers.values().fold(Joypad::default(), |all, pad| Joypad {
+            a: all.a || pad.a,
+            b: all.b || pad.b,
+            select: all.select || pad.select,
+            start: all.start || pad.start,
+            up: all.up || pad.up,
+            down: all.down || pad.down,
+            left: all.left || pad.left,
+            right: all.right || pad.right,
+        });
+        input.set_touch(&held);
+        self.held = held;
+    }
+
+    // Whether any of the control's buttons are held, to highlight it
+    pub fn is_held(&self, control: &Control) -> bool {
+        let h = &self.held;
+        match control.button {
+            TouchButton::A => h.a,
+            TouchButton::B => h.b,
+            TouchButton::Start => h.start,
+            TouchButton::Select => h.select,
+            TouchButton::DPad => h.up || h.down || h.left || h.right,
+        }
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod touch_tests {
+    use super::*;
+    use piston::input::Key;
+
+    fn finger(id: i64, x: f64, y: f64, touch: Touch) -> TouchArgs {
+        TouchArgs { device: 0, id: id, x: x, y: y, pressure: 1., is_3d: false, touch: touch }
+    }
+
+    #[test]
+    fn multitouch() {
+        let layout = parse_layout("dpad 0 0 0.5 1  # left half\na 0.5 0 0.5 1\n").unwrap();
+        let mut touch = TouchControls::new(layout, DEFAULT_OPACITY);
+        let mut input = Input::new();
+
+        // Up and left on the d-pad while holding A
+        touch.touch(&finger(1, 0.05, 0.05, Touch::Start), &mut input);
+        touch.touch(&finger(2, 0.75, 0.5, Touch::Start), &mut input);
+        assert!(touch.held.up && touch.held.left && touch.held.a && !touch.held.down);
+        // A is held on the controller too
+        input.wb(0x10);
+        assert_eq!(input.rb() & 0x1, 0);
+
+        // Sliding to the right edge, straight right
+        touch.touch(&finger(1, 0.45, 0.5, Touch::Move), &mut input);
+        assert!(touch.held.right && !touch.held.up && !touch.held.left);
+
+        touch.touch(&finger(2, 0.75, 0.5, Touch::End), &mut input);
+        assert!(!touch.held.a);
+        // Center of the d-pad holds nothing
+        touch.touch(&finger(1, 0.25, 0.5, Touch::Move), &mut input);
+        assert_eq!(touch.held, Joypad::default());
+
+        // Letting go of A on the screen while it's held on the keyboard
+        // leaves it held
+        input.key_press(&Key::Z);
+        touch.touch(&finger(2, 0.75, 0.5, Touch::Start), &mut input);
+        touch.touch(&finger(2, 0.75, 0.5, Touch::End), &mut input);
+        assert_eq!(input.rb() & 0x1, 0);
+        // And the other way around
+        touch.touch(&finger(2, 0.75, 0.5, Touch::Start), &mut input);
+        input.key_release(&Key::Z);
+        assert_eq!(input.rb() & 0x1, 0);
+        touch.touch(&finger(2, 0.75, 0.5, Touch::End), &mut input);
+        assert_eq!(input.rb() & 0x1, 1);
+
+        assert!(parse_layout("z 0 0 1 1").is_err());
+        assert!(parse_layout("a 0 0 1").is_err());
+    }
+}
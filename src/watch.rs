@@ -0,0 +1,331 @@
+//
+//      Watch expressions
+//
+
+// Expressions evaluated at the end of every frame and shown in the debug
+// panel, e.g. `[0xC0A0]`, `HL` or `[WRAM+0x123]*256+[WRAM+0x124]`.
+// Numbers are decimal unless prefixed with 0x or $, [addr] reads a byte and
+// WRAM, VRAM, SRAM, OAM, IO and HRAM are the start of those regions.
+// The values can also be logged to a CSV file, a row per frame.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use cpu::Registers;
+use mmu::Memory;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Num(i64),
+    Reg(&'static str),
+    Mem(Box<Expr>),
+    Neg(Box<Expr>),
+    Bin(char, Box<Expr>, Box<Expr>),
+}
+
+const REGISTERS: [&'static str; 14] =
+    ["A", "F", "B", "C", "D", "E", "H", "L", "AF", "BC", "DE", "HL", "SP", "PC"];
+
+const REGIONS: [(&'static str, i64); 6] = [
+    ("VRAM", 0x8000), ("SRAM", 0xA000), ("WRAM", 0xC000),
+    ("OAM", 0xFE00), ("IO", 0xFF00), ("HRAM", 0xFF80),
+];
+
+// Binary operators from loosest to tightest, shifts are '<' and '>'
+const PRECEDENCE: [&'static str; 6] = ["|", "^", "&", "<>", "+-", "*/%"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(char),
+    Open(char),
+    Close(char),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_digit(10) || c == '$' {
+            let (radix, start) = if c == '$' {
+                (16, i + 1)
+            } else if c == '0' && i + 1 < chars.len() && (chars[i + 1] == 'x' || chars[i + 1] == 'X') {
+                (16, i + 2)
+            } else {
+                (10, i)
+            };
+            let mut end = start;
+            while end < chars.len() && chars[end].is_digit(radix) {
+                end += 1;
+            }
+            let digits: String = chars[start..end].iter().cloned().collect();
+            let n = try!(i64::from_str_radix(&digits, radix)
+                .map_err(|_| format!("Invalid number at column {}", i + 1)));
+            tokens.push(Token::Num(n));
+            i = end;
+        } else if c.is_alphabetic() {
+            let mut end = i;
+            while end < chars.len() && chars[end].is_alphanumeric() {
+                end += 1;
+            }
+            tokens.push(Token::Ident(chars[i..end].iter().cloned().collect::<String>().to_uppercase()));
+            i = end;
+        } else if (c == '<' || c == '>') && i + 1 < chars.len() && chars[i + 1] == c {
+            tokens.push(Token::Op(c));
+            i += 2;
+        } else if "|^&+-*/%".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else if c == '(' || c == '[' {
+            tokens.push(Token::Open(c));
+            i += 1;
+        } else if c == ')' || c == ']' {
+            tokens.push(Token::Close(c));
+            i += 1;
+        } else {
+            return Err(format!("Unexpected '{}' at column {}", c, i + 1));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1).cloned()
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = try!(self.binary(level + 1));
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Op(op)) if PRECEDENCE[level].contains(op) => op,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = try!(self.binary(level + 1));
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Op('-')) => Ok(Expr::Neg(Box::new(try!(self.unary())))),
+            Some(Token::Ident(name)) => {
+                if let Some(reg) = REGISTERS.iter().find(|r| **r == name) {
+                    return Ok(Expr::Reg(reg));
+                }
+                match REGIONS.iter().find(|r| r.0 == name) {
+                    Some(&(_, addr)) => Ok(Expr::Num(addr)),
+                    None => Err(format!("Unknown name \"{}\"", name)),
+                }
+            }
+            Some(Token::Open(open)) => {
+                let inner = try!(self.binary(0));
+                let close = if open == '[' { ']' } else { ')' };
+                if self.next() != Some(Token::Close(close)) {
+                    return Err(format!("Missing '{}'", close));
+                }
+                Ok(if open == '[' { Expr::Mem(Box::new(inner)) } else { inner })
+            }
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err(String::from("Unexpected end of expression")),
+        }
+    }
+}
+
+fn parse(src: &str) -> Result<Expr, String> {
+    let mut parser = Parser { tokens: try!(tokenize(src)), pos: 0 };
+    let expr = try!(parser.binary(0));
+    match parser.peek() {
+        Some(token) => Err(format!("Unexpected {:?}", token)),
+        None => Ok(expr),
+    }
+}
+
+// Check an expression without evaluating it
+pub fn validate(src: &str) -> Result<(), String> {
+    parse(src).map(|_| ())
+}
+
+fn eval(expr: &Expr, regs: &Registers, mem: &Memory) -> Result<i64, String> {
+    Ok(match *expr {
+        Expr::Num(n) => n,
+        Expr::Reg(name) => match name {
+            "A" => (regs.af() >> 8) as i64,
+            "F" => (regs.af() & 0xFF) as i64,
+            "B" => (regs.bc() >> 8) as i64,
+            "C" => (regs.bc() & 0xFF) as i64,
+            "D" => (regs.de() >> 8) as i64,
+            "E" => (regs.de() & 0xFF) as i64,
+            "H" => (regs.hl() >> 8) as i64,
+            "L" => (regs.hl() & 0xFF) as i64,
+            "AF" => regs.af() as i64,
+            "BC" => regs.bc() as i64,
+            "DE" => regs.de() as i64,
+            "HL" => regs.hl() as i64,
+            "SP" => regs.sp() as i64,
+            _ => regs.pc() as i64,
+        },
+        Expr::Mem(ref addr) => mem.peek(try!(eval(addr, regs, mem)) as u16) as i64,
+        Expr::Neg(ref e) => try!(eval(e, regs, mem)).wrapping_neg(),
+        Expr::Bin(op, ref lhs, ref rhs) => {
+            let (a, b) = (try!(eval(lhs, regs, mem)), try!(eval(rhs, regs, mem)));
+            match op {
+                '|' => a | b,
+                '^' => a ^ b,
+                '&' => a & b,
+                '<' => a.wrapping_shl(b as u32),
+                '>' => a.wrapping_shr(b as u32),
+                '+' => a.wrapping_add(b),
+                '-' => a.wrapping_sub(b),
+                '*' => a.wrapping_mul(b),
+                _ if b == 0 => return Err(String::from("Division by zero")),
+                '/' => a.wrapping_div(b),
+                _ => a.wrapping_rem(b),
+            }
+        }
+    })
+}
+
+pub struct Watch {
+    pub source: String,
+    expr: Expr,
+    pub value: Result<i64, String>,
+}
+
+pub struct WatchList {
+    pub watches: Vec<Watch>,
+    csv: Option<File>,
+    // The columns changed since the last CSV header
+    header_stale: bool,
+}
+
+impl WatchList {
+    pub fn new() -> WatchList {
+        WatchList { watches: Vec::new(), csv: None, header_stale: false }
+    }
+
+    pub fn add(&mut self, source: &str) -> Result<(), String> {
+        let expr = try!(parse(source));
+        self.watches.push(Watch {
+            source: source.trim().to_string(),
+            expr: expr,
+            value: Err(String::from("not evaluated yet")),
+        });
+        self.header_stale = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watch> {
+        if index >= self.watches.len() {
+            return None;
+        }
+        self.header_stale = true;
+        Some(self.watches.remove(index))
+    }
+
+    pub fn log_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.csv = Some(try!(File::create(path)));
+        self.header_stale = true;
+        Ok(())
+    }
+
+    // Evaluate everything at the end of a frame
+    pub fn update(&mut self, frame: u32, regs: &Registers, mem: &Memory) {
+        self.refresh(regs, mem);
+        if let Err(err) = self.write_csv(frame) {
+            error!("Couldn't log watch expressions, stopping: {}", err);
+            self.csv = None;
+        }
+    }
+
+    // Evaluate without logging
+    pub fn refresh(&mut self, regs: &Registers, mem: &Memory) {
+        for watch in self.watches.iter_mut() {
+            watch.value = eval(&watch.expr, regs, mem);
+        }
+    }
+
+    fn write_csv(&mut self, frame: u32) -> io::Result<()> {
+        let csv = match self.csv {
+            Some(ref mut csv) => csv,
+            None => return Ok(()),
+        };
+        if self.watches.is_empty() {
+            return Ok(());
+        }
+        // Adding or removing watches starts a new header
+        if self.header_stale {
+            let names: Vec<String> = self.watches.iter()
+                .map(|w| format!("\"{}\"", w.source.replace('"', "\"\"")))
+                .collect();
+            try!(writeln!(csv, "frame,{}", names.join(",")));
+            self.header_stale = false;
+        }
+        let values: Vec<String> = self.watches.iter()
+            .map(|w| w.value.as_ref().map(|v| v.to_string()).unwrap_or(String::new()))
+            .collect();
+        writeln!(csv, "{},{}", frame, values.join(","))
+    }
+}
+
+impl Watch {
+    pub fn display(&self) -> String {
+        match self.value {
+            Ok(v) => format!("{} = {} (${:X})", self.source, v, v),
+            Err(ref err) => format!("{} = <{}>", self.source, err),
+        }
+    }
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use cpu::Cpu;
+
+    fn eval_str(src: &str, mem: &Memory) -> Result<i64, String> {
+        let cpu = Cpu::new();
+        parse(src).and_then(|e| eval(&e, cpu.get_regs(), mem))
+    }
+
+    #[test]
+    fn expressions() {
+        let mut mem = Memory::new();
+        mem.wb(0xC123, 0x12);
+        mem.wb(0xC124, 0x34);
+
+        assert_eq!(eval_str("[WRAM+0x123]*256+[wram+$124]", &mem), Ok(0x1234));
+        assert_eq!(eval_str("HL", &mem), Ok(0x014D));
+        assert_eq!(eval_str("(1+2)*3 - -1", &mem), Ok(10));
+        assert_eq!(eval_str("1 << 4 | 1", &mem), Ok(0x11));
+        assert!(eval_str("1/0", &mem).is_err());
+        assert!(eval_str("[0xC000", &mem).is_err());
+        assert!(eval_str("XY", &mem).is_err());
+        assert!(eval_str("1 2", &mem).is_err());
+    }
+}
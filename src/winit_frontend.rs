@@ -0,0 +1,154 @@
+//
+//      winit + pixels frontend
+//
+
+// A lighter frontend than the Piston one, built with the winit-frontend
+// feature (cargo run --features winit-frontend). The window is a pixels
+// surface the size of the screen: every VBlank the frame is copied straight
+// into it and pixels scales it to the window on the GPU, keeping the shape.
+// The scaling itself is done by screen_shader.rs, which also draws the LCD
+// grid and the CRT look there, or runs the shader from --shader.
+//
+// It only plays: Z/X are A/B, Space is Select, Return is Start, the arrows
+// are the d-pad, Escape quits. The debug views, recording, HD packs and the
+// rest are only in the Piston frontend for now.
+
+use std::time::{Duration, Instant};
+
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::WindowBuilder;
+use pixels::{Pixels, SurfaceTexture};
+
+use config::Config;
+use screen_shader::ScreenShader;
+use emulator::Emulator;
+use gpu::{WIDTH, HEIGHT};
+use input::Joypad;
+
+// 70224 cycles a frame at 4194304 Hz
+const FRAME_TIME: Duration = Duration::from_nanos(16_742_706);
+// How often the --shader file is checked for changes
+const SHADER_POLL: Duration = Duration::from_millis(500);
+
+// Track a key on the joypad, false if it isn't one of the buttons
+fn press(joypad: &mut Joypad, key: VirtualKeyCode, held: bool) -> bool {
+    match key {
+        VirtualKeyCode::Z => joypad.a = held,
+        VirtualKeyCode::X => joypad.b = held,
+        VirtualKeyCode::Space => joypad.select = held,
+        VirtualKeyCode::Return => joypad.start = held,
+        VirtualKeyCode::Up => joypad.up = held,
+        VirtualKeyCode::Down => joypad.down = held,
+        VirtualKeyCode::Left => joypad.left = held,
+        VirtualKeyCode::Right => joypad.right = held,
+        _ => return false,
+    }
+    true
+}
+
+pub fn run(config: &Config, title: &str, scale: u32) -> Result<(), String> {
+    let mut emu = Emulator::new(config);
+
+    let event_loop = EventLoop::new();
+    let size = LogicalSize::new((WIDTH as u32 * scale) as f64, (HEIGHT as u32 * scale) as f64);
+    let window = try!(WindowBuilder::new()
+        .with_title(format!("{} - {}", title, emu.game_title()))
+        .with_inner_size(size)
+        .with_min_inner_size(LogicalSize::new(WIDTH as f64, HEIGHT as f64))
+        .build(&event_loop)
+        .map_err(|e| format!("Couldn't create the window: {}", e)));
+    let inner = window.inner_size();
+    let surface = SurfaceTexture::new(inner.width, inner.height, &window);
+    let mut pixels = try!(Pixels::new(WIDTH as u32, HEIGHT as u32, surface)
+        .map_err(|e| format!("Couldn't create the pixels surface: {}", e)));
+    let mut shader = ScreenShader::new(&pixels, config.lcd_grid, config.crt,
+        config.shader.as_ref().map(|s| &s[..]));
+    let mut shader_checked = Instant::now();
+
+    let mut joypad = Joypad::default();
+    let mut next_frame = Instant::now();
+    event_loop.run(move |event, _, control_flow| {
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(size) => {
+                    if let Err(err) = pixels.resize_surface(size.width, size.height) {
+                        error!("Couldn't resize the surface: {}", err);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    input: KeyboardInput { virtual_keycode: Some(key), state, .. }, ..
+                } => {
+                    if !press(&mut joypad, key, state == ElementState::Pressed) && key == VirtualKeyCode::Escape {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
+                _ => {}
+            },
+            // Run frames until the emulated time catches up
+            Event::MainEventsCleared => {
+                // Don't try to make up for a long stall
+                if Instant::now() > next_frame + FRAME_TIME * 5 {
+                    next_frame = Instant::now();
+                }
+                let mut frame_ready = false;
+                while Instant::now() >= next_frame {
+                    frame_ready |= emu.step_frame(joypad).frame_ready;
+                    next_frame += FRAME_TIME;
+                }
+                if frame_ready {
+                    pixels.frame_mut().copy_from_slice(&emu.mem.gpu.screen()[..]);
+                    window.request_redraw();
+                }
+                if shader_checked.elapsed() >= SHADER_POLL {
+                    shader_checked = Instant::now();
+                    if shader.reload(&pixels) {
+                        window.request_redraw();
+                    }
+                }
+                if *control_flow != ControlFlow::Exit {
+                    *control_flow = ControlFlow::WaitUntil(next_frame);
+                }
+            }
+            Event::RedrawRequested(_) => {
+                let drawn = pixels.render_with(|encoder, target, context| {
+                    shader.render(encoder, target, context);
+                    Ok(())
+                });
+                if let Err(err) = drawn {
+                    error!("Couldn't draw the frame: {}", err);
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            // Window closed, keep the battery save and the profile
+            Event::LoopDestroyed => {
+                emu.flush_save();
+                emu.finish_profile();
+            }
+            _ => {}
+        }
+    })
+}
+
+//  ======================================
+//  |               TESTS                |
+//  ======================================
+
+#[cfg(test)]
+mod winit_frontend_tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let mut joypad = Joypad::default();
+        assert!(press(&mut joypad, VirtualKeyCode::Z, true));
+        assert!(press(&mut joypad, VirtualKeyCode::Return, true));
+        assert!(!press(&mut joypad, VirtualKeyCode::Escape, true));
+        assert_eq!(joypad, Joypad { a: true, start: true, ..Joypad::default() });
+        press(&mut joypad, VirtualKeyCode::Z, false);
+        assert!(!joypad.a);
+    }
+}